@@ -1,19 +1,14 @@
-extern crate zxcvbn;
-
-use crate::auth::{api_error, util, ApiResponse};
+use crate::auth::{api_error, hashing, password_policy, util, ApiResponse};
 use actix_web::{
 	http, post,
 	web::{Data, Json},
 	Responder,
 };
-use argon2::{self, Config as ArgonConfig, ThreadMode, Variant, Version};
 use chrono::Utc;
 use entity::admins;
 use migration::{DbErr, OnConflict};
-use rand::{thread_rng, Rng};
 use sea_orm::{EntityTrait, Set};
 use uuid::Uuid;
-use zxcvbn::zxcvbn;
 
 use crate::AppState;
 
@@ -25,57 +20,20 @@ pub struct SignupBody {
 }
 
 #[post("/api/admin/create")]
-pub async fn handler(data: Data<AppState>, body: Json<SignupBody>) -> impl Responder {
-	// Check password strength
-	let estimate = match zxcvbn(&body.password, &[]) {
-		Ok(ent) => ent,
-		Err(_) => {
-			return (
-				Json(api_error(
-					"An invalid password was provided.".to_string(),
-					"INVALID_PASSWORD".to_string(),
-				)),
-				http::StatusCode::BAD_REQUEST,
-			);
-		}
-	};
-
-	let score = estimate.score();
-	if score < data.config.minimum_password_strength {
-		let feedback_msg = match estimate.feedback().clone() {
-			Some(w) => match w.warning() {
-				Some(w) => format!("The password provided is too weak. {w}",),
-				None => "The password provided is too weak.".to_string(),
-			},
-			None => "The password provided is too weak.".to_string(),
-		};
-		return (
-			Json(api_error(feedback_msg, "WEAK_PASSWORD".to_string())),
-			http::StatusCode::BAD_REQUEST,
-		);
+pub async fn handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<SignupBody>,
+) -> impl Responder {
+	// Admins have no security events, so breached passwords that only warn are accepted
+	if let Err(response) = password_policy::check(&data, &body.password, &body.email, None).await {
+		return response;
 	}
 
 	// Get uid for new admin
 	let user_uid = Uuid::new_v4();
 
-	let config = ArgonConfig {
-		variant: Variant::Argon2id,
-		version: Version::Version13,
-		mem_cost: data.config.argon2_config.memory,
-		time_cost: data.config.argon2_config.iterations,
-		lanes: data.config.argon2_config.parallelism,
-		thread_mode: ThreadMode::Parallel,
-		secret: &[],
-		ad: &[],
-		hash_length: data.config.argon2_config.tag_length,
-	};
-
-	let salt: Vec<u8> = (0..data.config.argon2_config.salt_length)
-		.map(|_| thread_rng().gen_range(0..255))
-		.collect();
-
-	let password_hash =
-		argon2::hash_encoded(body.password.as_bytes(), salt.as_slice(), &config).unwrap();
+	let password_hash = hashing::hash(&body.password, &data.config);
 
 	// FIXME: Vulnerable until sanitize middleware is implemented
 	let new_user = admins::ActiveModel {
@@ -105,8 +63,15 @@ pub async fn handler(data: Data<AppState>, body: Json<SignupBody>) -> impl Respo
 			if body.login {
 				let uid_str = user_uid.to_string();
 
-				let (token_str, rt_str, short_exp) =
-					util::get_at_and_rt(&data.connection, &uid_str, &data.config.secret_key, true).await;
+				let (token_str, rt_str, short_exp) = util::get_at_and_rt(
+					&data.connection,
+					&uid_str,
+					&data.key_ring,
+					&data.config.tokens,
+					true,
+					util::Session::new(&request, &data.ua_parser),
+				)
+				.await;
 
 				(
 					Json(ApiResponse::LoginResponse {
@@ -0,0 +1,179 @@
+//! Claims that admins set for a user, which are added to every access token the user gets. Unlike
+//! `metadata`, users can't change them, so other services can authorize requests from the token alone.
+//! Tokens that were already issued keep their claims until they are refreshed.
+//!
+//! Besides admins, tokens with `users:read` can read them and `users:write` can set them.
+
+use crate::{
+	auth::{api_error, claims::RESERVED_CLAIMS, ApiResponse},
+	AppState,
+};
+use actix_web::{
+	http,
+	web::{Data, Json, Path},
+	Either, HttpResponse,
+};
+use chrono::Utc;
+use entity::{custom_claims, users};
+use log::error;
+use migration::{DbErr, OnConflict};
+use sea_orm::{ConnectionTrait, EntityTrait, Set};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+/// Lets tokens without the admin role read custom claims
+pub const READ_PERMISSION: &str = "users:read";
+/// Lets tokens without the admin role set custom claims
+pub const WRITE_PERMISSION: &str = "users:write";
+
+/// The user's custom claims, which are empty if none were set
+pub async fn find<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+) -> Result<Map<String, Value>, DbErr> {
+	let claims = custom_claims::Entity::find_by_id(uid)
+		.one(connection)
+		.await?;
+	// Claims are checked to be an object before they are stored
+	Ok(claims
+		.and_then(|claims| serde_json::from_str(&claims.claims).ok())
+		.unwrap_or_default())
+}
+
+/// Forgets the user's custom claims
+pub async fn delete<C: ConnectionTrait>(connection: &C, uid: Uuid) -> Result<(), DbErr> {
+	custom_claims::Entity::delete_by_id(uid)
+		.exec(connection)
+		.await?;
+	Ok(())
+}
+
+pub async fn get_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+) -> (Json<ApiResponse>, http::StatusCode) {
+	let uid = match find_user(&data, &path.into_inner()).await {
+		Ok(uid) => uid,
+		Err(response) => return response,
+	};
+	match find(&data.connection, uid).await {
+		Ok(claims) => (Json(ApiResponse::CustomClaimsResponse { claims }), http::StatusCode::OK),
+		Err(e) => {
+			error!("Unable to find custom claims. Error: {}", e.to_string());
+			internal_error()
+		}
+	}
+}
+
+/// Replaces the user's custom claims with a JSON object. An empty object removes them.
+pub async fn put_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+	body: Json<Value>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let claims = match body.into_inner() {
+		Value::Object(claims) => claims,
+		_ => {
+			return Either::Left((
+				Json(api_error(
+					"Custom claims must be a JSON object.".to_string(),
+					"INVALID_CLAIMS".to_string(),
+				)),
+				http::StatusCode::BAD_REQUEST,
+			))
+		}
+	};
+	if let Some(name) = claims
+		.keys()
+		.find(|name| RESERVED_CLAIMS.contains(&name.as_str()))
+	{
+		return Either::Left((
+			Json(api_error(
+				format!("The claim {name} is reserved."),
+				"RESERVED_CLAIM".to_string(),
+			)),
+			http::StatusCode::BAD_REQUEST,
+		));
+	}
+	let serialized = Value::Object(claims.to_owned()).to_string();
+	if serialized.len() > data.config.tokens.custom_claims_max_size {
+		return Either::Left((
+			Json(api_error(
+				format!(
+					"Custom claims can be at most {} bytes of JSON.",
+					data.config.tokens.custom_claims_max_size
+				),
+				"CLAIMS_TOO_LARGE".to_string(),
+			)),
+			http::StatusCode::BAD_REQUEST,
+		));
+	}
+
+	let uid = match find_user(&data, &path.into_inner()).await {
+		Ok(uid) => uid,
+		Err(response) => return Either::Left(response),
+	};
+
+	let res = match claims.is_empty() {
+		true => delete(&data.connection, uid).await,
+		false => custom_claims::Entity::insert(custom_claims::ActiveModel {
+			uid: Set(uid),
+			claims: Set(serialized),
+			updated_at: Set(Utc::now().naive_utc()),
+		})
+		.on_conflict(
+			OnConflict::column(custom_claims::Column::Uid)
+				.update_columns([
+					custom_claims::Column::Claims,
+					custom_claims::Column::UpdatedAt,
+				])
+				.to_owned(),
+		)
+		.exec(&data.connection)
+		.await
+		.map(|_| ()),
+	};
+	match res {
+		Ok(()) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => {
+			error!("Unable to set custom claims. Error: {}", e.to_string());
+			Either::Left(internal_error())
+		}
+	}
+}
+
+/// The uid in the path, as long as the user exists
+async fn find_user(
+	data: &AppState,
+	uid: &str,
+) -> Result<Uuid, (Json<ApiResponse>, http::StatusCode)> {
+	let uid = match Uuid::parse_str(uid) {
+		Ok(uid) => uid,
+		Err(_) => return Err(not_found()),
+	};
+	match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(_)) => Ok(uid),
+		Ok(None) => Err(not_found()),
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			Err(internal_error())
+		}
+	}
+}
+
+fn not_found() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error("The user was not found.".to_string(), "USER_NOT_FOUND".to_string())),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
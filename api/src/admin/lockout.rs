@@ -0,0 +1,72 @@
+use crate::{
+	auth::{
+		api_error,
+		lockout::{self, Subject},
+		ApiResponse,
+	},
+	AppState,
+};
+use actix_web::{
+	delete, http,
+	web::{Data, Json, Path},
+	Either, HttpResponse,
+};
+use entity::users;
+use log::error;
+use sea_orm::EntityTrait;
+use uuid::Uuid;
+
+/// Lifts a user's lockout, and forgets their failed logins and wrong second factors. Failed logins from IP
+/// addresses are kept.
+#[delete("/api/admin/user/{uid}/lockout")]
+pub async fn clear_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let uid = match Uuid::parse_str(&path.into_inner()) {
+		Ok(uid) => uid,
+		Err(_) => return Either::Left(not_found()),
+	};
+	let user = match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(user)) => user,
+		Ok(None) => return Either::Left(not_found()),
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	};
+
+	// Users without an email can't log in with a password, so they only have second factor failures
+	let cleared = match user.email {
+		Some(ref email) => lockout::clear(&data.connection, &Subject::User(email)).await,
+		None => Ok(()),
+	};
+	let cleared = match cleared {
+		Ok(_) => lockout::clear(&data.connection, &Subject::SecondFactor(user.uid)).await,
+		Err(e) => Err(e),
+	};
+	match cleared {
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => {
+			error!("Unable to clear login failures. Error: {}", e.to_string());
+			Either::Left(internal_error())
+		}
+	}
+}
+
+fn not_found() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error("The user was not found.".to_string(), "USER_NOT_FOUND".to_string())),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
@@ -1,5 +1,10 @@
 use crate::{
-	auth::{api_error, util::get_at_and_rt, ApiResponse},
+	auth::{
+		api_error, hashing,
+		lockout::{self, Subject},
+		util::{get_at_and_rt, Session},
+		ApiResponse,
+	},
 	AppState,
 };
 use actix_web::{
@@ -7,10 +12,9 @@ use actix_web::{
 	web::{Data, Json},
 	Responder,
 };
-use argon2;
 use entity::admins;
 use log::error;
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
 use serde::Deserialize;
 
 
@@ -21,7 +25,16 @@ pub struct LoginBody {
 }
 
 #[post("/api/admin/login")]
-pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Responder {
+pub async fn handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<LoginBody>,
+) -> impl Responder {
+	let account = Subject::Admin(&body.email);
+	if let Some(locked) = lockout::check(&data, &account, &request).await {
+		return locked;
+	}
+
     let res = admins::Entity::find()
 		.filter(admins::Column::Email.eq(&body.email))
 		.one(&data.connection)
@@ -31,7 +44,12 @@ pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Respon
         Ok(opt) => {
             match opt {
                 Some(admin) => {
-					if !argon2::verify_encoded(&admin.password, body.password.as_bytes()).unwrap() {
+					if !hashing::verify(&admin.password, &body.password, &data.config) {
+						if let Some(locked) =
+							lockout::login_failed(&data, &account, Some(admin.uid), &request).await
+						{
+							return locked;
+						}
 						return (
 							Json(api_error(
 								"The email or password is invalid".to_string(),
@@ -40,9 +58,22 @@ pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Respon
 							http::StatusCode::UNAUTHORIZED,
 						);
 					}
+					if let Err(e) = lockout::clear(&data.connection, &account).await {
+						error!("Unable to clear login failures. Error: {}", e.to_string());
+					}
+					if hashing::needs_rehash(&admin.password, &data.config) {
+						rehash(&data, &admin, &body.password).await;
+					}
 					let uid_str = &admin.uid.to_string();
-					let (at, rt, exp) =
-						get_at_and_rt(&data.connection, uid_str, &data.config.secret_key, true).await;
+					let (at, rt, exp) = get_at_and_rt(
+						&data.connection,
+						uid_str,
+						&data.key_ring,
+						&data.config.tokens,
+						true,
+						Session::new(&request, &data.ua_parser),
+					)
+					.await;
 					(
 						Json(ApiResponse::LoginResponse {
 							uid: uid_str.to_string(),
@@ -55,13 +86,18 @@ pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Respon
 						http::StatusCode::OK,
 					)
                 }, 
-                None => (
-					Json(api_error(
-						"The email or password is invalid".to_string(),
-						"INVALID_CREDENTIALS".to_string(),
-					)),
-					http::StatusCode::UNAUTHORIZED,
-				)
+                None => {
+					if let Some(locked) = lockout::login_failed(&data, &account, None, &request).await {
+						return locked;
+					}
+					(
+						Json(api_error(
+							"The email or password is invalid".to_string(),
+							"INVALID_CREDENTIALS".to_string(),
+						)),
+						http::StatusCode::UNAUTHORIZED,
+					)
+				}
             }
         },
         Err(e) => {
@@ -76,4 +112,13 @@ pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Respon
         }
     }
 
-}
\ No newline at end of file
+}
+
+/// Replaces a hash made with an outdated algorithm or parameters, now that the password is known
+async fn rehash(data: &AppState, admin: &admins::Model, password: &str) {
+	let mut admin: admins::ActiveModel = admin.to_owned().into();
+	admin.password = Set(hashing::hash(password, &data.config));
+	if let Err(e) = admin.update(&data.connection).await {
+		error!("Unable to rehash admin password. Error: {}", e.to_string());
+	}
+}
@@ -1,10 +1,101 @@
-use actix_web::web;
+use actix_web::{web, Route};
 
 pub mod create_admin;
+pub mod custom_claims;
+pub mod lockout;
 pub mod login;
+pub mod rbac;
+pub mod user_export;
+pub mod user_import;
 
+/// The admin routes that tokens without the admin role may use, given the permission the route requires.
+/// The admin middleware leaves these to `requires`.
+pub const PERMISSION_ROUTES: [&str; 14] = [
+    "/api/admin/users/export",
+    "/api/admin/users/import",
+    "/api/admin/user/{uid}/claims",
+    "/api/admin/user/{uid}/permissions",
+    "/api/admin/permissions",
+    "/api/admin/permissions/{name}",
+    "/api/admin/roles",
+    "/api/admin/roles/{name}",
+    "/api/admin/roles/{name}/permissions",
+    "/api/admin/roles/{name}/members/{uid}",
+    "/api/admin/groups",
+    "/api/admin/groups/{name}",
+    "/api/admin/groups/{name}/roles",
+    "/api/admin/groups/{name}/members/{uid}",
+];
+
+/// Adds the admin routes. `requires` wraps the routes in `PERMISSION_ROUTES` so that they reject tokens
+/// without the permission it is given.
+pub fn add_routes(cfg: &mut web::ServiceConfig, requires: impl Fn(Route, &str) -> Route) {
+    use crate::admin::{custom_claims, rbac, user_export, user_import};
+    let (read, write) = (rbac::READ_PERMISSION, rbac::WRITE_PERMISSION);
 
-pub fn add_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(crate::admin::create_admin::handler)
-        .service(crate::admin::login::handler);
+        .service(crate::admin::login::handler)
+        .service(crate::admin::lockout::clear_handler)
+        .service(
+            web::resource("/api/admin/user/{uid}/claims")
+                .route(requires(web::get().to(custom_claims::get_handler), custom_claims::READ_PERMISSION))
+                .route(requires(web::put().to(custom_claims::put_handler), custom_claims::WRITE_PERMISSION)),
+        )
+        .service(
+            web::resource("/api/admin/user/{uid}/permissions")
+                .route(requires(web::get().to(rbac::user_permissions_handler), read)),
+        )
+        .service(
+            web::resource("/api/admin/permissions")
+                .route(requires(web::post().to(rbac::create_permission_handler), write))
+                .route(requires(web::get().to(rbac::list_permissions_handler), read)),
+        )
+        .service(
+            web::resource("/api/admin/permissions/{name}")
+                .route(requires(web::delete().to(rbac::delete_permission_handler), write)),
+        )
+        .service(
+            web::resource("/api/admin/roles")
+                .route(requires(web::post().to(rbac::create_role_handler), write))
+                .route(requires(web::get().to(rbac::list_roles_handler), read)),
+        )
+        .service(
+            web::resource("/api/admin/roles/{name}")
+                .route(requires(web::delete().to(rbac::delete_role_handler), write)),
+        )
+        .service(
+            web::resource("/api/admin/roles/{name}/permissions")
+                .route(requires(web::put().to(rbac::set_role_permissions_handler), write)),
+        )
+        .service(
+            web::resource("/api/admin/roles/{name}/members/{uid}")
+                .route(requires(web::put().to(rbac::add_role_member_handler), write))
+                .route(requires(web::delete().to(rbac::remove_role_member_handler), write)),
+        )
+        .service(
+            web::resource("/api/admin/groups")
+                .route(requires(web::post().to(rbac::create_group_handler), write))
+                .route(requires(web::get().to(rbac::list_groups_handler), read)),
+        )
+        .service(
+            web::resource("/api/admin/groups/{name}")
+                .route(requires(web::delete().to(rbac::delete_group_handler), write)),
+        )
+        .service(
+            web::resource("/api/admin/groups/{name}/roles")
+                .route(requires(web::put().to(rbac::set_group_roles_handler), write)),
+        )
+        .service(
+            web::resource("/api/admin/groups/{name}/members/{uid}")
+                .route(requires(web::put().to(rbac::add_group_member_handler), write))
+                .route(requires(web::delete().to(rbac::remove_group_member_handler), write)),
+        )
+        .service(
+            web::resource("/api/admin/users/export")
+                .route(requires(web::get().to(user_export::handler), user_export::PERMISSION)),
+        )
+        .service(
+            web::resource("/api/admin/users/import")
+                .route(requires(web::post().to(user_import::handler), user_import::PERMISSION)),
+        );
 }
\ No newline at end of file
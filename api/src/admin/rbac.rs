@@ -0,0 +1,824 @@
+//! Roles, permissions and groups. A role grants permissions, and a group grants roles. Users and admins
+//! are made members of roles and groups, and every access token lists the permissions of their roles and
+//! their groups' roles, so that routes can require one with `middlewares::permissions::requires`.
+//! Permissions are only written to tokens when they are issued, so changes apply once tokens are refreshed.
+//!
+//! Roles, permissions and groups are identified by their name, such as `users:read`.
+//!
+//! Besides admins, tokens with `rbac:read` can list them and `rbac:write` can change them, once those
+//! permissions are created and granted like any other.
+
+use crate::{
+	auth::{api_error, ApiResponse},
+	AppState,
+};
+use actix_web::{
+	http,
+	web::{Data, Json, Path},
+	Either, HttpResponse,
+};
+use chrono::{NaiveDateTime, Utc};
+use entity::{
+	admins, group_members, group_roles, groups, permissions, role_members, role_permissions, roles,
+	users,
+};
+use log::error;
+use migration::{DbErr, OnConflict};
+use sea_orm::{
+	sea_query::Query, ColumnTrait, Condition, ConnectionTrait, EntityTrait, QueryFilter,
+	QueryOrder, QuerySelect, Set, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+type ErrorResponse = (Json<ApiResponse>, http::StatusCode);
+
+/// Lets tokens without the admin role read roles, permissions, groups and their members
+pub const READ_PERMISSION: &str = "rbac:read";
+/// Lets tokens without the admin role change roles, permissions, groups and their members
+pub const WRITE_PERMISSION: &str = "rbac:write";
+
+#[derive(Deserialize)]
+pub struct PermissionBody {
+	name: String,
+	description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RoleBody {
+	name: String,
+	description: Option<String>,
+	#[serde(default)]
+	permissions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GroupBody {
+	name: String,
+	description: Option<String>,
+	#[serde(default)]
+	roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PermissionsBody {
+	permissions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RolesBody {
+	roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PermissionInfo {
+	name: String,
+	description: Option<String>,
+	created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoleInfo {
+	name: String,
+	description: Option<String>,
+	permissions: Vec<String>,
+	created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupInfo {
+	name: String,
+	description: Option<String>,
+	roles: Vec<String>,
+	created_at: NaiveDateTime,
+}
+
+/// The roles the user is a member of, directly or through one of their groups
+pub async fn roles_of<C: ConnectionTrait>(connection: &C, uid: Uuid) -> Result<Vec<String>, DbErr> {
+	roles::Entity::find()
+		.select_only()
+		.column(roles::Column::Name)
+		.filter(
+			Condition::any()
+				.add(
+					roles::Column::Name.in_subquery(
+						Query::select()
+							.column(role_members::Column::Role)
+							.from(role_members::Entity)
+							.and_where(role_members::Column::Uid.eq(uid))
+							.to_owned(),
+					),
+				)
+				.add(
+					roles::Column::Name.in_subquery(
+						Query::select()
+							.column(group_roles::Column::Role)
+							.from(group_roles::Entity)
+							.and_where(group_roles::Column::Group.in_subquery(groups_query(uid)))
+							.to_owned(),
+					),
+				),
+		)
+		.order_by_asc(roles::Column::Name)
+		.into_tuple()
+		.all(connection)
+		.await
+}
+
+/// The permissions of every role the user is a member of, which are written to their access tokens
+pub async fn permissions_of<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+) -> Result<Vec<String>, DbErr> {
+	let roles = roles_of(connection, uid).await?;
+	if roles.is_empty() {
+		return Ok(vec![]);
+	}
+	role_permissions::Entity::find()
+		.select_only()
+		.column(role_permissions::Column::Permission)
+		.distinct()
+		.filter(role_permissions::Column::Role.is_in(roles))
+		.order_by_asc(role_permissions::Column::Permission)
+		.into_tuple()
+		.all(connection)
+		.await
+}
+
+/// Removes the user from every role and group, for when they are deleted
+pub async fn remove_member<C: ConnectionTrait>(connection: &C, uid: Uuid) -> Result<(), DbErr> {
+	role_members::Entity::delete_many()
+		.filter(role_members::Column::Uid.eq(uid))
+		.exec(connection)
+		.await?;
+	group_members::Entity::delete_many()
+		.filter(group_members::Column::Uid.eq(uid))
+		.exec(connection)
+		.await?;
+	Ok(())
+}
+
+fn groups_query(uid: Uuid) -> sea_orm::sea_query::SelectStatement {
+	Query::select()
+		.column(group_members::Column::Group)
+		.from(group_members::Entity)
+		.and_where(group_members::Column::Uid.eq(uid))
+		.to_owned()
+}
+
+pub async fn create_permission_handler(
+	data: Data<AppState>,
+	body: Json<PermissionBody>,
+) -> Either<ErrorResponse, HttpResponse> {
+	if !valid_name(&body.name) {
+		return Either::Left(invalid_name());
+	}
+	let res = permissions::Entity::insert(permissions::ActiveModel {
+		name: Set(body.name.to_owned()),
+		description: Set(body.description.to_owned()),
+		created_at: Set(Utc::now().naive_utc()),
+	})
+	.on_conflict(
+		OnConflict::column(permissions::Column::Name)
+			.do_nothing()
+			.to_owned(),
+	)
+	.exec(&data.connection)
+	.await;
+	match res {
+		Ok(_) => Either::Right(HttpResponse::Created().finish()),
+		Err(DbErr::RecordNotInserted) => Either::Left(exists("permission", "PERMISSION_EXISTS")),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+pub async fn list_permissions_handler(data: Data<AppState>) -> ErrorResponse {
+	match permissions::Entity::find()
+		.order_by_asc(permissions::Column::Name)
+		.all(&data.connection)
+		.await
+	{
+		Ok(permissions) => (
+			Json(ApiResponse::PermissionListResponse {
+				permissions: permissions
+					.into_iter()
+					.map(|permission| PermissionInfo {
+						name: permission.name,
+						description: permission.description,
+						created_at: permission.created_at,
+					})
+					.collect(),
+			}),
+			http::StatusCode::OK,
+		),
+		Err(e) => db_error(e),
+	}
+}
+
+/// Deletes the permission, which is taken away from every role
+pub async fn delete_permission_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let name = path.into_inner();
+	let deleted = async {
+		let txn = data.connection.begin().await?;
+		role_permissions::Entity::delete_many()
+			.filter(role_permissions::Column::Permission.eq(&name))
+			.exec(&txn)
+			.await?;
+		let res = permissions::Entity::delete_by_id(name.to_owned())
+			.exec(&txn)
+			.await?;
+		txn.commit().await?;
+		Ok::<_, DbErr>(res.rows_affected)
+	};
+	match deleted.await {
+		Ok(0) => Either::Left(not_found(&name, "PERMISSION_NOT_FOUND")),
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+pub async fn create_role_handler(
+	data: Data<AppState>,
+	body: Json<RoleBody>,
+) -> Either<ErrorResponse, HttpResponse> {
+	if !valid_name(&body.name) {
+		return Either::Left(invalid_name());
+	}
+	let created = async {
+		let txn = data.connection.begin().await?;
+		if let Some(missing) = missing_permission(&txn, &body.permissions).await? {
+			return Ok(Err(not_found(&missing, "PERMISSION_NOT_FOUND")));
+		}
+		let res = roles::Entity::insert(roles::ActiveModel {
+			name: Set(body.name.to_owned()),
+			description: Set(body.description.to_owned()),
+			created_at: Set(Utc::now().naive_utc()),
+		})
+		.on_conflict(
+			OnConflict::column(roles::Column::Name)
+				.do_nothing()
+				.to_owned(),
+		)
+		.exec(&txn)
+		.await;
+		match res {
+			Ok(_) => (),
+			Err(DbErr::RecordNotInserted) => return Ok(Err(exists("role", "ROLE_EXISTS"))),
+			Err(e) => return Err(e),
+		}
+		set_role_permissions(&txn, &body.name, &body.permissions).await?;
+		txn.commit().await?;
+		Ok(Ok(()))
+	};
+	match created.await {
+		Ok(Ok(())) => Either::Right(HttpResponse::Created().finish()),
+		Ok(Err(response)) => Either::Left(response),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+pub async fn list_roles_handler(data: Data<AppState>) -> ErrorResponse {
+	let listed = async {
+		let roles = roles::Entity::find()
+			.order_by_asc(roles::Column::Name)
+			.all(&data.connection)
+			.await?;
+		let mut granted = role_permissions::Entity::find()
+			.order_by_asc(role_permissions::Column::Permission)
+			.all(&data.connection)
+			.await?;
+		Ok::<_, DbErr>(
+			roles
+				.into_iter()
+				.map(|role| RoleInfo {
+					permissions: take_children(&mut granted, |grant| grant.role == role.name)
+						.into_iter()
+						.map(|grant| grant.permission)
+						.collect(),
+					name: role.name,
+					description: role.description,
+					created_at: role.created_at,
+				})
+				.collect(),
+		)
+	};
+	match listed.await {
+		Ok(roles) => (Json(ApiResponse::RoleListResponse { roles }), http::StatusCode::OK),
+		Err(e) => db_error(e),
+	}
+}
+
+/// Replaces the permissions the role grants
+pub async fn set_role_permissions_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+	body: Json<PermissionsBody>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let name = path.into_inner();
+	let updated = async {
+		let txn = data.connection.begin().await?;
+		if roles::Entity::find_by_id(name.to_owned())
+			.one(&txn)
+			.await?
+			.is_none()
+		{
+			return Ok(Err(not_found(&name, "ROLE_NOT_FOUND")));
+		}
+		if let Some(missing) = missing_permission(&txn, &body.permissions).await? {
+			return Ok(Err(not_found(&missing, "PERMISSION_NOT_FOUND")));
+		}
+		set_role_permissions(&txn, &name, &body.permissions).await?;
+		txn.commit().await?;
+		Ok(Ok(()))
+	};
+	match updated.await {
+		Ok(Ok(())) => Either::Right(HttpResponse::Ok().finish()),
+		Ok(Err(response)) => Either::Left(response),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+/// Deletes the role, which is taken away from its members and groups
+pub async fn delete_role_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let name = path.into_inner();
+	let deleted = async {
+		let txn = data.connection.begin().await?;
+		role_permissions::Entity::delete_many()
+			.filter(role_permissions::Column::Role.eq(&name))
+			.exec(&txn)
+			.await?;
+		group_roles::Entity::delete_many()
+			.filter(group_roles::Column::Role.eq(&name))
+			.exec(&txn)
+			.await?;
+		role_members::Entity::delete_many()
+			.filter(role_members::Column::Role.eq(&name))
+			.exec(&txn)
+			.await?;
+		let res = roles::Entity::delete_by_id(name.to_owned())
+			.exec(&txn)
+			.await?;
+		txn.commit().await?;
+		Ok::<_, DbErr>(res.rows_affected)
+	};
+	match deleted.await {
+		Ok(0) => Either::Left(not_found(&name, "ROLE_NOT_FOUND")),
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+pub async fn create_group_handler(
+	data: Data<AppState>,
+	body: Json<GroupBody>,
+) -> Either<ErrorResponse, HttpResponse> {
+	if !valid_name(&body.name) {
+		return Either::Left(invalid_name());
+	}
+	let created = async {
+		let txn = data.connection.begin().await?;
+		if let Some(missing) = missing_role(&txn, &body.roles).await? {
+			return Ok(Err(not_found(&missing, "ROLE_NOT_FOUND")));
+		}
+		let res = groups::Entity::insert(groups::ActiveModel {
+			name: Set(body.name.to_owned()),
+			description: Set(body.description.to_owned()),
+			created_at: Set(Utc::now().naive_utc()),
+		})
+		.on_conflict(
+			OnConflict::column(groups::Column::Name)
+				.do_nothing()
+				.to_owned(),
+		)
+		.exec(&txn)
+		.await;
+		match res {
+			Ok(_) => (),
+			Err(DbErr::RecordNotInserted) => return Ok(Err(exists("group", "GROUP_EXISTS"))),
+			Err(e) => return Err(e),
+		}
+		set_group_roles(&txn, &body.name, &body.roles).await?;
+		txn.commit().await?;
+		Ok(Ok(()))
+	};
+	match created.await {
+		Ok(Ok(())) => Either::Right(HttpResponse::Created().finish()),
+		Ok(Err(response)) => Either::Left(response),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+pub async fn list_groups_handler(data: Data<AppState>) -> ErrorResponse {
+	let listed = async {
+		let groups = groups::Entity::find()
+			.order_by_asc(groups::Column::Name)
+			.all(&data.connection)
+			.await?;
+		let mut granted = group_roles::Entity::find()
+			.order_by_asc(group_roles::Column::Role)
+			.all(&data.connection)
+			.await?;
+		Ok::<_, DbErr>(
+			groups
+				.into_iter()
+				.map(|group| GroupInfo {
+					roles: take_children(&mut granted, |grant| grant.group == group.name)
+						.into_iter()
+						.map(|grant| grant.role)
+						.collect(),
+					name: group.name,
+					description: group.description,
+					created_at: group.created_at,
+				})
+				.collect(),
+		)
+	};
+	match listed.await {
+		Ok(groups) => (Json(ApiResponse::GroupListResponse { groups }), http::StatusCode::OK),
+		Err(e) => db_error(e),
+	}
+}
+
+/// Replaces the roles the group grants
+pub async fn set_group_roles_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+	body: Json<RolesBody>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let name = path.into_inner();
+	let updated = async {
+		let txn = data.connection.begin().await?;
+		if groups::Entity::find_by_id(name.to_owned())
+			.one(&txn)
+			.await?
+			.is_none()
+		{
+			return Ok(Err(not_found(&name, "GROUP_NOT_FOUND")));
+		}
+		if let Some(missing) = missing_role(&txn, &body.roles).await? {
+			return Ok(Err(not_found(&missing, "ROLE_NOT_FOUND")));
+		}
+		set_group_roles(&txn, &name, &body.roles).await?;
+		txn.commit().await?;
+		Ok(Ok(()))
+	};
+	match updated.await {
+		Ok(Ok(())) => Either::Right(HttpResponse::Ok().finish()),
+		Ok(Err(response)) => Either::Left(response),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+/// Deletes the group, and with it its memberships
+pub async fn delete_group_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let name = path.into_inner();
+	let deleted = async {
+		let txn = data.connection.begin().await?;
+		group_roles::Entity::delete_many()
+			.filter(group_roles::Column::Group.eq(&name))
+			.exec(&txn)
+			.await?;
+		group_members::Entity::delete_many()
+			.filter(group_members::Column::Group.eq(&name))
+			.exec(&txn)
+			.await?;
+		let res = groups::Entity::delete_by_id(name.to_owned())
+			.exec(&txn)
+			.await?;
+		txn.commit().await?;
+		Ok::<_, DbErr>(res.rows_affected)
+	};
+	match deleted.await {
+		Ok(0) => Either::Left(not_found(&name, "GROUP_NOT_FOUND")),
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+/// Makes the user or admin a member of the role
+pub async fn add_role_member_handler(
+	data: Data<AppState>,
+	path: Path<(String, String)>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let (name, uid) = path.into_inner();
+	let uid = match find_member(&data, &uid).await {
+		Ok(uid) => uid,
+		Err(response) => return Either::Left(response),
+	};
+	match roles::Entity::find_by_id(name.to_owned())
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(_)) => (),
+		Ok(None) => return Either::Left(not_found(&name, "ROLE_NOT_FOUND")),
+		Err(e) => return Either::Left(db_error(e)),
+	}
+	let res = role_members::Entity::insert(role_members::ActiveModel {
+		role: Set(name),
+		uid: Set(uid),
+	})
+	.on_conflict(
+		OnConflict::columns([role_members::Column::Role, role_members::Column::Uid])
+			.do_nothing()
+			.to_owned(),
+	)
+	.exec(&data.connection)
+	.await;
+	match res {
+		// Adding a member twice changes nothing
+		Ok(_) | Err(DbErr::RecordNotInserted) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+pub async fn remove_role_member_handler(
+	data: Data<AppState>,
+	path: Path<(String, String)>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let (name, uid) = path.into_inner();
+	let uid = match Uuid::parse_str(&uid) {
+		Ok(uid) => uid,
+		Err(_) => return Either::Left(member_not_found()),
+	};
+	match role_members::Entity::delete_by_id((name, uid))
+		.exec(&data.connection)
+		.await
+	{
+		Ok(res) if res.rows_affected == 0 => Either::Left(member_not_found()),
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+/// Makes the user or admin a member of the group
+pub async fn add_group_member_handler(
+	data: Data<AppState>,
+	path: Path<(String, String)>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let (name, uid) = path.into_inner();
+	let uid = match find_member(&data, &uid).await {
+		Ok(uid) => uid,
+		Err(response) => return Either::Left(response),
+	};
+	match groups::Entity::find_by_id(name.to_owned())
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(_)) => (),
+		Ok(None) => return Either::Left(not_found(&name, "GROUP_NOT_FOUND")),
+		Err(e) => return Either::Left(db_error(e)),
+	}
+	let res = group_members::Entity::insert(group_members::ActiveModel {
+		group: Set(name),
+		uid: Set(uid),
+	})
+	.on_conflict(
+		OnConflict::columns([group_members::Column::Group, group_members::Column::Uid])
+			.do_nothing()
+			.to_owned(),
+	)
+	.exec(&data.connection)
+	.await;
+	match res {
+		// Adding a member twice changes nothing
+		Ok(_) | Err(DbErr::RecordNotInserted) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+pub async fn remove_group_member_handler(
+	data: Data<AppState>,
+	path: Path<(String, String)>,
+) -> Either<ErrorResponse, HttpResponse> {
+	let (name, uid) = path.into_inner();
+	let uid = match Uuid::parse_str(&uid) {
+		Ok(uid) => uid,
+		Err(_) => return Either::Left(member_not_found()),
+	};
+	match group_members::Entity::delete_by_id((name, uid))
+		.exec(&data.connection)
+		.await
+	{
+		Ok(res) if res.rows_affected == 0 => Either::Left(member_not_found()),
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(db_error(e)),
+	}
+}
+
+/// The roles, groups and permissions of a user or admin
+pub async fn user_permissions_handler(data: Data<AppState>, path: Path<String>) -> ErrorResponse {
+	let uid = match find_member(&data, &path.into_inner()).await {
+		Ok(uid) => uid,
+		Err(response) => return response,
+	};
+	let found = async {
+		let groups = group_members::Entity::find()
+			.select_only()
+			.column(group_members::Column::Group)
+			.filter(group_members::Column::Uid.eq(uid))
+			.order_by_asc(group_members::Column::Group)
+			.into_tuple()
+			.all(&data.connection)
+			.await?;
+		let roles = roles_of(&data.connection, uid).await?;
+		let permissions = permissions_of(&data.connection, uid).await?;
+		Ok::<_, DbErr>((roles, groups, permissions))
+	};
+	match found.await {
+		Ok((roles, groups, permissions)) => (
+			Json(ApiResponse::UserPermissionsResponse {
+				roles,
+				groups,
+				permissions,
+			}),
+			http::StatusCode::OK,
+		),
+		Err(e) => db_error(e),
+	}
+}
+
+async fn set_role_permissions<C: ConnectionTrait>(
+	connection: &C,
+	role: &str,
+	permissions: &[String],
+) -> Result<(), DbErr> {
+	role_permissions::Entity::delete_many()
+		.filter(role_permissions::Column::Role.eq(role))
+		.exec(connection)
+		.await?;
+	let permissions: BTreeSet<&String> = permissions.iter().collect();
+	if permissions.is_empty() {
+		return Ok(());
+	}
+	role_permissions::Entity::insert_many(permissions.into_iter().map(|permission| {
+		role_permissions::ActiveModel {
+			role: Set(role.to_owned()),
+			permission: Set(permission.to_owned()),
+		}
+	}))
+	.exec(connection)
+	.await?;
+	Ok(())
+}
+
+async fn set_group_roles<C: ConnectionTrait>(
+	connection: &C,
+	group: &str,
+	roles: &[String],
+) -> Result<(), DbErr> {
+	group_roles::Entity::delete_many()
+		.filter(group_roles::Column::Group.eq(group))
+		.exec(connection)
+		.await?;
+	let roles: BTreeSet<&String> = roles.iter().collect();
+	if roles.is_empty() {
+		return Ok(());
+	}
+	group_roles::Entity::insert_many(roles.into_iter().map(|role| group_roles::ActiveModel {
+		group: Set(group.to_owned()),
+		role: Set(role.to_owned()),
+	}))
+	.exec(connection)
+	.await?;
+	Ok(())
+}
+
+/// The first of the permissions that does not exist
+async fn missing_permission<C: ConnectionTrait>(
+	connection: &C,
+	names: &[String],
+) -> Result<Option<String>, DbErr> {
+	let existing: Vec<String> = permissions::Entity::find()
+		.select_only()
+		.column(permissions::Column::Name)
+		.filter(permissions::Column::Name.is_in(names.iter().cloned()))
+		.into_tuple()
+		.all(connection)
+		.await?;
+	Ok(names.iter().find(|name| !existing.contains(name)).cloned())
+}
+
+/// The first of the roles that does not exist
+async fn missing_role<C: ConnectionTrait>(
+	connection: &C,
+	names: &[String],
+) -> Result<Option<String>, DbErr> {
+	let existing: Vec<String> = roles::Entity::find()
+		.select_only()
+		.column(roles::Column::Name)
+		.filter(roles::Column::Name.is_in(names.iter().cloned()))
+		.into_tuple()
+		.all(connection)
+		.await?;
+	Ok(names.iter().find(|name| !existing.contains(name)).cloned())
+}
+
+/// Removes the rows that belong to one parent, keeping their order
+fn take_children<T>(rows: &mut Vec<T>, belongs: impl Fn(&T) -> bool) -> Vec<T> {
+	let (children, rest) = std::mem::take(rows).into_iter().partition(belongs);
+	*rows = rest;
+	children
+}
+
+/// The uid in the path, as long as it belongs to a user or an admin
+async fn find_member(data: &AppState, uid: &str) -> Result<Uuid, ErrorResponse> {
+	let uid = match Uuid::parse_str(uid) {
+		Ok(uid) => uid,
+		Err(_) => return Err(user_not_found()),
+	};
+	let found = async {
+		if users::Entity::find_by_id(uid)
+			.one(&data.connection)
+			.await?
+			.is_some()
+		{
+			return Ok(true);
+		}
+		Ok::<_, DbErr>(
+			admins::Entity::find_by_id(uid)
+				.one(&data.connection)
+				.await?
+				.is_some(),
+		)
+	};
+	match found.await {
+		Ok(true) => Ok(uid),
+		Ok(false) => Err(user_not_found()),
+		Err(e) => Err(db_error(e)),
+	}
+}
+
+/// Names are used in URLs and tokens, so they are kept to letters, digits and `:._-`
+fn valid_name(name: &str) -> bool {
+	!name.is_empty()
+		&& name.len() <= 100
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || ":._-".contains(c))
+}
+
+fn invalid_name() -> ErrorResponse {
+	(
+		Json(api_error(
+			"Names must be at most 100 letters, digits, colons, dots, underscores or dashes."
+				.to_string(),
+			"INVALID_NAME".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn exists(kind: &str, error_code: &str) -> ErrorResponse {
+	(
+		Json(api_error(
+			format!("A {kind} with that name already exists."),
+			error_code.to_string(),
+		)),
+		http::StatusCode::CONFLICT,
+	)
+}
+
+fn not_found(name: &str, error_code: &str) -> ErrorResponse {
+	(
+		Json(api_error(format!("{name} was not found."), error_code.to_string())),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn member_not_found() -> ErrorResponse {
+	(
+		Json(api_error(
+			"The user is not a member.".to_string(),
+			"MEMBER_NOT_FOUND".to_string(),
+		)),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn user_not_found() -> ErrorResponse {
+	(
+		Json(api_error("The user was not found.".to_string(), "USER_NOT_FOUND".to_string())),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn db_error(e: DbErr) -> ErrorResponse {
+	error!("Unable to update roles and permissions. Error: {}", e.to_string());
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
@@ -0,0 +1,72 @@
+//! Exporting every user in the format that `user_import` reads, a JSON user per line, so that users can
+//! be moved to another TurboCore instance or another provider.
+//!
+//! Exports include password hashes, so besides admins only tokens with `users:read` can export.
+
+use actix_web::{error::ErrorInternalServerError, web::Data, HttpResponse};
+use entity::users;
+use log::error;
+use migration::DbErr;
+use sea_orm::{ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect};
+use uuid::Uuid;
+
+use crate::{admin::user_import::UserRecord, AppState};
+
+/// Lets tokens without the admin role export users
+pub const PERMISSION: &str = "users:read";
+
+/// How many users are read at once
+pub const PAGE_SIZE: u64 = 1000;
+
+/// The users that come after `after`, ordered by uid. Anonymous users are left out, since they have
+/// nothing to log in with elsewhere.
+pub async fn page<C: ConnectionTrait>(
+	connection: &C,
+	after: Option<Uuid>,
+) -> Result<Vec<users::Model>, DbErr> {
+	let mut query = users::Entity::find()
+		.filter(users::Column::Anonymous.eq(false))
+		.order_by_asc(users::Column::Uid)
+		.limit(PAGE_SIZE);
+	if let Some(after) = after {
+		query = query.filter(users::Column::Uid.gt(after));
+	}
+	query.all(connection).await
+}
+
+/// The lines of the export for a page of users
+pub fn lines(users: Vec<users::Model>) -> String {
+	users
+		.into_iter()
+		.map(|user| serde_json::to_string(&UserRecord::from(user)).unwrap() + "\n")
+		.collect()
+}
+
+/// Streams every user. Users are read a page at a time as the response is sent, so they are never all
+/// held in memory.
+pub async fn handler(data: Data<AppState>) -> HttpResponse {
+	let connection = data.connection.to_owned();
+	// The uid the next page starts after, or None once every page was sent
+	let pages = futures::stream::try_unfold(Some(None), move |after| {
+		let connection = connection.to_owned();
+		async move {
+			let after = match after {
+				Some(after) => after,
+				None => return Ok(None),
+			};
+			let users = page(&connection, after).await.map_err(|e| {
+				error!("Unable to export users. Error: {}", e.to_string());
+				ErrorInternalServerError("Unable to export users")
+			})?;
+			let next = match users.last() {
+				Some(last) if users.len() as u64 == PAGE_SIZE => Some(Some(last.uid)),
+				_ => None,
+			};
+			Ok::<_, actix_web::Error>(Some((actix_web::web::Bytes::from(lines(users)), next)))
+		}
+	});
+
+	HttpResponse::Ok()
+		.content_type("application/x-ndjson")
+		.streaming(pages)
+}
@@ -0,0 +1,659 @@
+//! Importing users from other providers, or from a TurboCore export. Users are added in batches, and every
+//! row that can't be imported is reported with the reason, so that the rest of the import still goes
+//! through.
+//!
+//! Imports can set password hashes, so besides admins only tokens with `users:write` can import.
+
+use std::{collections::HashSet, str::FromStr};
+
+use actix_web::{
+	http,
+	web::{self, Data, Json, Query},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use entity::users;
+use futures::StreamExt;
+use log::error;
+use migration::DbErr;
+use sea_orm::{
+	ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QuerySelect, Set, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::{
+	auth::{
+		api_error, hashing,
+		identities::{self, EMAIL_PROVIDERS, MAGIC_LINK, PASSWORD, PHONE},
+		phone, ApiResponse,
+	},
+	AppState, Config,
+};
+
+/// Lets tokens without the admin role import users
+pub const PERMISSION: &str = "users:write";
+
+/// How many users are inserted at once
+const BATCH_SIZE: usize = 1000;
+/// Larger imports can use the `import-users` command, which reads the file directly
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+/// The password of users who can't log in with one, like users created through magic links or OAuth
+pub const NO_PASSWORD: &str = "0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+	/// TurboCore's own format, as a JSON array or a user per line. Exports are written in it.
+	#[default]
+	Json,
+	/// TurboCore's own format, with a header row
+	Csv,
+	/// The JSON written by `firebase auth:export`
+	Firebase,
+	/// Auth0's user exports, as a JSON array or a user per line. Password hashes are only included in
+	/// exports requested from Auth0 support.
+	Auth0,
+}
+
+impl FromStr for ImportFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"json" => Ok(ImportFormat::Json),
+			"csv" => Ok(ImportFormat::Csv),
+			"firebase" => Ok(ImportFormat::Firebase),
+			"auth0" => Ok(ImportFormat::Auth0),
+			_ => Err(format!("Unsupported import format: {s}")),
+		}
+	}
+}
+
+/// A user in TurboCore's import and export format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRecord {
+	/// Users without a uid are given a new one
+	#[serde(default)]
+	pub uid: Option<Uuid>,
+	/// Users must have an email, a phone number or both
+	#[serde(default)]
+	pub email: Option<String>,
+	/// Any hash that `hashing` can verify. Users without one can't log in with a password.
+	#[serde(default)]
+	pub password_hash: Option<String>,
+	#[serde(default)]
+	pub email_verified: bool,
+	#[serde(default)]
+	pub metadata: Option<String>,
+	#[serde(default)]
+	pub created_at: Option<NaiveDateTime>,
+	#[serde(default = "default_active")]
+	pub active: bool,
+	/// In E.164, or any format `phone::normalize` reads
+	#[serde(default)]
+	pub phone: Option<String>,
+	#[serde(default)]
+	pub phone_verified: bool,
+}
+
+fn default_active() -> bool {
+	true
+}
+
+impl From<users::Model> for UserRecord {
+	fn from(user: users::Model) -> Self {
+		Self {
+			uid: Some(user.uid),
+			email: user.email,
+			password_hash: (user.password != NO_PASSWORD).then_some(user.password),
+			email_verified: user.email_verified,
+			metadata: user.metadata,
+			created_at: Some(user.created_at),
+			active: user.active,
+			phone: user.phone,
+			phone_verified: user.phone_verified,
+		}
+	}
+}
+
+/// A row that could not be imported
+#[derive(Debug, Serialize)]
+pub struct RowError {
+	/// Counted from 1, without the header of a CSV file
+	pub row: usize,
+	pub email: Option<String>,
+	pub error_code: &'static str,
+	pub message: String,
+}
+
+impl RowError {
+	fn new(row: usize, email: Option<String>, error_code: &'static str, message: String) -> Self {
+		Self {
+			row,
+			email,
+			error_code,
+			message,
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+	pub imported: usize,
+	pub errors: Vec<RowError>,
+}
+
+/// Turns a user from an export into a `UserRecord`
+type Convert = fn(Value) -> Result<UserRecord, String>;
+
+/// Reads the users of an export. Rows that can't be read are returned as errors, while an export that
+/// can't be read at all is an error of its own.
+pub fn parse(
+	input: &[u8],
+	format: ImportFormat,
+) -> Result<Vec<Result<UserRecord, RowError>>, String> {
+	let (rows, convert): (Vec<Result<Value, String>>, Convert) = match format {
+		ImportFormat::Csv => return Ok(parse_csv(input)),
+		ImportFormat::Json => (json_rows(input)?, |value| {
+			serde_json::from_value(value).map_err(|e| e.to_string())
+		}),
+		ImportFormat::Firebase => {
+			#[derive(Deserialize)]
+			struct FirebaseExport {
+				users: Vec<Value>,
+			}
+			let export: FirebaseExport =
+				serde_json::from_slice(input).map_err(|e| e.to_string())?;
+			(export.users.into_iter().map(Ok).collect(), from_firebase)
+		}
+		ImportFormat::Auth0 => (json_rows(input)?, from_auth0),
+	};
+
+	Ok(rows
+		.into_iter()
+		.enumerate()
+		.map(|(i, value)| {
+			let value = value.map_err(|e| RowError::new(i + 1, None, "INVALID_ROW", e))?;
+			let email = value
+				.get("email")
+				.and_then(Value::as_str)
+				.map(str::to_owned);
+			convert(value).map_err(|e| RowError::new(i + 1, email, "INVALID_ROW", e))
+		})
+		.collect())
+}
+
+/// Reads a JSON array, or a JSON value per line
+fn json_rows(input: &[u8]) -> Result<Vec<Result<Value, String>>, String> {
+	let input = std::str::from_utf8(input).map_err(|e| e.to_string())?;
+	if input.trim_start().starts_with('[') {
+		let rows: Vec<Value> = serde_json::from_str(input).map_err(|e| e.to_string())?;
+		return Ok(rows.into_iter().map(Ok).collect());
+	}
+	Ok(input
+		.lines()
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| serde_json::from_str(line).map_err(|e| e.to_string()))
+		.collect())
+}
+
+fn parse_csv(input: &[u8]) -> Vec<Result<UserRecord, RowError>> {
+	csv::Reader::from_reader(input)
+		.deserialize::<UserRecord>()
+		.enumerate()
+		.map(|(i, record)| {
+			record.map_err(|e| RowError::new(i + 1, None, "INVALID_ROW", e.to_string()))
+		})
+		.collect()
+}
+
+/// Keeps profile fields that TurboCore has no column for in the metadata
+fn add_profile(metadata: &mut Map<String, Value>, key: &str, value: Option<String>) {
+	if let Some(value) = value {
+		metadata.entry(key).or_insert_with(|| Value::String(value));
+	}
+}
+
+fn metadata_string(metadata: Map<String, Value>) -> Option<String> {
+	(!metadata.is_empty()).then(|| Value::Object(metadata).to_string())
+}
+
+/// Reads a time in milliseconds since the epoch, which Firebase writes as a string
+fn from_millis(value: &Value) -> Option<NaiveDateTime> {
+	let millis = match value {
+		Value::String(millis) => millis.parse().ok()?,
+		value => value.as_i64()?,
+	};
+	NaiveDateTime::from_timestamp_millis(millis)
+}
+
+/// Reads an RFC 3339 time, which Auth0's password exports wrap in `{"$date": ...}`
+fn from_rfc3339(value: &Value) -> Option<NaiveDateTime> {
+	match value {
+		Value::String(date) => DateTime::parse_from_rfc3339(date)
+			.ok()
+			.map(|date| date.with_timezone(&Utc).naive_utc()),
+		Value::Object(wrapped) => from_rfc3339(wrapped.get("$date")?),
+		_ => None,
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FirebaseUser {
+	email: Option<String>,
+	#[serde(default)]
+	email_verified: bool,
+	password_hash: Option<String>,
+	salt: Option<String>,
+	display_name: Option<String>,
+	photo_url: Option<String>,
+	custom_attributes: Option<String>,
+	created_at: Option<Value>,
+	#[serde(default)]
+	disabled: bool,
+	phone_number: Option<String>,
+}
+
+fn from_firebase(value: Value) -> Result<UserRecord, String> {
+	let user: FirebaseUser = serde_json::from_value(value).map_err(|e| e.to_string())?;
+	let mut metadata = match user.custom_attributes {
+		Some(attributes) => match serde_json::from_str(&attributes) {
+			Ok(Value::Object(attributes)) => attributes,
+			_ => return Err("The custom attributes must be a JSON object.".to_string()),
+		},
+		None => Map::new(),
+	};
+	add_profile(&mut metadata, "displayName", user.display_name);
+	add_profile(&mut metadata, "photoUrl", user.photo_url);
+
+	Ok(UserRecord {
+		uid: None,
+		email: user.email,
+		password_hash: match (user.password_hash, user.salt) {
+			(Some(hash), Some(salt)) => {
+				Some(format!("{}{salt}${hash}", hashing::FIREBASE_SCRYPT_PREFIX))
+			}
+			_ => None,
+		},
+		email_verified: user.email_verified,
+		metadata: metadata_string(metadata),
+		created_at: user.created_at.as_ref().and_then(from_millis),
+		active: !user.disabled,
+		// Firebase only adds numbers that were verified with a code
+		phone_verified: user.phone_number.is_some(),
+		phone: user.phone_number,
+	})
+}
+
+#[derive(Deserialize)]
+struct Auth0User {
+	email: Option<String>,
+	#[serde(default)]
+	email_verified: bool,
+	#[serde(alias = "passwordHash")]
+	password_hash: Option<String>,
+	name: Option<String>,
+	picture: Option<String>,
+	user_metadata: Option<Map<String, Value>>,
+	created_at: Option<Value>,
+	#[serde(default)]
+	blocked: bool,
+	phone_number: Option<String>,
+	#[serde(default)]
+	phone_verified: bool,
+}
+
+fn from_auth0(value: Value) -> Result<UserRecord, String> {
+	let user: Auth0User = serde_json::from_value(value).map_err(|e| e.to_string())?;
+	let mut metadata = user.user_metadata.unwrap_or_default();
+	add_profile(&mut metadata, "name", user.name);
+	add_profile(&mut metadata, "picture", user.picture);
+
+	Ok(UserRecord {
+		uid: None,
+		email: user.email,
+		password_hash: user.password_hash,
+		email_verified: user.email_verified,
+		metadata: metadata_string(metadata),
+		created_at: user.created_at.as_ref().and_then(from_rfc3339),
+		active: !user.blocked,
+		phone: user.phone_number,
+		phone_verified: user.phone_verified,
+	})
+}
+
+fn validate(user: &UserRecord, config: &Config) -> Result<(), (&'static str, String)> {
+	match (&user.email, &user.phone) {
+		(None, None) => {
+			return Err(("INVALID_EMAIL", "The user has no email or phone number.".to_string()))
+		}
+		(Some(email), _) if !crate::EMAIL_REGEX.is_match(email) => {
+			return Err(("INVALID_EMAIL", "The email provided is invalid.".to_string()))
+		}
+		(_, Some(number)) if phone::normalize(number).is_none() => {
+			return Err(("INVALID_PHONE", "The phone number provided is invalid.".to_string()))
+		}
+		_ => (),
+	}
+	match &user.password_hash {
+		Some(hash) if !hashing::is_supported(hash, config) => Err((
+			"UNSUPPORTED_PASSWORD_HASH",
+			"The password hash is not in a supported format.".to_string(),
+		)),
+		_ => Ok(()),
+	}
+}
+
+/// Adds the users in batches. Users that already exist, or that appear twice, are reported rather than
+/// changed.
+pub async fn import<C: ConnectionTrait + TransactionTrait>(
+	connection: &C,
+	config: &Config,
+	rows: Vec<Result<UserRecord, RowError>>,
+) -> Result<ImportReport, DbErr> {
+	let mut report = ImportReport::default();
+	let mut seen = (HashSet::new(), HashSet::new(), HashSet::new());
+	let mut rows = rows.into_iter().enumerate();
+	loop {
+		let batch: Vec<_> = rows.by_ref().take(BATCH_SIZE).collect();
+		if batch.is_empty() {
+			return Ok(report);
+		}
+		import_batch(connection, config, batch, &mut seen, &mut report).await?;
+	}
+}
+
+async fn import_batch<C: ConnectionTrait + TransactionTrait>(
+	connection: &C,
+	config: &Config,
+	batch: Vec<(usize, Result<UserRecord, RowError>)>,
+	(seen_emails, seen_phones, seen_uids): &mut (HashSet<String>, HashSet<String>, HashSet<Uuid>),
+	report: &mut ImportReport,
+) -> Result<(), DbErr> {
+	let mut candidates = Vec::new();
+	for (i, row) in batch {
+		let mut user = match row {
+			Ok(user) => user,
+			Err(e) => {
+				report.errors.push(e);
+				continue;
+			}
+		};
+		let reject =
+			|error_code, message| RowError::new(i + 1, user.email.to_owned(), error_code, message);
+		if let Err((error_code, message)) = validate(&user, config) {
+			report.errors.push(reject(error_code, message));
+			continue;
+		}
+		let uid = user.uid.unwrap_or_else(Uuid::new_v4);
+		let number = user.phone.as_deref().and_then(phone::normalize);
+		if user
+			.email
+			.as_ref()
+			.is_some_and(|email| !seen_emails.insert(email.to_owned()))
+			|| number
+				.as_ref()
+				.is_some_and(|number| !seen_phones.insert(number.to_owned()))
+			|| !seen_uids.insert(uid)
+		{
+			report.errors.push(reject(
+				"DUPLICATE_USER",
+				"The user appears more than once in the import.".to_string(),
+			));
+			continue;
+		}
+		user.phone = number;
+		candidates.push((i + 1, uid, user));
+	}
+
+	let emails: Vec<String> = candidates
+		.iter()
+		.filter_map(|(_, _, user)| user.email.to_owned())
+		.collect();
+	let phones: Vec<String> = candidates
+		.iter()
+		.filter_map(|(_, _, user)| user.phone.to_owned())
+		.collect();
+	let mut existing_emails: HashSet<String> = users::Entity::find()
+		.select_only()
+		.column(users::Column::Email)
+		.filter(users::Column::Email.is_in(emails.to_owned()))
+		.into_tuple()
+		.all(connection)
+		.await?
+		.into_iter()
+		.collect();
+	// Someone else may log in with the email, even though it isn't their own
+	existing_emails.extend(
+		entity::identities::Entity::find()
+			.select_only()
+			.column(entity::identities::Column::ProviderSubject)
+			.filter(entity::identities::Column::Provider.is_in(EMAIL_PROVIDERS))
+			.filter(entity::identities::Column::ProviderSubject.is_in(emails))
+			.into_tuple::<String>()
+			.all(connection)
+			.await?,
+	);
+	let mut existing_phones: HashSet<String> = users::Entity::find()
+		.select_only()
+		.column(users::Column::Phone)
+		.filter(users::Column::Phone.is_in(phones.to_owned()))
+		.into_tuple::<Option<String>>()
+		.all(connection)
+		.await?
+		.into_iter()
+		.flatten()
+		.collect();
+	existing_phones.extend(
+		entity::identities::Entity::find()
+			.select_only()
+			.column(entity::identities::Column::ProviderSubject)
+			.filter(entity::identities::Column::Provider.eq(PHONE))
+			.filter(entity::identities::Column::ProviderSubject.is_in(phones))
+			.into_tuple::<String>()
+			.all(connection)
+			.await?,
+	);
+	let existing_uids: HashSet<Uuid> = users::Entity::find()
+		.select_only()
+		.column(users::Column::Uid)
+		.filter(users::Column::Uid.is_in(candidates.iter().map(|(_, uid, _)| *uid)))
+		.into_tuple()
+		.all(connection)
+		.await?
+		.into_iter()
+		.collect();
+
+	let now = Utc::now().naive_utc();
+	let mut models = Vec::new();
+	for (row, uid, user) in candidates {
+		let reject =
+			|error_code, message| RowError::new(row, user.email.to_owned(), error_code, message);
+		if user
+			.email
+			.as_ref()
+			.is_some_and(|email| existing_emails.contains(email))
+		{
+			report
+				.errors
+				.push(reject("EMAIL_IN_USE", "The email provided is already in use.".to_string()));
+			continue;
+		}
+		if user
+			.phone
+			.as_ref()
+			.is_some_and(|number| existing_phones.contains(number))
+		{
+			report.errors.push(reject(
+				"PHONE_IN_USE",
+				"The phone number provided is already in use.".to_string(),
+			));
+			continue;
+		}
+		if existing_uids.contains(&uid) {
+			report
+				.errors
+				.push(reject("USER_EXISTS", "A user with the uid already exists.".to_string()));
+			continue;
+		}
+
+		let mut user_identities = Vec::new();
+		if let Some(email) = &user.email {
+			// Users without a password log in with magic links until they set one
+			let provider = match user.password_hash {
+				Some(_) => PASSWORD,
+				None => MAGIC_LINK,
+			};
+			user_identities.push(identities::new(uid, provider, email, Some(email.to_owned())));
+		}
+		if let Some(number) = &user.phone {
+			user_identities.push(identities::new(uid, PHONE, number, None));
+		}
+		models.push((
+			row,
+			user.email.to_owned(),
+			user_identities,
+			users::ActiveModel {
+				uid: Set(uid),
+				email: Set(user.email),
+				password: Set(user
+					.password_hash
+					.unwrap_or_else(|| NO_PASSWORD.to_string())),
+				created_at: Set(user.created_at.unwrap_or(now)),
+				updated_at: Set(now),
+				last_login: Set(None),
+				active: Set(user.active),
+				metadata: Set(user.metadata),
+				email_verified: Set(user.email_verified),
+				password_changed_at: Set(None),
+				anonymous: Set(false),
+				phone: Set(user.phone),
+				phone_verified: Set(user.phone_verified),
+			},
+		));
+	}
+	if models.is_empty() {
+		return Ok(());
+	}
+
+	// The users are added together with their identities, so that a failure leaves neither behind
+	let txn = connection.begin().await?;
+	let inserted = async {
+		users::Entity::insert_many(models.iter().map(|(_, _, _, model)| model.to_owned()))
+			.exec(&txn)
+			.await?;
+		entity::identities::Entity::insert_many(
+			models
+				.iter()
+				.flat_map(|(_, _, identities, _)| identities.to_owned()),
+		)
+		.exec(&txn)
+		.await
+	}
+	.await;
+	match inserted {
+		Ok(_) => {
+			txn.commit().await?;
+			report.imported += models.len();
+			return Ok(());
+		}
+		Err(_) => txn.rollback().await?,
+	}
+
+	// A user may have been added since the batch was checked, so the users are added one by one to find it
+	for (row, email, identities, model) in models {
+		let txn = connection.begin().await?;
+		let inserted = async {
+			users::Entity::insert(model).exec(&txn).await?;
+			entity::identities::Entity::insert_many(identities)
+				.exec(&txn)
+				.await
+		}
+		.await;
+		match inserted {
+			Ok(_) => {
+				txn.commit().await?;
+				report.imported += 1;
+			}
+			Err(e) => {
+				txn.rollback().await?;
+				report
+					.errors
+					.push(RowError::new(row, email, "INSERT_FAILED", e.to_string()));
+			}
+		}
+	}
+	Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+	#[serde(default)]
+	format: ImportFormat,
+}
+
+/// Imports the users of an export in the body. The users that could not be imported are listed in the
+/// response.
+pub async fn handler(
+	data: Data<AppState>,
+	query: Query<ImportQuery>,
+	mut payload: web::Payload,
+) -> (Json<ApiResponse>, http::StatusCode) {
+	let mut body = web::BytesMut::new();
+	while let Some(chunk) = payload.next().await {
+		let chunk = match chunk {
+			Ok(chunk) => chunk,
+			Err(e) => {
+				return (
+					Json(api_error(e.to_string(), "INVALID_IMPORT".to_string())),
+					http::StatusCode::BAD_REQUEST,
+				)
+			}
+		};
+		if body.len() + chunk.len() > MAX_BODY_SIZE {
+			return (
+				Json(api_error(
+					"The import is too large. Use the import-users command instead.".to_string(),
+					"IMPORT_TOO_LARGE".to_string(),
+				)),
+				http::StatusCode::PAYLOAD_TOO_LARGE,
+			);
+		}
+		body.extend_from_slice(&chunk);
+	}
+
+	let rows = match parse(&body, query.format) {
+		Ok(rows) => rows,
+		Err(e) => {
+			return (
+				Json(api_error(
+					format!("The import could not be read. {e}"),
+					"INVALID_IMPORT".to_string(),
+				)),
+				http::StatusCode::BAD_REQUEST,
+			)
+		}
+	};
+
+	match import(&data.connection, &data.config, rows).await {
+		Ok(report) => (
+			Json(ApiResponse::UserImportResponse {
+				imported: report.imported,
+				errors: report.errors,
+			}),
+			http::StatusCode::OK,
+		),
+		Err(e) => {
+			error!("Unable to import users. Error: {}", e.to_string());
+			(
+				Json(api_error(
+					"An internal server error occurred.".to_string(),
+					"INTERNAL_SERVER_ERROR".to_string(),
+				)),
+				http::StatusCode::INTERNAL_SERVER_ERROR,
+			)
+		}
+	}
+}
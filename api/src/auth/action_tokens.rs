@@ -0,0 +1,143 @@
+//! Tokens that are emailed to users so they can perform an action, such as resetting their password. Each
+//! token is recorded by its `jti` when it is issued, so that it can only be used once, and issuing a new
+//! token of the same kind revokes the ones sent before it. Passkey login states are recorded the same way,
+//! so that each can only be used once.
+
+use chrono::{NaiveDateTime, Utc};
+use entity::action_tokens;
+use migration::DbErr;
+use sea_orm::{ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, Set};
+use uuid::Uuid;
+
+use crate::auth::claims::RegisteredClaims;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+	PasswordReset,
+	MagicLink,
+	EmailVerify,
+	EmailChange,
+	EmailChangeCancel,
+	/// The state of a passkey login, which is not issued to a user, see `record`
+	PasskeyLogin,
+}
+
+impl ActionKind {
+	/// The name stored in the database
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ActionKind::PasswordReset => "password_reset",
+			ActionKind::MagicLink => "magic_link",
+			ActionKind::EmailVerify => "email_verify",
+			ActionKind::EmailChange => "email_change",
+			ActionKind::EmailChangeCancel => "email_change_cancel",
+			ActionKind::PasskeyLogin => "passkey_login",
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum ConsumeError {
+	AlreadyUsed,
+	/// A newer token of the same kind was issued, or the token was never issued at all
+	Revoked,
+	Database(DbErr),
+}
+
+impl ConsumeError {
+	pub fn message(&self) -> &'static str {
+		match self {
+			ConsumeError::AlreadyUsed => "The provided token has already been used.",
+			ConsumeError::Revoked => "The provided token was replaced by a newer one.",
+			ConsumeError::Database(_) => "An internal server error occurred.",
+		}
+	}
+
+	pub fn error_code(&self) -> &'static str {
+		match self {
+			ConsumeError::AlreadyUsed => "TOKEN_ALREADY_USED",
+			ConsumeError::Revoked => "TOKEN_REVOKED",
+			ConsumeError::Database(_) => "INTERNAL_SERVER_ERROR",
+		}
+	}
+}
+
+/// Records a token that was issued to the user, and revokes the unused tokens of the same kind that were
+/// issued to them before
+pub async fn issue<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	kind: ActionKind,
+	claims: &RegisteredClaims,
+) -> Result<(), DbErr> {
+	revoke(connection, uid, kind).await?;
+	record(connection, uid, kind, claims).await
+}
+
+/// Records a token without revoking the ones issued before it. Tokens that aren't issued to a user, such
+/// as the state of a passkey login that has yet to find out whose passkey it is, use the nil uid.
+pub async fn record<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	kind: ActionKind,
+	claims: &RegisteredClaims,
+) -> Result<(), DbErr> {
+	action_tokens::Entity::insert(action_tokens::ActiveModel {
+		jti: Set(claims.jti.to_owned()),
+		uid: Set(uid),
+		kind: Set(kind.as_str().to_string()),
+		expiry: Set(NaiveDateTime::from_timestamp_opt(claims.exp, 0).unwrap_or_default()),
+		consumed_at: Set(None),
+	})
+	.exec(connection)
+	.await
+	.map(|_| ())
+}
+
+/// Revokes the user's unused tokens of the kind
+pub async fn revoke<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	kind: ActionKind,
+) -> Result<(), DbErr> {
+	action_tokens::Entity::delete_many()
+		.filter(action_tokens::Column::Uid.eq(uid))
+		.filter(action_tokens::Column::Kind.eq(kind.as_str()))
+		.filter(action_tokens::Column::ConsumedAt.is_null())
+		.exec(connection)
+		.await
+		.map(|_| ())
+}
+
+/// Uses up the token, which must have been issued to the user
+pub async fn consume<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	kind: ActionKind,
+	jti: &str,
+) -> Result<(), ConsumeError> {
+	let res = action_tokens::Entity::update_many()
+		.col_expr(action_tokens::Column::ConsumedAt, Utc::now().naive_utc().into())
+		.filter(action_tokens::Column::Jti.eq(jti))
+		.filter(action_tokens::Column::Uid.eq(uid))
+		.filter(action_tokens::Column::Kind.eq(kind.as_str()))
+		.filter(action_tokens::Column::ConsumedAt.is_null())
+		.exec(connection)
+		.await
+		.map_err(ConsumeError::Database)?;
+	if res.rows_affected == 1 {
+		return Ok(());
+	}
+
+	// Used tokens are kept until they expire, revoked ones are deleted
+	let used = action_tokens::Entity::find_by_id(jti.to_owned())
+		.filter(action_tokens::Column::Uid.eq(uid))
+		.filter(action_tokens::Column::Kind.eq(kind.as_str()))
+		.one(connection)
+		.await
+		.map_err(ConsumeError::Database)?;
+	match used {
+		Some(_) => Err(ConsumeError::AlreadyUsed),
+		None => Err(ConsumeError::Revoked),
+	}
+}
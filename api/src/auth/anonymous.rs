@@ -0,0 +1,360 @@
+//! Guest users, who can use an app before they sign up. An anonymous user has no email or password, so
+//! their session is the only way back into the account until they attach an email and password, an
+//! email through a magic link, or an OAuth identity. Upgrading keeps the uid, so anything the app stored
+//! for the guest carries over.
+
+use crate::{
+	auth::{
+		api_error, hashing, identities, password_policy, util, util::HeaderResult, ApiResponse,
+	},
+	AnonymousUserConfig, AppState,
+};
+use actix_web::{
+	http, post,
+	web::{Data, Json},
+	Either, HttpRequest, HttpResponse,
+};
+use chrono::{Duration, Utc};
+use entity::{
+	custom_claims, group_members, refresh_tokens, role_members, security_events, sessions, users,
+};
+use log::error;
+use migration::DbErr;
+use sea_orm::{
+	sea_query::{Expr, Query},
+	ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter,
+	QuerySelect, Set, TransactionTrait,
+};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct UpgradeBody {
+	email: String,
+	password: String,
+}
+
+pub enum UpgradeError {
+	EmailInUse,
+	/// The user was upgraded or deleted in the meantime
+	NotAnonymous,
+	Database(DbErr),
+}
+
+impl From<DbErr> for UpgradeError {
+	fn from(e: DbErr) -> Self {
+		UpgradeError::Database(e)
+	}
+}
+
+impl From<identities::LinkError> for UpgradeError {
+	fn from(e: identities::LinkError) -> Self {
+		match e {
+			identities::LinkError::InUse => UpgradeError::EmailInUse,
+			identities::LinkError::Database(e) => UpgradeError::Database(e),
+		}
+	}
+}
+
+impl UpgradeError {
+	pub fn response(&self) -> (Json<ApiResponse>, http::StatusCode) {
+		match self {
+			UpgradeError::EmailInUse => (
+				Json(api_error(
+					"The email provided is already in use.".to_string(),
+					"EMAIL_IN_USE".to_string(),
+				)),
+				http::StatusCode::CONFLICT,
+			),
+			UpgradeError::NotAnonymous => not_anonymous(),
+			UpgradeError::Database(e) => {
+				error!("Unable to upgrade anonymous user. Error: {}", e.to_string());
+				internal_error()
+			}
+		}
+	}
+}
+
+/// Anonymous users have no email, but emails must be unique. This is never a valid email, so it can't
+/// belong to anyone else or be sent mail.
+pub fn placeholder_email(uid: Uuid) -> String {
+	format!("anonymous:{uid}")
+}
+
+/// Finds the anonymous user that the request's access token belongs to
+pub async fn authenticate(
+	request: &HttpRequest,
+	data: &AppState,
+) -> Result<users::Model, (Json<ApiResponse>, http::StatusCode)> {
+	let uid = match util::verify_header(request.headers().get("Authorization"), &data.key_ring) {
+		HeaderResult::Error(r, s) => return Err((r, s)),
+		HeaderResult::Uid(uid) => uid,
+	};
+	match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(user)) if user.anonymous => Ok(user),
+		Ok(Some(_)) => Err(not_anonymous()),
+		Ok(None) => Err((
+			Json(api_error("The user does not exist.".to_string(), "USER_NOT_FOUND".to_string())),
+			http::StatusCode::NOT_FOUND,
+		)),
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			Err(internal_error())
+		}
+	}
+}
+
+/// Turns an anonymous user into a regular one with `email`, and a password when one is given. Only
+/// anonymous users are changed, so an account can't be upgraded twice. The caller links the identity that
+/// the user will log in with, in the same transaction.
+pub async fn upgrade<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	email: &str,
+	email_verified: bool,
+	password_hash: Option<String>,
+) -> Result<(), UpgradeError> {
+	let in_use = users::Entity::find()
+		.filter(users::Column::Email.eq(email))
+		.count(connection)
+		.await?;
+	if in_use > 0 {
+		return Err(UpgradeError::EmailInUse);
+	}
+
+	let now = Utc::now().naive_utc();
+	let mut update = users::Entity::update_many()
+		.col_expr(users::Column::Email, Expr::value(email))
+		.col_expr(users::Column::EmailVerified, Expr::value(email_verified))
+		.col_expr(users::Column::Anonymous, Expr::value(false))
+		.col_expr(users::Column::UpdatedAt, Expr::value(now));
+	if let Some(password_hash) = password_hash {
+		update = update
+			.col_expr(users::Column::Password, Expr::value(password_hash))
+			.col_expr(users::Column::PasswordChangedAt, Expr::value(now));
+	}
+	let res = update
+		.filter(users::Column::Uid.eq(uid))
+		.filter(users::Column::Anonymous.eq(true))
+		.exec(connection)
+		.await?;
+	match res.rows_affected {
+		1 => Ok(()),
+		_ => Err(UpgradeError::NotAnonymous),
+	}
+}
+
+/// Deletes the anonymous users that have gone `prune_after_days` without a session. Without a session,
+/// there is no way back into the account. Returns how many users were deleted.
+pub async fn prune(
+	connection: &DatabaseConnection,
+	config: &AnonymousUserConfig,
+) -> Result<u64, DbErr> {
+	let cutoff = Utc::now().naive_utc() - Duration::days(config.prune_after_days);
+	let stale: Vec<Uuid> = users::Entity::find()
+		.select_only()
+		.column(users::Column::Uid)
+		.filter(users::Column::Anonymous.eq(true))
+		.filter(users::Column::CreatedAt.lte(cutoff))
+		.filter(
+			users::Column::Uid.not_in_subquery(
+				Query::select()
+					.column(sessions::Column::Uid)
+					.from(sessions::Entity)
+					.and_where(sessions::Column::Expiry.gt(cutoff))
+					.to_owned(),
+			),
+		)
+		.into_tuple()
+		.all(connection)
+		.await?;
+	if stale.is_empty() {
+		return Ok(0);
+	}
+
+	let txn = connection.begin().await?;
+	refresh_tokens::Entity::delete_many()
+		.filter(refresh_tokens::Column::Uid.is_in(stale.to_owned()))
+		.exec(&txn)
+		.await?;
+	sessions::Entity::delete_many()
+		.filter(sessions::Column::Uid.is_in(stale.to_owned()))
+		.exec(&txn)
+		.await?;
+	security_events::Entity::delete_many()
+		.filter(security_events::Column::Uid.is_in(stale.to_owned()))
+		.exec(&txn)
+		.await?;
+	custom_claims::Entity::delete_many()
+		.filter(custom_claims::Column::Uid.is_in(stale.to_owned()))
+		.exec(&txn)
+		.await?;
+	role_members::Entity::delete_many()
+		.filter(role_members::Column::Uid.is_in(stale.to_owned()))
+		.exec(&txn)
+		.await?;
+	group_members::Entity::delete_many()
+		.filter(group_members::Column::Uid.is_in(stale.to_owned()))
+		.exec(&txn)
+		.await?;
+	// Only users that are still anonymous, in case one was upgraded in the meantime
+	let res = users::Entity::delete_many()
+		.filter(users::Column::Uid.is_in(stale))
+		.filter(users::Column::Anonymous.eq(true))
+		.exec(&txn)
+		.await?;
+	txn.commit().await?;
+
+	Ok(res.rows_affected)
+}
+
+/// Creates an anonymous user and logs them in
+#[post("/api/auth/user/anonymous")]
+pub async fn create_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+) -> (Json<ApiResponse>, http::StatusCode) {
+	if !data.config.anonymous_users.enabled {
+		return (
+			Json(api_error(
+				"The server does not allow anonymous users.".to_string(),
+				"ANONYMOUS_USERS_DISABLED".to_string(),
+			)),
+			http::StatusCode::BAD_REQUEST,
+		);
+	}
+
+	let uid = Uuid::new_v4();
+	let now = Utc::now().naive_utc();
+	let new_user = users::ActiveModel {
+		uid: Set(uid),
+		email: Set(Some(placeholder_email(uid))),
+		password: Set("0".to_string()), // Not a valid hash, so password login is impossible
+		created_at: Set(now),
+		updated_at: Set(now),
+		last_login: Set(None),
+		active: Set(true),
+		metadata: Set(None),
+		email_verified: Set(false),
+		password_changed_at: Set(None),
+		anonymous: Set(true),
+		phone: Set(None),
+		phone_verified: Set(false),
+	};
+	if let Err(e) = users::Entity::insert(new_user).exec(&data.connection).await {
+		error!("Unable to create anonymous user. Error: {}", e.to_string());
+		return internal_error();
+	}
+
+	let uid_str = uid.to_string();
+	let (at, rt, exp) = util::get_at_and_rt(
+		&data.connection,
+		&uid_str,
+		&data.key_ring,
+		&data.config.tokens,
+		false,
+		util::Session::new(&request, &data.ua_parser),
+	)
+	.await;
+
+	(
+		Json(ApiResponse::LoginResponse {
+			uid: uid_str,
+			token: at,
+			expiry: exp,
+			refresh_token: rt,
+			email_verified: false,
+			metadata: String::new(),
+		}),
+		http::StatusCode::CREATED,
+	)
+}
+
+/// Gives the anonymous user an email and password, which they can log in with from then on
+#[post("/api/auth/user/anonymous/upgrade")]
+pub async fn upgrade_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+	body: Json<UpgradeBody>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let user = match authenticate(&request, &data).await {
+		Ok(user) => user,
+		Err(response) => return Either::Left(response),
+	};
+
+	if !crate::EMAIL_REGEX.is_match(&body.email) {
+		return Either::Left(invalid_email());
+	}
+
+	let breached =
+		match password_policy::check(&data, &body.password, &body.email, Some(&user)).await {
+			Ok(breached) => breached,
+			Err(response) => return Either::Left(response),
+		};
+
+	let password_hash = hashing::hash(&body.password, &data.config);
+	let upgraded = async {
+		let txn = data.connection.begin().await?;
+		upgrade(&txn, user.uid, &body.email, false, Some(password_hash)).await?;
+		identities::link(
+			&txn,
+			user.uid,
+			identities::PASSWORD,
+			&body.email,
+			Some(body.email.to_owned()),
+		)
+		.await?;
+		txn.commit().await?;
+		Ok::<(), UpgradeError>(())
+	};
+	if let Err(e) = upgraded.await {
+		return Either::Left(e.response());
+	}
+
+	if breached {
+		password_policy::record_breached(&data, user.uid, &request).await;
+	}
+
+	Either::Right(HttpResponse::Ok().finish())
+}
+
+pub fn invalid_email() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The email provided is invalid.".to_string(),
+			"INVALID_EMAIL".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+/// For requests that need the user's email, which anonymous users only get by upgrading
+pub fn no_email() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"Anonymous users have no email. Upgrade the user to add one.".to_string(),
+			"ANONYMOUS_USER".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn not_anonymous() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"Only anonymous users can be upgraded.".to_string(),
+			"USER_NOT_ANONYMOUS".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"Internal Server Error".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
@@ -0,0 +1,372 @@
+//! The claims of every token the server issues, and the one place they are validated.
+//!
+//! Each kind of token has its own audience, so a token can never be used as another kind. Tokens are
+//! signed with the key ring when other services need to verify them (access and refresh tokens), and
+//! with the secret key when only this server reads them.
+
+use chrono::{Duration, Utc};
+use hmac::Hmac;
+use jwt::{SignWithKey, VerifyWithKey};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::keys::KeyRing;
+
+pub const ISSUER: &str = "TurboCore";
+/// The claims TurboCore sets in access tokens, which custom claims can't replace
+pub const RESERVED_CLAIMS: [&str; 10] = [
+	"iss",
+	"aud",
+	"exp",
+	"iat",
+	"nbf",
+	"jti",
+	"sub",
+	"sid",
+	"role",
+	"permissions",
+];
+/// How far ahead of this server's clock another instance's clock may be, in seconds
+const NBF_LEEWAY: i64 = 15;
+
+/// The claims shared by every token, see RFC 7519 section 4.1
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredClaims {
+	pub iss: String,
+	pub aud: String,
+	pub exp: i64,
+	pub iat: i64,
+	pub nbf: i64,
+	pub jti: String,
+}
+
+impl RegisteredClaims {
+	/// Claims for a new token that is valid from now on, for the given lifetime
+	pub fn new(audience: &str, lifetime: Duration) -> Self {
+		let now = Utc::now().timestamp();
+		Self {
+			iss: ISSUER.to_string(),
+			aud: audience.to_string(),
+			exp: now + lifetime.num_seconds(),
+			iat: now,
+			nbf: now,
+			jti: Uuid::new_v4().simple().to_string(),
+		}
+	}
+}
+
+pub trait TokenClaims: Serialize + DeserializeOwned {
+	/// The `aud` of this kind of token
+	const AUDIENCE: &'static str;
+
+	fn registered(&self) -> &RegisteredClaims;
+}
+
+macro_rules! token_claims {
+	($name:ident, $audience:literal) => {
+		impl TokenClaims for $name {
+			const AUDIENCE: &'static str = $audience;
+
+			fn registered(&self) -> &RegisteredClaims {
+				&self.registered
+			}
+		}
+	};
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+	/// The session the token was issued for
+	pub sid: Uuid,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub role: Option<String>,
+	/// The permissions of the user's roles, see `admin::rbac`
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub permissions: Vec<String>,
+	/// The claims an admin set for the user
+	#[serde(flatten)]
+	pub custom: serde_json::Map<String, serde_json::Value>,
+}
+token_claims!(AccessClaims, "TurboCore/access");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+	/// The session the token was issued for
+	pub sid: Uuid,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub role: Option<String>,
+}
+token_claims!(RefreshClaims, "TurboCore/refresh");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MagicLinkClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+	/// Where to send the user once they are logged in
+	pub next: String,
+	/// The email to give an anonymous user, who proves they own it by following the link
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub email: Option<String>,
+}
+token_claims!(MagicLinkClaims, "TurboCore/magic-link");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailVerifyClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+	/// Where to send the user once their email is verified
+	pub next: String,
+}
+token_claims!(EmailVerifyClaims, "TurboCore/email-verify");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordResetClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+}
+token_claims!(PasswordResetClaims, "TurboCore/password-reset");
+
+/// Confirms a pending email change. Sent to the new address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailChangeClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+	/// Where to send the user once the change is confirmed
+	pub next: String,
+}
+token_claims!(EmailChangeClaims, "TurboCore/email-change");
+
+/// Cancels a pending email change. Sent to the old address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailChangeCancelClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+	/// Where to send the user once the change is cancelled
+	pub next: String,
+}
+token_claims!(EmailChangeCancelClaims, "TurboCore/email-change-cancel");
+
+/// Proves that the user has passed the first factor, see `mfa::challenge_handler`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaChallengeClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+	/// Whether the login asked to be remembered, see `TokenConfig::short_refresh_token_lifetime`
+	#[serde(default = "remember_me_default")]
+	pub remember_me: bool,
+	/// Whether the first step was a password, which must be changed once it expires
+	#[serde(default)]
+	pub password_login: bool,
+}
+token_claims!(MfaChallengeClaims, "TurboCore/mfa");
+
+/// The state of a passkey registration, between the options and the response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasskeyRegisterClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub sub: Uuid,
+	pub challenge: String,
+}
+token_claims!(PasskeyRegisterClaims, "TurboCore/webauthn-register");
+
+/// The state of a passkey login, between the options and the response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasskeyLoginClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub challenge: String,
+}
+token_claims!(PasskeyLoginClaims, "TurboCore/webauthn-login");
+
+/// An authorization request to the identity provider, while the user logs in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcRequestClaims {
+	#[serde(flatten)]
+	pub registered: RegisteredClaims,
+	pub client_id: String,
+	pub redirect_uri: String,
+	pub scope: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub state: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub nonce: Option<String>,
+	pub code_challenge: String,
+}
+token_claims!(OidcRequestClaims, "TurboCore/oidc-request");
+
+fn remember_me_default() -> bool {
+	true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+	/// The signature is wrong, or the claims are missing or malformed. This includes tokens minted by
+	/// older versions, which used string claims.
+	Invalid,
+	/// The token is for another audience, such as a refresh token used as an access token
+	WrongAudience,
+	Expired,
+	NotYetValid,
+}
+
+impl TokenError {
+	pub fn message(&self) -> &'static str {
+		match self {
+			TokenError::Invalid => "The provided token could not be verified by the server.",
+			TokenError::WrongAudience => "The provided token cannot be used here.",
+			TokenError::Expired => "The provided token has already expired.",
+			TokenError::NotYetValid => "The provided token is not valid yet.",
+		}
+	}
+}
+
+/// A key that tokens can be signed and verified with
+pub trait TokenKey {
+	fn sign_claims<T: Serialize>(&self, claims: &T) -> String;
+
+	/// Returns the claims if the signature is valid, without validating them
+	fn verify_signature<T: DeserializeOwned>(&self, token: &str) -> Option<T>;
+}
+
+impl TokenKey for KeyRing {
+	fn sign_claims<T: Serialize>(&self, claims: &T) -> String {
+		self.sign(claims)
+	}
+
+	fn verify_signature<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+		self.verify(token)
+	}
+}
+
+impl TokenKey for Hmac<Sha256> {
+	fn sign_claims<T: Serialize>(&self, claims: &T) -> String {
+		claims.sign_with_key(self).unwrap()
+	}
+
+	fn verify_signature<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+		token.verify_with_key(self).ok()
+	}
+}
+
+pub fn sign<T: TokenClaims>(claims: &T, key: &impl TokenKey) -> String {
+	key.sign_claims(claims)
+}
+
+/// Verifies the token's signature and validates its claims
+pub fn verify<T: TokenClaims>(token: &str, key: &impl TokenKey) -> Result<T, TokenError> {
+	let claims: T = key.verify_signature(token).ok_or(TokenError::Invalid)?;
+	validate(claims.registered(), T::AUDIENCE)?;
+	Ok(claims)
+}
+
+fn validate(claims: &RegisteredClaims, audience: &str) -> Result<(), TokenError> {
+	let now = Utc::now().timestamp();
+	if claims.iss != ISSUER {
+		return Err(TokenError::Invalid);
+	}
+	if claims.aud != audience {
+		return Err(TokenError::WrongAudience);
+	}
+	if claims.nbf > now + NBF_LEEWAY {
+		return Err(TokenError::NotYetValid);
+	}
+	if now > claims.exp {
+		return Err(TokenError::Expired);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::keys::KeyAlgorithm;
+	use hmac::Mac;
+	use std::collections::BTreeMap;
+
+	fn secret_key() -> Hmac<Sha256> {
+		Hmac::new_from_slice(b"a_very_long_secret_key").unwrap()
+	}
+
+	fn reset_claims(lifetime: Duration) -> PasswordResetClaims {
+		PasswordResetClaims {
+			registered: RegisteredClaims::new(PasswordResetClaims::AUDIENCE, lifetime),
+			sub: Uuid::new_v4(),
+		}
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let key = secret_key();
+		let claims = reset_claims(Duration::minutes(15));
+		let token = sign(&claims, &key);
+
+		let verified: PasswordResetClaims = verify(&token, &key).unwrap();
+		assert_eq!(verified.sub, claims.sub);
+		assert_eq!(verified.registered.jti, claims.registered.jti);
+	}
+
+	#[test]
+	fn test_expired() {
+		let key = secret_key();
+		let token = sign(&reset_claims(Duration::minutes(-1)), &key);
+		assert_eq!(verify::<PasswordResetClaims>(&token, &key).unwrap_err(), TokenError::Expired);
+	}
+
+	#[test]
+	fn test_not_yet_valid() {
+		let key = secret_key();
+		let mut claims = reset_claims(Duration::minutes(15));
+		claims.registered.nbf += 60;
+		let token = sign(&claims, &key);
+		assert_eq!(
+			verify::<PasswordResetClaims>(&token, &key).unwrap_err(),
+			TokenError::NotYetValid
+		);
+	}
+
+	#[test]
+	fn test_wrong_audience() {
+		// A magic link must not work as a password reset token, even though it has every claim one needs
+		let key = secret_key();
+		let claims = MagicLinkClaims {
+			registered: RegisteredClaims::new(MagicLinkClaims::AUDIENCE, Duration::minutes(15)),
+			sub: Uuid::new_v4(),
+			next: "https://example.com".to_string(),
+			email: None,
+		};
+		let token = sign(&claims, &key);
+		assert_eq!(
+			verify::<PasswordResetClaims>(&token, &key).unwrap_err(),
+			TokenError::WrongAudience
+		);
+	}
+
+	#[test]
+	fn test_legacy_token() {
+		// Older versions used string claims, and no audience
+		let ring = KeyRing::ephemeral(KeyAlgorithm::ES256);
+		let uid = Uuid::new_v4().to_string();
+		let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
+		claims.insert("iss", "TurboCore");
+		claims.insert("type", "at");
+		claims.insert("uid", &uid);
+		claims.insert("exp", "9999999999");
+		let token = ring.sign(&claims);
+
+		assert_eq!(verify::<AccessClaims>(&token, &ring).unwrap_err(), TokenError::Invalid);
+	}
+}
@@ -1,19 +1,18 @@
-extern crate zxcvbn;
-
-use crate::auth::{api_error, util, ApiResponse};
+use crate::auth::{
+	api_error, hashing,
+	identities::{self, LinkError, PASSWORD},
+	password_policy, util, ApiResponse,
+};
 use actix_web::{
 	http, post,
 	web::{Data, Json},
 	Responder,
 };
-use argon2::{self, Config as ArgonConfig, ThreadMode, Variant, Version};
 use chrono::Utc;
 use entity::users;
 use migration::{DbErr, OnConflict};
-use rand::{thread_rng, Rng};
-use sea_orm::{EntityTrait, Set};
+use sea_orm::{EntityTrait, Set, TransactionTrait};
 use uuid::Uuid;
-use zxcvbn::zxcvbn;
 
 use crate::AppState;
 
@@ -26,7 +25,11 @@ pub struct SignupBody {
 }
 
 #[post("/api/auth/user/create")]
-pub async fn handler(data: Data<AppState>, body: Json<SignupBody>) -> impl Responder {
+pub async fn handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<SignupBody>,
+) -> impl Responder {
 	// Check email validity
 	if !crate::EMAIL_REGEX.is_match(&body.email) {
 		return (
@@ -38,60 +41,20 @@ pub async fn handler(data: Data<AppState>, body: Json<SignupBody>) -> impl Respo
 		);
 	}
 
-	// Check password strength
-	let estimate = match zxcvbn(&body.password, &[]) {
-		Ok(ent) => ent,
-		Err(_) => {
-			return (
-				Json(api_error(
-					"An invalid password was provided.".to_string(),
-					"INVALID_PASSWORD".to_string(),
-				)),
-				http::StatusCode::BAD_REQUEST,
-			);
-		}
+	let breached = match password_policy::check(&data, &body.password, &body.email, None).await {
+		Ok(breached) => breached,
+		Err(response) => return response,
 	};
-	let score = estimate.score();
-	if score < data.config.minimum_password_strength {
-		let feedback_msg = match estimate.feedback().clone() {
-			Some(w) => match w.warning() {
-				Some(w) => format!("The password provided is too weak. {w}",),
-				None => "The password provided is too weak.".to_string(),
-			},
-			None => "The password provided is too weak.".to_string(),
-		};
-		return (
-			Json(api_error(feedback_msg, "WEAK_PASSWORD".to_string())),
-			http::StatusCode::BAD_REQUEST,
-		);
-	}
 
 	// Get uid for new user
 	let user_uid = Uuid::new_v4();
 
-	let config = ArgonConfig {
-		variant: Variant::Argon2id,
-		version: Version::Version13,
-		mem_cost: data.config.argon2_config.memory,
-		time_cost: data.config.argon2_config.iterations,
-		lanes: data.config.argon2_config.parallelism,
-		thread_mode: ThreadMode::Parallel,
-		secret: &[],
-		ad: &[],
-		hash_length: data.config.argon2_config.tag_length,
-	};
-
-	let salt: Vec<u8> = (0..data.config.argon2_config.salt_length)
-		.map(|_| thread_rng().gen_range(0..255))
-		.collect();
-
-	let password_hash =
-		argon2::hash_encoded(body.password.as_bytes(), salt.as_slice(), &config).unwrap();
+	let password_hash = hashing::hash(&body.password, &data.config);
 
 	// FIXME: Vulnerable until sanitize middleware is implemented
 	let new_user = users::ActiveModel {
 		uid: Set(user_uid),
-		email: Set(body.email.to_owned()),
+		email: Set(Some(body.email.to_owned())),
 		password: Set(password_hash),
 		created_at: Set(Utc::now().naive_utc()),
 		last_login: Set(None),
@@ -99,25 +62,47 @@ pub async fn handler(data: Data<AppState>, body: Json<SignupBody>) -> impl Respo
 		active: Set(true),
 		metadata: Set(Some(body.metadata.to_owned())),
 		email_verified: Set(false),
+		password_changed_at: Set(Some(Utc::now().naive_utc())),
+		anonymous: Set(false),
+		phone: Set(None),
+		phone_verified: Set(false),
 	};
 
-	let res = users::Entity::insert(new_user)
-		.on_conflict(
-			// If email exists, we will ignore the request to create a new user, causing a DbErr::RecordNotInserted
-			OnConflict::column(users::Column::Email)
-				.do_nothing()
-				.to_owned(),
-		)
-		.exec(&data.connection)
-		.await;
+	let res = async {
+		let txn = data.connection.begin().await?;
+		users::Entity::insert(new_user)
+			.on_conflict(
+				// If email exists, we will ignore the request to create a new user, causing a DbErr::RecordNotInserted
+				OnConflict::column(users::Column::Email)
+					.do_nothing()
+					.to_owned(),
+			)
+			.exec(&txn)
+			.await?;
+		identities::link(&txn, user_uid, PASSWORD, &body.email, Some(body.email.to_owned())).await?;
+		txn.commit().await?;
+		Ok::<(), LinkError>(())
+	}
+	.await;
 
 	match res {
 		Ok(_) => {
+			if breached {
+				password_policy::record_breached(&data, user_uid, &request).await;
+			}
+
 			if body.login {
 				let uid_str = user_uid.to_string();
 
-				let (token_str, rt_str, short_exp) =
-					util::get_at_and_rt(&data.connection, &uid_str, &data.config.secret_key, false).await;
+				let (token_str, rt_str, short_exp) = util::get_at_and_rt(
+					&data.connection,
+					&uid_str,
+					&data.key_ring,
+					&data.config.tokens,
+					false,
+					util::Session::new(&request, &data.ua_parser),
+				)
+				.await;
 
 				(
 					Json(ApiResponse::LoginResponse {
@@ -139,7 +124,7 @@ pub async fn handler(data: Data<AppState>, body: Json<SignupBody>) -> impl Respo
 				)
 			}
 		}
-		Err(DbErr::RecordNotInserted) => (
+		Err(LinkError::Database(DbErr::RecordNotInserted)) | Err(LinkError::InUse) => (
 			Json(api_error(
 				"The email provided is already in use.".to_string(),
 				"EMAIL_IN_USE".to_string(),
@@ -1,5 +1,9 @@
 use crate::{
-	auth::util::{self, HeaderResult},
+	admin::{custom_claims, rbac},
+	auth::{
+		identities,
+		util::{self, HeaderResult},
+	},
 	AppState,
 };
 use actix_web::{
@@ -7,7 +11,7 @@ use actix_web::{
 	web::{Data, Json},
 	Either, HttpResponse,
 };
-use entity::{refresh_tokens, users};
+use entity::{refresh_tokens, security_events, sessions, users};
 use log::error;
 use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 
@@ -21,7 +25,7 @@ pub async fn handler(
 	let header_map = request.headers();
 	let authorization = header_map.get("Authorization");
 
-	let uid = match util::verify_header(authorization, &data.config.secret_key) {
+	let uid = match util::verify_header(authorization, &data.key_ring) {
 		HeaderResult::Error(r, s) => {
 			return Either::Left((r, s));
 		}
@@ -37,6 +41,36 @@ pub async fn handler(
 		Err(e) => error!("Failed to delete user {}. Error: {}", uid.to_string(), e.to_string()),
 	}
 
+	// Delete the identities, so that they can be used to sign up again
+	match identities::delete_all(&data.connection, uid).await {
+		Ok(_) => (),
+		Err(e) => error!(
+			"Failed to delete identities for {}. Error: {}",
+			uid.to_string(),
+			e.to_string()
+		),
+	}
+
+	// Delete the custom claims
+	match custom_claims::delete(&data.connection, uid).await {
+		Ok(_) => (),
+		Err(e) => error!(
+			"Failed to delete custom claims for {}. Error: {}",
+			uid.to_string(),
+			e.to_string()
+		),
+	}
+
+	// Take the user out of their roles and groups
+	match rbac::remove_member(&data.connection, uid).await {
+		Ok(_) => (),
+		Err(e) => error!(
+			"Failed to delete role memberships for {}. Error: {}",
+			uid.to_string(),
+			e.to_string()
+		),
+	}
+
 	// Delete the refresh tokens
 	match refresh_tokens::Entity::delete_many()
 		.filter(refresh_tokens::Column::Uid.eq(uid))
@@ -51,6 +85,32 @@ pub async fn handler(
 		),
 	}
 
+	// Delete the sessions
+	match sessions::Entity::delete_many()
+		.filter(sessions::Column::Uid.eq(uid))
+		.exec(&data.connection)
+		.await
+	{
+		Ok(_) => (),
+		Err(e) => {
+			error!("Failed to delete sessions for {}. Error: {}", uid.to_string(), e.to_string())
+		}
+	}
+
+	// Delete the security events
+	match security_events::Entity::delete_many()
+		.filter(security_events::Column::Uid.eq(uid))
+		.exec(&data.connection)
+		.await
+	{
+		Ok(_) => (),
+		Err(e) => error!(
+			"Failed to delete security events for {}. Error: {}",
+			uid.to_string(),
+			e.to_string()
+		),
+	}
+
 	Either::Right(HttpResponse::Ok().finish())
 }
 
@@ -0,0 +1,361 @@
+//! Changing a user's email. The new address has to be confirmed before it replaces the old one, and the old
+//! address is sent a notice with a link to cancel the change.
+
+use actix_web::{
+	get, http,
+	web::{Data, Json, Path},
+	HttpResponse,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use email::{email_change, verification, EmailParams};
+use entity::{email_changes, login_codes, users};
+use log::error;
+use migration::DbErr;
+use sea_orm::{
+	ActiveModelTrait, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, Set, TransactionTrait,
+};
+
+use crate::{
+	auth::{
+		action_tokens::{self, ActionKind, ConsumeError},
+		api_error,
+		claims::{
+			self, EmailChangeCancelClaims, EmailChangeClaims, RegisteredClaims, TokenClaims,
+			TokenError,
+		},
+		identities, ApiResponse,
+	},
+	AppState,
+};
+use uaparser::Parser;
+
+/// Tokens that were sent to the old address, and must not be usable once the email has changed
+const OLD_ADDRESS_TOKENS: [ActionKind; 4] = [
+	ActionKind::PasswordReset,
+	ActionKind::MagicLink,
+	ActionKind::EmailVerify,
+	ActionKind::EmailChangeCancel,
+];
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
+
+fn email_in_use() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The email provided is already in use.".to_string(),
+			"EMAIL_IN_USE".to_string(),
+		)),
+		http::StatusCode::CONFLICT,
+	)
+}
+
+async fn email_taken<C: sea_orm::ConnectionTrait>(
+	connection: &C,
+	email: &str,
+) -> Result<bool, DbErr> {
+	users::Entity::find()
+		.filter(users::Column::Email.eq(email))
+		.count(connection)
+		.await
+		.map(|count| count > 0)
+}
+
+/// Starts changing the user's email to `new_email`. Any change that was already pending is replaced.
+pub async fn start(
+	request: &actix_web::HttpRequest,
+	data: &AppState,
+	user: &users::Model,
+	new_email: &str,
+	next_url: &str,
+) -> Result<(), (Json<ApiResponse>, http::StatusCode)> {
+	if data.config.mailer.is_none() || data.config.email.is_none() {
+		return Err((
+			Json(api_error(
+				"The server is not configured to send emails.".to_string(),
+				"EMAIL_NOT_CONFIGURED".to_string(),
+			)),
+			http::StatusCode::BAD_REQUEST,
+		));
+	}
+
+	if !crate::EMAIL_REGEX.is_match(new_email) {
+		return Err((
+			Json(api_error(
+				"The email provided is invalid.".to_string(),
+				"INVALID_EMAIL".to_string(),
+			)),
+			http::StatusCode::BAD_REQUEST,
+		));
+	}
+
+	match email_taken(&data.connection, new_email).await {
+		Ok(false) => (),
+		Ok(true) => return Err(email_in_use()),
+		Err(e) => {
+			error!("Unable to check if the email is in use. Error: {}", e.to_string());
+			return Err(internal_error());
+		}
+	}
+
+	let lifetime = Duration::seconds(data.config.tokens.email_change_lifetime);
+	let confirm_claims = EmailChangeClaims {
+		registered: RegisteredClaims::new(EmailChangeClaims::AUDIENCE, lifetime),
+		sub: user.uid,
+		next: next_url.to_owned(),
+	};
+	let cancel_claims = EmailChangeCancelClaims {
+		registered: RegisteredClaims::new(EmailChangeCancelClaims::AUDIENCE, lifetime),
+		sub: user.uid,
+		next: next_url.to_owned(),
+	};
+
+	let stored = async {
+		let txn = data.connection.begin().await?;
+		email_changes::Entity::delete_by_id(user.uid)
+			.exec(&txn)
+			.await?;
+		email_changes::Entity::insert(email_changes::ActiveModel {
+			uid: Set(user.uid),
+			new_email: Set(new_email.to_owned()),
+			expiry: Set(NaiveDateTime::from_timestamp_opt(confirm_claims.registered.exp, 0)
+				.unwrap_or_default()),
+		})
+		.exec(&txn)
+		.await?;
+		action_tokens::issue(&txn, user.uid, ActionKind::EmailChange, &confirm_claims.registered)
+			.await?;
+		action_tokens::issue(
+			&txn,
+			user.uid,
+			ActionKind::EmailChangeCancel,
+			&cancel_claims.registered,
+		)
+		.await?;
+		txn.commit().await
+	};
+	if let Err(e) = stored.await {
+		error!("Unable to record email change. Error: {}", e.to_string());
+		return Err(internal_error());
+	}
+
+	let confirm_url = format!(
+		"{}/api/auth/user/email-change/confirm/{}",
+		data.config.base_url,
+		claims::sign(&confirm_claims, &data.config.secret_key)
+	);
+	let cancel_url = format!(
+		"{}/api/auth/user/email-change/cancel/{}",
+		data.config.base_url,
+		claims::sign(&cancel_claims, &data.config.secret_key)
+	);
+
+	let mailer = data.config.mailer.as_ref().unwrap();
+	let email_config = data.config.email.to_owned().unwrap();
+
+	let (os, device) = match request.headers().get("User-Agent") {
+		Some(user_agent) => {
+			let a = data.ua_parser.parse_os(user_agent.to_str().unwrap()).family;
+			let b = data
+				.ua_parser
+				.parse_device(user_agent.to_str().unwrap())
+				.family;
+			(a.to_string(), b.to_string())
+		}
+		None => ("Unknown".to_string(), "Unknown".to_string()),
+	};
+
+	verification::send(EmailParams {
+		name: new_email.to_owned(),
+		action_url: confirm_url,
+		subject: email_config.confirmation_subject,
+		from: email_config.from.to_owned(),
+		to: new_email.to_owned(),
+		reply_to: email_config.reply_to.to_owned(),
+		os: os.to_owned(),
+		device: device.to_owned(),
+		mailer,
+	})
+	.await;
+
+	// Users adding their first email have no old address to warn
+	if let Some(ref email) = user.email {
+		email_change::send(
+			EmailParams {
+				name: email.to_owned(),
+				action_url: cancel_url,
+				subject: email_config.email_change_subject,
+				from: email_config.from,
+				to: email.to_owned(),
+				reply_to: email_config.reply_to,
+				os,
+				device,
+				mailer,
+			},
+			new_email,
+		)
+		.await;
+	}
+
+	Ok(())
+}
+
+/// Maps a failure to use an emailed token to a response
+fn consume_error(e: ConsumeError) -> HttpResponse {
+	match e {
+		ConsumeError::Database(e) => {
+			error!("Unable to use email change token. Error: {}", e.to_string());
+			HttpResponse::InternalServerError().finish()
+		}
+		e => HttpResponse::BadRequest()
+			.json(api_error(e.message().to_string(), e.error_code().to_string())),
+	}
+}
+
+#[get("/api/auth/user/email-change/confirm/{token}")]
+pub async fn confirm_handler(data: Data<AppState>, path: Path<String>) -> HttpResponse {
+	let claims: EmailChangeClaims =
+		match claims::verify(&path.into_inner(), &data.config.secret_key) {
+			Ok(claims) => claims,
+			Err(TokenError::Expired) => {
+				return HttpResponse::Gone().finish();
+			}
+			Err(_) => {
+				return HttpResponse::BadRequest().finish();
+			}
+		};
+
+	let txn = match data.connection.begin().await {
+		Ok(txn) => txn,
+		Err(e) => {
+			error!("Unable to start transaction. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().finish();
+		}
+	};
+
+	if let Err(e) =
+		action_tokens::consume(&txn, claims.sub, ActionKind::EmailChange, &claims.registered.jti)
+			.await
+	{
+		return consume_error(e);
+	}
+
+	let pending = email_changes::Entity::find_by_id(claims.sub)
+		.filter(email_changes::Column::Expiry.gt(Utc::now().naive_utc()))
+		.one(&txn)
+		.await;
+	let (pending, user) = match pending {
+		Ok(Some(pending)) => match users::Entity::find_by_id(claims.sub).one(&txn).await {
+			Ok(Some(user)) => (pending, user),
+			Ok(None) => return HttpResponse::NotFound().finish(),
+			Err(e) => {
+				error!("Unable to find user. Error: {}", e.to_string());
+				return HttpResponse::InternalServerError().finish();
+			}
+		},
+		Ok(None) => {
+			return HttpResponse::BadRequest().json(api_error(
+				"There is no pending email change.".to_string(),
+				"NO_PENDING_EMAIL_CHANGE".to_string(),
+			));
+		}
+		Err(e) => {
+			error!("Unable to find email change. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().finish();
+		}
+	};
+
+	// Someone may have signed up with the address since the change was started
+	match email_taken(&txn, &pending.new_email).await {
+		Ok(false) => (),
+		Ok(true) => {
+			let (body, _) = email_in_use();
+			return HttpResponse::Conflict().json(body.into_inner());
+		}
+		Err(e) => {
+			error!("Unable to check if the email is in use. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().finish();
+		}
+	}
+
+	let swapped = async {
+		let mut user: users::ActiveModel = user.into();
+		user.email = Set(Some(pending.new_email.to_owned()));
+		// Following the link proves the new address belongs to the user
+		user.email_verified = Set(true);
+		user.updated_at = Set(Utc::now().naive_utc());
+		user.update(&txn).await?;
+		identities::set_email(&txn, claims.sub, &pending.new_email).await?;
+
+		email_changes::Entity::delete_by_id(claims.sub)
+			.exec(&txn)
+			.await?;
+		login_codes::Entity::delete_by_id(claims.sub)
+			.exec(&txn)
+			.await?;
+		for kind in OLD_ADDRESS_TOKENS {
+			action_tokens::revoke(&txn, claims.sub, kind).await?;
+		}
+		txn.commit().await
+	};
+	if let Err(e) = swapped.await {
+		error!("Unable to change user email. Error: {}", e.to_string());
+		return HttpResponse::InternalServerError().finish();
+	}
+
+	HttpResponse::Found()
+		.append_header(("Location", format!("{}/?email_changed=true", claims.next)))
+		.finish()
+}
+
+#[get("/api/auth/user/email-change/cancel/{token}")]
+pub async fn cancel_handler(data: Data<AppState>, path: Path<String>) -> HttpResponse {
+	let claims: EmailChangeCancelClaims =
+		match claims::verify(&path.into_inner(), &data.config.secret_key) {
+			Ok(claims) => claims,
+			Err(TokenError::Expired) => {
+				return HttpResponse::Gone().finish();
+			}
+			Err(_) => {
+				return HttpResponse::BadRequest().finish();
+			}
+		};
+
+	let cancelled = async {
+		let txn = data.connection.begin().await?;
+		if let Err(e) = action_tokens::consume(
+			&txn,
+			claims.sub,
+			ActionKind::EmailChangeCancel,
+			&claims.registered.jti,
+		)
+		.await
+		{
+			return Ok(Err(e));
+		}
+		email_changes::Entity::delete_by_id(claims.sub)
+			.exec(&txn)
+			.await?;
+		action_tokens::revoke(&txn, claims.sub, ActionKind::EmailChange).await?;
+		txn.commit().await.map(Ok)
+	};
+	match cancelled.await {
+		Ok(Ok(())) => (),
+		Ok(Err(e)) => return consume_error(e),
+		Err(e) => {
+			error!("Unable to cancel email change. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().finish();
+		}
+	}
+
+	HttpResponse::Found()
+		.append_header(("Location", format!("{}/?email_change_cancelled=true", claims.next)))
+		.finish()
+}
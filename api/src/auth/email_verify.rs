@@ -1,8 +1,9 @@
-use std::collections::BTreeMap;
-
 use crate::{
 	auth::{
-		api_error,
+		action_tokens::{self, ActionKind, ConsumeError},
+		anonymous, api_error,
+		claims::{self, EmailVerifyClaims, RegisteredClaims, TokenClaims, TokenError},
+		phone,
 		util::{self, HeaderResult},
 		ApiResponse,
 	},
@@ -14,15 +15,13 @@ use actix_web::{
 	Either, HttpResponse,
 };
 
-use chrono::{Duration, Utc};
+use chrono::Duration;
 use email::{verification, EmailParams};
 use entity::users;
 use log::error;
 use sea_orm::{ActiveModelTrait, EntityTrait, Set};
 
-use jwt::{SignWithKey, VerifyWithKey};
 use uaparser::Parser;
-use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct VerifyBody {
@@ -38,7 +37,7 @@ pub async fn send_handler(
 	let header_map = request.headers();
 	let authorization = header_map.get("Authorization");
 
-	let uid = match util::verify_header(authorization, &data.config.secret_key) {
+	let uid = match util::verify_header(authorization, &data.key_ring) {
 		HeaderResult::Error(r, s) => {
 			return Either::Left((r, s));
 		}
@@ -70,6 +69,14 @@ pub async fn send_handler(
 		}
 	};
 
+	if user.anonymous {
+		return Either::Left(anonymous::no_email());
+	}
+	let email = match user.email {
+		Some(ref email) => email.to_owned(),
+		None => return Either::Left(phone::no_email()),
+	};
+
 	if user.email_verified {
 		return Either::Left((
 			Json(api_error(
@@ -92,19 +99,32 @@ pub async fn send_handler(
 
 	let mailer = data.config.mailer.as_ref().unwrap();
 
-	let uid_str = user.uid.clone().to_string();
-	let exp_str = Utc::now().timestamp() + Duration::minutes(15).num_seconds();
-	let exp_str = exp_str.to_string();
-	let next_url = body.next_url.to_owned();
-
-	let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
-	claims.insert("iss", "TurboCore");
-	claims.insert("uid", &uid_str);
-	claims.insert("exp", &exp_str);
-	claims.insert("type", "email_verify");
-	claims.insert("next", &next_url);
-
-	let token = claims.sign_with_key(&data.config.secret_key).unwrap();
+	let claims = EmailVerifyClaims {
+		registered: RegisteredClaims::new(
+			EmailVerifyClaims::AUDIENCE,
+			Duration::seconds(data.config.tokens.email_verify_lifetime),
+		),
+		sub: user.uid,
+		next: body.next_url.to_owned(),
+	};
+	if let Err(e) = action_tokens::issue(
+		&data.connection,
+		user.uid,
+		ActionKind::EmailVerify,
+		&claims.registered,
+	)
+	.await
+	{
+		error!("Unable to record email verification token. Error: {}", e.to_string());
+		return Either::Left((
+			Json(api_error(
+				"An internal server error occurred.".to_string(),
+				"INTERNAL_SERVER_ERROR".to_string(),
+			)),
+			http::StatusCode::INTERNAL_SERVER_ERROR,
+		));
+	}
+	let token = claims::sign(&claims, &data.config.secret_key);
 
 	let action_link = format!("{}/api/auth/user/verify-email/{}", data.config.base_url, token);
 
@@ -123,11 +143,11 @@ pub async fn send_handler(
 	};
 
 	verification::send(EmailParams {
-		name: user.email.to_owned(),
+		name: email.to_owned(),
 		action_url: action_link,
 		subject: email_config.confirmation_subject,
 		from: email_config.from,
-		to: user.email,
+		to: email,
 		reply_to: email_config.reply_to,
 		os,
 		device,
@@ -142,24 +162,20 @@ pub async fn send_handler(
 pub async fn receive_handler(data: Data<AppState>, path: Path<String>) -> HttpResponse {
 	let token = path.into_inner();
 
-	let claims: BTreeMap<String, String> = match token.verify_with_key(&data.config.secret_key) {
+	let claims: EmailVerifyClaims = match claims::verify(&token, &data.config.secret_key) {
 		Ok(claims) => claims,
+		Err(TokenError::Expired) => {
+			return HttpResponse::Gone().finish();
+		}
 		Err(_) => {
 			return HttpResponse::BadRequest().finish();
 		}
 	};
 
-	if Utc::now().timestamp() > claims.get("exp").unwrap().parse().unwrap() {
-		return HttpResponse::Gone().finish();
-	}
-
-	if claims["type"] != "email_verify" {
-		return HttpResponse::BadRequest().finish();
-	}
-
-	let uid = Uuid::parse_str(claims.get("uid").unwrap()).unwrap();
-
-	let user = match users::Entity::find_by_id(uid).one(&data.connection).await {
+	let user = match users::Entity::find_by_id(claims.sub)
+		.one(&data.connection)
+		.await
+	{
 		Ok(user) => match user {
 			Some(user) => user,
 			None => {
@@ -171,6 +187,25 @@ pub async fn receive_handler(data: Data<AppState>, path: Path<String>) -> HttpRe
 		}
 	};
 
+	match action_tokens::consume(
+		&data.connection,
+		user.uid,
+		ActionKind::EmailVerify,
+		&claims.registered.jti,
+	)
+	.await
+	{
+		Ok(()) => (),
+		Err(ConsumeError::Database(e)) => {
+			error!("Unable to use email verification token. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().finish();
+		}
+		Err(e) => {
+			return HttpResponse::BadRequest()
+				.json(api_error(e.message().to_string(), e.error_code().to_string()));
+		}
+	}
+
 	let mut user: users::ActiveModel = user.into();
 	user.email_verified = Set(true);
 
@@ -182,7 +217,7 @@ pub async fn receive_handler(data: Data<AppState>, path: Path<String>) -> HttpRe
 		}
 	}
 
-	let next_url = format!("{}/?verified=true", claims.get("next").unwrap());
+	let next_url = format!("{}/?verified=true", claims.next);
 
 	HttpResponse::Found()
 		.append_header(("Location", next_url))
@@ -16,7 +16,7 @@ pub async fn handler(request: actix_web::HttpRequest, data: Data<AppState>) -> i
 	let header_map = request.headers();
 	let authorization = header_map.get("Authorization");
 
-	let uid = match util::verify_header(authorization, &data.config.secret_key) {
+	let uid = match util::verify_header(authorization, &data.key_ring) {
 		HeaderResult::Error(r, s) => {
 			return (r, s);
 		}
@@ -30,13 +30,20 @@ pub async fn handler(request: actix_web::HttpRequest, data: Data<AppState>) -> i
 			Some(user) => (
 				Json(ApiResponse::UserResponse {
 					uid: user.uid.to_string(),
-					email: user.email,
+					// Anonymous users only have a placeholder, and phone users may have no email at all
+					email: match user.anonymous {
+						true => String::new(),
+						false => user.email.unwrap_or_default(),
+					},
 					created_at: user.created_at,
 					updated_at: user.updated_at,
 					last_login: user.last_login,
 					active: user.active,
 					metadata: user.metadata,
 					email_verified: user.email_verified,
+					anonymous: user.anonymous,
+					phone: user.phone,
+					phone_verified: user.phone_verified,
 				}),
 				http::StatusCode::OK,
 			),
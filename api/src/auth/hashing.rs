@@ -0,0 +1,268 @@
+//! Password hashes. New passwords are hashed with the configured algorithm, and the hashes of users
+//! imported from other providers can still be verified, so that they don't have to reset their password:
+//!
+//! - argon2, as `$argon2id$...`
+//! - bcrypt, as `$2b$...` and its other versions
+//! - scrypt and PBKDF2, as PHC strings such as `$scrypt$ln=15,r=8,p=1$...` and `$pbkdf2-sha256$i=...`
+//! - Firebase's modified scrypt, as `$firebase-scrypt$SALT$HASH` with the standard base64 salt and hash
+//!   from a Firebase export. The project's parameters are set in `firebase_scrypt`.
+//!
+//! Hashes that don't match the configured algorithm and parameters are replaced when their user logs in.
+
+use argon2::{Config as ArgonConfig, ThreadMode, Variant, Version};
+use base64::{
+	engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+	Engine,
+};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use log::error;
+use rand::{thread_rng, Rng};
+use scrypt::password_hash::{PasswordHash, PasswordVerifier};
+use subtle::ConstantTimeEq;
+
+use crate::{Argon2Config, Config, FirebaseScryptConfig, PasswordHashAlgorithm};
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// The prefix of hashes imported from Firebase, followed by their base64 salt and hash, separated by `$`
+pub const FIREBASE_SCRYPT_PREFIX: &str = "$firebase-scrypt$";
+
+/// Hashes a password with the configured algorithm and a random salt
+pub fn hash(password: &str, config: &Config) -> String {
+	match config.password_hashing.algorithm {
+		PasswordHashAlgorithm::Argon2 => hash_argon2(password, &config.argon2_config),
+		// The cost is checked when the config is loaded
+		PasswordHashAlgorithm::Bcrypt => {
+			bcrypt::hash(password, config.password_hashing.bcrypt_cost).unwrap()
+		}
+	}
+}
+
+fn hash_argon2(password: &str, argon2_config: &Argon2Config) -> String {
+	let config = ArgonConfig {
+		variant: Variant::Argon2id,
+		version: Version::Version13,
+		mem_cost: argon2_config.memory,
+		time_cost: argon2_config.iterations,
+		lanes: argon2_config.parallelism,
+		thread_mode: ThreadMode::Parallel,
+		secret: &[],
+		ad: &[],
+		hash_length: argon2_config.tag_length,
+	};
+
+	let salt: Vec<u8> = (0..argon2_config.salt_length)
+		.map(|_| thread_rng().gen_range(0..255))
+		.collect();
+
+	argon2::hash_encoded(password.as_bytes(), salt.as_slice(), &config).unwrap()
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+	["$2a$", "$2b$", "$2x$", "$2y$"]
+		.iter()
+		.any(|prefix| hash.starts_with(prefix))
+}
+
+/// Whether the password matches the hash. Users created through magic links or OAuth don't have a valid
+/// hash, so hashes that can't be read never match.
+pub fn verify(hash: &str, password: &str, config: &Config) -> bool {
+	if hash.starts_with("$argon2") {
+		argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+	} else if is_bcrypt(hash) {
+		bcrypt::verify(password, hash).unwrap_or(false)
+	} else if let Some(encoded) = hash.strip_prefix(FIREBASE_SCRYPT_PREFIX) {
+		match &config.password_hashing.firebase_scrypt {
+			Some(params) => verify_firebase_scrypt(encoded, password, params),
+			None => {
+				error!("Unable to verify a Firebase scrypt hash, as firebase_scrypt is not configured.");
+				false
+			}
+		}
+	} else {
+		verify_phc(hash, password)
+	}
+}
+
+/// Whether `verify` can check passwords against the hash, for hashes imported from other providers
+pub fn is_supported(hash: &str, config: &Config) -> bool {
+	if hash.starts_with("$argon2") {
+		true
+	} else if is_bcrypt(hash) {
+		hash.parse::<bcrypt::HashParts>().is_ok()
+	} else if hash.starts_with(FIREBASE_SCRYPT_PREFIX) {
+		config.password_hashing.firebase_scrypt.is_some()
+	} else {
+		PasswordHash::new(hash)
+			.map(|parsed| phc_verifier(parsed.algorithm.as_str()).is_some())
+			.unwrap_or(false)
+	}
+}
+
+fn phc_verifier(algorithm: &str) -> Option<&'static dyn PasswordVerifier> {
+	match algorithm {
+		"scrypt" => Some(&scrypt::Scrypt),
+		"pbkdf2" | "pbkdf2-sha256" | "pbkdf2-sha512" => Some(&pbkdf2::Pbkdf2),
+		_ => None,
+	}
+}
+
+fn verify_phc(hash: &str, password: &str) -> bool {
+	let parsed = match PasswordHash::new(hash) {
+		Ok(parsed) => parsed,
+		Err(_) => return false,
+	};
+	match phc_verifier(parsed.algorithm.as_str()) {
+		Some(verifier) => verifier
+			.verify_password(password.as_bytes(), &parsed)
+			.is_ok(),
+		None => false,
+	}
+}
+
+/// Firebase derives an AES key from the password with scrypt, and stores the project's signer key
+/// encrypted with it
+fn verify_firebase_scrypt(encoded: &str, password: &str, params: &FirebaseScryptConfig) -> bool {
+	let (mut salt, expected) = match encoded.split_once('$') {
+		Some((salt, hash)) => match (STANDARD.decode(salt), STANDARD.decode(hash)) {
+			(Ok(salt), Ok(hash)) => (salt, hash),
+			_ => return false,
+		},
+		None => return false,
+	};
+	let (mut signer_key, separator) =
+		match (STANDARD.decode(&params.signer_key), STANDARD.decode(&params.salt_separator)) {
+			(Ok(signer_key), Ok(separator)) => (signer_key, separator),
+			_ => {
+				error!("The Firebase scrypt signer key and salt separator must be base64 encoded.");
+				return false;
+			}
+		};
+	let scrypt_params = u8::try_from(params.mem_cost)
+		.ok()
+		.and_then(|log_n| scrypt::Params::new(log_n, params.rounds, 1, 32).ok());
+	let scrypt_params = match scrypt_params {
+		Some(scrypt_params) => scrypt_params,
+		None => {
+			error!("The Firebase scrypt rounds or memory cost are invalid.");
+			return false;
+		}
+	};
+
+	salt.extend_from_slice(&separator);
+	let mut key = [0u8; 32];
+	if scrypt::scrypt(password.as_bytes(), &salt, &scrypt_params, &mut key).is_err() {
+		return false;
+	}
+	Aes256Ctr::new(&key.into(), &[0u8; 16].into()).apply_keystream(&mut signer_key);
+	signer_key.ct_eq(&expected).into()
+}
+
+/// Whether the hash was made with another algorithm or other parameters than the configured ones, and
+/// should be replaced now that the password is known
+pub fn needs_rehash(hash: &str, config: &Config) -> bool {
+	match config.password_hashing.algorithm {
+		PasswordHashAlgorithm::Argon2 => !is_current_argon2(hash, &config.argon2_config),
+		PasswordHashAlgorithm::Bcrypt => match hash.parse::<bcrypt::HashParts>() {
+			Ok(parts) => parts.get_cost() != config.password_hashing.bcrypt_cost,
+			Err(_) => true,
+		},
+	}
+}
+
+fn is_current_argon2(hash: &str, argon2_config: &Argon2Config) -> bool {
+	let params = format!(
+		"$argon2id$v=19$m={},t={},p={}$",
+		argon2_config.memory, argon2_config.iterations, argon2_config.parallelism
+	);
+	let decoded_len = |part: &str| STANDARD_NO_PAD.decode(part).map(|bytes| bytes.len() as u32);
+	match hash
+		.strip_prefix(&params)
+		.and_then(|rest| rest.split_once('$'))
+	{
+		Some((salt, tag)) => {
+			decoded_len(salt) == Ok(argon2_config.salt_length)
+				&& decoded_len(tag) == Ok(argon2_config.tag_length)
+		}
+		None => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use scrypt::password_hash::{PasswordHasher, SaltString};
+
+	const ARGON2: Argon2Config = Argon2Config {
+		salt_length: 16,
+		memory: 256,
+		iterations: 1,
+		parallelism: 1,
+		tag_length: 32,
+	};
+
+	#[test]
+	fn test_argon2_params() {
+		let hash = hash_argon2("password", &ARGON2);
+		assert!(argon2::verify_encoded(&hash, b"password").unwrap());
+		assert!(is_current_argon2(&hash, &ARGON2));
+		assert!(!is_current_argon2(
+			&hash,
+			&Argon2Config {
+				iterations: 2,
+				..ARGON2
+			}
+		));
+		assert!(!is_current_argon2(
+			&hash,
+			&Argon2Config {
+				tag_length: 16,
+				..ARGON2
+			}
+		));
+		assert!(!is_current_argon2("$2b$04$", &ARGON2));
+	}
+
+	#[test]
+	fn test_phc() {
+		let salt = SaltString::generate(&mut rand::rngs::OsRng);
+		let scrypt = scrypt::Scrypt
+			.hash_password_customized(
+				b"password",
+				None,
+				None,
+				scrypt::Params::new(4, 8, 1, 32).unwrap(),
+				&salt,
+			)
+			.unwrap()
+			.to_string();
+		assert!(scrypt.starts_with("$scrypt$"));
+		assert!(verify_phc(&scrypt, "password"));
+		assert!(!verify_phc(&scrypt, "passwort"));
+
+		let pbkdf2 = pbkdf2::Pbkdf2
+			.hash_password(b"password", &salt)
+			.unwrap()
+			.to_string();
+		assert!(pbkdf2.starts_with("$pbkdf2-sha256$"));
+		assert!(verify_phc(&pbkdf2, "password"));
+		assert!(!verify_phc(&pbkdf2, "passwort"));
+
+		assert!(!verify_phc("0", "password"));
+	}
+
+	#[test]
+	fn test_firebase_scrypt() {
+		// The example from Firebase's scrypt documentation
+		let params = FirebaseScryptConfig {
+			signer_key: "jxspr8Ki0RYycVU8zykbdLGjFQ3McFUH0uiiTvC8pVMXAn210wjLNmdZJzxUECKbm0QsEmYUSDzZvpjeJ9WmXA=="
+				.to_string(),
+			salt_separator: "Bw==".to_string(),
+			rounds: 8,
+			mem_cost: 14,
+		};
+		let encoded = "42xEC+ixf3L2lw==$lSrfV15cpx95/sZS2W9c9Kp6i/LVgQNDNC/qzrCnh1SAyZvqmZqAjTdn3aoItz+VHjoZilo78198JAdRuid5lQ==";
+		assert!(verify_firebase_scrypt(encoded, "user1password", &params));
+		assert!(!verify_firebase_scrypt(encoded, "user2password", &params));
+	}
+}
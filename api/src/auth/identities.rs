@@ -0,0 +1,511 @@
+//! The ways a user can log in. An identity is a provider and the user's subject with it: their email for
+//! passwords and magic links, their E.164 number for text messages, and the `sub` claim for OAuth
+//! providers, which are named as configured. Logins find the user through an identity, so one user can
+//! log in several ways.
+//!
+//! Linking another way to log in needs a session that started within `TokenConfig::reauthentication_window`,
+//! so that a stolen access token can't be used to take over the account. The last identity can't be
+//! unlinked, since the user could not log in again.
+
+use crate::{
+	admin::user_import::NO_PASSWORD,
+	auth::{
+		anonymous, api_error, hashing, password_policy, phone,
+		util::{self, ClaimsResult, HeaderResult},
+		ApiResponse,
+	},
+	AppState,
+};
+use actix_web::{
+	delete, get, http, post,
+	web::{Data, Json, Path},
+	Either, HttpRequest, HttpResponse, Responder,
+};
+use chrono::{Duration, Utc};
+use entity::{identities, sessions, users};
+use log::error;
+use migration::DbErr;
+use sea_orm::{
+	prelude::DateTime, sea_query::Expr, ColumnTrait, ConnectionTrait, EntityTrait, PaginatorTrait,
+	QueryFilter, QueryOrder, QuerySelect, Set, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+pub const PASSWORD: &str = "password";
+pub const MAGIC_LINK: &str = "magic_link";
+pub const PHONE: &str = "phone";
+
+/// The providers whose subject is the user's email. Emailed links and codes log in through either.
+pub const EMAIL_PROVIDERS: [&str; 2] = [PASSWORD, MAGIC_LINK];
+
+/// OAuth providers can't be given these names, since their subjects would be mixed up with TurboCore's own
+pub const RESERVED_PROVIDERS: [&str; 3] = [PASSWORD, MAGIC_LINK, PHONE];
+
+#[derive(Debug, Serialize)]
+pub struct IdentityInfo {
+	id: String,
+	provider: String,
+	provider_subject: String,
+	email: Option<String>,
+	linked_at: DateTime,
+	last_login: Option<DateTime>,
+}
+
+impl From<identities::Model> for IdentityInfo {
+	fn from(model: identities::Model) -> Self {
+		Self {
+			id: model.id.to_string(),
+			provider: model.provider,
+			provider_subject: model.provider_subject,
+			email: model.email,
+			linked_at: model.linked_at,
+			last_login: model.last_login,
+		}
+	}
+}
+
+#[derive(Deserialize)]
+pub struct PasswordBody {
+	password: String,
+}
+
+pub enum LinkError {
+	/// Another user already logs in with the identity
+	InUse,
+	Database(DbErr),
+}
+
+impl From<DbErr> for LinkError {
+	fn from(e: DbErr) -> Self {
+		LinkError::Database(e)
+	}
+}
+
+impl LinkError {
+	pub fn response(&self) -> (Json<ApiResponse>, http::StatusCode) {
+		match self {
+			LinkError::InUse => (
+				Json(api_error(
+					"The identity is already linked to another user.".to_string(),
+					"IDENTITY_IN_USE".to_string(),
+				)),
+				http::StatusCode::CONFLICT,
+			),
+			LinkError::Database(e) => {
+				error!("Unable to link identity. Error: {}", e.to_string());
+				internal_error()
+			}
+		}
+	}
+}
+
+/// Finds the identity from one of `providers` for `subject`
+pub async fn find<C: ConnectionTrait>(
+	connection: &C,
+	providers: &[&str],
+	subject: &str,
+) -> Result<Option<identities::Model>, DbErr> {
+	identities::Entity::find()
+		.filter(identities::Column::Provider.is_in(providers.iter().copied()))
+		.filter(identities::Column::ProviderSubject.eq(subject))
+		.one(connection)
+		.await
+}
+
+/// Finds the user who logs in with an identity from one of `providers` for `subject`
+pub async fn find_user<C: ConnectionTrait>(
+	connection: &C,
+	providers: &[&str],
+	subject: &str,
+) -> Result<Option<users::Model>, DbErr> {
+	match find(connection, providers, subject).await? {
+		Some(identity) => {
+			users::Entity::find_by_id(identity.uid)
+				.one(connection)
+				.await
+		}
+		None => Ok(None),
+	}
+}
+
+/// Records that the user logged in with the identity
+pub async fn record_login<C: ConnectionTrait>(connection: &C, id: Uuid) -> Result<(), DbErr> {
+	identities::Entity::update_many()
+		.col_expr(identities::Column::LastLogin, Expr::value(Utc::now().naive_utc()))
+		.filter(identities::Column::Id.eq(id))
+		.exec(connection)
+		.await
+		.map(|_| ())
+}
+
+/// Links the identity to the user. Linking an identity the user already has does nothing.
+pub async fn link<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	provider: &str,
+	subject: &str,
+	email: Option<String>,
+) -> Result<(), LinkError> {
+	match find(connection, &[provider], subject).await? {
+		Some(identity) if identity.uid == uid => return Ok(()),
+		Some(_) => return Err(LinkError::InUse),
+		None => (),
+	}
+	identities::Entity::insert(new(uid, provider, subject, email))
+		.exec(connection)
+		.await?;
+	Ok(())
+}
+
+/// A new identity for the user, for inserting along with other rows
+pub fn new(
+	uid: Uuid,
+	provider: &str,
+	subject: &str,
+	email: Option<String>,
+) -> identities::ActiveModel {
+	identities::ActiveModel {
+		id: Set(Uuid::new_v4()),
+		uid: Set(uid),
+		provider: Set(provider.to_owned()),
+		provider_subject: Set(subject.to_owned()),
+		email: Set(email),
+		linked_at: Set(Utc::now().naive_utc()),
+		last_login: Set(None),
+	}
+}
+
+/// Links the user's email as a password identity, for when they are given a password
+pub async fn link_password<C: ConnectionTrait>(
+	connection: &C,
+	user: &users::Model,
+) -> Result<(), LinkError> {
+	match user.email {
+		Some(ref email) => {
+			link(connection, user.uid, PASSWORD, email, Some(email.to_owned())).await
+		}
+		// Without an email there is nothing to log in with the password
+		None => Ok(()),
+	}
+}
+
+/// Keeps the user's email identities in step with their email when it changes
+pub async fn set_email<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	email: &str,
+) -> Result<(), DbErr> {
+	identities::Entity::update_many()
+		.col_expr(identities::Column::ProviderSubject, Expr::value(email))
+		.col_expr(identities::Column::Email, Expr::value(email))
+		.filter(identities::Column::Uid.eq(uid))
+		.filter(identities::Column::Provider.is_in(EMAIL_PROVIDERS))
+		.exec(connection)
+		.await
+		.map(|_| ())
+}
+
+/// Unlinks the user's identities from the provider
+pub async fn unlink_provider<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	provider: &str,
+) -> Result<(), DbErr> {
+	identities::Entity::delete_many()
+		.filter(identities::Column::Uid.eq(uid))
+		.filter(identities::Column::Provider.eq(provider))
+		.exec(connection)
+		.await
+		.map(|_| ())
+}
+
+/// Forgets the user's identities, so that they can be used by another user
+pub async fn delete_all<C: ConnectionTrait>(connection: &C, uid: Uuid) -> Result<(), DbErr> {
+	identities::Entity::delete_many()
+		.filter(identities::Column::Uid.eq(uid))
+		.exec(connection)
+		.await
+		.map(|_| ())
+}
+
+/// Finds the user that the request's access token belongs to, as long as they logged in recently enough
+/// to link another way to log in
+pub async fn reauthenticate(
+	request: &HttpRequest,
+	data: &AppState,
+) -> Result<users::Model, (Json<ApiResponse>, http::StatusCode)> {
+	let claims =
+		match util::verify_header_claims(request.headers().get("Authorization"), &data.key_ring) {
+			ClaimsResult::Error(r, s) => return Err((r, s)),
+			ClaimsResult::Claims(claims) => claims,
+		};
+
+	let window = Duration::seconds(data.config.tokens.reauthentication_window);
+	match sessions::Entity::find_by_id(claims.sid)
+		.filter(sessions::Column::Uid.eq(claims.sub))
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(session)) if session.created_at + window >= Utc::now().naive_utc() => (),
+		Ok(_) => {
+			return Err((
+				Json(api_error(
+					"Log in again to link another way to log in.".to_string(),
+					"REAUTHENTICATION_REQUIRED".to_string(),
+				)),
+				http::StatusCode::UNAUTHORIZED,
+			))
+		}
+		Err(e) => {
+			error!("Unable to find session. Error: {}", e.to_string());
+			return Err(internal_error());
+		}
+	}
+
+	match users::Entity::find_by_id(claims.sub)
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(user)) => Ok(user),
+		Ok(None) => Err(user_not_found()),
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			Err(internal_error())
+		}
+	}
+}
+
+/// Lists the ways the user can log in, oldest first
+#[get("/api/auth/user/identities")]
+pub async fn list_handler(request: HttpRequest, data: Data<AppState>) -> impl Responder {
+	let uid = match util::verify_header(request.headers().get("Authorization"), &data.key_ring) {
+		HeaderResult::Error(r, s) => return (r, s),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	match identities::Entity::find()
+		.filter(identities::Column::Uid.eq(uid))
+		.order_by_asc(identities::Column::LinkedAt)
+		.all(&data.connection)
+		.await
+	{
+		Ok(identities) => (
+			Json(ApiResponse::IdentityListResponse {
+				identities: identities.into_iter().map(IdentityInfo::from).collect(),
+			}),
+			http::StatusCode::OK,
+		),
+		Err(e) => {
+			error!("Unable to find identities. Error: {}", e.to_string());
+			internal_error()
+		}
+	}
+}
+
+/// Lets the user log in with their email and a password
+#[post("/api/auth/user/identities/password")]
+pub async fn link_password_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+	body: Json<PasswordBody>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let user = match reauthenticate(&request, &data).await {
+		Ok(user) => user,
+		Err(response) => return Either::Left(response),
+	};
+	let email = match real_email(&user) {
+		Some(email) => email,
+		None => return Either::Left(no_email(&user)),
+	};
+	match find(&data.connection, &[PASSWORD], &email).await {
+		Ok(None) => (),
+		Ok(Some(_)) => {
+			return Either::Left((
+				Json(api_error(
+					"The user already has a password. Change it instead.".to_string(),
+					"IDENTITY_ALREADY_LINKED".to_string(),
+				)),
+				http::StatusCode::CONFLICT,
+			))
+		}
+		Err(e) => {
+			error!("Unable to find identity. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	}
+
+	let breached = match password_policy::check(&data, &body.password, &email, Some(&user)).await {
+		Ok(breached) => breached,
+		Err(response) => return Either::Left(response),
+	};
+
+	let now = Utc::now().naive_utc();
+	let linked = async {
+		let txn = data.connection.begin().await?;
+		users::Entity::update_many()
+			.col_expr(
+				users::Column::Password,
+				Expr::value(hashing::hash(&body.password, &data.config)),
+			)
+			.col_expr(users::Column::PasswordChangedAt, Expr::value(now))
+			.col_expr(users::Column::UpdatedAt, Expr::value(now))
+			.filter(users::Column::Uid.eq(user.uid))
+			.exec(&txn)
+			.await?;
+		link_password(&txn, &user).await?;
+		txn.commit().await?;
+		Ok::<(), LinkError>(())
+	};
+	if let Err(e) = linked.await {
+		return Either::Left(e.response());
+	}
+
+	if breached {
+		password_policy::record_breached(&data, user.uid, &request).await;
+	}
+
+	Either::Right(HttpResponse::Ok().finish())
+}
+
+/// Lets the user log in with magic links and codes sent to their email
+#[post("/api/auth/user/identities/magic-link")]
+pub async fn link_magic_link_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let user = match reauthenticate(&request, &data).await {
+		Ok(user) => user,
+		Err(response) => return Either::Left(response),
+	};
+	let email = match real_email(&user) {
+		Some(email) => email,
+		None => return Either::Left(no_email(&user)),
+	};
+
+	match link(&data.connection, user.uid, MAGIC_LINK, &email, Some(email.to_owned())).await {
+		Ok(()) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(e.response()),
+	}
+}
+
+/// Unlinks one of the user's identities, unless it is the only way they can log in
+#[delete("/api/auth/user/identities/{id}")]
+pub async fn unlink_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+	path: Path<String>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let uid = match util::verify_header(request.headers().get("Authorization"), &data.key_ring) {
+		HeaderResult::Error(r, s) => return Either::Left((r, s)),
+		HeaderResult::Uid(uid) => uid,
+	};
+	let id = match Uuid::from_str(&path.into_inner()) {
+		Ok(id) => id,
+		Err(_) => return Either::Left(identity_not_found()),
+	};
+
+	let unlinked = async {
+		let txn = data.connection.begin().await?;
+		// Locking the user keeps two requests from each unlinking one of the last two identities
+		users::Entity::find_by_id(uid)
+			.lock_exclusive()
+			.one(&txn)
+			.await?;
+
+		// Filtering on uid makes sure users can only unlink their own identities
+		let identity = match identities::Entity::find_by_id(id)
+			.filter(identities::Column::Uid.eq(uid))
+			.one(&txn)
+			.await?
+		{
+			Some(identity) => identity,
+			None => return Ok(Err(identity_not_found())),
+		};
+		let count = identities::Entity::find()
+			.filter(identities::Column::Uid.eq(uid))
+			.count(&txn)
+			.await?;
+		if count <= 1 {
+			return Ok(Err((
+				Json(api_error(
+					"The user's only way to log in can't be unlinked.".to_string(),
+					"LAST_IDENTITY".to_string(),
+				)),
+				http::StatusCode::BAD_REQUEST,
+			)));
+		}
+
+		identities::Entity::delete_by_id(identity.id)
+			.exec(&txn)
+			.await?;
+		// The password or phone number would otherwise stay on the user without a way to log in with it
+		let mut update = users::Entity::update_many();
+		match identity.provider.as_str() {
+			PASSWORD => {
+				update = update.col_expr(users::Column::Password, Expr::value(NO_PASSWORD));
+			}
+			PHONE => {
+				update = update
+					.col_expr(users::Column::Phone, Expr::value(Option::<String>::None))
+					.col_expr(users::Column::PhoneVerified, Expr::value(false));
+			}
+			_ => (),
+		}
+		update
+			.col_expr(users::Column::UpdatedAt, Expr::value(Utc::now().naive_utc()))
+			.filter(users::Column::Uid.eq(uid))
+			.exec(&txn)
+			.await?;
+		txn.commit().await?;
+		Ok::<_, DbErr>(Ok(()))
+	};
+	match unlinked.await {
+		Ok(Ok(())) => Either::Right(HttpResponse::Ok().finish()),
+		Ok(Err(response)) => Either::Left(response),
+		Err(e) => {
+			error!("Unable to unlink identity. Error: {}", e.to_string());
+			Either::Left(internal_error())
+		}
+	}
+}
+
+/// Email identities need a real email. Anonymous users get one by upgrading instead.
+fn real_email(user: &users::Model) -> Option<String> {
+	user.email.to_owned().filter(|_| !user.anonymous)
+}
+
+fn no_email(user: &users::Model) -> (Json<ApiResponse>, http::StatusCode) {
+	match user.anonymous {
+		true => anonymous::no_email(),
+		false => phone::no_email(),
+	}
+}
+
+fn identity_not_found() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The identity was not found.".to_string(),
+			"IDENTITY_NOT_FOUND".to_string(),
+		)),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn user_not_found() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error("The user does not exist.".to_string(), "USER_NOT_FOUND".to_string())),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
@@ -0,0 +1,327 @@
+//! Throttles password guessing. Failed logins are counted per account and per IP address, and each is
+//! locked for a while once it reaches its limit, see `LockoutConfig`.
+//!
+//! Accounts are keyed by email rather than uid, so that emails without an account lock the same way, and
+//! the lockout does not reveal which emails are registered. IP addresses are read like `Client::address`,
+//! so clients cannot pick a new one for each login.
+
+use actix_web::{http, web::Json, HttpRequest};
+use chrono::{Duration, NaiveDateTime, Utc};
+use email::{account_locked, EmailParams};
+use entity::login_failures;
+use log::error;
+use migration::{DbErr, OnConflict};
+use sea_orm::{sea_query::Expr, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, Set};
+use uuid::Uuid;
+
+use crate::{
+	auth::{
+		api_error,
+		security_events::{self, SecurityEvent},
+		sessions::Client,
+		ApiResponse,
+	},
+	AppState, LockoutConfig,
+};
+
+/// What failed logins are counted against
+#[derive(Clone, Copy)]
+pub enum Subject<'a> {
+	/// A user account, by email
+	User(&'a str),
+	/// An admin account, by email
+	Admin(&'a str),
+	/// Every login from an IP address
+	Ip(&'a str),
+	/// The second factor of a user, by uid
+	SecondFactor(Uuid),
+}
+
+impl Subject<'_> {
+	fn key(&self) -> String {
+		match self {
+			Subject::User(email) => format!("user:{}", email.to_lowercase()),
+			Subject::Admin(email) => format!("admin:{}", email.to_lowercase()),
+			Subject::Ip(ip) => format!("ip:{ip}"),
+			Subject::SecondFactor(uid) => format!("mfa:{uid}"),
+		}
+	}
+
+	fn max_failures(&self, config: &LockoutConfig) -> i32 {
+		match self {
+			Subject::User(_) | Subject::Admin(_) | Subject::SecondFactor(_) => config.max_failures,
+			Subject::Ip(_) => config.max_ip_failures,
+		}
+	}
+}
+
+/// Returns how many seconds are left on the longest lock of the subjects, if any of them is locked
+pub async fn locked_for<C: ConnectionTrait>(
+	connection: &C,
+	subjects: &[Subject<'_>],
+) -> Result<Option<i64>, DbErr> {
+	let now = Utc::now().naive_utc();
+	let mut longest = None;
+	for subject in subjects {
+		let locked_until = login_failures::Entity::find_by_id(subject.key())
+			.one(connection)
+			.await?
+			.and_then(|failures| failures.locked_until);
+		if let Some(locked_until) = locked_until.filter(|until| *until > now) {
+			let seconds = (locked_until - now).num_seconds() + 1;
+			longest = Some(longest.map_or(seconds, |longest: i64| longest.max(seconds)));
+		}
+	}
+	Ok(longest)
+}
+
+/// Counts a failed login against the subject. Returns whether this failure locked it for the first time
+/// since its failures were last reset.
+pub async fn record_failure<C: ConnectionTrait>(
+	connection: &C,
+	config: &LockoutConfig,
+	subject: &Subject<'_>,
+) -> Result<bool, DbErr> {
+	let now = Utc::now().naive_utc();
+	let reset_before = now - Duration::seconds(config.reset_after);
+	let key = subject.key();
+
+	// Failures are counted in the database, so that failed logins made at the same time are all counted
+	let counted = count_failure(connection, &key, reset_before, now).await?;
+	if counted == 0 {
+		// Start over. If another failure starts first, the insert does nothing, and this one is counted
+		// after it instead.
+		login_failures::Entity::delete_many()
+			.filter(login_failures::Column::Key.eq(key.to_owned()))
+			.filter(login_failures::Column::LastFailure.lte(reset_before))
+			.exec(connection)
+			.await?;
+		let started = login_failures::Entity::insert(login_failures::ActiveModel {
+			key: Set(key.to_owned()),
+			failures: Set(1),
+			last_failure: Set(now),
+			locked_until: Set(None),
+		})
+		.on_conflict(
+			OnConflict::column(login_failures::Column::Key)
+				.do_nothing()
+				.to_owned(),
+		)
+		.exec_without_returning(connection)
+		.await?;
+		if started == 0 {
+			count_failure(connection, &key, reset_before, now).await?;
+		}
+	}
+
+	let failures = login_failures::Entity::find_by_id(key.to_owned())
+		.one(connection)
+		.await?
+		.map_or(0, |failures| failures.failures);
+	let max_failures = subject.max_failures(config);
+	if failures < max_failures {
+		return Ok(false);
+	}
+
+	// Only the failure that locks the subject first reports it. Later ones extend the lock.
+	let locked_until = lock_expiry(config, failures - max_failures, now);
+	let locked = login_failures::Entity::update_many()
+		.col_expr(login_failures::Column::LockedUntil, Expr::value(locked_until))
+		.filter(login_failures::Column::Key.eq(key.to_owned()))
+		.filter(login_failures::Column::LockedUntil.is_null())
+		.exec(connection)
+		.await?;
+	if locked.rows_affected == 1 {
+		return Ok(true);
+	}
+	login_failures::Entity::update_many()
+		.col_expr(login_failures::Column::LockedUntil, Expr::value(locked_until))
+		.filter(login_failures::Column::Key.eq(key))
+		.filter(login_failures::Column::LockedUntil.lt(locked_until))
+		.exec(connection)
+		.await?;
+	Ok(false)
+}
+
+/// Counts a failure against the key's failures, unless they were reset. Returns 0 if there are none.
+async fn count_failure<C: ConnectionTrait>(
+	connection: &C,
+	key: &str,
+	reset_before: NaiveDateTime,
+	now: NaiveDateTime,
+) -> Result<u64, DbErr> {
+	login_failures::Entity::update_many()
+		.col_expr(
+			login_failures::Column::Failures,
+			Expr::col(login_failures::Column::Failures).add(1),
+		)
+		.col_expr(login_failures::Column::LastFailure, Expr::value(now))
+		.filter(login_failures::Column::Key.eq(key))
+		.filter(login_failures::Column::LastFailure.gt(reset_before))
+		.exec(connection)
+		.await
+		.map(|res| res.rows_affected)
+}
+
+/// The lock doubles with each failure past the limit
+fn lock_expiry(config: &LockoutConfig, past_limit: i32, now: NaiveDateTime) -> NaiveDateTime {
+	let doublings = past_limit.clamp(0, 30) as u32;
+	let seconds = config
+		.lockout_duration
+		.saturating_mul(2_i64.saturating_pow(doublings))
+		.min(config.max_lockout_duration);
+	now + Duration::seconds(seconds)
+}
+
+/// Forgets the subject's failed logins, which also lifts its lock
+pub async fn clear<C: ConnectionTrait>(connection: &C, subject: &Subject<'_>) -> Result<(), DbErr> {
+	login_failures::Entity::delete_by_id(subject.key())
+		.exec(connection)
+		.await
+		.map(|_| ())
+}
+
+/// Returns the error for a locked login, if the account or the client's IP address is locked
+pub async fn check(
+	data: &AppState,
+	account: &Subject<'_>,
+	request: &HttpRequest,
+) -> Option<(Json<ApiResponse>, http::StatusCode)> {
+	let ip = Client::address(request, &data.config.trusted_proxies).unwrap_or_default();
+	match locked_for(&data.connection, &[Subject::Ip(&ip), *account]).await {
+		Ok(Some(seconds)) => Some(locked_error(seconds)),
+		Ok(None) => None,
+		Err(e) => {
+			error!("Unable to check login failures. Error: {}", e.to_string());
+			Some((
+				Json(api_error(
+					"An internal server error occurred.".to_string(),
+					"INTERNAL_SERVER_ERROR".to_string(),
+				)),
+				http::StatusCode::INTERNAL_SERVER_ERROR,
+			))
+		}
+	}
+}
+
+/// Counts a failed login against the account and the client's IP address. When this first locks the
+/// account, the lock is recorded and its owner is emailed. Returns the error for a locked login, if either
+/// is locked now.
+pub async fn login_failed(
+	data: &AppState,
+	account: &Subject<'_>,
+	uid: Option<Uuid>,
+	request: &HttpRequest,
+) -> Option<(Json<ApiResponse>, http::StatusCode)> {
+	let ip = Client::address(request, &data.config.trusted_proxies).unwrap_or_default();
+	if let Err(e) = record_failure(&data.connection, &data.config.lockout, &Subject::Ip(&ip)).await
+	{
+		error!("Unable to record login failure. Error: {}", e.to_string());
+	}
+	let newly_locked = match record_failure(&data.connection, &data.config.lockout, account).await {
+		Ok(newly_locked) => newly_locked,
+		Err(e) => {
+			error!("Unable to record login failure. Error: {}", e.to_string());
+			false
+		}
+	};
+
+	// Only accounts that exist are told about it
+	if let (true, Some(uid), Subject::User(email) | Subject::Admin(email)) =
+		(newly_locked, uid, account)
+	{
+		let recorded = security_events::record(
+			&data.connection,
+			uid,
+			SecurityEvent::AccountLocked,
+			None,
+			request,
+		)
+		.await;
+		if let Err(e) = recorded {
+			error!("Failed to record security event. Error: {}", e.to_string());
+		}
+		send_locked_email(data, email, request).await;
+	}
+
+	match locked_for(&data.connection, &[Subject::Ip(&ip), *account]).await {
+		Ok(seconds) => seconds.map(locked_error),
+		Err(e) => {
+			error!("Unable to check login failures. Error: {}", e.to_string());
+			None
+		}
+	}
+}
+
+/// Counts a wrong second factor against the user. Only clients that know the first factor get to guess, so
+/// these are not counted against their IP address. When this first locks the user's second factor, the
+/// lock is recorded. Returns the error for a locked login, if it is locked now.
+pub async fn second_factor_failed(
+	data: &AppState,
+	uid: Uuid,
+	request: &HttpRequest,
+) -> Option<(Json<ApiResponse>, http::StatusCode)> {
+	let subject = Subject::SecondFactor(uid);
+	match record_failure(&data.connection, &data.config.lockout, &subject).await {
+		Ok(true) => {
+			let recorded = security_events::record(
+				&data.connection,
+				uid,
+				SecurityEvent::AccountLocked,
+				None,
+				request,
+			)
+			.await;
+			if let Err(e) = recorded {
+				error!("Failed to record security event. Error: {}", e.to_string());
+			}
+		}
+		Ok(false) => {}
+		Err(e) => error!("Unable to record login failure. Error: {}", e.to_string()),
+	}
+
+	match locked_for(&data.connection, &[subject]).await {
+		Ok(seconds) => seconds.map(locked_error),
+		Err(e) => {
+			error!("Unable to check login failures. Error: {}", e.to_string());
+			None
+		}
+	}
+}
+
+pub fn locked_error(seconds: i64) -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			format!("Too many failed login attempts. Try again in {seconds} seconds."),
+			"ACCOUNT_LOCKED".to_string(),
+		)),
+		http::StatusCode::TOO_MANY_REQUESTS,
+	)
+}
+
+/// Emails the account's owner that it was locked. Does nothing unless email is configured with an
+/// `account_locked_subject`.
+async fn send_locked_email(data: &AppState, email: &str, request: &HttpRequest) {
+	let (mailer, email_config) = match (&data.config.mailer, &data.config.email) {
+		(Some(mailer), Some(email_config)) => (mailer, email_config),
+		_ => return,
+	};
+	let subject = match &email_config.account_locked_subject {
+		Some(subject) => subject.to_owned(),
+		None => return,
+	};
+
+	let client = Client::from_request(request, &data.ua_parser);
+	account_locked::send(EmailParams {
+		name: email.to_owned(),
+		action_url: data.config.base_url.to_owned(),
+		subject,
+		from: email_config.from.to_owned(),
+		to: email.to_owned(),
+		reply_to: email_config.reply_to.to_owned(),
+		os: client.os,
+		device: client.device,
+		mailer,
+	})
+	.await;
+}
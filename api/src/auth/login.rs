@@ -1,5 +1,15 @@
 use crate::{
-	auth::{api_error, util::get_at_and_rt, ApiResponse},
+	auth::{
+		action_tokens::{self, ActionKind},
+		api_error,
+		claims::{self, PasswordResetClaims, RegisteredClaims, TokenClaims},
+		hashing,
+		identities::{self, PASSWORD},
+		lockout::{self, Subject},
+		mfa, password_policy,
+		util::{get_at_and_rt, Session},
+		ApiResponse,
+	},
 	AppState,
 };
 use actix_web::{
@@ -7,24 +17,32 @@ use actix_web::{
 	web::{Data, Json},
 	Responder,
 };
-use argon2;
+use chrono::Duration;
 use entity::users;
 use log::error;
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{ActiveModelTrait, Set};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct LoginBody {
 	email: String,
 	password: String,
+	/// Whether to keep the user logged in for longer. Defaults to true.
+	remember_me: Option<bool>,
 }
 
 #[post("/api/auth/user/login")]
-pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Responder {
-	let user_res = users::Entity::find()
-		.filter(users::Column::Email.eq(&body.email))
-		.one(&data.connection)
-		.await;
+pub async fn handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<LoginBody>,
+) -> impl Responder {
+	let account = Subject::User(&body.email);
+	if let Some(locked) = lockout::check(&data, &account, &request).await {
+		return locked;
+	}
+
+	let user_res = identities::find_user(&data.connection, &[PASSWORD], &body.email).await;
 
 	match user_res {
 		Ok(user) => {
@@ -40,7 +58,13 @@ pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Respon
 							http::StatusCode::UNAUTHORIZED,
 						);
 					}
-					if !argon2::verify_encoded(&user.password, body.password.as_bytes()).unwrap() {
+					// Verification errors are treated as a mismatch
+					if !hashing::verify(&user.password, &body.password, &data.config) {
+						if let Some(locked) =
+							lockout::login_failed(&data, &account, Some(user.uid), &request).await
+						{
+							return locked;
+						}
 						return (
 							Json(api_error(
 								"The email or password is invalid".to_string(),
@@ -49,9 +73,64 @@ pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Respon
 							http::StatusCode::UNAUTHORIZED,
 						);
 					}
+					if let Err(e) = lockout::clear(&data.connection, &account).await {
+						error!("Unable to clear login failures. Error: {}", e.to_string());
+					}
+					if hashing::needs_rehash(&user.password, &data.config) {
+						rehash(&data, &user, &body.password).await;
+					}
 					let uid_str = &user.uid.to_string();
-					let (at, rt, exp) =
-						get_at_and_rt(&data.connection, uid_str, &data.config.secret_key, false).await;
+					let remember_me = body.remember_me.unwrap_or(true);
+
+					// Users with MFA enabled must complete a second step before receiving tokens, or a reset
+					// token for an expired password
+					match mfa::is_enabled(&data.connection, user.uid).await {
+						Ok(true) => {
+							let (mfa_token, expiry) = mfa::create_password_challenge_token(
+								user.uid,
+								remember_me,
+								&data.config.secret_key,
+							);
+							return (
+								Json(ApiResponse::MfaChallengeResponse {
+									uid: uid_str.to_string(),
+									mfa_required: true,
+									mfa_token,
+									expiry,
+								}),
+								http::StatusCode::OK,
+							);
+						}
+						Ok(false) => (),
+						Err(e) => {
+							error!("An error occurred when checking MFA status. Error: {}", e.to_string());
+							return (
+								Json(api_error(
+									"An internal server error occurred.".to_string(),
+									"INTERNAL_SERVER_ERROR".to_string(),
+								)),
+								http::StatusCode::INTERNAL_SERVER_ERROR,
+							);
+						}
+					}
+
+					if password_policy::is_expired(&data.config.password_policy, &user) {
+						return password_expired(&data, &user).await;
+					}
+
+					let (at, rt, exp) = get_at_and_rt(
+						&data.connection,
+						uid_str,
+						&data.key_ring,
+						&data.config.tokens,
+						false,
+						Session::New {
+							request: &request,
+							ua_parser: &data.ua_parser,
+							remember_me,
+						},
+					)
+					.await;
 					(
 						Json(ApiResponse::LoginResponse {
 							uid: uid_str.to_string(),
@@ -65,13 +144,18 @@ pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Respon
 					)
 				}
 				// User is not found
-				None => (
-					Json(api_error(
-						"The email or password is invalid".to_string(),
-						"INVALID_CREDENTIALS".to_string(),
-					)),
-					http::StatusCode::UNAUTHORIZED,
-				),
+				None => {
+					if let Some(locked) = lockout::login_failed(&data, &account, None, &request).await {
+						return locked;
+					}
+					(
+						Json(api_error(
+							"The email or password is invalid".to_string(),
+							"INVALID_CREDENTIALS".to_string(),
+						)),
+						http::StatusCode::UNAUTHORIZED,
+					)
+				}
 			}
 		}
 		Err(e) => {
@@ -86,3 +170,55 @@ pub async fn handler(data: Data<AppState>, body: Json<LoginBody>) -> impl Respon
 		}
 	}
 }
+
+/// Replaces a hash made with an outdated algorithm or parameters, now that the password is known. The
+/// user is still logged in if this fails.
+async fn rehash(data: &AppState, user: &users::Model, password: &str) {
+	let mut user: users::ActiveModel = user.to_owned().into();
+	user.password = Set(hashing::hash(password, &data.config));
+	if let Err(e) = user.update(&data.connection).await {
+		error!("Unable to rehash user password. Error: {}", e.to_string());
+	}
+}
+
+/// Instead of logging in, returns a reset token, which the user must set a new password with at
+/// `/api/auth/user/reset-password/confirm`
+pub async fn password_expired(
+	data: &AppState,
+	user: &users::Model,
+) -> (Json<ApiResponse>, http::StatusCode) {
+	let claims = PasswordResetClaims {
+		registered: RegisteredClaims::new(
+			PasswordResetClaims::AUDIENCE,
+			Duration::seconds(data.config.tokens.password_reset_lifetime),
+		),
+		sub: user.uid,
+	};
+	if let Err(e) = action_tokens::issue(
+		&data.connection,
+		user.uid,
+		ActionKind::PasswordReset,
+		&claims.registered,
+	)
+	.await
+	{
+		error!("Unable to record password reset token. Error: {}", e.to_string());
+		return (
+			Json(api_error(
+				"An internal server error occurred.".to_string(),
+				"INTERNAL_SERVER_ERROR".to_string(),
+			)),
+			http::StatusCode::INTERNAL_SERVER_ERROR,
+		);
+	}
+
+	(
+		Json(ApiResponse::PasswordExpiredResponse {
+			uid: user.uid.to_string(),
+			password_expired: true,
+			reset_token: claims::sign(&claims, &data.config.secret_key),
+			expiry: claims.registered.exp,
+		}),
+		http::StatusCode::OK,
+	)
+}
@@ -1,7 +1,8 @@
 use crate::{
 	auth::{
 		api_error,
-		util::{self, HeaderResult},
+		sessions::end_sessions,
+		util::{self, hash_secret, HeaderResult},
 	},
 	AppState,
 };
@@ -10,7 +11,7 @@ use actix_web::{
 	web::{Data, Json},
 	Either, HttpResponse,
 };
-use entity::refresh_tokens;
+use entity::{refresh_tokens, sessions};
 use log::error;
 use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 
@@ -30,57 +31,60 @@ pub async fn handler(
 	let header_map = request.headers();
 	let authorization = header_map.get("Authorization");
 
-	let uid = match util::verify_header(authorization, &data.config.secret_key) {
+	let uid = match util::verify_header(authorization, &data.key_ring) {
 		HeaderResult::Error(r, s) => {
 			return Either::Left((r, s));
 		}
 		HeaderResult::Uid(uid) => uid,
 	};
 
-	// If the refresh token is provided, delete it. Otherwise, delete all refresh tokens for the user
-	match body.refresh_token.clone() {
+	// If the refresh token is provided, end its session. Otherwise, end all of the user's sessions
+	let ended = match body.refresh_token.clone() {
 		Some(token) => {
-			match refresh_tokens::Entity::delete_by_id(&token)
-				.exec(&data.connection)
+			let session_id = match refresh_tokens::Entity::find_by_id(hash_secret(&token))
+				.filter(refresh_tokens::Column::Uid.eq(uid))
+				.one(&data.connection)
 				.await
 			{
-				Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+				Ok(rt) => rt.map(|rt| rt.session_id),
 				Err(e) => {
-					error!("Failed to delete refresh token {}. Error: {}", token, e.to_string());
-					Either::Left((
-						Json(api_error(
-							"Internal Server Error".to_string(),
-							"INTERNAL_SERVER_ERROR".to_string(),
-						)),
-						http::StatusCode::INTERNAL_SERVER_ERROR,
-					))
+					error!("Failed to find refresh token. Error: {}", e.to_string());
+					return internal_error();
+				}
+			};
+			match session_id {
+				Some(session_id) => {
+					end_sessions(&data.connection, sessions::Entity::find_by_id(session_id)).await
 				}
+				None => Ok(()),
 			}
 		}
 		None => {
-			match refresh_tokens::Entity::delete_many()
-				.filter(refresh_tokens::Column::Uid.eq(uid))
-				.exec(&data.connection)
-				.await
-			{
-				Ok(_) => Either::Right(HttpResponse::Ok().finish()),
-				Err(e) => {
-					error!(
-						"Failed to delete refresh tokens for {}. Error: {}",
-						uid.to_string(),
-						e.to_string()
-					);
-					Either::Left((
-						Json(api_error(
-							"Internal Server Error".to_string(),
-							"INTERNAL_SERVER_ERROR".to_string(),
-						)),
-						http::StatusCode::INTERNAL_SERVER_ERROR,
-					))
-				}
-			}
+			end_sessions(
+				&data.connection,
+				sessions::Entity::find().filter(sessions::Column::Uid.eq(uid)),
+			)
+			.await
+		}
+	};
+
+	match ended {
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => {
+			error!("Failed to end sessions for {}. Error: {}", uid.to_string(), e.to_string());
+			internal_error()
 		}
 	}
 }
 
+fn internal_error() -> DeleteUserResponse<'static> {
+	Either::Left((
+		Json(api_error(
+			"Internal Server Error".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	))
+}
+
 type DeleteUserResponse<'a> = Either<(Json<ApiResponse>, http::StatusCode), HttpResponse>;
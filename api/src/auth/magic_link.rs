@@ -1,7 +1,15 @@
-use std::collections::BTreeMap;
-
 use crate::{
-	auth::{api_error, util::get_at_and_rt, ApiResponse},
+	auth::{
+		action_tokens::{self, ActionKind, ConsumeError},
+		anonymous::{self, UpgradeError},
+		api_error,
+		claims::{self, MagicLinkClaims, RegisteredClaims, TokenClaims, TokenError},
+		identities::{self, LinkError, EMAIL_PROVIDERS, MAGIC_LINK},
+		mfa, otp,
+		sessions::Client,
+		util::{get_at_and_rt, Session},
+		ApiResponse,
+	},
 	AppState,
 };
 use actix_web::{
@@ -11,19 +19,42 @@ use actix_web::{
 };
 
 use chrono::{Duration, NaiveDateTime, Utc};
-use email::{magic, EmailParams};
+use email::{login_code, magic, EmailParams};
 use entity::users;
-use jwt::{SignWithKey, VerifyWithKey};
 use log::error;
-use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
+use migration::DbErr;
+use sea_orm::{
+	ActiveModelTrait, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, Set, TransactionTrait,
+};
 use uaparser::Parser;
 use uuid::Uuid;
 
+#[derive(serde::Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MagicMode {
+	/// A link that logs the user in and redirects them to `next_url`
+	#[default]
+	Link,
+	/// A short code that the client exchanges for tokens, for apps that cannot follow the link's redirect
+	Code,
+}
+
 #[derive(serde::Deserialize)]
 pub struct MagicBody {
+	/// Only used by links
+	#[serde(default)]
 	pub next_url: String,
 	pub email: String,
 	pub sign_up: bool,
+	#[serde(default)]
+	pub mode: MagicMode,
+}
+
+#[derive(serde::Deserialize)]
+pub struct VerifyCodeBody {
+	pub email: String,
+	pub code: String,
+	remember_me: Option<bool>,
 }
 
 #[post("/api/auth/user/magic-link")]
@@ -42,84 +73,116 @@ pub async fn post_handler(
 		));
 	}
 
-	let user = match users::Entity::find()
-		.filter(users::Column::Email.eq(body.email.to_owned()))
-		.one(&data.connection)
-		.await
-	{
-		Ok(user) => match user {
-			Some(user) => {
-				if body.sign_up {
-					return Either::Left((
-						Json(api_error(
-							"The user already exists.".to_string(),
-							"USER_ALREADY_EXISTS".to_string(),
-						)),
-						http::StatusCode::BAD_REQUEST,
-					));
-				}
-				user
+	// An anonymous user gets the email for their own account, instead of logging in to another one
+	if request.headers().contains_key("Authorization") {
+		return link_anonymous(&request, &data, &body).await;
+	}
+
+	let user = match identities::find_user(&data.connection, &EMAIL_PROVIDERS, &body.email).await {
+		Ok(Some(user)) => {
+			if body.sign_up {
+				return Either::Left(user_already_exists());
 			}
-			None => {
-				if !body.sign_up {
-					return Either::Left((
-						Json(api_error(
-							"The user does not exist.".to_string(),
-							"USER_DOES_NOT_EXIST".to_string(),
-						)),
-						http::StatusCode::BAD_REQUEST,
-					));
-				}
-				let uid = Uuid::new_v4();
-				let now = NaiveDateTime::from_timestamp_opt(Utc::now().timestamp(), 0).unwrap();
-				let new_user = users::ActiveModel {
-					uid: Set(uid),
-					password: Set("0".to_string()), // TODO: change this to something else
-					email: Set(body.email.to_owned()),
-					created_at: Set(now),
-					updated_at: Set(now),
-					active: Set(true),
-					email_verified: Set(false),
-					..Default::default()
-				};
-				let new_user = new_user.insert(&data.connection).await;
-				match new_user {
-					Ok(user) => user,
-					Err(e) => {
-						error!("Unable to create user. Error: {}", e.to_string());
-						return Either::Left((
-							Json(api_error(
-								"Internal Server Error".to_string(),
-								"INTERNAL_SERVER_ERROR".to_string(),
-							)),
-							http::StatusCode::INTERNAL_SERVER_ERROR,
-						));
-					}
+			user
+		}
+		Ok(None) => {
+			if !body.sign_up {
+				return Either::Left((
+					Json(api_error(
+						"The user does not exist.".to_string(),
+						"USER_DOES_NOT_EXIST".to_string(),
+					)),
+					http::StatusCode::BAD_REQUEST,
+				));
+			}
+			match sign_up(&data, &body.email).await {
+				Ok(Some(user)) => user,
+				// Someone who logs in another way has the email
+				Ok(None) => return Either::Left(user_already_exists()),
+				Err(e) => {
+					error!("Unable to create user. Error: {}", e.to_string());
+					return Either::Left(internal_error());
 				}
 			}
-		},
+		}
 		Err(e) => {
 			error!("Unable to find user. Error: {}", e.to_string());
-			return Either::Left((
-				Json(api_error(
-					"Internal Server Error".to_string(),
-					"INTERNAL_SERVER_ERROR".to_string(),
-				)),
-				http::StatusCode::INTERNAL_SERVER_ERROR,
-			));
+			return Either::Left(internal_error());
 		}
 	};
 
-	let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
-	let uid_str = user.uid.to_string();
-	let exp = (Utc::now().timestamp() + Duration::minutes(15).num_seconds()).to_string();
-	claims.insert("iss", "TurboCore");
-	claims.insert("type", "magic-link");
-	claims.insert("uid", &uid_str);
-	claims.insert("exp", &exp);
-	claims.insert("next", &body.next_url);
+	if body.mode == MagicMode::Code {
+		return send_code(&request, &data, user.uid, body.email.to_owned()).await;
+	}
 
-	let token = claims.sign_with_key(&data.config.secret_key).unwrap();
+	send_link(&request, &data, user.uid, body.email.to_owned(), &body.next_url, None).await
+}
+
+/// Creates a user who logs in with emailed links and codes. Returns `None` if the email is in use.
+async fn sign_up(data: &AppState, email: &str) -> Result<Option<users::Model>, DbErr> {
+	let in_use = users::Entity::find()
+		.filter(users::Column::Email.eq(email))
+		.count(&data.connection)
+		.await?;
+	if in_use > 0 {
+		return Ok(None);
+	}
+
+	let now = NaiveDateTime::from_timestamp_opt(Utc::now().timestamp(), 0).unwrap();
+	let txn = data.connection.begin().await?;
+	let user = users::ActiveModel {
+		uid: Set(Uuid::new_v4()),
+		password: Set("0".to_string()), // Not a valid hash, so password login is impossible
+		email: Set(Some(email.to_owned())),
+		created_at: Set(now),
+		updated_at: Set(now),
+		active: Set(true),
+		email_verified: Set(false),
+		..Default::default()
+	}
+	.insert(&txn)
+	.await?;
+	match identities::link(&txn, user.uid, MAGIC_LINK, email, Some(email.to_owned())).await {
+		Ok(()) => (),
+		Err(LinkError::InUse) => return Ok(None),
+		Err(LinkError::Database(e)) => return Err(e),
+	}
+	txn.commit().await?;
+	Ok(Some(user))
+}
+
+/// Emails a link that logs the user in. When `email` is set, following the link also gives it to the
+/// anonymous user.
+async fn send_link(
+	request: &actix_web::HttpRequest,
+	data: &AppState,
+	uid: Uuid,
+	to: String,
+	next_url: &str,
+	email: Option<String>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let claims = MagicLinkClaims {
+		registered: RegisteredClaims::new(
+			MagicLinkClaims::AUDIENCE,
+			Duration::seconds(data.config.tokens.magic_link_lifetime),
+		),
+		sub: uid,
+		next: next_url.to_owned(),
+		email,
+	};
+	if let Err(e) =
+		action_tokens::issue(&data.connection, uid, ActionKind::MagicLink, &claims.registered).await
+	{
+		error!("Unable to record magic link. Error: {}", e.to_string());
+		return Either::Left((
+			Json(api_error(
+				"Internal Server Error".to_string(),
+				"INTERNAL_SERVER_ERROR".to_string(),
+			)),
+			http::StatusCode::INTERNAL_SERVER_ERROR,
+		));
+	}
+	let token = claims::sign(&claims, &data.config.secret_key);
 
 	let action_url = format!("{}/api/auth/user/magic-link/{}", data.config.base_url, token);
 
@@ -141,11 +204,11 @@ pub async fn post_handler(
 	};
 
 	magic::send(EmailParams {
-		name: user.email.to_owned(),
+		name: to.to_owned(),
 		action_url,
 		subject: email_config.confirmation_subject,
 		from: email_config.from,
-		to: user.email,
+		to,
 		reply_to: email_config.reply_to,
 		os,
 		device,
@@ -156,35 +219,205 @@ pub async fn post_handler(
 	Either::Right(HttpResponse::Ok().finish())
 }
 
-#[get("/api/auth/user/magic-link/{uid}")]
-pub async fn get_handler(data: Data<AppState>, path: Path<String>) -> HttpResponse {
-	let token = path.into_inner();
-	let claims: BTreeMap<String, String> = match token.verify_with_key(&data.config.secret_key) {
-		Ok(claims) => claims,
+/// Emails a link that upgrades the anonymous user making the request. `sign_up` is ignored, since the
+/// email must not belong to any user yet.
+async fn link_anonymous(
+	request: &actix_web::HttpRequest,
+	data: &AppState,
+	body: &MagicBody,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let user = match anonymous::authenticate(request, data).await {
+		Ok(user) => user,
+		Err(response) => return Either::Left(response),
+	};
+
+	if !crate::EMAIL_REGEX.is_match(&body.email) {
+		return Either::Left(anonymous::invalid_email());
+	}
+	// Codes are exchanged along with the user's email, which the anonymous user does not have yet
+	if body.mode == MagicMode::Code {
+		return Either::Left((
+			Json(api_error(
+				"Anonymous users can only be upgraded with a link.".to_string(),
+				"INVALID_MODE".to_string(),
+			)),
+			http::StatusCode::BAD_REQUEST,
+		));
+	}
+
+	match users::Entity::find()
+		.filter(users::Column::Email.eq(body.email.to_owned()))
+		.count(&data.connection)
+		.await
+	{
+		Ok(0) => (),
+		Ok(_) => return Either::Left(UpgradeError::EmailInUse.response()),
 		Err(e) => {
-			error!("Unable to verify token. Error: {}", e.to_string());
-			return HttpResponse::BadRequest().json(api_error(
-				"The provided was invalid.".to_string(),
-				"INVALID_TOKEN".to_string(),
-			));
+			error!("Unable to find user. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	}
+
+	send_link(
+		request,
+		data,
+		user.uid,
+		body.email.to_owned(),
+		&body.next_url,
+		Some(body.email.to_owned()),
+	)
+	.await
+}
+
+/// Emails a one-time login code, which replaces any code sent before it
+async fn send_code(
+	request: &actix_web::HttpRequest,
+	data: &AppState,
+	uid: Uuid,
+	to: String,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let code = match otp::issue(&data.connection, &data.config.tokens, uid, &to).await {
+		Ok(code) => code,
+		Err(e) => {
+			error!("Unable to store login code. Error: {}", e.to_string());
+			return Either::Left(internal_error());
 		}
 	};
 
-	if Utc::now().timestamp() > claims["exp"].parse::<i64>().unwrap() {
-		return HttpResponse::BadRequest().json(api_error(
-			"The token has already expired".to_string(),
-			"EXPIRED_TOKEN".to_string(),
-		));
+	let mailer = data.config.mailer.as_ref().unwrap();
+	let email_config = data.config.email.to_owned().unwrap();
+	let client = Client::from_request(request, &data.ua_parser);
+	login_code::send(
+		EmailParams {
+			name: to.to_owned(),
+			action_url: data.config.base_url.to_owned(),
+			subject: email_config.magic_link_subject,
+			from: email_config.from,
+			to,
+			reply_to: email_config.reply_to,
+			os: client.os,
+			device: client.device,
+			mailer,
+		},
+		&code,
+	)
+	.await;
+
+	Either::Right(HttpResponse::Ok().finish())
+}
+
+/// Exchanges an emailed login code for tokens. Each code can be used once, and stops working after too
+/// many wrong guesses.
+#[post("/api/auth/user/magic-link/verify")]
+pub async fn verify_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<VerifyCodeBody>,
+) -> (Json<ApiResponse>, http::StatusCode) {
+	let user = match identities::find_user(&data.connection, &EMAIL_PROVIDERS, &body.email).await {
+		Ok(Some(user)) => user,
+		Ok(None) => return otp::RedeemError::Invalid.response("The email or code is invalid."),
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+
+	if let Err(e) = otp::redeem(&data.connection, &data.config.tokens, user.uid, &body.email, &body.code).await {
+		return e.response("The email or code is invalid.");
 	}
 
-	if claims["type"] != "magic-link" {
-		return HttpResponse::BadRequest().json(api_error(
-			"The provided token is invalid.".to_string(),
-			"INVALID_TOKEN".to_string(),
-		));
+	let uid_str = user.uid.to_string();
+	let remember_me = body.remember_me.unwrap_or(true);
+
+	// Users with MFA enabled must complete a second step before receiving tokens
+	match mfa::is_enabled(&data.connection, user.uid).await {
+		Ok(true) => {
+			let (mfa_token, expiry) =
+				mfa::create_challenge_token(user.uid, remember_me, &data.config.secret_key);
+			return (
+				Json(ApiResponse::MfaChallengeResponse {
+					uid: uid_str,
+					mfa_required: true,
+					mfa_token,
+					expiry,
+				}),
+				http::StatusCode::OK,
+			);
+		}
+		Ok(false) => (),
+		Err(e) => {
+			error!("An error occurred when checking MFA status. Error: {}", e.to_string());
+			return internal_error();
+		}
 	}
 
-	let uid = Uuid::parse_str(&claims["uid"]).unwrap();
+	let (at, rt, exp) = get_at_and_rt(
+		&data.connection,
+		&uid_str,
+		&data.key_ring,
+		&data.config.tokens,
+		false,
+		Session::New {
+			request: &request,
+			ua_parser: &data.ua_parser,
+			remember_me,
+		},
+	)
+	.await;
+	(
+		Json(ApiResponse::LoginResponse {
+			uid: uid_str,
+			token: at,
+			expiry: exp,
+			refresh_token: rt,
+			email_verified: user.email_verified,
+			metadata: user.metadata.unwrap_or_default(),
+		}),
+		http::StatusCode::OK,
+	)
+}
+
+fn user_already_exists() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error("The user already exists.".to_string(), "USER_ALREADY_EXISTS".to_string())),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"Internal Server Error".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
+
+#[get("/api/auth/user/magic-link/{uid}")]
+pub async fn get_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	path: Path<String>,
+) -> HttpResponse {
+	let token = path.into_inner();
+	let claims: MagicLinkClaims = match claims::verify(&token, &data.config.secret_key) {
+		Ok(claims) => claims,
+		Err(TokenError::Expired) => {
+			return HttpResponse::BadRequest().json(api_error(
+				"The token has already expired".to_string(),
+				"EXPIRED_TOKEN".to_string(),
+			));
+		}
+		Err(_) => {
+			return HttpResponse::BadRequest().json(api_error(
+				"The provided token is invalid.".to_string(),
+				"INVALID_TOKEN".to_string(),
+			));
+		}
+	};
+	let uid = claims.sub;
 
 	let user = match users::Entity::find()
 		.filter(users::Column::Uid.eq(uid))
@@ -209,13 +442,80 @@ pub async fn get_handler(data: Data<AppState>, path: Path<String>) -> HttpRespon
 		}
 	};
 
-	let (at, rt, exp) =
-		get_at_and_rt(&data.connection, &user.uid.to_string(), &data.config.secret_key, false).await;
+	match action_tokens::consume(
+		&data.connection,
+		user.uid,
+		ActionKind::MagicLink,
+		&claims.registered.jti,
+	)
+	.await
+	{
+		Ok(()) => (),
+		Err(ConsumeError::Database(e)) => {
+			error!("Unable to use magic link. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().json(api_error(
+				"Internal Server Error".to_string(),
+				"INTERNAL_SERVER_ERROR".to_string(),
+			));
+		}
+		Err(e) => {
+			return HttpResponse::BadRequest()
+				.json(api_error(e.message().to_string(), e.error_code().to_string()));
+		}
+	}
 
-	let redirect_url =
-		format!("{}?uid={}?at={}&rt={}&exp={}", user.uid, claims["next"], at, rt, exp);
+	if let Some(ref email) = claims.email {
+		if let Err(e) = upgrade_anonymous(&data, user.uid, email).await {
+			let (response, status) = e.response();
+			return HttpResponse::build(status).json(response.0);
+		}
+	}
+
+	// Users with MFA enabled are sent on with a challenge, which they exchange for tokens with a second factor
+	match mfa::is_enabled(&data.connection, user.uid).await {
+		Ok(true) => {
+			let (mfa_token, expiry) =
+				mfa::create_challenge_token(user.uid, true, &data.config.secret_key);
+			let redirect_url = format!(
+				"{}?uid={}&mfa_required=true&mfa_token={}&exp={}",
+				claims.next, user.uid, mfa_token, expiry
+			);
+			return HttpResponse::Found()
+				.append_header(("Location", redirect_url))
+				.finish();
+		}
+		Ok(false) => (),
+		Err(e) => {
+			error!("An error occurred when checking MFA status. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().json(api_error(
+				"Internal Server Error".to_string(),
+				"INTERNAL_SERVER_ERROR".to_string(),
+			));
+		}
+	}
+
+	let (at, rt, exp) = get_at_and_rt(
+		&data.connection,
+		&user.uid.to_string(),
+		&data.key_ring,
+		&data.config.tokens,
+		false,
+		Session::new(&request, &data.ua_parser),
+	)
+	.await;
+
+	let redirect_url = format!("{}?uid={}&at={}&rt={}&exp={}", claims.next, user.uid, at, rt, exp);
 
 	HttpResponse::Found()
 		.append_header(("Location", redirect_url))
 		.finish()
 }
+
+/// Gives the anonymous user the email they followed the link from, which they log in with from then on
+async fn upgrade_anonymous(data: &AppState, uid: Uuid, email: &str) -> Result<(), UpgradeError> {
+	let txn = data.connection.begin().await?;
+	anonymous::upgrade(&txn, uid, email, true, None).await?;
+	identities::link(&txn, uid, MAGIC_LINK, email, Some(email.to_owned())).await?;
+	txn.commit().await?;
+	Ok(())
+}
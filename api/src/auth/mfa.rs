@@ -0,0 +1,629 @@
+use crate::{
+	auth::{
+		api_error,
+		claims::{self, MfaChallengeClaims, RegisteredClaims, TokenClaims, TokenError},
+		lockout::{self, Subject},
+		login, password_policy, totp,
+		util::{self, get_at_and_rt, HeaderResult, Session},
+		ApiResponse,
+	},
+	AppState,
+};
+use actix_web::{
+	delete, http, post,
+	web::{Data, Json},
+	Either, HttpResponse, Responder,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use entity::{mfa_challenges, recovery_codes, totp_secrets, users};
+use hmac::Hmac;
+use log::error;
+use migration::{DbErr, OnConflict};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sea_orm::{
+	sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection, EntityTrait,
+	QueryFilter, Set, TransactionTrait,
+};
+use serde::Deserialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Number of recovery codes generated each time the codes are (re)generated
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Number of codes that can be tried with one challenge, after which the user must log in again
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+#[derive(Deserialize)]
+pub struct CodeBody {
+	code: String,
+}
+
+#[derive(Deserialize)]
+pub struct SecondFactorBody {
+	code: Option<String>,
+	recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChallengeBody {
+	mfa_token: String,
+	code: Option<String>,
+	recovery_code: Option<String>,
+}
+
+/// Starts TOTP enrollment. The secret is not used to protect the account until it is confirmed.
+#[post("/api/auth/user/mfa/totp")]
+pub async fn enroll_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+) -> impl Responder {
+	let uid = match util::verify_header(request.headers().get("Authorization"), &data.key_ring) {
+		HeaderResult::Error(r, s) => return (r, s),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	let user = match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(user)) => user,
+		Ok(None) => {
+			return (
+				Json(api_error(
+					"The user was not found.".to_string(),
+					"USER_NOT_FOUND".to_string(),
+				)),
+				http::StatusCode::NOT_FOUND,
+			)
+		}
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+
+	match totp_secrets::Entity::find_by_id(uid)
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(existing)) => {
+			if existing.confirmed {
+				return (
+					Json(api_error(
+						"Multi-factor authentication is already enabled.".to_string(),
+						"MFA_ALREADY_ENABLED".to_string(),
+					)),
+					http::StatusCode::CONFLICT,
+				);
+			}
+			// Restarting enrollment replaces the unconfirmed secret
+			if let Err(e) = totp_secrets::Entity::delete_by_id(uid)
+				.exec(&data.connection)
+				.await
+			{
+				error!("Unable to delete unconfirmed TOTP secret. Error: {}", e.to_string());
+				return internal_error();
+			}
+		}
+		Ok(None) => (),
+		Err(e) => {
+			error!("Unable to find TOTP secret. Error: {}", e.to_string());
+			return internal_error();
+		}
+	}
+
+	let secret = totp::generate_secret();
+	let res = totp_secrets::Entity::insert(totp_secrets::ActiveModel {
+		uid: Set(uid),
+		secret: Set(secret.clone()),
+		confirmed: Set(false),
+		last_used_step: Set(None),
+		created_at: Set(Utc::now().naive_utc()),
+	})
+	.exec(&data.connection)
+	.await;
+	if let Err(e) = res {
+		error!("Unable to store TOTP secret. Error: {}", e.to_string());
+		return internal_error();
+	}
+
+	// Users who signed up with a phone number may not have an email to label the account with
+	let account = user
+		.email
+		.or(user.phone)
+		.unwrap_or_else(|| user.uid.to_string());
+	(
+		Json(ApiResponse::TotpEnrollResponse {
+			otpauth_uri: totp::otpauth_uri(&secret, &account, "TurboCore"),
+			secret,
+		}),
+		http::StatusCode::OK,
+	)
+}
+
+/// Confirms TOTP enrollment with a code from the authenticator app, and returns the recovery codes
+#[post("/api/auth/user/mfa/totp/confirm")]
+pub async fn confirm_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<CodeBody>,
+) -> impl Responder {
+	let uid = match util::verify_header(request.headers().get("Authorization"), &data.key_ring) {
+		HeaderResult::Error(r, s) => return (r, s),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	let secret = match totp_secrets::Entity::find_by_id(uid)
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(secret)) if !secret.confirmed => secret,
+		Ok(Some(_)) => {
+			return (
+				Json(api_error(
+					"Multi-factor authentication is already enabled.".to_string(),
+					"MFA_ALREADY_ENABLED".to_string(),
+				)),
+				http::StatusCode::CONFLICT,
+			)
+		}
+		Ok(None) => {
+			return (
+				Json(api_error(
+					"TOTP enrollment has not been started.".to_string(),
+					"MFA_NOT_ENROLLED".to_string(),
+				)),
+				http::StatusCode::BAD_REQUEST,
+			)
+		}
+		Err(e) => {
+			error!("Unable to find TOTP secret. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+
+	let step = match totp::verify(&secret.secret, &body.code, secret.last_used_step) {
+		Some(step) => step,
+		None => return invalid_code(),
+	};
+
+	let mut secret: totp_secrets::ActiveModel = secret.into();
+	secret.confirmed = Set(true);
+	secret.last_used_step = Set(Some(step));
+	if let Err(e) = secret.update(&data.connection).await {
+		error!("Unable to confirm TOTP secret. Error: {}", e.to_string());
+		return internal_error();
+	}
+
+	match replace_recovery_codes(&data.connection, uid).await {
+		Ok(recovery_codes) => (
+			Json(ApiResponse::RecoveryCodesResponse { recovery_codes }),
+			http::StatusCode::OK,
+		),
+		Err(e) => {
+			error!("Unable to create recovery codes. Error: {}", e.to_string());
+			internal_error()
+		}
+	}
+}
+
+/// Disables TOTP and removes the recovery codes. Requires a TOTP code or a recovery code.
+#[delete("/api/auth/user/mfa/totp")]
+pub async fn disable_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<SecondFactorBody>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let uid = match util::verify_header(request.headers().get("Authorization"), &data.key_ring) {
+		HeaderResult::Error(r, s) => return Either::Left((r, s)),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	match verify_second_factor(
+		&data.connection,
+		uid,
+		body.code.as_deref(),
+		body.recovery_code.as_deref(),
+	)
+	.await
+	{
+		Ok(true) => (),
+		Ok(false) => return Either::Left(invalid_code()),
+		Err(e) => {
+			error!("Unable to verify second factor. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	}
+
+	let res = data
+		.connection
+		.transaction::<_, (), DbErr>(|txn| {
+			Box::pin(async move {
+				totp_secrets::Entity::delete_by_id(uid).exec(txn).await?;
+				recovery_codes::Entity::delete_many()
+					.filter(recovery_codes::Column::Uid.eq(uid))
+					.exec(txn)
+					.await?;
+				Ok(())
+			})
+		})
+		.await;
+
+	match res {
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => {
+			error!("Unable to disable TOTP. Error: {}", e.to_string());
+			Either::Left(internal_error())
+		}
+	}
+}
+
+/// Invalidates the existing recovery codes and returns a new set. Requires a TOTP code.
+#[post("/api/auth/user/mfa/recovery-codes")]
+pub async fn regenerate_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<CodeBody>,
+) -> impl Responder {
+	let uid = match util::verify_header(request.headers().get("Authorization"), &data.key_ring) {
+		HeaderResult::Error(r, s) => return (r, s),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	match verify_second_factor(&data.connection, uid, Some(&body.code), None).await {
+		Ok(true) => (),
+		Ok(false) => return invalid_code(),
+		Err(e) => {
+			error!("Unable to verify second factor. Error: {}", e.to_string());
+			return internal_error();
+		}
+	}
+
+	match replace_recovery_codes(&data.connection, uid).await {
+		Ok(recovery_codes) => (
+			Json(ApiResponse::RecoveryCodesResponse { recovery_codes }),
+			http::StatusCode::OK,
+		),
+		Err(e) => {
+			error!("Unable to create recovery codes. Error: {}", e.to_string());
+			internal_error()
+		}
+	}
+}
+
+/// The second step of logging in, for users that have MFA enabled
+#[post("/api/auth/user/login/mfa")]
+pub async fn challenge_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<ChallengeBody>,
+) -> impl Responder {
+	let challenge =
+		match claims::verify::<MfaChallengeClaims>(&body.mfa_token, &data.config.secret_key) {
+			Ok(claims) => claims,
+			Err(TokenError::Expired) => {
+				return (
+					Json(api_error(
+						"The provided token has already expired".to_string(),
+						"EXPIRED_TOKEN".to_string(),
+					)),
+					http::StatusCode::UNAUTHORIZED,
+				)
+			}
+			Err(_) => {
+				return (
+					Json(api_error(
+						"The provided token is invalid.".to_string(),
+						"INVALID_TOKEN".to_string(),
+					)),
+					http::StatusCode::UNAUTHORIZED,
+				)
+			}
+		};
+
+	let uid = challenge.sub;
+
+	if let Some(locked) = lockout::check(&data, &Subject::SecondFactor(uid), &request).await {
+		return locked;
+	}
+	match count_attempt(&data.connection, &challenge).await {
+		Ok(true) => (),
+		Ok(false) => {
+			return (
+				Json(api_error(
+					"Too many codes were tried with this challenge. Log in again.".to_string(),
+					"EXPIRED_TOKEN".to_string(),
+				)),
+				http::StatusCode::UNAUTHORIZED,
+			)
+		}
+		Err(e) => {
+			error!("Unable to count MFA attempt. Error: {}", e.to_string());
+			return internal_error();
+		}
+	}
+
+	let user = match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(user)) => user,
+		Ok(None) => {
+			return (
+				Json(api_error(
+					"The user was not found.".to_string(),
+					"USER_NOT_FOUND".to_string(),
+				)),
+				http::StatusCode::NOT_FOUND,
+			)
+		}
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+	if !user.active {
+		return (
+			Json(api_error(
+				"The user has been disabled by an administrator.".to_string(),
+				"USER_DISABLED".to_string(),
+			)),
+			http::StatusCode::UNAUTHORIZED,
+		);
+	}
+
+	match verify_second_factor(
+		&data.connection,
+		uid,
+		body.code.as_deref(),
+		body.recovery_code.as_deref(),
+	)
+	.await
+	{
+		Ok(true) => (),
+		Ok(false) => {
+			// Wrong codes are also counted against the user, so that logging in again does not give
+			// more guesses
+			return match lockout::second_factor_failed(&data, uid, &request).await {
+				Some(locked) => locked,
+				None => invalid_code(),
+			};
+		}
+		Err(e) => {
+			error!("Unable to verify second factor. Error: {}", e.to_string());
+			return internal_error();
+		}
+	}
+	if let Err(e) = lockout::clear(&data.connection, &Subject::SecondFactor(uid)).await {
+		error!("Unable to clear login failures. Error: {}", e.to_string());
+	}
+
+	// An expired password must be changed before the user gets tokens
+	if challenge.password_login && password_policy::is_expired(&data.config.password_policy, &user)
+	{
+		return login::password_expired(&data, &user).await;
+	}
+
+	let uid_str = &user.uid.to_string();
+	let (at, rt, exp) = get_at_and_rt(
+		&data.connection,
+		uid_str,
+		&data.key_ring,
+		&data.config.tokens,
+		false,
+		Session::New {
+			request: &request,
+			ua_parser: &data.ua_parser,
+			remember_me: challenge.remember_me,
+		},
+	)
+	.await;
+	(
+		Json(ApiResponse::LoginResponse {
+			uid: uid_str.to_string(),
+			token: at,
+			expiry: exp,
+			refresh_token: rt,
+			email_verified: user.email_verified,
+			metadata: user.metadata.clone().unwrap_or("".to_string()),
+		}),
+		http::StatusCode::OK,
+	)
+}
+
+/// Returns true if the user has confirmed a TOTP secret, meaning a second factor is required to log in
+pub async fn is_enabled(connection: &DatabaseConnection, uid: Uuid) -> Result<bool, DbErr> {
+	Ok(totp_secrets::Entity::find_by_id(uid)
+		.one(connection)
+		.await?
+		.is_some_and(|secret| secret.confirmed))
+}
+
+/// Creates the short-lived token that is exchanged, along with a second factor, for an AT and RT
+pub fn create_challenge_token(uid: Uuid, remember_me: bool, key: &Hmac<Sha256>) -> (String, i64) {
+	challenge_token(uid, remember_me, false, key)
+}
+
+/// Like `create_challenge_token`, for logins with a password. If the password has expired, the second
+/// factor is exchanged for a reset token instead.
+pub fn create_password_challenge_token(
+	uid: Uuid,
+	remember_me: bool,
+	key: &Hmac<Sha256>,
+) -> (String, i64) {
+	challenge_token(uid, remember_me, true, key)
+}
+
+fn challenge_token(
+	uid: Uuid,
+	remember_me: bool,
+	password_login: bool,
+	key: &Hmac<Sha256>,
+) -> (String, i64) {
+	let claims = MfaChallengeClaims {
+		registered: RegisteredClaims::new(MfaChallengeClaims::AUDIENCE, Duration::minutes(5)),
+		sub: uid,
+		remember_me,
+		password_login,
+	};
+	(claims::sign(&claims, key), claims.registered.exp)
+}
+
+/// Counts an attempt at the challenge. Returns false once it has had too many.
+async fn count_attempt(
+	connection: &DatabaseConnection,
+	challenge: &MfaChallengeClaims,
+) -> Result<bool, DbErr> {
+	let jti = &challenge.registered.jti;
+	mfa_challenges::Entity::insert(mfa_challenges::ActiveModel {
+		jti: Set(jti.to_owned()),
+		attempts: Set(0),
+		expiry: Set(
+			NaiveDateTime::from_timestamp_opt(challenge.registered.exp, 0).unwrap_or_default()
+		),
+	})
+	.on_conflict(
+		OnConflict::column(mfa_challenges::Column::Jti)
+			.do_nothing()
+			.to_owned(),
+	)
+	.exec_without_returning(connection)
+	.await?;
+
+	// The attempt is counted before the code is checked, so that guesses made at the same time cannot get
+	// past the limit
+	let counted = mfa_challenges::Entity::update_many()
+		.col_expr(
+			mfa_challenges::Column::Attempts,
+			Expr::col(mfa_challenges::Column::Attempts).add(1),
+		)
+		.filter(mfa_challenges::Column::Jti.eq(jti.to_owned()))
+		.filter(mfa_challenges::Column::Attempts.lt(MAX_CHALLENGE_ATTEMPTS))
+		.exec(connection)
+		.await?;
+	Ok(counted.rows_affected == 1)
+}
+
+/// Checks a TOTP code, or else a recovery code, against the user's confirmed second factor.
+/// Successfully used codes cannot be used again.
+async fn verify_second_factor(
+	connection: &DatabaseConnection,
+	uid: Uuid,
+	code: Option<&str>,
+	recovery_code: Option<&str>,
+) -> Result<bool, DbErr> {
+	let secret = match totp_secrets::Entity::find_by_id(uid)
+		.one(connection)
+		.await?
+	{
+		Some(secret) if secret.confirmed => secret,
+		_ => return Ok(false),
+	};
+
+	if let Some(code) = code {
+		return match totp::verify(&secret.secret, code, secret.last_used_step) {
+			Some(step) => {
+				// Only move on to this step if no other request has used it, or a later one, so that
+				// concurrent requests can't both use the same code
+				let res = totp_secrets::Entity::update_many()
+					.col_expr(totp_secrets::Column::LastUsedStep, Expr::value(step))
+					.filter(totp_secrets::Column::Uid.eq(uid))
+					.filter(
+						Condition::any()
+							.add(totp_secrets::Column::LastUsedStep.is_null())
+							.add(totp_secrets::Column::LastUsedStep.lt(step)),
+					)
+					.exec(connection)
+					.await?;
+				Ok(res.rows_affected == 1)
+			}
+			None => Ok(false),
+		};
+	}
+
+	if let Some(recovery_code) = recovery_code {
+		let code_hash = util::hash_secret(&normalize_recovery_code(recovery_code));
+		// Only mark the code as used if it hasn't been used already, so concurrent requests can't both succeed
+		let res = recovery_codes::Entity::update_many()
+			.col_expr(recovery_codes::Column::Used, true.into())
+			.filter(recovery_codes::Column::CodeHash.eq(code_hash))
+			.filter(recovery_codes::Column::Uid.eq(uid))
+			.filter(recovery_codes::Column::Used.eq(false))
+			.exec(connection)
+			.await?;
+		return Ok(res.rows_affected == 1);
+	}
+
+	Ok(false)
+}
+
+/// Deletes the user's recovery codes and stores a new set. Only the hashes are stored.
+async fn replace_recovery_codes(
+	connection: &DatabaseConnection,
+	uid: Uuid,
+) -> Result<Vec<String>, DbErr> {
+	let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+		.map(|_| {
+			let code: String = thread_rng()
+				.sample_iter(&Alphanumeric)
+				.take(10)
+				.map(|c| char::from(c).to_ascii_lowercase())
+				.collect();
+			format!("{}-{}", &code[..5], &code[5..])
+		})
+		.collect();
+
+	let now = Utc::now().naive_utc();
+	let models: Vec<recovery_codes::ActiveModel> = codes
+		.iter()
+		.map(|code| recovery_codes::ActiveModel {
+			code_hash: Set(util::hash_secret(&normalize_recovery_code(code))),
+			uid: Set(uid),
+			used: Set(false),
+			created_at: Set(now),
+		})
+		.collect();
+
+	connection
+		.transaction::<_, (), DbErr>(|txn| {
+			Box::pin(async move {
+				recovery_codes::Entity::delete_many()
+					.filter(recovery_codes::Column::Uid.eq(uid))
+					.exec(txn)
+					.await?;
+				recovery_codes::Entity::insert_many(models)
+					.exec(txn)
+					.await?;
+				Ok(())
+			})
+		})
+		.await
+		.map_err(|e| match e {
+			sea_orm::TransactionError::Connection(e) => e,
+			sea_orm::TransactionError::Transaction(e) => e,
+		})?;
+
+	Ok(codes)
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+	code.chars()
+		.filter(|c| c.is_ascii_alphanumeric())
+		.map(|c| c.to_ascii_lowercase())
+		.collect()
+}
+
+fn invalid_code() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The provided code is invalid.".to_string(),
+			"INVALID_MFA_CODE".to_string(),
+		)),
+		http::StatusCode::UNAUTHORIZED,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
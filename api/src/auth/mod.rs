@@ -2,18 +2,36 @@ use actix_web::web;
 use sea_orm::entity::prelude::DateTime;
 use serde::Serialize;
 
+pub mod action_tokens;
+pub mod anonymous;
 pub mod change_password;
+pub mod claims;
+pub mod create_user;
 pub mod delete_user;
+pub mod email_change;
 pub mod email_verify;
 pub mod get_user;
+pub mod hashing;
+pub mod identities;
+pub mod lockout;
 pub mod login;
 pub mod logout;
 pub mod magic_link;
+pub mod mfa;
+pub mod oauth;
+pub mod oidc;
+pub mod otp;
+pub mod passkey;
+pub mod phone;
+pub mod password_policy;
 pub mod refresh;
 pub mod reset_password;
-pub mod create_user;
+pub mod security_events;
+pub mod sessions;
+pub mod totp;
 pub mod update_user;
 pub mod util;
+pub mod webauthn;
 
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
@@ -22,6 +40,11 @@ pub enum ApiResponse {
 		message: String,
 		error_code: String,
 	},
+	PasswordPolicyError {
+		message: String,
+		error_code: String,
+		violations: Vec<password_policy::Violation>,
+	},
 	SignupResponse {
 		uid: String,
 	},
@@ -33,6 +56,61 @@ pub enum ApiResponse {
 		email_verified: bool,
 		metadata: String,
 	},
+	MfaChallengeResponse {
+		uid: String,
+		mfa_required: bool,
+		mfa_token: String,
+		expiry: i64,
+	},
+	/// The password must be changed with the reset token before the user can log in
+	PasswordExpiredResponse {
+		uid: String,
+		password_expired: bool,
+		reset_token: String,
+		expiry: i64,
+	},
+	TotpEnrollResponse {
+		secret: String,
+		otpauth_uri: String,
+	},
+	RecoveryCodesResponse {
+		recovery_codes: Vec<String>,
+	},
+	PasskeyOptionsResponse {
+		options: serde_json::Value,
+		state: String,
+	},
+	PasskeyResponse(passkey::PasskeyInfo),
+	PasskeyListResponse {
+		passkeys: Vec<passkey::PasskeyInfo>,
+	},
+	RedirectResponse {
+		redirect_url: String,
+	},
+	SessionListResponse {
+		sessions: Vec<sessions::SessionInfo>,
+	},
+	IdentityListResponse {
+		identities: Vec<identities::IdentityInfo>,
+	},
+	CustomClaimsResponse {
+		claims: serde_json::Map<String, serde_json::Value>,
+	},
+	PermissionListResponse {
+		permissions: Vec<crate::admin::rbac::PermissionInfo>,
+	},
+	RoleListResponse {
+		roles: Vec<crate::admin::rbac::RoleInfo>,
+	},
+	GroupListResponse {
+		groups: Vec<crate::admin::rbac::GroupInfo>,
+	},
+	/// The roles include those granted by the groups
+	UserPermissionsResponse {
+		roles: Vec<String>,
+		groups: Vec<String>,
+		permissions: Vec<String>,
+	},
 	RefreshResponse {
 		uid: String,
 		access_token: String,
@@ -48,6 +126,14 @@ pub enum ApiResponse {
 		active: bool,
 		metadata: Option<String>,
 		email_verified: bool,
+		anonymous: bool,
+		phone: Option<String>,
+		phone_verified: bool,
+	},
+	/// The users that could not be imported are listed with the reason
+	UserImportResponse {
+		imported: usize,
+		errors: Vec<crate::admin::user_import::RowError>,
 	},
 }
 
@@ -63,11 +149,43 @@ pub fn add_routes(cfg: &mut web::ServiceConfig) {
 		.service(crate::auth::login::handler)
 		.service(crate::auth::refresh::handler)
 		.service(crate::auth::get_user::handler)
+		.service(crate::auth::update_user::handler)
 		.service(crate::auth::delete_user::handler)
 		.service(crate::auth::change_password::handler)
 		.service(crate::auth::email_verify::send_handler)
 		.service(crate::auth::email_verify::receive_handler)
+		.service(crate::auth::email_change::confirm_handler)
+		.service(crate::auth::email_change::cancel_handler)
 		.service(crate::auth::magic_link::get_handler)
 		.service(crate::auth::magic_link::post_handler)
-		.service(crate::auth::reset_password::handler);
+		.service(crate::auth::magic_link::verify_handler)
+		.service(crate::auth::phone::send_handler)
+		.service(crate::auth::phone::verify_handler)
+		.service(crate::auth::phone::link_handler)
+		.service(crate::auth::phone::link_verify_handler)
+		.service(crate::auth::reset_password::handler)
+		.service(crate::auth::reset_password::confirm_handler)
+		.service(crate::auth::mfa::enroll_handler)
+		.service(crate::auth::mfa::confirm_handler)
+		.service(crate::auth::mfa::disable_handler)
+		.service(crate::auth::mfa::regenerate_handler)
+		.service(crate::auth::mfa::challenge_handler)
+		.service(crate::auth::passkey::register_start_handler)
+		.service(crate::auth::passkey::register_finish_handler)
+		.service(crate::auth::passkey::login_start_handler)
+		.service(crate::auth::passkey::login_finish_handler)
+		.service(crate::auth::passkey::list_handler)
+		.service(crate::auth::passkey::delete_handler)
+		.service(crate::auth::anonymous::create_handler)
+		.service(crate::auth::anonymous::upgrade_handler)
+		.service(crate::auth::oauth::start_handler)
+		.service(crate::auth::oauth::link_handler)
+		.service(crate::auth::oauth::callback_handler)
+		.service(crate::auth::sessions::list_handler)
+		.service(crate::auth::sessions::delete_others_handler)
+		.service(crate::auth::sessions::delete_handler)
+		.service(crate::auth::identities::list_handler)
+		.service(crate::auth::identities::link_password_handler)
+		.service(crate::auth::identities::link_magic_link_handler)
+		.service(crate::auth::identities::unlink_handler);
 }
@@ -0,0 +1,626 @@
+use crate::{
+	admin::user_import::NO_PASSWORD,
+	auth::{
+		anonymous::{self, UpgradeError},
+		api_error, identities, mfa,
+		oidc::{self, IdTokenClaims, Pkce},
+		util::{get_at_and_rt, Session},
+		ApiResponse,
+	},
+	AppState, Config, OAuthProviderConfig,
+};
+use actix_web::{
+	cookie::{self, Cookie, SameSite},
+	get, http, post,
+	web::{Data, Json, Path, Query},
+	Either, HttpRequest, HttpResponse,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use entity::{oauth_states, users};
+use log::{error, warn};
+use migration::DbErr;
+use reqwest::Url;
+use sea_orm::{
+	ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, Set,
+	TransactionTrait,
+};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// How long the user has to complete the login with the provider, in minutes
+const STATE_TIMEOUT: i64 = 10;
+/// Holds the state in the browser that started the login, so that the callback only completes it there.
+/// Otherwise, anyone could start a login and send the provider's page to someone else, who would then be
+/// logged in to, or link their identity to, the other person's account.
+const STATE_COOKIE: &str = "turbocore_oauth_state";
+
+#[derive(Deserialize)]
+pub struct StartQuery {
+	next_url: String,
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+	code: Option<String>,
+	state: Option<String>,
+	error: Option<String>,
+}
+
+enum LinkError {
+	EmailRequired,
+	AccountExists,
+	/// The identity belongs to another user, so it can't be linked
+	IdentityInUse,
+	NotAnonymous,
+	/// The user was deleted before they finished linking the identity
+	UserNotFound,
+	Database(DbErr),
+}
+
+impl From<DbErr> for LinkError {
+	fn from(e: DbErr) -> Self {
+		LinkError::Database(e)
+	}
+}
+
+impl From<identities::LinkError> for LinkError {
+	fn from(e: identities::LinkError) -> Self {
+		match e {
+			identities::LinkError::InUse => LinkError::IdentityInUse,
+			identities::LinkError::Database(e) => LinkError::Database(e),
+		}
+	}
+}
+
+impl From<UpgradeError> for LinkError {
+	fn from(e: UpgradeError) -> Self {
+		match e {
+			UpgradeError::EmailInUse => LinkError::AccountExists,
+			UpgradeError::NotAnonymous => LinkError::NotAnonymous,
+			UpgradeError::Database(e) => LinkError::Database(e),
+		}
+	}
+}
+
+/// Redirects the user to the provider's login page.
+/// Once they're done, they are sent back to `next_url` with their tokens in the fragment.
+#[get("/api/auth/user/oauth/{provider}/start")]
+pub async fn start_handler(
+	data: Data<AppState>,
+	path: Path<String>,
+	query: Query<StartQuery>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	match begin(&data, &path.into_inner(), &query.next_url, None).await {
+		Ok((authorization_url, cookie)) => Either::Right(
+			HttpResponse::Found()
+				.append_header(("Location", authorization_url))
+				.cookie(cookie)
+				.finish(),
+		),
+		Err(response) => Either::Left(response),
+	}
+}
+
+/// Links an identity from the provider to the user making the request. Anonymous users are upgraded, and
+/// other users must have logged in recently, see `identities`. Since the request must be authenticated,
+/// the client is given the provider's login page to send the user to, rather than being redirected. The
+/// login then completes like any other, so the request must be made with credentials for the browser to
+/// keep the state cookie.
+#[post("/api/auth/user/oauth/{provider}/link")]
+pub async fn link_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+	path: Path<String>,
+	body: Json<StartQuery>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	// Anonymous users have no way to log in again, so they are the only ones who don't need to
+	let user = match anonymous::authenticate(&request, &data).await {
+		Ok(user) => user,
+		Err(_) => match identities::reauthenticate(&request, &data).await {
+			Ok(user) => user,
+			Err(response) => return Either::Left(response),
+		},
+	};
+
+	match begin(&data, &path.into_inner(), &body.next_url, Some(user.uid)).await {
+		Ok((redirect_url, cookie)) => Either::Right(
+			HttpResponse::Ok()
+				.cookie(cookie)
+				.json(ApiResponse::RedirectResponse { redirect_url }),
+		),
+		Err(response) => Either::Left(response),
+	}
+}
+
+/// Saves the state of a new login, and returns the provider's login page and the cookie that binds the
+/// state to the browser. When `uid` is set, the identity is linked to that user.
+async fn begin(
+	data: &AppState,
+	provider_name: &str,
+	next_url: &str,
+	uid: Option<Uuid>,
+) -> Result<(String, Cookie<'static>), (Json<ApiResponse>, http::StatusCode)> {
+	let provider = match find_provider(&data.config, provider_name) {
+		Some(provider) => provider,
+		None => return Err(provider_not_found()),
+	};
+
+	// Tokens are appended to next_url, so it must point somewhere we trust
+	if !is_allowed_redirect(&data.config, next_url) {
+		return Err((
+			Json(api_error(
+				"The next_url is not an allowed origin.".to_string(),
+				"INVALID_REDIRECT_URL".to_string(),
+			)),
+			http::StatusCode::BAD_REQUEST,
+		));
+	}
+
+	let metadata = match oidc::discover(provider).await {
+		Ok(metadata) => metadata,
+		Err(e) => {
+			warn!("Unable to discover OAuth provider {}. Error: {:?}", provider.name, e);
+			return Err(provider_error(e));
+		}
+	};
+
+	let state = oidc::random_token();
+	let nonce = oidc::random_token();
+	let pkce = Pkce::generate();
+
+	let authorization_url = oidc::authorization_url(
+		&metadata,
+		provider,
+		&redirect_uri(&data.config, provider),
+		&state,
+		&nonce,
+		&pkce.challenge,
+	)
+	.map_err(provider_error)?;
+
+	let cookie = state_cookie(&data.config, state.to_owned());
+	let oauth_state = oauth_states::ActiveModel {
+		state: Set(state),
+		provider: Set(provider.name.to_owned()),
+		code_verifier: Set(pkce.verifier),
+		nonce: Set(nonce),
+		next_url: Set(next_url.to_owned()),
+		expiry: Set(Utc::now().naive_utc() + Duration::minutes(STATE_TIMEOUT)),
+		uid: Set(uid),
+	};
+	if let Err(e) = oauth_state.insert(&data.connection).await {
+		error!("Unable to save OAuth state. Error: {}", e.to_string());
+		return Err(internal_error());
+	}
+
+	Ok((authorization_url, cookie))
+}
+
+/// The provider redirects the user here after they log in. The state must match the browser's state
+/// cookie. Once the state is known, errors are reported by redirecting to `next_url` with an `error`
+/// parameter.
+#[get("/api/auth/user/oauth/{provider}/callback")]
+pub async fn callback_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+	path: Path<String>,
+	query: Query<CallbackQuery>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let provider_name = path.into_inner();
+	let state = match query.state {
+		Some(ref state) => state.to_owned(),
+		None => return Either::Left(invalid_state()),
+	};
+	let started_here = request
+		.cookie(STATE_COOKIE)
+		.map(|cookie| bool::from(cookie.value().as_bytes().ct_eq(state.as_bytes())))
+		.unwrap_or(false);
+	if !started_here {
+		return Either::Left(invalid_state());
+	}
+
+	match complete(&request, &data, &provider_name, state, &query).await {
+		Either::Right(mut response) => {
+			// The state can only be used once, so the cookie isn't needed anymore
+			let _ = response.add_removal_cookie(&state_cookie(&data.config, String::new()));
+			Either::Right(response)
+		}
+		response => response,
+	}
+}
+
+/// Uses the state, and logs the user in or links the identity
+async fn complete(
+	request: &HttpRequest,
+	data: &AppState,
+	provider_name: &str,
+	state: String,
+	query: &CallbackQuery,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let saved = match oauth_states::Entity::find_by_id(state.clone())
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(saved)) => saved,
+		Ok(None) => return Either::Left(invalid_state()),
+		Err(e) => {
+			error!("Unable to find OAuth state. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	};
+
+	// States can only be used once. If another request deleted it first, it wins.
+	match oauth_states::Entity::delete_by_id(state)
+		.exec(&data.connection)
+		.await
+	{
+		Ok(res) if res.rows_affected == 1 => (),
+		Ok(_) => return Either::Left(invalid_state()),
+		Err(e) => {
+			error!("Unable to delete OAuth state. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	}
+
+	if saved.provider != provider_name || saved.expiry < Utc::now().naive_utc() {
+		return Either::Left(invalid_state());
+	}
+
+	let provider = match find_provider(&data.config, provider_name) {
+		Some(provider) => provider,
+		None => return Either::Left(provider_not_found()),
+	};
+
+	if let Some(ref e) = query.error {
+		return Either::Right(redirect_error(
+			&saved.next_url,
+			"OAUTH_DENIED",
+			&format!("The provider returned an error: {e}"),
+		));
+	}
+	let code = match query.code {
+		Some(ref code) => code,
+		None => {
+			return Either::Right(redirect_error(
+				&saved.next_url,
+				"OAUTH_DENIED",
+				"The provider did not return an authorization code.",
+			))
+		}
+	};
+
+	let claims = match authenticate(&data.config, provider, code, &saved).await {
+		Ok(claims) => claims,
+		Err(e) => {
+			warn!("OAuth login with {} failed. Error: {:?}", provider.name, e);
+			return Either::Right(redirect_error(
+				&saved.next_url,
+				"OAUTH_PROVIDER_ERROR",
+				e.message(),
+			));
+		}
+	};
+
+	let user = match saved.uid {
+		Some(uid) => link_user(&data.connection, provider, &claims, uid).await,
+		None => find_or_link_user(&data.connection, provider, &claims).await,
+	};
+	let user = match user {
+		Ok(user) => user,
+		Err(LinkError::EmailRequired) => {
+			return Either::Right(redirect_error(
+				&saved.next_url,
+				"OAUTH_EMAIL_REQUIRED",
+				"The provider did not share an email address.",
+			))
+		}
+		Err(LinkError::AccountExists) => return Either::Right(redirect_error(
+			&saved.next_url,
+			"OAUTH_ACCOUNT_EXISTS",
+			"An account with this email already exists. Log in and verify your email to link it.",
+		)),
+		Err(LinkError::IdentityInUse) => {
+			return Either::Right(redirect_error(
+				&saved.next_url,
+				"OAUTH_IDENTITY_IN_USE",
+				"This account with the provider is already linked to another user.",
+			))
+		}
+		Err(LinkError::UserNotFound) => {
+			return Either::Right(redirect_error(
+				&saved.next_url,
+				"USER_NOT_FOUND",
+				"The user does not exist.",
+			))
+		}
+		Err(LinkError::NotAnonymous) => {
+			return Either::Right(redirect_error(
+				&saved.next_url,
+				"USER_NOT_ANONYMOUS",
+				"Only anonymous users can be upgraded.",
+			))
+		}
+		Err(LinkError::Database(e)) => {
+			error!("Unable to find or create OAuth user. Error: {}", e.to_string());
+			return Either::Right(redirect_error(
+				&saved.next_url,
+				"INTERNAL_SERVER_ERROR",
+				"An internal server error occurred.",
+			));
+		}
+	};
+
+	if !user.active {
+		return Either::Right(redirect_error(
+			&saved.next_url,
+			"USER_DISABLED",
+			"The user has been disabled by an administrator.",
+		));
+	}
+
+	let uid_str = user.uid.to_string();
+
+	// The provider only replaces the password, so users with MFA still need to complete the challenge
+	match mfa::is_enabled(&data.connection, user.uid).await {
+		Ok(true) => {
+			let (mfa_token, expiry) =
+				mfa::create_challenge_token(user.uid, true, &data.config.secret_key);
+			return Either::Right(redirect(
+				&saved.next_url,
+				&[
+					("uid", &uid_str),
+					("mfa_required", "true"),
+					("mfa_token", &mfa_token),
+					("exp", &expiry.to_string()),
+				],
+			));
+		}
+		Ok(false) => (),
+		Err(e) => {
+			error!("An error occurred when checking MFA status. Error: {}", e.to_string());
+			return Either::Right(redirect_error(
+				&saved.next_url,
+				"INTERNAL_SERVER_ERROR",
+				"An internal server error occurred.",
+			));
+		}
+	}
+
+	let (at, rt, exp) = get_at_and_rt(
+		&data.connection,
+		&uid_str,
+		&data.key_ring,
+		&data.config.tokens,
+		false,
+		Session::new(request, &data.ua_parser),
+	)
+	.await;
+
+	Either::Right(redirect(
+		&saved.next_url,
+		&[
+			("uid", &uid_str),
+			("at", &at),
+			("rt", &rt),
+			("exp", &exp.to_string()),
+		],
+	))
+}
+
+async fn authenticate(
+	config: &Config,
+	provider: &OAuthProviderConfig,
+	code: &str,
+	saved: &oauth_states::Model,
+) -> Result<IdTokenClaims, oidc::OidcError> {
+	let metadata = oidc::discover(provider).await?;
+	let id_token = oidc::exchange_code(
+		&metadata,
+		provider,
+		code,
+		&redirect_uri(config, provider),
+		&saved.code_verifier,
+	)
+	.await?;
+	oidc::validate_id_token(&metadata, provider, &id_token, &saved.nonce).await
+}
+
+/// Finds the user linked to the provider's subject. Otherwise, the identity is linked to the user
+/// with the same verified email, or a new user is created.
+async fn find_or_link_user(
+	connection: &DatabaseConnection,
+	provider: &OAuthProviderConfig,
+	claims: &IdTokenClaims,
+) -> Result<users::Model, LinkError> {
+	let now = NaiveDateTime::from_timestamp_opt(Utc::now().timestamp(), 0).unwrap();
+
+	let identity = identities::find(connection, &[&provider.name], &claims.sub).await?;
+
+	if let Some(identity) = identity {
+		if let Some(user) = users::Entity::find_by_id(identity.uid)
+			.one(connection)
+			.await?
+		{
+			identities::record_login(connection, identity.id).await?;
+			return Ok(user);
+		}
+		// The user was deleted, so the identity is stale
+		identities::unlink_provider(connection, identity.uid, &provider.name).await?;
+	}
+
+	let email = match claims.email {
+		Some(ref email) if !email.is_empty() => email.to_owned(),
+		_ => return Err(LinkError::EmailRequired),
+	};
+
+	let existing = users::Entity::find()
+		.filter(users::Column::Email.eq(email.to_owned()))
+		.one(connection)
+		.await?;
+
+	// Only link accounts when both sides have proven they own the email. Otherwise, someone could
+	// sign up with another person's email and gain access once that person uses the provider.
+	if let Some(ref user) = existing {
+		if !claims.email_verified || !user.email_verified {
+			return Err(LinkError::AccountExists);
+		}
+	}
+
+	let txn = connection.begin().await?;
+	let user = match existing {
+		Some(user) => user,
+		None => {
+			users::ActiveModel {
+				uid: Set(Uuid::new_v4()),
+				password: Set(NO_PASSWORD.to_string()),
+				email: Set(Some(email.to_owned())),
+				created_at: Set(now),
+				updated_at: Set(now),
+				active: Set(true),
+				email_verified: Set(claims.email_verified),
+				..Default::default()
+			}
+			.insert(&txn)
+			.await?
+		}
+	};
+	identities::link(&txn, user.uid, &provider.name, &claims.sub, Some(email)).await?;
+	txn.commit().await?;
+
+	Ok(user)
+}
+
+/// Links the provider's identity to the user. Anonymous users are upgraded, and take the provider's email.
+async fn link_user(
+	connection: &DatabaseConnection,
+	provider: &OAuthProviderConfig,
+	claims: &IdTokenClaims,
+	uid: Uuid,
+) -> Result<users::Model, LinkError> {
+	let user = match users::Entity::find_by_id(uid).one(connection).await? {
+		Some(user) => user,
+		None => return Err(LinkError::UserNotFound),
+	};
+	match identities::find(connection, &[provider.name.as_str()], &claims.sub).await? {
+		Some(identity) if identity.uid != uid => return Err(LinkError::IdentityInUse),
+		_ => (),
+	}
+
+	let txn = connection.begin().await?;
+	if user.anonymous {
+		let email = match claims.email {
+			Some(ref email) if !email.is_empty() => email.to_owned(),
+			_ => return Err(LinkError::EmailRequired),
+		};
+		anonymous::upgrade(&txn, uid, &email, claims.email_verified, None).await?;
+	}
+	identities::link(&txn, uid, &provider.name, &claims.sub, claims.email.to_owned()).await?;
+	txn.commit().await?;
+
+	users::Entity::find_by_id(uid)
+		.one(connection)
+		.await?
+		.ok_or(LinkError::UserNotFound)
+}
+
+fn find_provider<'a>(config: &'a Config, name: &str) -> Option<&'a OAuthProviderConfig> {
+	config
+		.oauth_providers
+		.iter()
+		.find(|provider| provider.name == name)
+}
+
+/// The state cookie is only sent to the OAuth routes, including a project's path prefix in `base_url`
+fn state_cookie(config: &Config, state: String) -> Cookie<'static> {
+	let path = Url::parse(&config.base_url)
+		.map(|url| url.path().trim_end_matches('/').to_string())
+		.unwrap_or_default();
+	Cookie::build(STATE_COOKIE, state)
+		.path(format!("{path}/api/auth/user/oauth"))
+		.http_only(true)
+		.secure(config.base_url.starts_with("https://"))
+		.same_site(SameSite::Lax)
+		.max_age(cookie::time::Duration::minutes(STATE_TIMEOUT))
+		.finish()
+}
+
+fn redirect_uri(config: &Config, provider: &OAuthProviderConfig) -> String {
+	format!(
+		"{}/api/auth/user/oauth/{}/callback",
+		config.base_url.trim_end_matches('/'),
+		provider.name
+	)
+}
+
+/// Only URLs on TurboCore's own origin, or one of the allowed origins, may receive tokens
+fn is_allowed_redirect(config: &Config, next_url: &str) -> bool {
+	let origin = match Url::parse(next_url) {
+		Ok(url) => url.origin(),
+		Err(_) => return false,
+	};
+	config
+		.allowed_origins
+		.iter()
+		.chain(std::iter::once(&config.base_url))
+		.filter_map(|allowed| Url::parse(allowed).ok())
+		.any(|allowed| allowed.origin() == origin)
+}
+
+/// Sends the user to `next_url` with the parameters in the fragment, which browsers never send to servers,
+/// so tokens don't end up in access logs or `Referer` headers
+fn redirect(next_url: &str, params: &[(&str, &str)]) -> HttpResponse {
+	// next_url was validated when the state was created
+	let mut url = match Url::parse(next_url) {
+		Ok(url) => url,
+		Err(_) => return HttpResponse::BadRequest().finish(),
+	};
+	let fragment = form_urlencoded::Serializer::new(String::new())
+		.extend_pairs(params)
+		.finish();
+	url.set_fragment(Some(&fragment));
+	HttpResponse::Found()
+		.append_header(("Location", url.to_string()))
+		.finish()
+}
+
+fn redirect_error(next_url: &str, error_code: &str, message: &str) -> HttpResponse {
+	redirect(next_url, &[("error", error_code), ("message", message)])
+}
+
+fn provider_not_found() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The OAuth provider was not found.".to_string(),
+			"PROVIDER_NOT_FOUND".to_string(),
+		)),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn provider_error(e: oidc::OidcError) -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(e.message().to_string(), "OAUTH_PROVIDER_ERROR".to_string())),
+		http::StatusCode::BAD_GATEWAY,
+	)
+}
+
+fn invalid_state() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The provided state is invalid or has expired.".to_string(),
+			"INVALID_STATE".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
@@ -0,0 +1,263 @@
+//! A minimal OpenID Connect relying party, used to sign users in with upstream providers.
+//!
+//! Only the authorization code flow with PKCE (S256) is supported. Provider endpoints are found
+//! through discovery, and ID tokens may be signed with the client secret (HS*) or with a key
+//! published in the provider's JWKS.
+
+use std::time::Duration;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::{thread_rng, Rng};
+use reqwest::Url;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+
+use crate::OAuthProviderConfig;
+
+/// Clock skew allowed when checking the ID token's `exp`, `iat` and `nbf`, in seconds
+const LEEWAY: u64 = 60;
+
+lazy_static! {
+	static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+		.timeout(Duration::from_secs(10))
+		.build()
+		.unwrap();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OidcError {
+	Discovery,
+	TokenExchange,
+	InvalidIdToken,
+	NonceMismatch,
+}
+
+impl OidcError {
+	pub fn message(&self) -> &'static str {
+		match self {
+			OidcError::Discovery => "The provider's configuration could not be loaded.",
+			OidcError::TokenExchange => "The authorization code could not be exchanged.",
+			OidcError::InvalidIdToken => "The ID token returned by the provider is invalid.",
+			OidcError::NonceMismatch => "The ID token was not issued for this request.",
+		}
+	}
+}
+
+/// The subset of the discovery document that TurboCore needs
+#[derive(Debug, Deserialize)]
+pub struct ProviderMetadata {
+	pub issuer: String,
+	pub authorization_endpoint: String,
+	pub token_endpoint: String,
+	pub jwks_uri: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+	pub sub: String,
+	pub email: Option<String>,
+	#[serde(default, deserialize_with = "bool_or_string")]
+	pub email_verified: bool,
+	pub nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+	id_token: Option<String>,
+}
+
+/// A PKCE code verifier and the matching S256 challenge
+pub struct Pkce {
+	pub verifier: String,
+	pub challenge: String,
+}
+
+impl Pkce {
+	pub fn generate() -> Self {
+		let verifier = random_token();
+		let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+		Self {
+			verifier,
+			challenge,
+		}
+	}
+}
+
+/// Generates 256 random bits, base64url encoded, for use as a state, nonce or code verifier
+pub fn random_token() -> String {
+	let bytes: [u8; 32] = thread_rng().gen();
+	URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Fetches the provider's discovery document
+pub async fn discover(provider: &OAuthProviderConfig) -> Result<ProviderMetadata, OidcError> {
+	let url = format!("{}/.well-known/openid-configuration", provider.issuer.trim_end_matches('/'));
+	let metadata: ProviderMetadata = HTTP_CLIENT
+		.get(url)
+		.send()
+		.await
+		.and_then(|res| res.error_for_status())
+		.map_err(|_| OidcError::Discovery)?
+		.json()
+		.await
+		.map_err(|_| OidcError::Discovery)?;
+
+	// The document must describe the issuer we were configured with, see OpenID Connect Discovery 4.3
+	if metadata.issuer.trim_end_matches('/') != provider.issuer.trim_end_matches('/') {
+		return Err(OidcError::Discovery);
+	}
+	Ok(metadata)
+}
+
+/// Builds the URL the user is sent to in order to authenticate with the provider
+pub fn authorization_url(
+	metadata: &ProviderMetadata,
+	provider: &OAuthProviderConfig,
+	redirect_uri: &str,
+	state: &str,
+	nonce: &str,
+	code_challenge: &str,
+) -> Result<String, OidcError> {
+	let scope = provider.scopes.join(" ");
+	let url = Url::parse_with_params(
+		&metadata.authorization_endpoint,
+		&[
+			("response_type", "code"),
+			("client_id", provider.client_id.as_str()),
+			("redirect_uri", redirect_uri),
+			("scope", scope.as_str()),
+			("state", state),
+			("nonce", nonce),
+			("code_challenge", code_challenge),
+			("code_challenge_method", "S256"),
+		],
+	)
+	.map_err(|_| OidcError::Discovery)?;
+	Ok(url.into())
+}
+
+/// Exchanges the authorization code at the token endpoint and returns the raw ID token
+pub async fn exchange_code(
+	metadata: &ProviderMetadata,
+	provider: &OAuthProviderConfig,
+	code: &str,
+	redirect_uri: &str,
+	code_verifier: &str,
+) -> Result<String, OidcError> {
+	let response: TokenResponse = HTTP_CLIENT
+		.post(&metadata.token_endpoint)
+		.header("Accept", "application/json")
+		.form(&[
+			("grant_type", "authorization_code"),
+			("code", code),
+			("redirect_uri", redirect_uri),
+			("client_id", provider.client_id.as_str()),
+			("client_secret", provider.client_secret.as_str()),
+			("code_verifier", code_verifier),
+		])
+		.send()
+		.await
+		.and_then(|res| res.error_for_status())
+		.map_err(|_| OidcError::TokenExchange)?
+		.json()
+		.await
+		.map_err(|_| OidcError::TokenExchange)?;
+
+	response.id_token.ok_or(OidcError::TokenExchange)
+}
+
+/// Validates the ID token's signature, issuer, audience, lifetime and nonce
+pub async fn validate_id_token(
+	metadata: &ProviderMetadata,
+	provider: &OAuthProviderConfig,
+	id_token: &str,
+	nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+	let header = jsonwebtoken::decode_header(id_token).map_err(|_| OidcError::InvalidIdToken)?;
+
+	let key = match header.alg {
+		Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+			DecodingKey::from_secret(provider.client_secret.as_bytes())
+		}
+		_ => {
+			let jwks_uri = metadata
+				.jwks_uri
+				.as_ref()
+				.ok_or(OidcError::InvalidIdToken)?;
+			let jwks: JwkSet = HTTP_CLIENT
+				.get(jwks_uri)
+				.send()
+				.await
+				.and_then(|res| res.error_for_status())
+				.map_err(|_| OidcError::Discovery)?
+				.json()
+				.await
+				.map_err(|_| OidcError::Discovery)?;
+			let jwk = match header.kid {
+				Some(ref kid) => jwks.find(kid),
+				// Without a key id, the provider must only publish a single key
+				None if jwks.keys.len() == 1 => jwks.keys.first(),
+				None => None,
+			}
+			.ok_or(OidcError::InvalidIdToken)?;
+			DecodingKey::from_jwk(jwk).map_err(|_| OidcError::InvalidIdToken)?
+		}
+	};
+
+	let mut validation = Validation::new(header.alg);
+	validation.leeway = LEEWAY;
+	validation.set_issuer(&[&metadata.issuer]);
+	validation.set_audience(&[&provider.client_id]);
+	validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+	let claims = jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+		.map_err(|_| OidcError::InvalidIdToken)?
+		.claims;
+
+	if claims.nonce.as_deref() != Some(nonce) {
+		return Err(OidcError::NonceMismatch);
+	}
+	Ok(claims)
+}
+
+/// Some providers send `email_verified` as a string
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum BoolOrString {
+		Bool(bool),
+		String(String),
+	}
+
+	Ok(match BoolOrString::deserialize(deserializer)? {
+		BoolOrString::Bool(b) => b,
+		BoolOrString::String(s) => s.eq_ignore_ascii_case("true"),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_pkce_challenge() {
+		// The challenge is the unpadded base64url SHA-256 of the verifier
+		let verifier = "dBjftJeZ4CVP-mJ92K9mZ_3EW0xYRBxzkyBbGtA3fHQ";
+		let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+		assert_eq!(challenge, "8OJeuSN-A7nry3vUarDyll_MQVaxSb6NOfrdalwF4lQ");
+
+		let pkce = Pkce::generate();
+		assert_eq!(pkce.verifier.len(), 43);
+		assert_ne!(pkce.verifier, Pkce::generate().verifier);
+	}
+
+	#[test]
+	fn test_email_verified_as_string() {
+		let claims: IdTokenClaims =
+			serde_json::from_str(r#"{"sub": "1", "email_verified": "true"}"#).unwrap();
+		assert!(claims.email_verified);
+		let claims: IdTokenClaims = serde_json::from_str(r#"{"sub": "1"}"#).unwrap();
+		assert!(!claims.email_verified);
+	}
+}
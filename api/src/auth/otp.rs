@@ -0,0 +1,128 @@
+//! One-time login codes, which are sent by email or text message and exchanged for tokens. A user has at
+//! most one code at a time.
+
+use actix_web::{http, web::Json};
+use chrono::{Duration, Utc};
+use entity::login_codes;
+use log::error;
+use migration::DbErr;
+use rand::{thread_rng, Rng};
+use sea_orm::{sea_query::Expr, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, Set};
+use uuid::Uuid;
+
+use crate::{
+	auth::{api_error, util::hash_secret, ApiResponse},
+	TokenConfig,
+};
+
+pub enum RedeemError {
+	/// The code is wrong
+	Invalid,
+	/// The code has expired, has been guessed at too often, or was never sent
+	Expired,
+	Database(DbErr),
+}
+
+impl RedeemError {
+	/// `invalid_message` names what the code was sent to, which the user may have gotten wrong instead
+	pub fn response(&self, invalid_message: &str) -> (Json<ApiResponse>, http::StatusCode) {
+		match self {
+			RedeemError::Invalid => (
+				Json(api_error(invalid_message.to_string(), "INVALID_CODE".to_string())),
+				http::StatusCode::UNAUTHORIZED,
+			),
+			RedeemError::Expired => (
+				Json(api_error(
+					"The code has expired or was guessed too many times. Request a new one."
+						.to_string(),
+					"CODE_EXPIRED".to_string(),
+				)),
+				http::StatusCode::BAD_REQUEST,
+			),
+			RedeemError::Database(e) => {
+				error!("Unable to use login code. Error: {}", e.to_string());
+				(
+					Json(api_error(
+						"Internal Server Error".to_string(),
+						"INTERNAL_SERVER_ERROR".to_string(),
+					)),
+					http::StatusCode::INTERNAL_SERVER_ERROR,
+				)
+			}
+		}
+	}
+}
+
+impl From<DbErr> for RedeemError {
+	fn from(e: DbErr) -> Self {
+		RedeemError::Database(e)
+	}
+}
+
+/// The code is only valid along with where it was sent, so a code sent to one address or number can't be
+/// used to prove another one belongs to the user
+fn code_hash(to: &str, code: &str) -> String {
+	hash_secret(&format!("{to}:{code}"))
+}
+
+/// Creates a code for the user to send to `to`, which replaces any code sent before it
+pub async fn issue(
+	connection: &DatabaseConnection,
+	config: &TokenConfig,
+	uid: Uuid,
+	to: &str,
+) -> Result<String, DbErr> {
+	let code = format!("{:06}", thread_rng().gen_range(0..1_000_000));
+
+	login_codes::Entity::delete_by_id(uid)
+		.exec(connection)
+		.await?;
+	login_codes::Entity::insert(login_codes::ActiveModel {
+		uid: Set(uid),
+		code_hash: Set(code_hash(to, &code)),
+		attempts: Set(0),
+		expiry: Set(Utc::now().naive_utc() + Duration::seconds(config.login_code_lifetime)),
+	})
+	.exec(connection)
+	.await?;
+
+	Ok(code)
+}
+
+/// Uses up the user's code that was sent to `to`. Each code can be used once, and stops working after too
+/// many wrong guesses.
+pub async fn redeem(
+	connection: &DatabaseConnection,
+	config: &TokenConfig,
+	uid: Uuid,
+	to: &str,
+	code: &str,
+) -> Result<(), RedeemError> {
+	// The attempt is counted before the code is checked, so that guesses made at the same time cannot get
+	// past the limit
+	let now = Utc::now().naive_utc();
+	let counted = login_codes::Entity::update_many()
+		.col_expr(login_codes::Column::Attempts, Expr::col(login_codes::Column::Attempts).add(1))
+		.filter(login_codes::Column::Uid.eq(uid))
+		.filter(login_codes::Column::Expiry.gt(now))
+		.filter(login_codes::Column::Attempts.lt(config.login_code_max_attempts))
+		.exec(connection)
+		.await?;
+	if counted.rows_affected != 1 {
+		login_codes::Entity::delete_by_id(uid)
+			.exec(connection)
+			.await?;
+		return Err(RedeemError::Expired);
+	}
+
+	// The code is used up by deleting it, which only one request can do
+	let used = login_codes::Entity::delete_many()
+		.filter(login_codes::Column::Uid.eq(uid))
+		.filter(login_codes::Column::CodeHash.eq(code_hash(to, code.trim())))
+		.exec(connection)
+		.await?;
+	match used.rows_affected {
+		1 => Ok(()),
+		_ => Err(RedeemError::Invalid),
+	}
+}
@@ -0,0 +1,596 @@
+use crate::{
+	auth::{
+		action_tokens::{self, ActionKind, ConsumeError},
+		api_error,
+		claims::{self, PasskeyLoginClaims, PasskeyRegisterClaims, RegisteredClaims, TokenClaims},
+		util::{self, get_at_and_rt, HeaderResult, Session},
+		webauthn::{self, WebauthnError},
+		ApiResponse,
+	},
+	AppState,
+};
+use actix_web::{
+	delete, get, http, post,
+	web::{Data, Json, Path},
+	Either, HttpResponse, Responder,
+};
+use chrono::{Duration, Utc};
+use entity::{users, webauthn_credentials};
+use log::error;
+use migration::{DbErr, OnConflict};
+use sea_orm::{
+	prelude::DateTime, ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+/// How long the client has to complete a ceremony, in minutes
+const CEREMONY_TIMEOUT: i64 = 5;
+
+#[derive(Debug, Serialize)]
+pub struct PasskeyInfo {
+	pub id: String,
+	pub name: String,
+	pub created_at: DateTime,
+	pub last_used: Option<DateTime>,
+}
+
+impl From<webauthn_credentials::Model> for PasskeyInfo {
+	fn from(model: webauthn_credentials::Model) -> Self {
+		Self {
+			id: model.credential_id,
+			name: model.name,
+			created_at: model.created_at,
+			last_used: model.last_used,
+		}
+	}
+}
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+	#[serde(rename = "clientDataJSON")]
+	client_data_json: String,
+	#[serde(rename = "attestationObject")]
+	attestation_object: String,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationCredential {
+	id: String,
+	response: AttestationResponse,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterFinishBody {
+	state: String,
+	name: Option<String>,
+	credential: AttestationCredential,
+}
+
+#[derive(Deserialize)]
+pub struct LoginStartBody {
+	email: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+	#[serde(rename = "clientDataJSON")]
+	client_data_json: String,
+	#[serde(rename = "authenticatorData")]
+	authenticator_data: String,
+	signature: String,
+	#[serde(rename = "userHandle")]
+	user_handle: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionCredential {
+	id: String,
+	response: AssertionResponse,
+}
+
+#[derive(Deserialize)]
+pub struct LoginFinishBody {
+	state: String,
+	credential: AssertionCredential,
+}
+
+/// Returns the options for `navigator.credentials.create()`, and a state token for the finish step
+#[post("/api/auth/user/passkey/register/start")]
+pub async fn register_start_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+) -> impl Responder {
+	let header_map = request.headers();
+	let authorization = header_map.get("Authorization");
+
+	let uid = match util::verify_header(authorization, &data.key_ring) {
+		HeaderResult::Error(r, s) => return (r, s),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	let user = match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(user)) => user,
+		Ok(None) => {
+			return (
+				Json(api_error(
+					"The user was not found.".to_string(),
+					"USER_NOT_FOUND".to_string(),
+				)),
+				http::StatusCode::NOT_FOUND,
+			)
+		}
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+
+	// Stop the user from registering the same authenticator twice
+	let existing = match webauthn_credentials::Entity::find()
+		.filter(webauthn_credentials::Column::Uid.eq(uid))
+		.all(&data.connection)
+		.await
+	{
+		Ok(existing) => existing,
+		Err(e) => {
+			error!("Unable to find passkeys. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+
+	let challenge = webauthn::generate_challenge();
+	let state = PasskeyRegisterClaims {
+		registered: RegisteredClaims::new(
+			PasskeyRegisterClaims::AUDIENCE,
+			Duration::minutes(CEREMONY_TIMEOUT),
+		),
+		sub: uid,
+		challenge: challenge.clone(),
+	};
+	let state = claims::sign(&state, &data.config.secret_key);
+
+	let options = json!({
+		"challenge": challenge,
+		"rp": {
+			"id": data.config.webauthn.rp_id,
+			"name": data.config.webauthn.rp_name,
+		},
+		"user": {
+			"id": webauthn::encode_base64url(uid.as_bytes()),
+			"name": user.email,
+			"displayName": user.email,
+		},
+		"pubKeyCredParams": [
+			{ "type": "public-key", "alg": webauthn::ES256 },
+			{ "type": "public-key", "alg": webauthn::RS256 },
+		],
+		"timeout": CEREMONY_TIMEOUT * 60 * 1000,
+		"attestation": "none",
+		"authenticatorSelection": {
+			"residentKey": "preferred",
+			"userVerification": "preferred",
+		},
+		"excludeCredentials": existing
+			.iter()
+			.map(|c| json!({ "type": "public-key", "id": c.credential_id }))
+			.collect::<Vec<_>>(),
+	});
+
+	(
+		Json(ApiResponse::PasskeyOptionsResponse { options, state }),
+		http::StatusCode::OK,
+	)
+}
+
+/// Verifies the new credential and stores it
+#[post("/api/auth/user/passkey/register/finish")]
+pub async fn register_finish_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<RegisterFinishBody>,
+) -> impl Responder {
+	let header_map = request.headers();
+	let authorization = header_map.get("Authorization");
+
+	let uid = match util::verify_header(authorization, &data.key_ring) {
+		HeaderResult::Error(r, s) => return (r, s),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	let state = match claims::verify::<PasskeyRegisterClaims>(&body.state, &data.config.secret_key)
+	{
+		Ok(state) if state.sub == uid => state,
+		_ => return invalid_state(),
+	};
+
+	let (client_data_json, attestation_object) = match (
+		webauthn::decode_base64url(&body.credential.response.client_data_json),
+		webauthn::decode_base64url(&body.credential.response.attestation_object),
+	) {
+		(Some(c), Some(a)) => (c, a),
+		_ => return verification_failed(WebauthnError::InvalidClientData),
+	};
+
+	let credential = match webauthn::verify_registration(
+		&client_data_json,
+		&attestation_object,
+		&state.challenge,
+		&data.config.webauthn.rp_id,
+		&data.config.webauthn.origins,
+	) {
+		Ok(credential) => credential,
+		Err(e) => return verification_failed(e),
+	};
+
+	let credential_id = webauthn::encode_base64url(&credential.credential_id);
+	if credential_id != body.credential.id.trim_end_matches('=') {
+		return verification_failed(WebauthnError::InvalidAuthenticatorData);
+	}
+
+	let info = PasskeyInfo {
+		id: credential_id,
+		name: body.name.clone().unwrap_or("Passkey".to_string()),
+		created_at: Utc::now().naive_utc(),
+		last_used: None,
+	};
+
+	let res = webauthn_credentials::Entity::insert(webauthn_credentials::ActiveModel {
+		credential_id: Set(info.id.clone()),
+		uid: Set(uid),
+		public_key: Set(webauthn::encode_base64url(&credential.public_key)),
+		sign_count: Set(credential.sign_count as i64),
+		name: Set(info.name.clone()),
+		created_at: Set(info.created_at),
+		last_used: Set(None),
+	})
+	.on_conflict(
+		// A credential id can only be registered once, causing a DbErr::RecordNotInserted
+		OnConflict::column(webauthn_credentials::Column::CredentialId)
+			.do_nothing()
+			.to_owned(),
+	)
+	.exec(&data.connection)
+	.await;
+
+	match res {
+		Ok(_) => (Json(ApiResponse::PasskeyResponse(info)), http::StatusCode::CREATED),
+		Err(DbErr::RecordNotInserted) => (
+			Json(api_error(
+				"The passkey has already been registered.".to_string(),
+				"PASSKEY_EXISTS".to_string(),
+			)),
+			http::StatusCode::CONFLICT,
+		),
+		Err(e) => {
+			error!("Unable to store passkey. Error: {}", e.to_string());
+			internal_error()
+		}
+	}
+}
+
+/// Returns the options for `navigator.credentials.get()`. If an email is provided, the user's
+/// passkeys are listed, otherwise the authenticator must offer a discoverable credential.
+#[post("/api/auth/user/passkey/login/start")]
+pub async fn login_start_handler(
+	data: Data<AppState>,
+	body: Json<LoginStartBody>,
+) -> impl Responder {
+	let mut allow_credentials = vec![];
+
+	if let Some(email) = &body.email {
+		let user = match users::Entity::find()
+			.filter(users::Column::Email.eq(email))
+			.one(&data.connection)
+			.await
+		{
+			Ok(user) => user,
+			Err(e) => {
+				error!("Unable to find user. Error: {}", e.to_string());
+				return internal_error();
+			}
+		};
+		if let Some(user) = user {
+			match webauthn_credentials::Entity::find()
+				.filter(webauthn_credentials::Column::Uid.eq(user.uid))
+				.all(&data.connection)
+				.await
+			{
+				Ok(credentials) => {
+					allow_credentials = credentials
+						.iter()
+						.map(|c| json!({ "type": "public-key", "id": c.credential_id }))
+						.collect();
+				}
+				Err(e) => {
+					error!("Unable to find passkeys. Error: {}", e.to_string());
+					return internal_error();
+				}
+			}
+		}
+	}
+
+	let challenge = webauthn::generate_challenge();
+	let state = PasskeyLoginClaims {
+		registered: RegisteredClaims::new(
+			PasskeyLoginClaims::AUDIENCE,
+			Duration::minutes(CEREMONY_TIMEOUT),
+		),
+		challenge: challenge.clone(),
+	};
+	// Authenticators may not count their signatures, so the state is what stops an assertion being replayed
+	if let Err(e) = action_tokens::record(
+		&data.connection,
+		Uuid::nil(),
+		ActionKind::PasskeyLogin,
+		&state.registered,
+	)
+	.await
+	{
+		error!("Unable to save passkey login state. Error: {}", e.to_string());
+		return internal_error();
+	}
+	let state = claims::sign(&state, &data.config.secret_key);
+
+	let options = json!({
+		"challenge": challenge,
+		"rpId": data.config.webauthn.rp_id,
+		"timeout": CEREMONY_TIMEOUT * 60 * 1000,
+		"userVerification": "preferred",
+		"allowCredentials": allow_credentials,
+	});
+
+	(
+		Json(ApiResponse::PasskeyOptionsResponse { options, state }),
+		http::StatusCode::OK,
+	)
+}
+
+/// Verifies the assertion and logs the user in
+#[post("/api/auth/user/passkey/login/finish")]
+pub async fn login_finish_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<LoginFinishBody>,
+) -> impl Responder {
+	let state: PasskeyLoginClaims = match claims::verify(&body.state, &data.config.secret_key) {
+		Ok(state) => state,
+		Err(_) => return invalid_state(),
+	};
+
+	let credential = match webauthn_credentials::Entity::find_by_id(
+		body.credential.id.trim_end_matches('=').to_string(),
+	)
+	.one(&data.connection)
+	.await
+	{
+		Ok(Some(credential)) => credential,
+		Ok(None) => {
+			return (
+				Json(api_error(
+					"The passkey is not registered.".to_string(),
+					"PASSKEY_NOT_FOUND".to_string(),
+				)),
+				http::StatusCode::UNAUTHORIZED,
+			)
+		}
+		Err(e) => {
+			error!("Unable to find passkey. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+
+	let response = &body.credential.response;
+	let decoded = (
+		webauthn::decode_base64url(&response.client_data_json),
+		webauthn::decode_base64url(&response.authenticator_data),
+		webauthn::decode_base64url(&response.signature),
+		webauthn::decode_base64url(&credential.public_key),
+	);
+	let (client_data_json, authenticator_data, signature, public_key) = match decoded {
+		(Some(c), Some(a), Some(s), Some(p)) => (c, a, s, p),
+		_ => return login_failed(WebauthnError::InvalidClientData),
+	};
+
+	// The user handle, when present, must belong to the owner of the credential
+	if let Some(user_handle) = &response.user_handle {
+		if webauthn::decode_base64url(user_handle).as_deref() != Some(credential.uid.as_bytes()) {
+			return login_failed(WebauthnError::InvalidAuthenticatorData);
+		}
+	}
+
+	let sign_count = match webauthn::verify_assertion(
+		&client_data_json,
+		&authenticator_data,
+		&signature,
+		&public_key,
+		credential.sign_count as u32,
+		&state.challenge,
+		&data.config.webauthn.rp_id,
+		&data.config.webauthn.origins,
+	) {
+		Ok(sign_count) => sign_count,
+		Err(e) => return login_failed(e),
+	};
+
+	// Each state logs in once
+	match action_tokens::consume(
+		&data.connection,
+		Uuid::nil(),
+		ActionKind::PasskeyLogin,
+		&state.registered.jti,
+	)
+	.await
+	{
+		Ok(()) => (),
+		Err(ConsumeError::Database(e)) => {
+			error!("Unable to use passkey login state. Error: {}", e.to_string());
+			return internal_error();
+		}
+		Err(e) => {
+			return (
+				Json(api_error(e.message().to_string(), e.error_code().to_string())),
+				http::StatusCode::BAD_REQUEST,
+			)
+		}
+	}
+
+	let user = match users::Entity::find_by_id(credential.uid)
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(user)) => user,
+		Ok(None) => {
+			return (
+				Json(api_error(
+					"The user was not found.".to_string(),
+					"USER_NOT_FOUND".to_string(),
+				)),
+				http::StatusCode::NOT_FOUND,
+			)
+		}
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+	if !user.active {
+		return (
+			Json(api_error(
+				"The user has been disabled by an administrator.".to_string(),
+				"USER_DISABLED".to_string(),
+			)),
+			http::StatusCode::UNAUTHORIZED,
+		);
+	}
+
+	let mut credential: webauthn_credentials::ActiveModel = credential.into();
+	credential.sign_count = Set(sign_count as i64);
+	credential.last_used = Set(Some(Utc::now().naive_utc()));
+	if let Err(e) = credential.update(&data.connection).await {
+		error!("Unable to update passkey. Error: {}", e.to_string());
+		return internal_error();
+	}
+
+	let uid_str = &user.uid.to_string();
+	let (at, rt, exp) = get_at_and_rt(
+		&data.connection,
+		uid_str,
+		&data.key_ring,
+		&data.config.tokens,
+		false,
+		Session::new(&request, &data.ua_parser),
+	)
+	.await;
+	(
+		Json(ApiResponse::LoginResponse {
+			uid: uid_str.to_string(),
+			token: at,
+			expiry: exp,
+			refresh_token: rt,
+			email_verified: user.email_verified,
+			metadata: user.metadata.clone().unwrap_or("".to_string()),
+		}),
+		http::StatusCode::OK,
+	)
+}
+
+#[get("/api/auth/user/passkeys")]
+pub async fn list_handler(request: actix_web::HttpRequest, data: Data<AppState>) -> impl Responder {
+	let header_map = request.headers();
+	let authorization = header_map.get("Authorization");
+
+	let uid = match util::verify_header(authorization, &data.key_ring) {
+		HeaderResult::Error(r, s) => return (r, s),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	match webauthn_credentials::Entity::find()
+		.filter(webauthn_credentials::Column::Uid.eq(uid))
+		.order_by_asc(webauthn_credentials::Column::CreatedAt)
+		.all(&data.connection)
+		.await
+	{
+		Ok(credentials) => (
+			Json(ApiResponse::PasskeyListResponse {
+				passkeys: credentials.into_iter().map(PasskeyInfo::from).collect(),
+			}),
+			http::StatusCode::OK,
+		),
+		Err(e) => {
+			error!("Unable to find passkeys. Error: {}", e.to_string());
+			internal_error()
+		}
+	}
+}
+
+#[delete("/api/auth/user/passkeys/{id}")]
+pub async fn delete_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	path: Path<String>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let header_map = request.headers();
+	let authorization = header_map.get("Authorization");
+
+	let uid = match util::verify_header(authorization, &data.key_ring) {
+		HeaderResult::Error(r, s) => return Either::Left((r, s)),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	// Filtering on uid makes sure users can only delete their own passkeys
+	match webauthn_credentials::Entity::delete_many()
+		.filter(webauthn_credentials::Column::CredentialId.eq(path.into_inner()))
+		.filter(webauthn_credentials::Column::Uid.eq(uid))
+		.exec(&data.connection)
+		.await
+	{
+		Ok(res) if res.rows_affected == 0 => Either::Left((
+			Json(api_error(
+				"The passkey was not found.".to_string(),
+				"PASSKEY_NOT_FOUND".to_string(),
+			)),
+			http::StatusCode::NOT_FOUND,
+		)),
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => {
+			error!("Unable to delete passkey. Error: {}", e.to_string());
+			Either::Left(internal_error())
+		}
+	}
+}
+
+fn invalid_state() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The provided state is invalid or has expired.".to_string(),
+			"INVALID_TOKEN".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn verification_failed(e: WebauthnError) -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(e.message().to_string(), "WEBAUTHN_VERIFICATION_FAILED".to_string())),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn login_failed(e: WebauthnError) -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(e.message().to_string(), "WEBAUTHN_VERIFICATION_FAILED".to_string())),
+		http::StatusCode::UNAUTHORIZED,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
@@ -0,0 +1,251 @@
+//! The rules new passwords must follow. Every rule that a password breaks is reported, so that users can
+//! fix them all at once.
+
+use actix_web::{
+	http::{self, StatusCode},
+	web::Json,
+	HttpRequest,
+};
+use chrono::{Duration, Utc};
+use entity::{password_history, users};
+use log::error;
+use migration::DbErr;
+use sea_orm::{
+	ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect, Set,
+};
+use serde::Serialize;
+use uuid::Uuid;
+use zxcvbn::zxcvbn;
+
+use crate::{
+	auth::{
+		api_error, hashing,
+		security_events::{self, SecurityEvent},
+		ApiResponse,
+	},
+	AppState, BreachedPasswordAction, Config, PasswordPolicyConfig,
+};
+
+/// A rule that a password breaks
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+	pub code: &'static str,
+	pub message: String,
+}
+
+impl Violation {
+	fn new(code: &'static str, message: String) -> Self {
+		Self { code, message }
+	}
+}
+
+fn rejected(violations: Vec<Violation>) -> (Json<ApiResponse>, StatusCode) {
+	let message = match violations.as_slice() {
+		[violation] => violation.message.to_owned(),
+		_ => format!("The password provided breaks {} password rules.", violations.len()),
+	};
+	(
+		Json(ApiResponse::PasswordPolicyError {
+			message,
+			error_code: "INVALID_PASSWORD".to_string(),
+			violations,
+		}),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+/// Checks a new password. The email makes passwords based on it weaker, and the password may not be one
+/// of the user's latest ones. Returns whether the password was breached, for deployments that only warn
+/// about breached passwords.
+pub async fn check(
+	data: &AppState,
+	password: &str,
+	email: &str,
+	user: Option<&users::Model>,
+) -> Result<bool, (Json<ApiResponse>, StatusCode)> {
+	let policy = &data.config.password_policy;
+	let mut violations = Vec::new();
+
+	let length = password.chars().count();
+	if length < policy.min_length {
+		violations.push(Violation::new(
+			"PASSWORD_TOO_SHORT",
+			format!("The password must be at least {} characters long.", policy.min_length),
+		));
+	}
+	if length > policy.max_length {
+		violations.push(Violation::new(
+			"PASSWORD_TOO_LONG",
+			format!("The password must be at most {} characters long.", policy.max_length),
+		));
+		// The other checks are slow for long passwords
+		return Err(rejected(violations));
+	}
+
+	if let Some(message) = weakness(password, email, data.config.minimum_password_strength) {
+		violations.push(Violation::new("WEAK_PASSWORD", message));
+	}
+
+	if let Some(user) = user {
+		match is_reused(&data.connection, &data.config, user, password).await {
+			Ok(true) => violations.push(Violation::new(
+				"PASSWORD_REUSED",
+				"The password was used recently. Please choose another one.".to_string(),
+			)),
+			Ok(false) => (),
+			Err(e) => {
+				error!("Unable to check password history. Error: {}", e.to_string());
+				return Err((
+					Json(api_error(
+						"An internal server error occurred.".to_string(),
+						"INTERNAL_SERVER_ERROR".to_string(),
+					)),
+					http::StatusCode::INTERNAL_SERVER_ERROR,
+				));
+			}
+		}
+	}
+
+	// Passwords are accepted when the check cannot be made
+	let mut breached = false;
+	if let Some(breached_passwords) = &data.breached_passwords {
+		match breached_passwords.is_breached(password).await {
+			Ok(true) if breached_passwords.action == BreachedPasswordAction::Reject => {
+				violations.push(Violation::new(
+					"BREACHED_PASSWORD",
+					"The password provided has appeared in a data breach. Please choose another one."
+						.to_string(),
+				))
+			}
+			Ok(found) => breached = found,
+			Err(e) => log::warn!("Unable to check for a breached password. {e}"),
+		}
+	}
+
+	if violations.is_empty() {
+		Ok(breached)
+	} else {
+		Err(rejected(violations))
+	}
+}
+
+/// Scores the password with zxcvbn, which also penalizes passwords based on the email
+fn weakness(password: &str, email: &str, minimum_strength: u8) -> Option<String> {
+	let mut user_inputs = vec![email];
+	if let Some((name, _)) = email.split_once('@') {
+		user_inputs.push(name);
+	}
+
+	let estimate = match zxcvbn(password, &user_inputs) {
+		Ok(estimate) => estimate,
+		Err(_) => return Some("The password provided is too weak.".to_string()),
+	};
+	if estimate.score() >= minimum_strength {
+		return None;
+	}
+	match estimate
+		.feedback()
+		.as_ref()
+		.and_then(|feedback| feedback.warning())
+	{
+		Some(warning) => Some(format!("The password provided is too weak. {warning}")),
+		None => Some("The password provided is too weak.".to_string()),
+	}
+}
+
+/// Whether the password is the user's current one, or one of the ones before it that the policy keeps.
+/// An expired password always counts, so that it is really changed.
+async fn is_reused<C: ConnectionTrait>(
+	connection: &C,
+	config: &Config,
+	user: &users::Model,
+	password: &str,
+) -> Result<bool, DbErr> {
+	let policy = &config.password_policy;
+	let history = match is_expired(policy, user) {
+		true => policy.history.max(1),
+		false => policy.history,
+	};
+	if history == 0 {
+		return Ok(false);
+	}
+
+	let previous = password_history::Entity::find()
+		.filter(password_history::Column::Uid.eq(user.uid))
+		.order_by_desc(password_history::Column::CreatedAt)
+		.limit(u64::from(history - 1))
+		.all(connection)
+		.await?;
+
+	// Users without a password have a hash that never verifies
+	Ok(std::iter::once(&user.password)
+		.chain(previous.iter().map(|entry| &entry.password))
+		.any(|hash| hashing::verify(hash, password, config)))
+}
+
+/// Keeps the password the user is replacing, and forgets the ones the policy no longer needs
+pub async fn record_change<C: ConnectionTrait>(
+	connection: &C,
+	policy: &PasswordPolicyConfig,
+	user: &users::Model,
+) -> Result<(), DbErr> {
+	// The current password is always checked, so only older ones are kept
+	let keep = u64::from(policy.history.saturating_sub(1));
+	if keep > 0 {
+		password_history::Entity::insert(password_history::ActiveModel {
+			id: Set(Uuid::new_v4()),
+			uid: Set(user.uid),
+			password: Set(user.password.to_owned()),
+			created_at: Set(Utc::now().naive_utc()),
+		})
+		.exec(connection)
+		.await?;
+	}
+
+	// SQLite does not allow an offset without a limit, so the kept entries are skipped here
+	let forgotten: Vec<Uuid> = password_history::Entity::find()
+		.select_only()
+		.column(password_history::Column::Id)
+		.filter(password_history::Column::Uid.eq(user.uid))
+		.order_by_desc(password_history::Column::CreatedAt)
+		.into_tuple::<Uuid>()
+		.all(connection)
+		.await?
+		.into_iter()
+		.skip(keep as usize)
+		.collect();
+	if !forgotten.is_empty() {
+		password_history::Entity::delete_many()
+			.filter(password_history::Column::Id.is_in(forgotten))
+			.exec(connection)
+			.await?;
+	}
+	Ok(())
+}
+
+/// Whether the user's password is older than the policy allows. Users from before password changes were
+/// recorded are treated as having set their password when they signed up.
+pub fn is_expired(policy: &PasswordPolicyConfig, user: &users::Model) -> bool {
+	match policy.max_age_days {
+		Some(days) => {
+			let changed_at = user.password_changed_at.unwrap_or(user.created_at);
+			changed_at + Duration::days(days) <= Utc::now().naive_utc()
+		}
+		None => false,
+	}
+}
+
+/// Records that the user chose a breached password, for deployments that only warn about them
+pub async fn record_breached(data: &AppState, uid: Uuid, request: &HttpRequest) {
+	let recorded = security_events::record(
+		&data.connection,
+		uid,
+		SecurityEvent::BreachedPassword,
+		None,
+		request,
+	)
+	.await;
+	if let Err(e) = recorded {
+		error!("Unable to record breached password. Error: {}", e.to_string());
+	}
+}
@@ -0,0 +1,411 @@
+//! Sign-up and login with a phone number. A code is sent by text message through `AppState::sms`, and
+//! exchanged for tokens like an emailed login code. Users who log in another way can link a number the
+//! same way.
+
+use crate::{
+	auth::{
+		api_error,
+		identities::{self, LinkError, PHONE},
+		mfa, otp,
+		util::{get_at_and_rt, Session},
+		ApiResponse,
+	},
+	AppState,
+};
+use actix_web::{
+	http, post,
+	web::{Data, Json},
+	Either, HttpResponse,
+};
+use chrono::{NaiveDateTime, Utc};
+use entity::users;
+use log::error;
+use migration::DbErr;
+use sea_orm::{
+	sea_query::Expr, ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set,
+	TransactionTrait,
+};
+use sms::SmsSender;
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct PhoneBody {
+	pub phone: String,
+	pub sign_up: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct LinkPhoneBody {
+	pub phone: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct VerifyLinkBody {
+	pub phone: String,
+	pub code: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct VerifyPhoneBody {
+	pub phone: String,
+	pub code: String,
+	remember_me: Option<bool>,
+}
+
+/// Converts a number such as `+1 (555) 555-0100` to E.164, which is how numbers are stored and sent.
+/// Numbers must include the country code.
+pub fn normalize(phone: &str) -> Option<String> {
+	let phone: String = phone
+		.chars()
+		.filter(|c| !matches!(c, ' ' | '-' | '(' | ')' | '.'))
+		.collect();
+	match crate::PHONE_REGEX.is_match(&phone) {
+		true => Some(phone),
+		false => None,
+	}
+}
+
+/// For requests that need the user's email, which users who signed up with a phone number may not have
+pub fn no_email() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The user has no email. Add one by updating the user.".to_string(),
+			"NO_EMAIL".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+/// Texts a login code to the number, creating a user for it when `sign_up` is set
+#[post("/api/auth/user/phone")]
+pub async fn send_handler(
+	data: Data<AppState>,
+	body: Json<PhoneBody>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let sender = match &data.sms {
+		Some(sender) => sender,
+		None => return Either::Left(sms_not_configured()),
+	};
+	let phone = match normalize(&body.phone) {
+		Some(phone) => phone,
+		None => return Either::Left(invalid_phone()),
+	};
+
+	let user = match identities::find_user(&data.connection, &[PHONE], &phone).await {
+		Ok(Some(user)) => {
+			if body.sign_up {
+				return Either::Left(user_already_exists());
+			}
+			user
+		}
+		Ok(None) => {
+			if !body.sign_up {
+				return Either::Left((
+					Json(api_error(
+						"The user does not exist.".to_string(),
+						"USER_DOES_NOT_EXIST".to_string(),
+					)),
+					http::StatusCode::BAD_REQUEST,
+				));
+			}
+			match sign_up(&data, &phone).await {
+				Ok(Some(user)) => user,
+				Ok(None) => return Either::Left(user_already_exists()),
+				Err(e) => {
+					error!("Unable to create user. Error: {}", e.to_string());
+					return Either::Left(internal_error());
+				}
+			}
+		}
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	};
+
+	match send_code(&data, sender, user.uid, &phone).await {
+		Ok(()) => Either::Right(HttpResponse::Ok().finish()),
+		Err(response) => Either::Left(response),
+	}
+}
+
+/// Creates a user who logs in with the number. Returns `None` if another user has it.
+async fn sign_up(data: &AppState, phone: &str) -> Result<Option<users::Model>, DbErr> {
+	let uid = Uuid::new_v4();
+	let now = NaiveDateTime::from_timestamp_opt(Utc::now().timestamp(), 0).unwrap();
+	let txn = data.connection.begin().await?;
+	let user = users::ActiveModel {
+		uid: Set(uid),
+		password: Set("0".to_string()), // Not a valid hash, so password login is impossible
+		email: Set(None), // Until they add one
+		created_at: Set(now),
+		updated_at: Set(now),
+		active: Set(true),
+		email_verified: Set(false),
+		phone: Set(Some(phone.to_owned())),
+		phone_verified: Set(false),
+		..Default::default()
+	}
+	.insert(&txn)
+	.await?;
+	match identities::link(&txn, uid, PHONE, phone, None).await {
+		Ok(()) => (),
+		Err(LinkError::InUse) => return Ok(None),
+		Err(LinkError::Database(e)) => return Err(e),
+	}
+	txn.commit().await?;
+	Ok(Some(user))
+}
+
+/// Exchanges a texted login code for tokens, which also verifies the phone number
+#[post("/api/auth/user/phone/verify")]
+pub async fn verify_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<VerifyPhoneBody>,
+) -> (Json<ApiResponse>, http::StatusCode) {
+	let invalid_message = "The phone number or code is invalid.";
+	let phone = match normalize(&body.phone) {
+		Some(phone) => phone,
+		None => return otp::RedeemError::Invalid.response(invalid_message),
+	};
+
+	let user = match identities::find_user(&data.connection, &[PHONE], &phone).await {
+		Ok(Some(user)) => user,
+		Ok(None) => return otp::RedeemError::Invalid.response(invalid_message),
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+
+	if let Err(e) =
+		otp::redeem(&data.connection, &data.config.tokens, user.uid, &phone, &body.code).await
+	{
+		return e.response(invalid_message);
+	}
+
+	if !user.active {
+		return (
+			Json(api_error(
+				"The user has been disabled by an administrator.".to_string(),
+				"USER_DISABLED".to_string(),
+			)),
+			http::StatusCode::UNAUTHORIZED,
+		);
+	}
+
+	if !user.phone_verified {
+		let mut active_user: users::ActiveModel = user.clone().into();
+		active_user.phone_verified = Set(true);
+		if let Err(e) = active_user.update(&data.connection).await {
+			error!("Unable to verify phone number. Error: {}", e.to_string());
+			return internal_error();
+		}
+	}
+
+	let uid_str = user.uid.to_string();
+	let remember_me = body.remember_me.unwrap_or(true);
+
+	// Users with MFA enabled must complete a second step before receiving tokens
+	match mfa::is_enabled(&data.connection, user.uid).await {
+		Ok(true) => {
+			let (mfa_token, expiry) =
+				mfa::create_challenge_token(user.uid, remember_me, &data.config.secret_key);
+			return (
+				Json(ApiResponse::MfaChallengeResponse {
+					uid: uid_str,
+					mfa_required: true,
+					mfa_token,
+					expiry,
+				}),
+				http::StatusCode::OK,
+			);
+		}
+		Ok(false) => (),
+		Err(e) => {
+			error!("An error occurred when checking MFA status. Error: {}", e.to_string());
+			return internal_error();
+		}
+	}
+
+	let (at, rt, exp) = get_at_and_rt(
+		&data.connection,
+		&uid_str,
+		&data.key_ring,
+		&data.config.tokens,
+		false,
+		Session::New {
+			request: &request,
+			ua_parser: &data.ua_parser,
+			remember_me,
+		},
+	)
+	.await;
+	(
+		Json(ApiResponse::LoginResponse {
+			uid: uid_str,
+			token: at,
+			expiry: exp,
+			refresh_token: rt,
+			email_verified: user.email_verified,
+			metadata: user.metadata.unwrap_or_default(),
+		}),
+		http::StatusCode::OK,
+	)
+}
+
+/// Texts a code to the number, which the user sends back to link it. See `identities` for why the user
+/// must have logged in recently.
+#[post("/api/auth/user/phone/link")]
+pub async fn link_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<LinkPhoneBody>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let sender = match &data.sms {
+		Some(sender) => sender,
+		None => return Either::Left(sms_not_configured()),
+	};
+	let user = match identities::reauthenticate(&request, &data).await {
+		Ok(user) => user,
+		Err(response) => return Either::Left(response),
+	};
+	let phone = match normalize(&body.phone) {
+		Some(phone) => phone,
+		None => return Either::Left(invalid_phone()),
+	};
+
+	match identities::find(&data.connection, &[PHONE], &phone).await {
+		Ok(None) => (),
+		Ok(Some(identity)) if identity.uid == user.uid => {
+			return Either::Left((
+				Json(api_error(
+					"The phone number is already linked to the user.".to_string(),
+					"IDENTITY_ALREADY_LINKED".to_string(),
+				)),
+				http::StatusCode::CONFLICT,
+			))
+		}
+		Ok(Some(_)) => return Either::Left(LinkError::InUse.response()),
+		Err(e) => {
+			error!("Unable to find identity. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	}
+
+	match send_code(&data, sender, user.uid, &phone).await {
+		Ok(()) => Either::Right(HttpResponse::Ok().finish()),
+		Err(response) => Either::Left(response),
+	}
+}
+
+/// Links the number once the user sends back the code it was texted. A user has one phone number, so it
+/// replaces any number they had.
+#[post("/api/auth/user/phone/link/verify")]
+pub async fn link_verify_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<VerifyLinkBody>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let user = match identities::reauthenticate(&request, &data).await {
+		Ok(user) => user,
+		Err(response) => return Either::Left(response),
+	};
+	let invalid_message = "The phone number or code is invalid.";
+	let phone = match normalize(&body.phone) {
+		Some(phone) => phone,
+		None => return Either::Left(otp::RedeemError::Invalid.response(invalid_message)),
+	};
+
+	if let Err(e) =
+		otp::redeem(&data.connection, &data.config.tokens, user.uid, &phone, &body.code).await
+	{
+		return Either::Left(e.response(invalid_message));
+	}
+
+	let linked = async {
+		let txn = data.connection.begin().await?;
+		identities::unlink_provider(&txn, user.uid, PHONE).await?;
+		identities::link(&txn, user.uid, PHONE, &phone, None).await?;
+		users::Entity::update_many()
+			.col_expr(users::Column::Phone, Expr::value(phone.to_owned()))
+			.col_expr(users::Column::PhoneVerified, Expr::value(true))
+			.col_expr(users::Column::UpdatedAt, Expr::value(Utc::now().naive_utc()))
+			.filter(users::Column::Uid.eq(user.uid))
+			.exec(&txn)
+			.await?;
+		txn.commit().await?;
+		Ok::<(), LinkError>(())
+	};
+	match linked.await {
+		Ok(()) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => Either::Left(e.response()),
+	}
+}
+
+fn sms_not_configured() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The server is not configured to send text messages.".to_string(),
+			"SMS_NOT_CONFIGURED".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+/// Texts the user a code for the number, which replaces any code sent before it
+async fn send_code(
+	data: &AppState,
+	sender: &Arc<dyn SmsSender>,
+	uid: Uuid,
+	phone: &str,
+) -> Result<(), (Json<ApiResponse>, http::StatusCode)> {
+	let code = match otp::issue(&data.connection, &data.config.tokens, uid, phone).await {
+		Ok(code) => code,
+		Err(e) => {
+			error!("Unable to store login code. Error: {}", e.to_string());
+			return Err(internal_error());
+		}
+	};
+
+	if let Err(e) = sms::otp::send(sender.as_ref(), phone, &code).await {
+		error!("Unable to send login code. Error: {}", e.to_string());
+		return Err((
+			Json(api_error(
+				"The text message could not be sent.".to_string(),
+				"SMS_NOT_SENT".to_string(),
+			)),
+			http::StatusCode::BAD_GATEWAY,
+		));
+	}
+	Ok(())
+}
+
+fn invalid_phone() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The phone number must include the country code, such as +15555550100.".to_string(),
+			"INVALID_PHONE".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn user_already_exists() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error("The user already exists.".to_string(), "USER_ALREADY_EXISTS".to_string())),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"Internal Server Error".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
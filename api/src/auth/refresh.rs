@@ -1,19 +1,27 @@
-use std::{collections::BTreeMap, str::FromStr};
+//! Exchanges a refresh token for a new pair of tokens.
+//!
+//! The refresh tokens of a session form a family: each one can be exchanged once, for the next. If a
+//! token is used twice, one of the two users is likely an attacker, so the whole family is revoked.
 
 use crate::{
-	auth::{api_error, util::get_at_and_rt, ApiResponse},
+	auth::{
+		api_error,
+		claims::{self, RefreshClaims, TokenError},
+		security_events::{self, SecurityEvent},
+		sessions::end_sessions,
+		util::{get_at_and_rt, hash_secret, Session},
+		ApiResponse,
+	},
 	AppState,
 };
 use actix_web::{
 	http, post,
 	web::{Data, Json},
-	Responder,
+	HttpRequest, Responder,
 };
-use chrono::Utc;
-use entity::refresh_tokens::{self, ActiveModel};
-use jwt::VerifyWithKey;
+use entity::{refresh_tokens, sessions};
 use log::error;
-use sea_orm::{ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, Set};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 use serde::Deserialize;
 use uuid::Uuid;
 
@@ -23,115 +31,145 @@ pub struct RefreshBody {
 }
 
 #[post("/api/auth/user/refresh")]
-pub async fn handler(data: Data<AppState>, body: Json<RefreshBody>) -> impl Responder {
+pub async fn handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+	body: Json<RefreshBody>,
+) -> impl Responder {
 	// Try to verify JWT
-	let claims: BTreeMap<String, String> =
-		match body.refresh_token.verify_with_key(&data.config.secret_key) {
-			Ok(c) => c,
-			Err(_) => {
-				return (
-					Json(api_error(
-						"The JWT provided is invalid".to_string(),
-						"INVALID_JWT".to_string(),
-					)),
-					http::StatusCode::UNAUTHORIZED,
-				);
-			}
-		};
-
-	// Check if RT is expired
-	if Utc::now().timestamp() > claims["get"].parse().unwrap() {
-		return (
-			Json(api_error(
-				"The provided token has already expired".to_string(),
-				"EXPIRED_JWT".to_string(),
-			)),
-			http::StatusCode::UNAUTHORIZED,
-		);
-	}
+	let claims: RefreshClaims = match claims::verify(&body.refresh_token, &data.key_ring) {
+		Ok(c) => c,
+		Err(TokenError::Expired) => {
+			return (
+				Json(api_error(
+					"The provided token has already expired".to_string(),
+					"EXPIRED_JWT".to_string(),
+				)),
+				http::StatusCode::UNAUTHORIZED,
+			);
+		}
+		Err(_) => {
+			return (
+				Json(api_error(
+					"The JWT provided is invalid".to_string(),
+					"INVALID_JWT".to_string(),
+				)),
+				http::StatusCode::UNAUTHORIZED,
+			);
+		}
+	};
 
-	// Check if th token is indeed an RT
-	if claims["type"] != "rt" {
-		return (
-			Json(api_error("The JWT provided is invalid".to_string(), "INVALID_JWT".to_string())),
-			http::StatusCode::UNAUTHORIZED,
-		);
-	}
+	let uid = claims.sub;
+	let admin = claims.role.as_deref() == Some("admin");
 
-	// Look for RT in DB
-	let res = refresh_tokens::Entity::find()
-		.filter(refresh_tokens::Column::RefreshToken.eq(&body.refresh_token))
+	// Ending a session deletes its tokens, so they can't be refreshed. The session's idle timeout and
+	// maximum lifetime are enforced by the expiry of its refresh tokens.
+	let session = match sessions::Entity::find_by_id(claims.sid)
 		.one(&data.connection)
-		.await;
+		.await
+	{
+		Ok(Some(session)) if session.uid == uid => session,
+		Ok(_) => return expired(),
+		Err(e) => {
+			error!("Unable to find session. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
 
-	let uid = claims["uid"].to_string();
+	// Refresh tokens are stored as hashes, so a leaked database can't be used to refresh
+	let token_hash = hash_secret(&body.refresh_token);
+	match refresh_tokens::Entity::find_by_id(token_hash.clone())
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(rt)) if rt.session_id == session.id => (),
+		Ok(Some(_)) => {
+			return (
+				Json(api_error(
+					"The JWT provided is invalid".to_string(),
+					"INVALID_JWT".to_string(),
+				)),
+				http::StatusCode::UNAUTHORIZED,
+			);
+		}
+		Ok(None) => {
+			// A validly signed token that is missing from a live session should never happen, so treat it
+			// like a reused token
+			revoke_family(&data, uid, session.id, &request).await;
+			return expired();
+		}
+		Err(e) => {
+			error!("Database error: {}", e.to_string());
+			return internal_error();
+		}
+	}
 
-	match res {
-		Err(err) => match err {
-			sea_orm::error::DbErr::RecordNotFound(_) => {
-				let uid = Uuid::from_str(&uid).unwrap();
-				// RT is not in DB, likely very old, all RTs should be revoked
-				refresh_tokens::Entity::delete_many()
-					.filter(refresh_tokens::Column::Uid.eq(uid))
-					.exec(&data.connection)
-					.await
-					.unwrap();
-			}
-			_ => {
-				error!("Database error: {}", err.to_string());
-				return (
-					Json(api_error(
-						"An internal server error occurred".to_string(),
-						"INTERNAL_SERVER_ERROR".to_string(),
-					)),
-					http::StatusCode::INTERNAL_SERVER_ERROR,
-				);
-			}
-		},
-		Ok(rt_model) => {
-			match rt_model {
-				Some(rt) => {
-					if rt.used {
-						// Again, revoke all RTs, reuse of RT is not allowed
-						delete_old_rt(&uid, &data.connection).await;
-						return (
-							Json(api_error(
-								"The provided token has already expired.".to_string(),
-								"EXPIRED_JWT".to_string(),
-							)),
-							http::StatusCode::UNAUTHORIZED,
-						);
-					}
+	// Mark the token as used, unless another request already has. Doing both in one statement means two
+	// concurrent requests with the same token can't both be issued new tokens.
+	let marked = refresh_tokens::Entity::update_many()
+		.col_expr(refresh_tokens::Column::Used, true.into())
+		.filter(refresh_tokens::Column::TokenHash.eq(token_hash))
+		.filter(refresh_tokens::Column::Used.eq(false))
+		.exec(&data.connection)
+		.await;
+	match marked {
+		Ok(res) if res.rows_affected == 1 => (),
+		Ok(_) => {
+			// The token was already exchanged, so either the client or an attacker holds a copy of it.
+			// Revoke the whole family, since we can't tell which of them holds the newer tokens.
+			revoke_family(&data, uid, session.id, &request).await;
+			return expired();
+		}
+		Err(e) => {
+			error!("Failed to mark refresh token as used. Error: {}", e.to_string());
+			return internal_error();
+		}
+	}
 
-					// First, mark the old token as used
-					let mut rt_update: ActiveModel = rt.into();
-					rt_update.used = Set(true);
-					match rt_update.update(&data.connection).await {
-						Ok(_) => (),
-						Err(_) => log::error!("Failed to mark refresh token as used"),
-					}
+	// Here is the only time we issue a new token
+	let uid = uid.to_string();
+	let (access_token, refresh_token, expiry) = get_at_and_rt(
+		&data.connection,
+		&uid,
+		&data.key_ring,
+		&data.config.tokens,
+		admin,
+		Session::Refresh(&session, &request),
+	)
+	.await;
 
-					// Here is the only time we issue a new token
-					let (access_token, refresh_token, expiry) =
-						get_at_and_rt(&data.connection, &uid, &data.config.secret_key, false).await;
+	(
+		Json(ApiResponse::RefreshResponse {
+			uid,
+			access_token,
+			refresh_token,
+			expiry,
+		}),
+		http::StatusCode::OK,
+	)
+}
 
-					return (
-						Json(ApiResponse::RefreshResponse {
-							uid,
-							access_token,
-							refresh_token,
-							expiry,
-						}),
-						http::StatusCode::OK,
-					);
-				}
-				None => {
-					// Record not found, revoke all RTs
-					delete_old_rt(&uid, &data.connection).await;
-				}
-			}
-		}
+/// Ends the session that the refresh token family belongs to, records it, and lets the user know
+async fn revoke_family(data: &AppState, uid: Uuid, session_id: Uuid, request: &HttpRequest) {
+	log::warn!("Refresh token reuse detected for session {session_id}, revoking it");
+	if let Err(e) = end_sessions(&data.connection, sessions::Entity::find_by_id(session_id)).await {
+		error!("Failed to end session. Error: {}", e.to_string());
+	}
+	let recorded = security_events::record(
+		&data.connection,
+		uid,
+		SecurityEvent::RefreshTokenReuse,
+		Some(session_id),
+		request,
+	)
+	.await;
+	if let Err(e) = recorded {
+		error!("Failed to record security event. Error: {}", e.to_string());
 	}
+	security_events::send_alert(data, uid, request).await;
+}
+
+fn expired() -> (Json<ApiResponse>, http::StatusCode) {
 	(
 		Json(api_error(
 			"The provided token has already expired.".to_string(),
@@ -141,15 +179,12 @@ pub async fn handler(data: Data<AppState>, body: Json<RefreshBody>) -> impl Resp
 	)
 }
 
-async fn delete_old_rt(uid: &str, connection: &DatabaseConnection) {
-	let uid = Uuid::from_str(uid).unwrap();
-	// RT is not in DB, likely very old, all RTs should be revoked
-	match refresh_tokens::Entity::delete_many()
-		.filter(refresh_tokens::Column::Uid.eq(uid))
-		.exec(connection)
-		.await
-	{
-		Ok(_) => (),
-		Err(e) => log::error!("Failed to delete old refresh tokens: {}", e.to_string()),
-	}
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
 }
@@ -1,21 +1,25 @@
-use std::collections::BTreeMap;
-
 use actix_web::{
 	http, post,
 	web::{Data, Json},
 	Either, HttpResponse,
 };
 use chrono::{Duration, Utc};
-use email::{forgot_password, EmailParams};
-use entity::users;
-use jwt::SignWithKey;
+use email::{forgot_password, password_changed, EmailParams};
+use entity::{sessions, users};
 use log::error;
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
 use serde::Deserialize;
 use uaparser::Parser;
 
 use crate::{
-	auth::{api_error, ApiResponse},
+	auth::{
+		action_tokens::{self, ActionKind, ConsumeError},
+		api_error,
+		claims::{self, PasswordResetClaims, RegisteredClaims, TokenClaims},
+		hashing, identities, password_policy,
+		sessions::{end_sessions, Client},
+		ApiResponse,
+	},
 	AppState,
 };
 
@@ -25,6 +29,12 @@ pub struct ResetPasswordRequest {
 	pub reset_url: String,
 }
 
+#[derive(Deserialize)]
+pub struct ConfirmResetBody {
+	pub token: String,
+	pub new_password: String,
+}
+
 #[post("/api/auth/user/reset-password")]
 pub async fn handler(
 	request: actix_web::HttpRequest,
@@ -73,18 +83,25 @@ pub async fn handler(
 	};
 
 	// Generate a reset token
-	let exp = Utc::now().timestamp() + Duration::minutes(15).num_seconds();
-	let exp_str = exp.to_string();
-	let uid = user.uid.to_string();
-
-	// Generate a reset token and save it to the database
-	let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
-	claims.insert("iss", "TurboCore");
-	claims.insert("uid", &uid);
-	claims.insert("exp", &exp_str);
-	claims.insert("type", "password_reset");
-
-	let reset_token = claims.sign_with_key(&data.config.secret_key).unwrap();
+	let claims = PasswordResetClaims {
+		registered: RegisteredClaims::new(
+			PasswordResetClaims::AUDIENCE,
+			Duration::seconds(data.config.tokens.password_reset_lifetime),
+		),
+		sub: user.uid,
+	};
+	if let Err(e) = action_tokens::issue(
+		&data.connection,
+		user.uid,
+		ActionKind::PasswordReset,
+		&claims.registered,
+	)
+	.await
+	{
+		error!("Unable to record password reset token. Error: {}", e.to_string());
+		return Either::Left(internal_error());
+	}
+	let reset_token = claims::sign(&claims, &data.config.secret_key);
 
 	let action_url = format!("{}?token={}", body.reset_url, reset_token);
 
@@ -106,7 +123,7 @@ pub async fn handler(
 		action_url,
 		subject: email_config.forgot_password_subject,
 		from: email_config.from,
-		to: user.email,
+		to: body.email.to_owned(),
 		reply_to: email_config.reply_to,
 		os,
 		device,
@@ -116,3 +133,154 @@ pub async fn handler(
 
 	Either::Right(HttpResponse::Ok().finish())
 }
+
+/// Sets a new password with the token from the reset email. The token can only be used once, and every
+/// session of the user is ended.
+#[post("/api/auth/user/reset-password/confirm")]
+pub async fn confirm_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<ConfirmResetBody>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let claims = match claims::verify::<PasswordResetClaims>(&body.token, &data.config.secret_key) {
+		Ok(claims) => claims,
+		Err(e) => {
+			return Either::Left((
+				Json(api_error(e.message().to_string(), "INVALID_TOKEN".to_string())),
+				http::StatusCode::BAD_REQUEST,
+			));
+		}
+	};
+
+	let user = match users::Entity::find_by_id(claims.sub)
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(user)) => user,
+		Ok(None) => {
+			return Either::Left((
+				Json(api_error(
+					"The user was not found.".to_string(),
+					"USER_NOT_FOUND".to_string(),
+				)),
+				http::StatusCode::NOT_FOUND,
+			));
+		}
+		Err(e) => {
+			error!("Unable to find user. Database Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	};
+
+	// Checked before the token is used, so that a rejected password does not use it up
+	let breached = match password_policy::check(
+		&data,
+		&body.new_password,
+		user.email.as_deref().unwrap_or_default(),
+		Some(&user),
+	)
+	.await
+	{
+		Ok(breached) => breached,
+		Err(response) => return Either::Left(response),
+	};
+
+	match action_tokens::consume(
+		&data.connection,
+		user.uid,
+		ActionKind::PasswordReset,
+		&claims.registered.jti,
+	)
+	.await
+	{
+		Ok(()) => (),
+		Err(ConsumeError::Database(e)) => {
+			error!("Unable to use password reset token. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+		Err(e) => {
+			return Either::Left((
+				Json(api_error(e.message().to_string(), e.error_code().to_string())),
+				http::StatusCode::BAD_REQUEST,
+			));
+		}
+	}
+
+	if let Err(e) =
+		password_policy::record_change(&data.connection, &data.config.password_policy, &user).await
+	{
+		error!("Failed to record password history. Error: {}", e.to_string());
+		return Either::Left(internal_error());
+	}
+
+	let password_hash = hashing::hash(&body.new_password, &data.config);
+	let email = user.email.to_owned();
+	let mut user: users::ActiveModel = user.into();
+	user.password = Set(password_hash);
+	user.password_changed_at = Set(Some(Utc::now().naive_utc()));
+	let user = match user.update(&data.connection).await {
+		Ok(user) => user,
+		Err(e) => {
+			error!("Failed to reset user password. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	};
+	// Users who logged in another way can log in with the password from now on
+	if let Err(e) = identities::link_password(&data.connection, &user).await {
+		return Either::Left(e.response());
+	}
+
+	// Whoever had the old password is logged out
+	let user_sessions = sessions::Entity::find().filter(sessions::Column::Uid.eq(claims.sub));
+	if let Err(e) = end_sessions(&data.connection, user_sessions).await {
+		error!("Failed to end sessions after password reset. Error: {}", e.to_string());
+		return Either::Left(internal_error());
+	}
+
+	if breached {
+		password_policy::record_breached(&data, claims.sub, &request).await;
+	}
+
+	if let Some(email) = email {
+		send_changed_email(&data, &email, &request).await;
+	}
+
+	Either::Right(HttpResponse::Ok().finish())
+}
+
+/// Emails the user that their password was changed. Does nothing unless email is configured with a
+/// `password_changed_subject`.
+async fn send_changed_email(data: &AppState, email: &str, request: &actix_web::HttpRequest) {
+	let (mailer, email_config) = match (&data.config.mailer, &data.config.email) {
+		(Some(mailer), Some(email_config)) => (mailer, email_config),
+		_ => return,
+	};
+	let subject = match &email_config.password_changed_subject {
+		Some(subject) => subject.to_owned(),
+		None => return,
+	};
+
+	let client = Client::from_request(request, &data.ua_parser);
+	password_changed::send(EmailParams {
+		name: email.to_owned(),
+		action_url: data.config.base_url.to_owned(),
+		subject,
+		from: email_config.from.to_owned(),
+		to: email.to_owned(),
+		reply_to: email_config.reply_to.to_owned(),
+		os: client.os,
+		device: client.device,
+		mailer,
+	})
+	.await;
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"Internal Server Error.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
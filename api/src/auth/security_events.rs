@@ -0,0 +1,93 @@
+//! A record of events that may mean a user's account is under attack, such as a stolen refresh token.
+
+use actix_web::HttpRequest;
+use chrono::Utc;
+use email::{security_alert, EmailParams};
+use entity::{security_events, users};
+use log::error;
+use migration::DbErr;
+use sea_orm::{ConnectionTrait, EntityTrait, Set};
+use uuid::Uuid;
+
+use crate::{auth::sessions::Client, AppState};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityEvent {
+	/// A refresh token was used after it had already been exchanged, so its family was revoked
+	RefreshTokenReuse,
+	/// Too many failed logins locked the account
+	AccountLocked,
+	/// The user chose a password that appears in a known breach, and breached passwords only warn
+	BreachedPassword,
+}
+
+impl SecurityEvent {
+	/// The name stored in the database
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			SecurityEvent::RefreshTokenReuse => "refresh_token_reuse",
+			SecurityEvent::AccountLocked => "account_locked",
+			SecurityEvent::BreachedPassword => "breached_password",
+		}
+	}
+}
+
+/// Records the event, for the client that made the request
+pub async fn record<C: ConnectionTrait>(
+	connection: &C,
+	uid: Uuid,
+	event: SecurityEvent,
+	session_id: Option<Uuid>,
+	request: &HttpRequest,
+) -> Result<(), DbErr> {
+	security_events::Entity::insert(security_events::ActiveModel {
+		id: Set(Uuid::new_v4()),
+		uid: Set(uid),
+		event: Set(event.as_str().to_string()),
+		session_id: Set(session_id),
+		ip: Set(Client::ip(request)),
+		created_at: Set(Utc::now().naive_utc()),
+	})
+	.exec(connection)
+	.await
+	.map(|_| ())
+}
+
+/// Emails the user that one of their sessions may have been stolen. Does nothing unless email is
+/// configured with a `security_alert_subject`.
+pub async fn send_alert(data: &AppState, uid: Uuid, request: &HttpRequest) {
+	let (mailer, email_config) = match (&data.config.mailer, &data.config.email) {
+		(Some(mailer), Some(email_config)) => (mailer, email_config),
+		_ => return,
+	};
+	let subject = match &email_config.security_alert_subject {
+		Some(subject) => subject.to_owned(),
+		None => return,
+	};
+
+	// Users who signed up with a phone number may have nowhere to send the alert
+	let email = match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(users::Model {
+			email: Some(email), ..
+		})) => email,
+		Ok(_) => return,
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return;
+		}
+	};
+
+	let client = Client::from_request(request, &data.ua_parser);
+	security_alert::send(EmailParams {
+		name: email.to_owned(),
+		action_url: data.config.base_url.to_owned(),
+		subject,
+		from: email_config.from.to_owned(),
+		to: email,
+		reply_to: email_config.reply_to.to_owned(),
+		os: client.os,
+		device: client.device,
+		mailer,
+	})
+	.await;
+}
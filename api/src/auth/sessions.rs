@@ -0,0 +1,257 @@
+//! Lets users see where they are logged in, and log out of other devices.
+//!
+//! A session starts when the user logs in, and is continued by each refresh token issued from it. Ending
+//! a session deletes its refresh tokens, so it cannot be refreshed again. Its access tokens stay valid
+//! until they expire.
+
+use crate::{
+	auth::{
+		api_error,
+		util::{self, ClaimsResult, HeaderResult},
+		ApiResponse,
+	},
+	AppState,
+};
+use actix_web::{
+	delete, get, http,
+	web::{Data, Json, Path},
+	Either, HttpRequest, HttpResponse, Responder,
+};
+use chrono::Utc;
+use entity::{refresh_tokens, sessions};
+use log::error;
+use migration::DbErr;
+use sea_orm::{
+	prelude::DateTime, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder,
+	QuerySelect, Select, TransactionTrait,
+};
+use serde::Serialize;
+use std::net::IpAddr;
+use uaparser::{Parser, UserAgentParser};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+	id: String,
+	created_at: DateTime,
+	last_refresh: DateTime,
+	ip: Option<String>,
+	os: String,
+	browser: String,
+	device: String,
+	/// Whether this is the session of the access token that made the request
+	current: bool,
+}
+
+/// The client that started or refreshed a session
+pub struct Client {
+	pub ip: Option<String>,
+	pub os: String,
+	pub browser: String,
+	pub device: String,
+}
+
+impl Client {
+	pub fn from_request(request: &HttpRequest, ua_parser: &UserAgentParser) -> Self {
+		let (os, browser, device) = match request
+			.headers()
+			.get("User-Agent")
+			.and_then(|user_agent| user_agent.to_str().ok())
+		{
+			Some(user_agent) => (
+				ua_parser.parse_os(user_agent).family.to_string(),
+				ua_parser.parse_user_agent(user_agent).family.to_string(),
+				ua_parser.parse_device(user_agent).family.to_string(),
+			),
+			None => ("Unknown".to_string(), "Unknown".to_string(), "Unknown".to_string()),
+		};
+		Self {
+			ip: Self::ip(request),
+			os,
+			browser,
+			device,
+		}
+	}
+
+	/// The client's IP address. This trusts the `Forwarded` and `X-Forwarded-For` headers, so it is only
+	/// meant to be shown to the user.
+	pub fn ip(request: &HttpRequest) -> Option<String> {
+		request
+			.connection_info()
+			.realip_remote_addr()
+			.map(str::to_string)
+	}
+
+	/// The client's IP address, which is only read from `X-Forwarded-For` when the request comes from a
+	/// trusted proxy. Unlike `ip`, clients cannot pick it, so it is what logins and requests are limited by.
+	pub fn address(request: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<String> {
+		let peer = request.peer_addr()?.ip();
+		if !trusted_proxies.contains(&peer) {
+			return Some(peer.to_string());
+		}
+
+		// Each proxy appends the address it was connected from, so the client is the last one that is not
+		// a trusted proxy
+		let forwarded: Vec<IpAddr> = request
+			.headers()
+			.get_all("X-Forwarded-For")
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(','))
+			.filter_map(|address| address.trim().parse().ok())
+			.collect();
+		let client = forwarded
+			.iter()
+			.rev()
+			.find(|address| !trusted_proxies.contains(address))
+			.or(forwarded.first())
+			.unwrap_or(&peer);
+		Some(client.to_string())
+	}
+}
+
+/// Ends the sessions and deletes their refresh tokens
+pub async fn end_sessions<C: ConnectionTrait + TransactionTrait>(
+	connection: &C,
+	sessions: Select<sessions::Entity>,
+) -> Result<(), DbErr> {
+	let ids: Vec<Uuid> = sessions
+		.select_only()
+		.column(sessions::Column::Id)
+		.into_tuple()
+		.all(connection)
+		.await?;
+	if ids.is_empty() {
+		return Ok(());
+	}
+
+	let txn = connection.begin().await?;
+	refresh_tokens::Entity::delete_many()
+		.filter(refresh_tokens::Column::SessionId.is_in(ids.clone()))
+		.exec(&txn)
+		.await?;
+	sessions::Entity::delete_many()
+		.filter(sessions::Column::Id.is_in(ids))
+		.exec(&txn)
+		.await?;
+	txn.commit().await
+}
+
+/// Lists the user's active sessions, most recently used first
+#[get("/api/auth/user/sessions")]
+pub async fn list_handler(request: HttpRequest, data: Data<AppState>) -> impl Responder {
+	let claims =
+		match util::verify_header_claims(request.headers().get("Authorization"), &data.key_ring) {
+			ClaimsResult::Error(r, s) => return (r, s),
+			ClaimsResult::Claims(claims) => claims,
+		};
+
+	let sessions = match sessions::Entity::find()
+		.filter(sessions::Column::Uid.eq(claims.sub))
+		.filter(sessions::Column::Expiry.gt(Utc::now().naive_utc()))
+		.order_by_desc(sessions::Column::LastRefresh)
+		.all(&data.connection)
+		.await
+	{
+		Ok(sessions) => sessions,
+		Err(e) => {
+			error!("Unable to find sessions. Error: {}", e.to_string());
+			return internal_error();
+		}
+	};
+
+	let sessions = sessions
+		.into_iter()
+		.map(|session| SessionInfo {
+			id: session.id.to_string(),
+			created_at: session.created_at,
+			last_refresh: session.last_refresh,
+			ip: session.ip,
+			os: session.os,
+			browser: session.browser,
+			device: session.device,
+			current: session.id == claims.sid,
+		})
+		.collect();
+
+	(Json(ApiResponse::SessionListResponse { sessions }), http::StatusCode::OK)
+}
+
+/// Ends one of the user's sessions
+#[delete("/api/auth/user/sessions/{id}")]
+pub async fn delete_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+	path: Path<String>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let uid = match util::verify_header(request.headers().get("Authorization"), &data.key_ring) {
+		HeaderResult::Error(r, s) => return Either::Left((r, s)),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	let id = match Uuid::parse_str(&path.into_inner()) {
+		Ok(id) => id,
+		Err(_) => return Either::Left(not_found()),
+	};
+	// Users can only end their own sessions
+	let session = sessions::Entity::find_by_id(id).filter(sessions::Column::Uid.eq(uid));
+	match session.clone().one(&data.connection).await {
+		Ok(Some(_)) => (),
+		Ok(None) => return Either::Left(not_found()),
+		Err(e) => {
+			error!("Unable to find session. Error: {}", e.to_string());
+			return Either::Left(internal_error());
+		}
+	}
+
+	match end_sessions(&data.connection, session).await {
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => {
+			error!("Unable to end session. Error: {}", e.to_string());
+			Either::Left(internal_error())
+		}
+	}
+}
+
+/// Ends all of the user's sessions, except the one making the request
+#[delete("/api/auth/user/sessions")]
+pub async fn delete_others_handler(
+	request: HttpRequest,
+	data: Data<AppState>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let claims =
+		match util::verify_header_claims(request.headers().get("Authorization"), &data.key_ring) {
+			ClaimsResult::Error(r, s) => return Either::Left((r, s)),
+			ClaimsResult::Claims(claims) => claims,
+		};
+
+	let others = sessions::Entity::find()
+		.filter(sessions::Column::Uid.eq(claims.sub))
+		.filter(sessions::Column::Id.ne(claims.sid));
+	match end_sessions(&data.connection, others).await {
+		Ok(_) => Either::Right(HttpResponse::Ok().finish()),
+		Err(e) => {
+			error!("Unable to end sessions. Error: {}", e.to_string());
+			Either::Left(internal_error())
+		}
+	}
+}
+
+fn not_found() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The session was not found.".to_string(),
+			"SESSION_NOT_FOUND".to_string(),
+		)),
+		http::StatusCode::NOT_FOUND,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
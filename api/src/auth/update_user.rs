@@ -9,7 +9,7 @@ use sea_orm::{ActiveModelTrait, EntityTrait, Set};
 
 use crate::{
 	auth::{
-		api_error,
+		anonymous, api_error, email_change,
 		util::{self, HeaderResult},
 		ApiResponse,
 	},
@@ -18,8 +18,11 @@ use crate::{
 
 #[derive(serde::Deserialize)]
 pub struct UpdateUserBody {
+	/// A new email is only used once it has been confirmed
 	email: Option<String>,
 	metadata: Option<Metadata>,
+	/// Where the email change links send the user, defaults to the base URL
+	next_url: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
@@ -37,7 +40,7 @@ pub async fn handler(
 	let header_map = request.headers();
 	let authorization = header_map.get("Authorization");
 
-	let uid = match util::verify_header(authorization, &data.config.secret_key) {
+	let uid = match util::verify_header(authorization, &data.key_ring) {
 		HeaderResult::Error(r, s) => {
 			return Either::Left((r, s));
 		}
@@ -70,12 +73,22 @@ pub async fn handler(
 		}
 	};
 
-	let mut user: ActiveModel = user.into();
-
-	if let Some(email) = body.email.to_owned() {
-		user.email = Set(email);
+	let new_email = body
+		.email
+		.to_owned()
+		.filter(|email| Some(email) != user.email.as_ref());
+	if let Some(new_email) = &new_email {
+		if user.anonymous {
+			return Either::Left(anonymous::no_email());
+		}
+		let next_url = body.next_url.as_deref().unwrap_or(&data.config.base_url);
+		if let Err(e) = email_change::start(&request, &data, &user, new_email, next_url).await {
+			return Either::Left(e);
+		}
 	}
 
+	let mut user: ActiveModel = user.into();
+
 	if let Some(metadata) = &body.metadata {
 		match metadata {
 			Metadata::Data(data) => user.metadata = Set(Some(data.to_string())),
@@ -97,5 +110,10 @@ pub async fn handler(
 		}
 	};
 
+	if new_email.is_some() {
+		// The email changes once the new address is confirmed
+		return Either::Right(HttpResponse::Accepted().finish());
+	}
+
 	Either::Right(HttpResponse::Ok().finish())
 }
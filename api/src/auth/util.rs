@@ -1,79 +1,181 @@
-/// This module contains utility functions for the auth module
+//! This module contains utility functions for the auth module
 
+use actix_web::HttpRequest;
 use actix_web::{
 	http::{self, header::HeaderValue, StatusCode},
 	web::Json,
 };
 use chrono::{Duration, NaiveDateTime, Utc};
-use entity::refresh_tokens;
-use hmac::Hmac;
-use jwt::{SignWithKey, VerifyWithKey};
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use sea_orm::{DatabaseConnection, EntityTrait, Set};
-use sha2::Sha256;
-use std::{collections::BTreeMap, str::FromStr};
+use entity::{refresh_tokens, sessions};
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, Set};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
 use uuid::Uuid;
 
-use super::{api_error, ApiResponse};
+use super::{
+	api_error,
+	claims::{self, AccessClaims, RefreshClaims, RegisteredClaims, TokenClaims, TokenError},
+	sessions::Client,
+	ApiResponse,
+};
+use crate::{
+	admin::{custom_claims, rbac},
+	keys::KeyRing,
+	TokenConfig,
+};
+use uaparser::UserAgentParser;
+
+/// The session that tokens are issued for
+pub enum Session<'a> {
+	/// A new login, by the client that made the request. Logins that are not remembered get the short
+	/// refresh token lifetime.
+	New {
+		request: &'a HttpRequest,
+		ua_parser: &'a UserAgentParser,
+		remember_me: bool,
+	},
+	/// Another pair of tokens for an existing session, when its refresh token is used
+	Refresh(&'a sessions::Model, &'a HttpRequest),
+}
+
+impl<'a> Session<'a> {
+	/// A new login that is remembered
+	pub fn new(request: &'a HttpRequest, ua_parser: &'a UserAgentParser) -> Self {
+		Session::New {
+			request,
+			ua_parser,
+			remember_me: true,
+		}
+	}
+}
 
 /// Generates a JWT access token and a JWT refresh token, and expiry for the AT.
-/// Returns the value as a tuple and store the refresh token in the database
+/// Returns the value as a tuple and stores the refresh token's hash and its session in the database
 pub async fn get_at_and_rt(
 	connection: &DatabaseConnection,
-	uid: &String,
-	key: &hmac::Hmac<sha2::Sha256>,
-    admin: bool
+	uid: &str,
+	key_ring: &KeyRing,
+	config: &TokenConfig,
+	admin: bool,
+	session: Session<'_>,
 ) -> (String, String, i64) {
-	let mut token = BTreeMap::new();
-	let mut refresh_token = BTreeMap::new();
+	let uid = Uuid::from_str(uid).unwrap();
+	let role = admin.then(|| "admin".to_string());
+	// Admins are not users, so they have no custom claims
+	let custom = match admin {
+		true => Default::default(),
+		false => custom_claims::find(connection, uid).await.unwrap(),
+	};
+	let permissions = rbac::permissions_of(connection, uid).await.unwrap();
+	let now = Utc::now().naive_utc();
+	let (sid, created_at, remember_me) = match session {
+		Session::New { remember_me, .. } => (Uuid::new_v4(), now, remember_me),
+		Session::Refresh(session, _) => (session.id, session.created_at, session.remember_me),
+	};
+
+	// With an idle timeout, each refresh token only lives until the timeout, and the refresh token lifetime
+	// caps the session. Otherwise, each refresh token lives for the whole lifetime.
+	let lifetime = Duration::seconds(match remember_me {
+		true => config.refresh_token_lifetime,
+		false => config.short_refresh_token_lifetime,
+	});
+	let rt_lifetime = match config.refresh_token_idle_timeout {
+		Some(idle) => Duration::seconds(idle).min(created_at + lifetime - now),
+		None => lifetime,
+	};
 
 	// The RFC protocol allows for some lee way ("up to a few minutes") in exp, hence +15 seconds
-	let short_exp = Utc::now().timestamp() + Duration::minutes(15).num_seconds() + 15;
-	let short_exp_str = short_exp.to_string();
-	let long_exp = Utc::now().timestamp() + Duration::days(30).num_seconds();
-	let long_exp_str = long_exp.to_string();
-
-	// RT is used as a primary key in db and must be unique. Two tokens (with same uid) generated in the same second will
-	// be the same, so we add some randomness to make the possibility of a collision during the same second 1 / 62^5
-	let rand_val: String = thread_rng()
-		.sample_iter(&Alphanumeric)
-		.take(5)
-		.map(char::from)
-		.collect();
-
-	refresh_token.insert("iss", "TurboCore");
-	refresh_token.insert("exp", &long_exp_str);
-	refresh_token.insert("uid", uid);
-	refresh_token.insert("type", "rt");
-	refresh_token.insert("rand", &rand_val);
-
-	token.insert("iss", "TurboCore");
-	token.insert("exp", &short_exp_str);
-	token.insert("type", "at");
-	token.insert("uid", uid);
-
-    if admin {
-        token.insert("role", "admin");
-        refresh_token.insert("role", "admin");
-    }
-
-	let rt = refresh_token.sign_with_key(key).unwrap();
+	let token = AccessClaims {
+		registered: RegisteredClaims::new(
+			AccessClaims::AUDIENCE,
+			Duration::seconds(config.access_token_lifetime) + Duration::seconds(15),
+		),
+		sub: uid,
+		sid,
+		role: role.clone(),
+		permissions,
+		custom,
+	};
+	// RT is used as a primary key in db and must be unique, which the jti guarantees
+	let refresh_token = RefreshClaims {
+		registered: RegisteredClaims::new(RefreshClaims::AUDIENCE, rt_lifetime),
+		sub: uid,
+		sid,
+		role,
+	};
+	let short_exp = token.registered.exp;
+	let long_exp = refresh_token.registered.exp;
+	let long_exp_date = NaiveDateTime::from_timestamp_opt(long_exp, 0).unwrap();
+
+	// The session lives as long as its newest refresh token
+	match session {
+		Session::New {
+			request, ua_parser, ..
+		} => {
+			let client = Client::from_request(request, ua_parser);
+			sessions::Entity::insert(sessions::ActiveModel {
+				id: Set(sid),
+				uid: Set(uid),
+				created_at: Set(now),
+				last_refresh: Set(now),
+				expiry: Set(long_exp_date),
+				ip: Set(client.ip),
+				os: Set(client.os),
+				browser: Set(client.browser),
+				device: Set(client.device),
+				remember_me: Set(remember_me),
+			})
+			.exec(connection)
+			.await
+			.unwrap();
+		}
+		Session::Refresh(_, request) => {
+			sessions::Entity::update_many()
+				.col_expr(sessions::Column::LastRefresh, now.into())
+				.col_expr(sessions::Column::Expiry, long_exp_date.into())
+				.col_expr(sessions::Column::Ip, Client::ip(request).into())
+				.filter(sessions::Column::Id.eq(sid))
+				.exec(connection)
+				.await
+				.unwrap();
+		}
+	}
+
+	let rt = claims::sign(&refresh_token, key_ring);
 
 	// Add new one
 	refresh_tokens::Entity::insert(refresh_tokens::ActiveModel {
-		uid: Set(Uuid::from_str(uid).unwrap()),
-		refresh_token: Set(rt.to_owned()),
-		expiry: Set(NaiveDateTime::from_timestamp_opt(long_exp, 0).unwrap()),
+		token_hash: Set(hash_secret(&rt)),
+		uid: Set(uid),
+		session_id: Set(sid),
+		expiry: Set(long_exp_date),
 		used: Set(false),
 	})
 	.exec(connection)
 	.await
 	.unwrap();
 
-	(token.sign_with_key(key).unwrap(), rt, short_exp)
+	(claims::sign(&token, key_ring), rt, short_exp)
+}
+
+/// Hashes a high-entropy secret, such as a recovery code, so that it can be stored and looked up.
+/// This is not suitable for passwords, which must use argon2.
+pub fn hash_secret(secret: &str) -> String {
+	Sha256::digest(secret.as_bytes())
+		.iter()
+		.map(|b| format!("{b:02x}"))
+		.collect()
 }
 
-pub fn verify_header(auth_header: Option<&HeaderValue>, secret_key: &Hmac<Sha256>) -> HeaderResult {
+pub fn verify_header(auth_header: Option<&HeaderValue>, key_ring: &KeyRing) -> HeaderResult {
+	match verify_header_claims(auth_header, key_ring) {
+		ClaimsResult::Error(r, s) => HeaderResult::Error(r, s),
+		ClaimsResult::Claims(claims) => HeaderResult::Uid(claims.sub),
+	}
+}
+
+/// Like `verify_header`, but returns all of the access token's claims
+pub fn verify_header_claims(auth_header: Option<&HeaderValue>, key_ring: &KeyRing) -> ClaimsResult {
 	let authorization = match auth_header {
 		Some(a) => {
 			match a.to_str() {
@@ -82,7 +184,7 @@ pub fn verify_header(auth_header: Option<&HeaderValue>, secret_key: &Hmac<Sha256
 					// The request contains headers with opaque bytes.
 					// TODO: Log IP address of the request
 					log::warn!("Received a request that contains headers with opaque bytes. ");
-					return HeaderResult::Error(
+					return ClaimsResult::Error(
 						Json(api_error(
 							"The 'Authorization' header is improperly formatted".to_string(),
 							"BAD_HEADER".to_string(),
@@ -93,7 +195,7 @@ pub fn verify_header(auth_header: Option<&HeaderValue>, secret_key: &Hmac<Sha256
 			}
 		}
 		None => {
-			return HeaderResult::Error(
+			return ClaimsResult::Error(
 				Json(api_error(
 					"The 'Authorization' header is missing".to_string(),
 					"NOT_AUTHENTICATED".to_string(),
@@ -104,8 +206,9 @@ pub fn verify_header(auth_header: Option<&HeaderValue>, secret_key: &Hmac<Sha256
 	};
 
 	let parts: Vec<&str> = authorization.split_whitespace().collect();
-	if parts[0] != "Bearer" && parts[0] != "bearer" {
-		return HeaderResult::Error(
+	// Empty headers have no parts at all
+	if !matches!(parts.first(), Some(&"Bearer") | Some(&"bearer")) {
+		return ClaimsResult::Error(
 			Json(api_error(
 				"The 'Authorization' header is improperly formatted".to_string(),
 				"BAD_HEADER".to_string(),
@@ -116,7 +219,7 @@ pub fn verify_header(auth_header: Option<&HeaderValue>, secret_key: &Hmac<Sha256
 	let token = match parts.get(1) {
 		Some(token) => *token,
 		None => {
-			return HeaderResult::Error(
+			return ClaimsResult::Error(
 				Json(api_error(
 					"The 'Authorization' header is improperly formatted".to_string(),
 					"BAD_HEADER".to_string(),
@@ -126,42 +229,20 @@ pub fn verify_header(auth_header: Option<&HeaderValue>, secret_key: &Hmac<Sha256
 		}
 	};
 
-	let claims: BTreeMap<String, String> = match token.verify_with_key(secret_key) {
-		Ok(c) => c,
-		Err(_) => {
-			return HeaderResult::Error(
-				Json(api_error(
-					"The provided token could not be verified by the server.".to_string(),
-					"BAD_TOKEN".to_string(),
-				)),
-				http::StatusCode::UNAUTHORIZED,
-			);
-		}
-	};
-
-	if Utc::now().timestamp() > claims["exp"].parse().unwrap() {
-		return HeaderResult::Error(
+	match claims::verify::<AccessClaims>(token, key_ring) {
+		Ok(claims) => ClaimsResult::Claims(claims),
+		Err(TokenError::Expired) => ClaimsResult::Error(
 			Json(api_error(
 				"The provided token has already expired".to_string(),
 				"EXPIRED_TOKEN".to_string(),
 			)),
 			http::StatusCode::UNAUTHORIZED,
-		);
-	}
-
-	if claims["type"] != "at" {
-		return HeaderResult::Error(
-			Json(api_error(
-				"The provided JWT is not an access token.".to_string(),
-				"BAD_TOKEN".to_string(),
-			)),
+		),
+		Err(e) => ClaimsResult::Error(
+			Json(api_error(e.message().to_string(), "BAD_TOKEN".to_string())),
 			http::StatusCode::UNAUTHORIZED,
-		);
+		),
 	}
-
-	let uid = &claims.get("uid").unwrap()[..];
-
-	HeaderResult::Uid(Uuid::from_str(uid).unwrap())
 }
 
 pub enum HeaderResult {
@@ -169,81 +250,210 @@ pub enum HeaderResult {
 	Uid(Uuid),
 }
 
+pub enum ClaimsResult {
+	Error(Json<ApiResponse>, StatusCode),
+	Claims(AccessClaims),
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::keys::KeyAlgorithm;
 	use actix_web::http::header;
-	use entity::refresh_tokens;
-	use hmac::{Hmac, Mac};
+	use chrono::Utc;
+	use entity::{
+		custom_claims, group_members, group_roles, refresh_tokens, role_members, role_permissions,
+		roles, sessions,
+	};
 	use migration::TableCreateStatement;
 	use sea_orm::{ConnectionTrait, DbBackend, Schema};
 
+	/// Signs an access token for the user, that expires after the lifetime
+	fn access_token(key: &KeyRing, uid: &str, lifetime: Duration) -> String {
+		let claims = AccessClaims {
+			registered: RegisteredClaims::new(AccessClaims::AUDIENCE, lifetime),
+			sub: Uuid::from_str(uid).unwrap(),
+			sid: Uuid::new_v4(),
+			role: None,
+			permissions: vec![],
+			custom: Default::default(),
+		};
+		claims::sign(&claims, key)
+	}
+
+	/// Creates a test database with the tables that tokens are stored in
+	async fn test_connection() -> DatabaseConnection {
+		let connection = sea_orm::Database::connect("sqlite::memory:").await.unwrap();
+		let schema = Schema::new(DbBackend::Sqlite);
+		for stmt in [
+			schema.create_table_from_entity(refresh_tokens::Entity),
+			schema.create_table_from_entity(sessions::Entity),
+			schema.create_table_from_entity(custom_claims::Entity),
+			schema.create_table_from_entity(roles::Entity),
+			schema.create_table_from_entity(role_members::Entity),
+			schema.create_table_from_entity(role_permissions::Entity),
+			schema.create_table_from_entity(group_members::Entity),
+			schema.create_table_from_entity(group_roles::Entity),
+		] {
+			let stmt: TableCreateStatement = stmt;
+			connection
+				.execute(connection.get_database_backend().build(&stmt))
+				.await
+				.unwrap();
+		}
+		connection
+	}
+
 	#[actix_web::test]
 	async fn test_get_at_and_rt() {
 		let uid = "6755d7b1-38f2-4a3a-b872-98d0e7bbd1ee";
 
 		// Create a connection to a test database
-		let connection = sea_orm::Database::connect("sqlite::memory:").await.unwrap();
+		let connection = test_connection().await;
 
-		// Create the schema
-		let schema = Schema::new(DbBackend::Sqlite);
-		let stmt: TableCreateStatement = schema.create_table_from_entity(refresh_tokens::Entity);
-
-		let _result = connection
-			.execute(connection.get_database_backend().build(&stmt))
-			.await
-			.unwrap();
-
-		// Create Hmac key
-		let key: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"a_very_long_secret_key").unwrap();
+		// Create a key ring
+		let key = KeyRing::ephemeral(KeyAlgorithm::ES256);
 
 		// Create a new at and rt pair
-		let (at, rt, exp) = get_at_and_rt(&connection, &uid.to_string(), &key, false).await;
+		let request = actix_web::test::TestRequest::default()
+			.insert_header((
+				header::USER_AGENT,
+				"Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/116.0",
+			))
+			.to_http_request();
+		let ua_parser = UserAgentParser::from_yaml("../regexes.yaml").unwrap();
+		let (at, rt, exp) = get_at_and_rt(
+			&connection,
+			uid,
+			&key,
+			&TokenConfig::default(),
+			false,
+			Session::new(&request, &ua_parser),
+		)
+		.await;
 
 		// Verify the at
-		let claims: BTreeMap<String, String> = at.verify_with_key(&key).unwrap();
-		assert_eq!(claims["type"], "at");
-		assert_eq!(claims["uid"], uid);
-		assert_eq!(claims["exp"], exp.to_string());
-		assert!(claims["exp"].parse::<i64>().unwrap() - Utc::now().timestamp() > 905); // 915 is the default expiry time
-		assert!(claims["exp"].parse::<i64>().unwrap() - Utc::now().timestamp() < 925);
-		assert_eq!(claims["iss"], "TurboCore");
-
-		// Verify the rt
-		let claims: BTreeMap<String, String> = rt.verify_with_key(&key).unwrap();
-		assert_eq!(claims["type"], "rt");
-		assert_eq!(claims["uid"], uid);
-		assert!(claims["exp"].parse::<i64>().unwrap() - Utc::now().timestamp() > 2591990); // 2592000 is the default expiry time
-		assert!(claims["exp"].parse::<i64>().unwrap() - Utc::now().timestamp() < 2592010);
-		assert!(claims["rand"].len() > 0);
-		assert_eq!(claims["iss"], "TurboCore");
-
-		// Verify that the rt is in the database
-		let rt = refresh_tokens::Entity::find_by_id(rt)
+		let claims: AccessClaims = claims::verify(&at, &key).unwrap();
+		assert_eq!(claims.sub.to_string(), uid);
+		assert_eq!(claims.registered.exp, exp);
+		assert!(claims.registered.exp - Utc::now().timestamp() > 905); // 915 is the default expiry time
+		assert!(claims.registered.exp - Utc::now().timestamp() < 925);
+		assert_eq!(claims.registered.iss, "TurboCore");
+		assert_eq!(claims.role, None);
+		// The registered claims are not mistaken for custom claims
+		assert!(claims.custom.is_empty());
+
+		// Verify the rt, which can't be used as an at
+		assert_eq!(
+			claims::verify::<AccessClaims>(&rt, &key).unwrap_err(),
+			TokenError::WrongAudience
+		);
+		let claims: RefreshClaims = claims::verify(&rt, &key).unwrap();
+		assert_eq!(claims.sub.to_string(), uid);
+		assert!(claims.registered.exp - Utc::now().timestamp() > 2591990); // 2592000 is the default expiry time
+		assert!(claims.registered.exp - Utc::now().timestamp() < 2592010);
+		assert!(!claims.registered.jti.is_empty());
+		assert_eq!(claims.registered.iss, "TurboCore");
+
+		// Verify that the rt is in the database, by its hash
+		let rt = refresh_tokens::Entity::find_by_id(hash_secret(&rt))
 			.one(&connection)
 			.await
 			.unwrap()
 			.unwrap();
 		assert_eq!(rt.uid, Uuid::from_str(uid).unwrap());
 
+		// Verify that the rt belongs to a new session, which records the client
+		assert_eq!(rt.session_id, claims.sid);
+		let session = sessions::Entity::find_by_id(claims.sid)
+			.one(&connection)
+			.await
+			.unwrap()
+			.unwrap();
+		assert_eq!(session.uid, Uuid::from_str(uid).unwrap());
+		assert_eq!(session.browser, "Firefox");
+		assert_eq!(session.os, "Linux");
+
 		// Close the connection
 		connection.close().await.unwrap();
 	}
 
+	#[actix_web::test]
+	async fn test_refresh_token_lifetimes() {
+		let uid = "6755d7b1-38f2-4a3a-b872-98d0e7bbd1ee";
+		let connection = test_connection().await;
+		let key = KeyRing::ephemeral(KeyAlgorithm::ES256);
+		let request = actix_web::test::TestRequest::default().to_http_request();
+		let ua_parser = UserAgentParser::from_yaml("../regexes.yaml").unwrap();
+		let lifetime = |rt: &str| {
+			let claims: RefreshClaims = claims::verify(rt, &key).unwrap();
+			claims.registered.exp - Utc::now().timestamp()
+		};
+
+		// Logins that are not remembered get the short lifetime
+		let mut config = TokenConfig::default();
+		let (_, rt, _) = get_at_and_rt(
+			&connection,
+			uid,
+			&key,
+			&config,
+			false,
+			Session::New {
+				request: &request,
+				ua_parser: &ua_parser,
+				remember_me: false,
+			},
+		)
+		.await;
+		assert!((86390..86410).contains(&lifetime(&rt)));
+
+		// With an idle timeout, each token only lives until the timeout
+		config.refresh_token_idle_timeout = Some(3600);
+		let (_, rt, _) = get_at_and_rt(
+			&connection,
+			uid,
+			&key,
+			&config,
+			false,
+			Session::new(&request, &ua_parser),
+		)
+		.await;
+		assert!((3590..3610).contains(&lifetime(&rt)));
+
+		// and refreshing can't extend the session past the refresh token lifetime
+		let claims: RefreshClaims = claims::verify(&rt, &key).unwrap();
+		let session = sessions::Entity::find_by_id(claims.sid)
+			.one(&connection)
+			.await
+			.unwrap()
+			.unwrap();
+		let session = sessions::Model {
+			created_at: session.created_at - Duration::days(30) + Duration::minutes(10),
+			..session
+		};
+		let (_, rt, _) = get_at_and_rt(
+			&connection,
+			uid,
+			&key,
+			&config,
+			false,
+			Session::Refresh(&session, &request),
+		)
+		.await;
+		assert!((590..610).contains(&lifetime(&rt)));
+
+		connection.close().await.unwrap();
+	}
+
 	#[test]
 	fn test_good_header() {
 		let uid = "6755d7b1-38f2-4a3a-b872-98d0e7bbd1ee";
 
-		// Create Hmac key
-		let key: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"a_very_long_secret_key").unwrap();
+		// Create a key ring
+		let key = KeyRing::ephemeral(KeyAlgorithm::ES256);
 
 		// Create a good at
-		let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
-		claims.insert("iss", "TurboCore");
-		claims.insert("type", "at");
-		claims.insert("uid", uid);
-		claims.insert("exp", "9999999999");
-		let at = claims.sign_with_key(&key).unwrap();
+		let at = access_token(&key, uid, Duration::minutes(15));
 
 		// Create the request and pull the headers
 		let request = actix_web::test::TestRequest::default()
@@ -256,7 +466,7 @@ mod tests {
 
 		match res {
 			HeaderResult::Uid(u) => assert_eq!(u, Uuid::from_str(uid).unwrap()),
-			_ => assert!(false),
+			_ => panic!(),
 		}
 	}
 
@@ -264,19 +474,14 @@ mod tests {
 	fn test_bad_header() {
 		let uid = "6755d7b1-38f2-4a3a-b872-98d0e7bbd1ee";
 
-		// Create a good Hmac key
-		let key1: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"a_very_long_secret_key").unwrap();
+		// Create a key ring
+		let key1 = KeyRing::ephemeral(KeyAlgorithm::ES256);
 
-		// and a bad one
-		let key2: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"incorrect_secret_key").unwrap();
+		// and another one
+		let key2 = KeyRing::ephemeral(KeyAlgorithm::ES256);
 
 		// Create a good at
-		let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
-		claims.insert("iss", "TurboCore");
-		claims.insert("type", "at");
-		claims.insert("uid", uid);
-		claims.insert("exp", "9999999999");
-		let at = claims.sign_with_key(&key1).unwrap();
+		let at = access_token(&key1, uid, Duration::minutes(15));
 
 		// Create the request and pull the headers
 		let request = actix_web::test::TestRequest::default()
@@ -298,17 +503,41 @@ mod tests {
 					} => {
 						assert_eq!(error_code, "BAD_TOKEN");
 					}
-					_ => assert!(false),
+					_ => panic!(),
+				}
+			}
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn test_empty_header() {
+		let key = KeyRing::ephemeral(KeyAlgorithm::ES256);
+
+		for authorization in ["", "   "] {
+			let request = actix_web::test::TestRequest::default()
+				.insert_header((header::AUTHORIZATION, authorization))
+				.to_http_request();
+			match verify_header(request.headers().get("authorization"), &key) {
+				HeaderResult::Error(json, code) => {
+					assert_eq!(code, http::StatusCode::BAD_REQUEST);
+					match json.into_inner() {
+						ApiResponse::ApiError {
+							message: _,
+							error_code,
+						} => assert_eq!(error_code, "BAD_HEADER"),
+						_ => panic!(),
+					}
 				}
+				_ => panic!(),
 			}
-			_ => assert!(false),
 		}
 	}
 
 	#[test]
 	fn test_no_header() {
 		// Create a key
-		let key: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"not_used").unwrap();
+		let key = KeyRing::ephemeral(KeyAlgorithm::ES256);
 
 		// Test no header
 		let res = verify_header(None, &key);
@@ -323,10 +552,10 @@ mod tests {
 					} => {
 						assert_eq!(error_code, "NOT_AUTHENTICATED");
 					}
-					_ => assert!(false),
+					_ => panic!(),
 				}
 			}
-			_ => assert!(false),
+			_ => panic!(),
 		}
 	}
 
@@ -334,16 +563,11 @@ mod tests {
 	fn test_bad_formatting() {
 		let uid = "6755d7b1-38f2-4a3a-b872-98d0e7bbd1ee";
 
-		// Create Hmac key
-		let key: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"a_very_long_secret_key").unwrap();
+		// Create a key ring
+		let key = KeyRing::ephemeral(KeyAlgorithm::ES256);
 
 		// Create a good at
-		let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
-		claims.insert("iss", "TurboCore");
-		claims.insert("type", "at");
-		claims.insert("uid", uid);
-		claims.insert("exp", "9999999999");
-		let at = claims.sign_with_key(&key).unwrap();
+		let at = access_token(&key, uid, Duration::minutes(15));
 
 		// Create the request and pull the headers
 		let request = actix_web::test::TestRequest::default()
@@ -365,10 +589,10 @@ mod tests {
 					} => {
 						assert_eq!(error_code, "BAD_HEADER");
 					}
-					_ => assert!(false),
+					_ => panic!(),
 				}
 			}
-			_ => assert!(false),
+			_ => panic!(),
 		}
 	}
 
@@ -376,16 +600,11 @@ mod tests {
 	pub fn test_not_bearer() {
 		let uid = "6755d7b1-38f2-4a3a-b872-98d0e7bbd1ee";
 
-		// Create Hmac key
-		let key: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"a_very_long_secret_key").unwrap();
+		// Create a key ring
+		let key = KeyRing::ephemeral(KeyAlgorithm::ES256);
 
 		// Create a good at
-		let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
-		claims.insert("iss", "TurboCore");
-		claims.insert("type", "at");
-		claims.insert("uid", uid);
-		claims.insert("exp", "9999999999");
-		let at = claims.sign_with_key(&key).unwrap();
+		let at = access_token(&key, uid, Duration::minutes(15));
 
 		// Create the request and pull the headers
 		let request = actix_web::test::TestRequest::default()
@@ -407,10 +626,10 @@ mod tests {
 					} => {
 						assert_eq!(error_code, "BAD_HEADER");
 					}
-					_ => assert!(false),
+					_ => panic!(),
 				}
 			}
-			_ => assert!(false),
+			_ => panic!(),
 		}
 	}
 
@@ -418,20 +637,11 @@ mod tests {
 	pub fn expired_token() {
 		let uid = "6755d7b1-38f2-4a3a-b872-98d0e7bbd1ee";
 
-		// Create Hmac key
-		let key: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"a_very_long_secret_key").unwrap();
+		// Create a key ring
+		let key = KeyRing::ephemeral(KeyAlgorithm::ES256);
 
-		// Get the current time
-		let now = Utc::now().timestamp() - 16; // 16 seconds ago
-		let now = now.to_string();
-
-		// Create a good at
-		let mut claims: BTreeMap<&str, &str> = BTreeMap::new();
-		claims.insert("iss", "TurboCore");
-		claims.insert("type", "at");
-		claims.insert("uid", uid);
-		claims.insert("exp", &now);
-		let at = claims.sign_with_key(&key).unwrap();
+		// Create an at that expired 16 seconds ago
+		let at = access_token(&key, uid, Duration::seconds(-16));
 
 		// Create the request and pull the headers
 		let request = actix_web::test::TestRequest::default()
@@ -453,10 +663,10 @@ mod tests {
 					} => {
 						assert_eq!(error_code, "EXPIRED_TOKEN");
 					}
-					_ => assert!(false),
+					_ => panic!(),
 				}
 			}
-			_ => assert!(false),
+			_ => panic!(),
 		}
 	}
 }
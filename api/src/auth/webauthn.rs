@@ -0,0 +1,321 @@
+//! Verification of WebAuthn registration and authentication ceremonies.
+//!
+//! TurboCore requests no attestation, so only the "none" and self-attested "packed" formats are
+//! accepted. Credential keys may be ES256 or RS256, which covers platform and roaming authenticators.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::value::Value;
+use p256::ecdsa::{signature::Verifier, Signature as EcdsaSignature, VerifyingKey};
+use rsa::{pkcs1v15, BigUint, RsaPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// COSE algorithm identifier for ECDSA with P-256 and SHA-256
+pub const ES256: i64 = -7;
+/// COSE algorithm identifier for RSASSA-PKCS1-v1_5 with SHA-256
+pub const RS256: i64 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WebauthnError {
+	InvalidClientData,
+	ChallengeMismatch,
+	OriginMismatch,
+	InvalidAuthenticatorData,
+	RpIdMismatch,
+	UserNotPresent,
+	UnsupportedAttestation,
+	UnsupportedAlgorithm,
+	InvalidSignature,
+	CounterRegression,
+}
+
+impl WebauthnError {
+	pub fn message(&self) -> &'static str {
+		match self {
+			WebauthnError::InvalidClientData => "The client data is invalid.",
+			WebauthnError::ChallengeMismatch => "The challenge does not match.",
+			WebauthnError::OriginMismatch => "The origin is not allowed.",
+			WebauthnError::InvalidAuthenticatorData => "The authenticator data is invalid.",
+			WebauthnError::RpIdMismatch => "The credential was created for another relying party.",
+			WebauthnError::UserNotPresent => "The authenticator did not confirm user presence.",
+			WebauthnError::UnsupportedAttestation => "The attestation format is not supported.",
+			WebauthnError::UnsupportedAlgorithm => "The credential algorithm is not supported.",
+			WebauthnError::InvalidSignature => "The signature could not be verified.",
+			WebauthnError::CounterRegression => {
+				"The signature counter went backwards. The authenticator may have been cloned."
+			}
+		}
+	}
+}
+
+/// A credential that passed registration, ready to be stored
+#[derive(Debug)]
+pub struct RegisteredCredential {
+	pub credential_id: Vec<u8>,
+	/// The credential public key, as the COSE_Key bytes produced by the authenticator
+	pub public_key: Vec<u8>,
+	pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+	#[serde(rename = "type")]
+	ceremony: String,
+	challenge: String,
+	origin: String,
+}
+
+struct AuthenticatorData {
+	rp_id_hash: Vec<u8>,
+	flags: u8,
+	sign_count: u32,
+	credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Verifies the response to `navigator.credentials.create()`
+pub fn verify_registration(
+	client_data_json: &[u8],
+	attestation_object: &[u8],
+	challenge: &str,
+	rp_id: &str,
+	origins: &[String],
+) -> Result<RegisteredCredential, WebauthnError> {
+	check_client_data(client_data_json, "webauthn.create", challenge, origins)?;
+
+	let attestation: Value = ciborium::de::from_reader(attestation_object)
+		.map_err(|_| WebauthnError::InvalidAuthenticatorData)?;
+	let fmt = map_get(&attestation, &Value::Text("fmt".to_string()))
+		.and_then(Value::as_text)
+		.ok_or(WebauthnError::InvalidAuthenticatorData)?;
+	let auth_data_bytes = map_get(&attestation, &Value::Text("authData".to_string()))
+		.and_then(Value::as_bytes)
+		.ok_or(WebauthnError::InvalidAuthenticatorData)?;
+	let att_stmt = map_get(&attestation, &Value::Text("attStmt".to_string()))
+		.ok_or(WebauthnError::InvalidAuthenticatorData)?;
+
+	let auth_data = parse_authenticator_data(auth_data_bytes)?;
+	check_authenticator_data(&auth_data, rp_id)?;
+	let (credential_id, public_key) = auth_data
+		.credential
+		.ok_or(WebauthnError::InvalidAuthenticatorData)?;
+
+	match fmt {
+		"none" => (),
+		"packed" => {
+			// Only self attestation is supported, where the credential signs its own creation
+			if map_get(att_stmt, &Value::Text("x5c".to_string())).is_some() {
+				return Err(WebauthnError::UnsupportedAttestation);
+			}
+			let alg = map_get(att_stmt, &Value::Text("alg".to_string()))
+				.and_then(as_i64)
+				.ok_or(WebauthnError::UnsupportedAttestation)?;
+			let sig = map_get(att_stmt, &Value::Text("sig".to_string()))
+				.and_then(Value::as_bytes)
+				.ok_or(WebauthnError::UnsupportedAttestation)?;
+			if alg != credential_algorithm(&public_key)? {
+				return Err(WebauthnError::UnsupportedAttestation);
+			}
+			verify_signature(&public_key, auth_data_bytes, client_data_json, sig)?;
+		}
+		_ => return Err(WebauthnError::UnsupportedAttestation),
+	}
+
+	// Make sure the key can be used before storing it
+	credential_algorithm(&public_key)?;
+
+	Ok(RegisteredCredential {
+		credential_id,
+		public_key,
+		sign_count: auth_data.sign_count,
+	})
+}
+
+/// Verifies the response to `navigator.credentials.get()` and returns the new signature counter
+#[allow(clippy::too_many_arguments)]
+pub fn verify_assertion(
+	client_data_json: &[u8],
+	authenticator_data: &[u8],
+	signature: &[u8],
+	public_key: &[u8],
+	stored_sign_count: u32,
+	challenge: &str,
+	rp_id: &str,
+	origins: &[String],
+) -> Result<u32, WebauthnError> {
+	check_client_data(client_data_json, "webauthn.get", challenge, origins)?;
+
+	let auth_data = parse_authenticator_data(authenticator_data)?;
+	check_authenticator_data(&auth_data, rp_id)?;
+
+	verify_signature(public_key, authenticator_data, client_data_json, signature)?;
+
+	// Authenticators that don't implement a counter always report 0
+	if (auth_data.sign_count != 0 || stored_sign_count != 0)
+		&& auth_data.sign_count <= stored_sign_count
+	{
+		return Err(WebauthnError::CounterRegression);
+	}
+
+	Ok(auth_data.sign_count)
+}
+
+/// Generates a random challenge, encoded as base64url
+pub fn generate_challenge() -> String {
+	let bytes: [u8; 32] = rand::random();
+	URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Decodes base64url, with or without padding, as sent by browsers
+pub fn decode_base64url(input: &str) -> Option<Vec<u8>> {
+	URL_SAFE_NO_PAD.decode(input.trim_end_matches('=')).ok()
+}
+
+pub fn encode_base64url(input: &[u8]) -> String {
+	URL_SAFE_NO_PAD.encode(input)
+}
+
+fn check_client_data(
+	client_data_json: &[u8],
+	ceremony: &str,
+	challenge: &str,
+	origins: &[String],
+) -> Result<(), WebauthnError> {
+	let client_data: ClientData =
+		serde_json::from_slice(client_data_json).map_err(|_| WebauthnError::InvalidClientData)?;
+	if client_data.ceremony != ceremony {
+		return Err(WebauthnError::InvalidClientData);
+	}
+	if client_data.challenge.trim_end_matches('=') != challenge {
+		return Err(WebauthnError::ChallengeMismatch);
+	}
+	if !origins.contains(&client_data.origin) {
+		return Err(WebauthnError::OriginMismatch);
+	}
+	Ok(())
+}
+
+fn check_authenticator_data(
+	auth_data: &AuthenticatorData,
+	rp_id: &str,
+) -> Result<(), WebauthnError> {
+	if auth_data.rp_id_hash[..] != Sha256::digest(rp_id.as_bytes())[..] {
+		return Err(WebauthnError::RpIdMismatch);
+	}
+	if auth_data.flags & FLAG_USER_PRESENT == 0 {
+		return Err(WebauthnError::UserNotPresent);
+	}
+	Ok(())
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, WebauthnError> {
+	if data.len() < 37 {
+		return Err(WebauthnError::InvalidAuthenticatorData);
+	}
+	let flags = data[32];
+	let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+	let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+		// 16 bytes of AAGUID, then a 2 byte length, the credential id, and the COSE key
+		let rest = data
+			.get(37 + 16..)
+			.ok_or(WebauthnError::InvalidAuthenticatorData)?;
+		if rest.len() < 2 {
+			return Err(WebauthnError::InvalidAuthenticatorData);
+		}
+		let id_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+		let credential_id = rest
+			.get(2..2 + id_len)
+			.ok_or(WebauthnError::InvalidAuthenticatorData)?
+			.to_vec();
+		let key_bytes = &rest[2 + id_len..];
+
+		// The key is followed by optional extensions, so decode it to find where it ends
+		let mut cursor = std::io::Cursor::new(key_bytes);
+		let _key: Value = ciborium::de::from_reader(&mut cursor)
+			.map_err(|_| WebauthnError::InvalidAuthenticatorData)?;
+		let key_len = cursor.position() as usize;
+
+		Some((credential_id, key_bytes[..key_len].to_vec()))
+	} else {
+		None
+	};
+
+	Ok(AuthenticatorData {
+		rp_id_hash: data[..32].to_vec(),
+		flags,
+		sign_count,
+		credential,
+	})
+}
+
+/// Returns the COSE algorithm of the key, if it is supported
+fn credential_algorithm(public_key: &[u8]) -> Result<i64, WebauthnError> {
+	let key: Value =
+		ciborium::de::from_reader(public_key).map_err(|_| WebauthnError::UnsupportedAlgorithm)?;
+	match map_get(&key, &Value::Integer(3.into())).and_then(as_i64) {
+		Some(alg) if alg == ES256 || alg == RS256 => Ok(alg),
+		_ => Err(WebauthnError::UnsupportedAlgorithm),
+	}
+}
+
+/// Verifies a signature over `authenticator_data || SHA-256(client_data_json)`
+fn verify_signature(
+	public_key: &[u8],
+	authenticator_data: &[u8],
+	client_data_json: &[u8],
+	signature: &[u8],
+) -> Result<(), WebauthnError> {
+	let key: Value =
+		ciborium::de::from_reader(public_key).map_err(|_| WebauthnError::UnsupportedAlgorithm)?;
+	let param = |label: i64| map_get(&key, &Value::Integer(label.into()));
+
+	let mut message = authenticator_data.to_vec();
+	message.extend_from_slice(&Sha256::digest(client_data_json));
+
+	match credential_algorithm(public_key)? {
+		ES256 => {
+			let x = param(-2).and_then(Value::as_bytes);
+			let y = param(-3).and_then(Value::as_bytes);
+			let (x, y) = match (x, y) {
+				(Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => (x, y),
+				_ => return Err(WebauthnError::UnsupportedAlgorithm),
+			};
+			let mut point = vec![0x04];
+			point.extend_from_slice(x);
+			point.extend_from_slice(y);
+			let key = VerifyingKey::from_sec1_bytes(&point)
+				.map_err(|_| WebauthnError::UnsupportedAlgorithm)?;
+			let signature =
+				EcdsaSignature::from_der(signature).map_err(|_| WebauthnError::InvalidSignature)?;
+			key.verify(&message, &signature)
+				.map_err(|_| WebauthnError::InvalidSignature)
+		}
+		RS256 => {
+			let n = param(-1).and_then(Value::as_bytes);
+			let e = param(-2).and_then(Value::as_bytes);
+			let (n, e) = match (n, e) {
+				(Some(n), Some(e)) => (n, e),
+				_ => return Err(WebauthnError::UnsupportedAlgorithm),
+			};
+			let key = RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e))
+				.map_err(|_| WebauthnError::UnsupportedAlgorithm)?;
+			let key = pkcs1v15::VerifyingKey::<Sha256>::new(key);
+			let signature = pkcs1v15::Signature::try_from(signature)
+				.map_err(|_| WebauthnError::InvalidSignature)?;
+			key.verify(&message, &signature)
+				.map_err(|_| WebauthnError::InvalidSignature)
+		}
+		_ => Err(WebauthnError::UnsupportedAlgorithm),
+	}
+}
+
+fn map_get<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+	map.as_map()?.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+fn as_i64(value: &Value) -> Option<i64> {
+	value.as_integer().and_then(|i| i64::try_from(i).ok())
+}
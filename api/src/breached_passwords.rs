@@ -0,0 +1,336 @@
+//! Checks passwords against the ones leaked in known breaches, such as the Have I Been Pwned dataset.
+//!
+//! Passwords are looked up by their SHA-1 hash. A local dataset keeps every lookup on the server. The
+//! range API only ever sees the first 5 characters of a hash, and returns every suffix that starts with
+//! them, so it never learns which password was checked.
+
+use std::{
+	fmt,
+	fs::File,
+	io::{self, BufRead, BufReader},
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
+
+use futures::future::BoxFuture;
+use sha1::{Digest, Sha1};
+
+use crate::{BreachedPasswordAction, BreachedPasswordConfig, BreachedPasswordSource};
+
+/// The length of the hash prefix sent to the range API, and used to name the files of a range directory
+const PREFIX_LENGTH: usize = 5;
+
+lazy_static! {
+	static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+		.timeout(Duration::from_secs(5))
+		.build()
+		.unwrap();
+}
+
+#[derive(Debug)]
+pub enum BreachError {
+	Io(io::Error),
+	Request(reqwest::Error),
+}
+
+impl fmt::Display for BreachError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			BreachError::Io(e) => write!(f, "Unable to read the breached password dataset: {e}"),
+			BreachError::Request(e) => write!(f, "Unable to query the breached password API: {e}"),
+		}
+	}
+}
+
+/// A source of breached passwords
+pub trait BreachChecker: Send + Sync {
+	fn is_breached<'a>(&'a self, password: &'a str) -> BoxFuture<'a, Result<bool, BreachError>>;
+}
+
+/// The breached password checker, and what to do with the passwords it finds
+#[derive(Clone)]
+pub struct BreachedPasswords {
+	checker: Arc<dyn BreachChecker>,
+	pub action: BreachedPasswordAction,
+}
+
+impl fmt::Debug for BreachedPasswords {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("BreachedPasswords")
+			.field("action", &self.action)
+			.finish()
+	}
+}
+
+impl BreachedPasswords {
+	pub fn new(checker: Arc<dyn BreachChecker>, action: BreachedPasswordAction) -> Self {
+		Self { checker, action }
+	}
+
+	/// Loads the configured source. Local datasets are read in full, so this can take a while.
+	pub fn load(config: &BreachedPasswordConfig) -> io::Result<Self> {
+		let checker: Arc<dyn BreachChecker> = match &config.source {
+			BreachedPasswordSource::HashList { path } => {
+				Arc::new(HashList::load(path, config.min_count)?)
+			}
+			BreachedPasswordSource::BloomFilter {
+				path,
+				false_positive_rate,
+			} => Arc::new(BloomFilter::load(path, config.min_count, *false_positive_rate)?),
+			BreachedPasswordSource::RangeDirectory { path } => Arc::new(RangeDirectory {
+				path: PathBuf::from(path),
+				min_count: config.min_count,
+			}),
+			BreachedPasswordSource::RangeApi { url } => Arc::new(RangeApi {
+				url: url.to_owned(),
+				min_count: config.min_count,
+			}),
+		};
+		Ok(Self::new(checker, config.action))
+	}
+
+	pub async fn is_breached(&self, password: &str) -> Result<bool, BreachError> {
+		self.checker.is_breached(password).await
+	}
+}
+
+fn sha1_hex(password: &str) -> String {
+	Sha1::digest(password.as_bytes())
+		.iter()
+		.map(|b| format!("{b:02X}"))
+		.collect()
+}
+
+/// Parses a `HASH:COUNT` line. Lines without a count are counted once.
+fn parse_line(line: &str) -> Option<(&str, u64)> {
+	let line = line.trim();
+	match line.split_once(':') {
+		Some((hash, count)) => Some((hash, count.trim().parse().ok()?)),
+		None if !line.is_empty() => Some((line, 1)),
+		None => None,
+	}
+}
+
+fn parse_hash(hex: &str) -> Option<[u8; 20]> {
+	if hex.len() != 40 {
+		return None;
+	}
+	let mut hash = [0; 20];
+	for (i, byte) in hash.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+	}
+	Some(hash)
+}
+
+/// Reads the hashes seen at least `min_count` times from a file of `HASH:COUNT` lines
+fn read_hashes(
+	path: &str,
+	min_count: u64,
+) -> io::Result<impl Iterator<Item = io::Result<[u8; 20]>>> {
+	let reader = BufReader::new(File::open(path)?);
+	Ok(reader.lines().filter_map(move |line| match line {
+		Ok(line) => match parse_line(&line) {
+			Some((hash, count)) if count >= min_count => parse_hash(hash).map(Ok),
+			_ => None,
+		},
+		Err(e) => Some(Err(e)),
+	}))
+}
+
+/// Every hash of a local dataset, kept sorted in memory. Takes 20 bytes per password.
+pub struct HashList {
+	hashes: Vec<[u8; 20]>,
+}
+
+impl HashList {
+	pub fn load(path: &str, min_count: u64) -> io::Result<Self> {
+		let mut hashes = read_hashes(path, min_count)?.collect::<io::Result<Vec<_>>>()?;
+		hashes.sort_unstable();
+		hashes.dedup();
+		Ok(Self { hashes })
+	}
+}
+
+impl BreachChecker for HashList {
+	fn is_breached<'a>(&'a self, password: &'a str) -> BoxFuture<'a, Result<bool, BreachError>> {
+		let hash: [u8; 20] = Sha1::digest(password.as_bytes()).into();
+		Box::pin(async move { Ok(self.hashes.binary_search(&hash).is_ok()) })
+	}
+}
+
+/// A bloom filter built from a local dataset. It takes a small part of the memory of a `HashList`, but
+/// reports a few passwords that were never breached, at about the configured false positive rate.
+pub struct BloomFilter {
+	bits: Vec<u64>,
+	hashes: u32,
+}
+
+impl BloomFilter {
+	/// Builds the filter from a file of `HASH:COUNT` lines, which is read twice
+	pub fn load(path: &str, min_count: u64, false_positive_rate: f64) -> io::Result<Self> {
+		let mut entries = 0u64;
+		for hash in read_hashes(path, min_count)? {
+			hash?;
+			entries += 1;
+		}
+
+		let mut filter = Self::with_capacity(entries, false_positive_rate);
+		for hash in read_hashes(path, min_count)? {
+			filter.insert(&hash?);
+		}
+		Ok(filter)
+	}
+
+	pub fn with_capacity(entries: u64, false_positive_rate: f64) -> Self {
+		let entries = entries.max(1) as f64;
+		let ln2 = std::f64::consts::LN_2;
+		let bits = (-entries * false_positive_rate.ln() / (ln2 * ln2))
+			.ceil()
+			.max(64.0);
+		let hashes = (bits / entries * ln2).round().max(1.0) as u32;
+		Self {
+			bits: vec![0; (bits as usize).div_ceil(64)],
+			hashes,
+		}
+	}
+
+	/// The bits for a hash. SHA-1 is already uniform, so two halves of it are combined into each index.
+	fn indexes(&self, hash: &[u8; 20]) -> impl Iterator<Item = usize> {
+		let first = u64::from_be_bytes(hash[0..8].try_into().unwrap());
+		let second = u64::from_be_bytes(hash[8..16].try_into().unwrap());
+		let len = self.bits.len() as u64 * 64;
+		(0..self.hashes as u64)
+			.map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+	}
+
+	pub fn insert(&mut self, hash: &[u8; 20]) {
+		for index in self.indexes(hash).collect::<Vec<_>>() {
+			self.bits[index / 64] |= 1 << (index % 64);
+		}
+	}
+
+	pub fn contains(&self, hash: &[u8; 20]) -> bool {
+		self.indexes(hash)
+			.all(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+	}
+}
+
+impl BreachChecker for BloomFilter {
+	fn is_breached<'a>(&'a self, password: &'a str) -> BoxFuture<'a, Result<bool, BreachError>> {
+		let hash: [u8; 20] = Sha1::digest(password.as_bytes()).into();
+		Box::pin(async move { Ok(self.contains(&hash)) })
+	}
+}
+
+/// Finds the suffix of the hash in a range of `SUFFIX:COUNT` lines
+fn range_contains(range: &str, suffix: &str, min_count: u64) -> bool {
+	range
+		.lines()
+		.filter_map(parse_line)
+		.any(|(candidate, count)| count >= min_count && candidate.eq_ignore_ascii_case(suffix))
+}
+
+/// A local copy of the range API: a directory with a file for each hash prefix, such as `21BD1` or
+/// `21BD1.txt`, holding the `SUFFIX:COUNT` lines the API would return. Files are read when needed, so
+/// the dataset is not kept in memory.
+pub struct RangeDirectory {
+	path: PathBuf,
+	min_count: u64,
+}
+
+impl RangeDirectory {
+	fn read_range(directory: &Path, prefix: &str) -> io::Result<String> {
+		match std::fs::read_to_string(directory.join(prefix)) {
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				match std::fs::read_to_string(directory.join(format!("{prefix}.txt"))) {
+					// A missing range has no breached passwords
+					Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+					res => res,
+				}
+			}
+			res => res,
+		}
+	}
+}
+
+impl BreachChecker for RangeDirectory {
+	fn is_breached<'a>(&'a self, password: &'a str) -> BoxFuture<'a, Result<bool, BreachError>> {
+		let hash = sha1_hex(password);
+		let directory = self.path.to_owned();
+		Box::pin(async move {
+			let prefix = hash[..PREFIX_LENGTH].to_owned();
+			let range = actix_web::web::block(move || Self::read_range(&directory, &prefix))
+				.await
+				.map_err(|e| BreachError::Io(io::Error::other(e)))?
+				.map_err(BreachError::Io)?;
+			Ok(range_contains(&range, &hash[PREFIX_LENGTH..], self.min_count))
+		})
+	}
+}
+
+/// A k-anonymity range API, such as `https://api.pwnedpasswords.com/range/`. The hash prefix is added to
+/// the end of the URL.
+pub struct RangeApi {
+	url: String,
+	min_count: u64,
+}
+
+impl BreachChecker for RangeApi {
+	fn is_breached<'a>(&'a self, password: &'a str) -> BoxFuture<'a, Result<bool, BreachError>> {
+		let hash = sha1_hex(password);
+		Box::pin(async move {
+			// Padding hides the size of the response. Padded entries have a count of 0.
+			let range = HTTP_CLIENT
+				.get(format!("{}{}", self.url, &hash[..PREFIX_LENGTH]))
+				.header("Add-Padding", "true")
+				.send()
+				.await
+				.and_then(|res| res.error_for_status())
+				.map_err(BreachError::Request)?
+				.text()
+				.await
+				.map_err(BreachError::Request)?;
+			Ok(range_contains(&range, &hash[PREFIX_LENGTH..], self.min_count.max(1)))
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hash(password: &str) -> [u8; 20] {
+		Sha1::digest(password.as_bytes()).into()
+	}
+
+	#[test]
+	fn test_sha1_hex() {
+		assert_eq!(sha1_hex("password"), "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8");
+		assert_eq!(parse_hash(&sha1_hex("password")), Some(hash("password")));
+	}
+
+	#[test]
+	fn test_range_contains() {
+		let range =
+			"1E4C9B93F3F0682250B6CF8331B7EE68FD8:3\r\n0000000000000000000000000000000000A:0";
+		assert!(range_contains(range, "1E4C9B93F3F0682250B6CF8331B7EE68FD8", 1));
+		assert!(!range_contains(range, "1E4C9B93F3F0682250B6CF8331B7EE68FD8", 4));
+		// Padding is never a match
+		assert!(!range_contains(range, "0000000000000000000000000000000000A", 1));
+	}
+
+	#[test]
+	fn test_bloom_filter() {
+		let mut filter = BloomFilter::with_capacity(1000, 0.001);
+		for i in 0..1000 {
+			filter.insert(&hash(&format!("breached{i}")));
+		}
+		assert!((0..1000).all(|i| filter.contains(&hash(&format!("breached{i}")))));
+
+		let false_positives = (0..10_000)
+			.filter(|i| filter.contains(&hash(&format!("safe{i}"))))
+			.count();
+		assert!(false_positives < 50);
+	}
+}
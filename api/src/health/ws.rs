@@ -1,18 +1,18 @@
 // Adapted from: https://github.com/actix/examples/blob/6571dfef82248a7172729e280bf4150aa12a8d49/websockets/echo-actorless/src/handler.rs
-use std::{
-	collections::BTreeMap,
-	time::{Duration, Instant},
-};
+use std::time::{Duration, Instant};
 
-use crate::{health::sysinfo::sysinfo as sysinfo_fn, AppState};
+use crate::{
+	auth::claims::{self, AccessClaims, TokenError},
+	health::sysinfo::sysinfo as sysinfo_fn,
+	keys::KeyRing,
+	AppState,
+};
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_ws::{Message, Session};
-use chrono::Utc;
 use futures_util::{
 	future::{self, Either},
 	StreamExt as _,
 };
-use jwt::VerifyWithKey;
 use log::debug;
 use sysinfo::{System, SystemExt};
 use tokio::{pin, task::spawn_local, time::interval};
@@ -51,7 +51,7 @@ pub async fn sysinfo(
 					Message::Text(text) => {
 						let text = text.to_string();
 						let text = text.as_str();
-						if handle_command(&mut data, &mut session, text, &state.config.secret_key)
+						if handle_command(&mut data, &mut session, text, &state.key_ring)
 							.await
 							.is_err()
 						{
@@ -129,7 +129,7 @@ async fn handle_command(
 	data: &mut WSData,
 	session: &mut Session,
 	msg: &str,
-	secret_key: &hmac::Hmac<sha2::Sha256>,
+	key_ring: &KeyRing,
 ) -> Result<(), String> {
 	let mut cmds = msg.split_whitespace();
 	let cmd = cmds.next();
@@ -138,22 +138,22 @@ async fn handle_command(
 		if cmd == "auth" {
 			let key = cmds.next();
 			if let Some(key) = key {
-				let claims: BTreeMap<String, String> = match key.verify_with_key(secret_key) {
-					Ok(claims) => claims,
-					Err(_) => {
+				// Confirm AT and not expired
+				match claims::verify::<AccessClaims>(key, key_ring) {
+					Ok(_) => {
+						data.auth = true;
+						session.text("ok").await.unwrap();
+						return Ok(());
+					}
+					Err(TokenError::Invalid) => {
 						session.text("invalid key").await.unwrap();
 						return Err("invalid key".to_string());
 					}
-				};
-				// Confirm AT and not expired
-				if claims["type"] == "at" && Utc::now().timestamp() < claims["exp"].parse().unwrap()
-				{
-					data.auth = true;
-					session.text("ok").await.unwrap();
-					return Ok(());
+					Err(_) => {
+						session.text("not authorized").await.unwrap();
+						return Err("not authorized".to_string());
+					}
 				}
-				session.text("not authorized").await.unwrap();
-				return Err("not authorized".to_string());
 			}
 			session.text("not authorized").await.unwrap();
 			return Err("not authorized".to_string());
@@ -0,0 +1,240 @@
+use crate::{
+	auth::{
+		api_error,
+		claims::{self, OidcRequestClaims, RegisteredClaims, TokenClaims},
+		oidc,
+		util::{self, HeaderResult},
+		ApiResponse,
+	},
+	idp::{find_client, not_configured},
+	AppState,
+};
+use actix_web::{
+	get, http, post,
+	web::{Data, Json, Query},
+	Either, HttpResponse, Responder,
+};
+use chrono::{Duration, Utc};
+use entity::{oidc_auth_codes, users};
+use log::error;
+use reqwest::Url;
+use sea_orm::{ActiveModelTrait, EntityTrait, Set};
+use serde::Deserialize;
+
+/// How long the user has to log in before the authorization request expires, in minutes
+const REQUEST_TIMEOUT: i64 = 10;
+/// How long the client has to exchange the authorization code, in minutes
+const CODE_TIMEOUT: i64 = 5;
+
+#[derive(Deserialize)]
+pub struct AuthorizeQuery {
+	response_type: Option<String>,
+	client_id: Option<String>,
+	redirect_uri: Option<String>,
+	scope: Option<String>,
+	state: Option<String>,
+	nonce: Option<String>,
+	code_challenge: Option<String>,
+	code_challenge_method: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CompleteBody {
+	request: String,
+}
+
+/// Validates the authorization request and sends the user to the login page.
+/// Until the redirect URI is known to belong to the client, errors are returned as JSON instead of
+/// redirecting, see RFC 6749 section 4.1.2.1.
+#[get("/authorize")]
+pub async fn authorize_handler(
+	data: Data<AppState>,
+	query: Query<AuthorizeQuery>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	let idp = match data.config.identity_provider {
+		Some(ref idp) => idp,
+		None => return Either::Left(not_configured()),
+	};
+
+	let client = match query.client_id.as_ref().and_then(|id| find_client(idp, id)) {
+		Some(client) => client,
+		None => {
+			return Either::Left((
+				Json(api_error(
+					"The client_id is not registered.".to_string(),
+					"INVALID_CLIENT".to_string(),
+				)),
+				http::StatusCode::BAD_REQUEST,
+			))
+		}
+	};
+	let redirect_uri = match query.redirect_uri {
+		Some(ref uri) if client.redirect_uris.contains(uri) => uri,
+		_ => {
+			return Either::Left((
+				Json(api_error(
+					"The redirect_uri is not registered for this client.".to_string(),
+					"INVALID_REDIRECT_URI".to_string(),
+				)),
+				http::StatusCode::BAD_REQUEST,
+			))
+		}
+	};
+	let state = query.state.clone().unwrap_or_default();
+
+	if query.response_type.as_deref() != Some("code") {
+		return Either::Right(error_redirect(redirect_uri, "unsupported_response_type", &state));
+	}
+	let scope = query.scope.clone().unwrap_or_default();
+	if !scope.split(' ').any(|s| s == "openid") {
+		return Either::Right(error_redirect(redirect_uri, "invalid_scope", &state));
+	}
+	// PKCE is required for every client, see the OAuth 2.0 Security Best Current Practice
+	let code_challenge = match query.code_challenge {
+		Some(ref challenge) if query.code_challenge_method.as_deref() == Some("S256") => challenge,
+		_ => return Either::Right(error_redirect(redirect_uri, "invalid_request", &state)),
+	};
+
+	let claims = OidcRequestClaims {
+		registered: RegisteredClaims::new(
+			OidcRequestClaims::AUDIENCE,
+			Duration::minutes(REQUEST_TIMEOUT),
+		),
+		client_id: client.client_id.to_owned(),
+		redirect_uri: redirect_uri.to_owned(),
+		scope,
+		state: query.state.clone(),
+		nonce: query.nonce.clone(),
+		code_challenge: code_challenge.to_owned(),
+	};
+	let request = claims::sign(&claims, &data.config.secret_key);
+
+	// login_url is validated when the config is loaded
+	let login_url = Url::parse_with_params(&idp.login_url, &[("request", request)]).unwrap();
+
+	Either::Right(
+		HttpResponse::Found()
+			.append_header(("Location", login_url.to_string()))
+			.finish(),
+	)
+}
+
+/// Called by the login page once the user is logged in. Returns the URL to send the user back to the client.
+#[post("/api/auth/user/oidc/authorize")]
+pub async fn complete_handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	body: Json<CompleteBody>,
+) -> impl Responder {
+	let idp = match data.config.identity_provider {
+		Some(ref idp) => idp,
+		None => return not_configured(),
+	};
+
+	let header_map = request.headers();
+	let authorization = header_map.get("Authorization");
+
+	let uid = match util::verify_header(authorization, &data.key_ring) {
+		HeaderResult::Error(r, s) => return (r, s),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	let claims: OidcRequestClaims = match claims::verify(&body.request, &data.config.secret_key) {
+		Ok(claims) => claims,
+		Err(_) => return invalid_request(),
+	};
+
+	// The config may have changed since the request was made
+	let redirect_uri = &claims.redirect_uri;
+	match find_client(idp, &claims.client_id) {
+		Some(client) if client.redirect_uris.contains(redirect_uri) => (),
+		_ => return invalid_request(),
+	}
+
+	match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(user)) if user.active => (),
+		Ok(Some(_)) => {
+			return (
+				Json(api_error(
+					"The user has been disabled by an administrator.".to_string(),
+					"USER_DISABLED".to_string(),
+				)),
+				http::StatusCode::UNAUTHORIZED,
+			)
+		}
+		Ok(None) => {
+			return (
+				Json(api_error(
+					"The user was not found.".to_string(),
+					"USER_NOT_FOUND".to_string(),
+				)),
+				http::StatusCode::NOT_FOUND,
+			)
+		}
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return internal_error();
+		}
+	}
+
+	let code = oidc::random_token();
+	let auth_code = oidc_auth_codes::ActiveModel {
+		code_hash: Set(util::hash_secret(&code)),
+		client_id: Set(claims.client_id.to_owned()),
+		uid: Set(uid),
+		redirect_uri: Set(redirect_uri.to_owned()),
+		scope: Set(claims.scope.to_owned()),
+		nonce: Set(claims.nonce.to_owned()),
+		code_challenge: Set(claims.code_challenge.to_owned()),
+		expiry: Set(Utc::now().naive_utc() + Duration::minutes(CODE_TIMEOUT)),
+	};
+	if let Err(e) = auth_code.insert(&data.connection).await {
+		error!("Unable to save authorization code. Error: {}", e.to_string());
+		return internal_error();
+	}
+
+	let mut params = vec![("code", code.as_str())];
+	if let Some(ref state) = claims.state {
+		params.push(("state", state));
+	}
+	// Registered redirect URIs are absolute, so this can only fail if the config is invalid
+	let redirect_url = match Url::parse_with_params(redirect_uri, params) {
+		Ok(url) => url.to_string(),
+		Err(_) => return invalid_request(),
+	};
+
+	(Json(ApiResponse::RedirectResponse { redirect_url }), http::StatusCode::OK)
+}
+
+fn error_redirect(redirect_uri: &str, error: &str, state: &str) -> HttpResponse {
+	let mut params = vec![("error", error)];
+	if !state.is_empty() {
+		params.push(("state", state));
+	}
+	match Url::parse_with_params(redirect_uri, params) {
+		Ok(url) => HttpResponse::Found()
+			.append_header(("Location", url.to_string()))
+			.finish(),
+		Err(_) => HttpResponse::BadRequest().finish(),
+	}
+}
+
+fn invalid_request() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The authorization request is invalid or has expired.".to_string(),
+			"INVALID_REQUEST".to_string(),
+		)),
+		http::StatusCode::BAD_REQUEST,
+	)
+}
+
+fn internal_error() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"An internal server error occurred.".to_string(),
+			"INTERNAL_SERVER_ERROR".to_string(),
+		)),
+		http::StatusCode::INTERNAL_SERVER_ERROR,
+	)
+}
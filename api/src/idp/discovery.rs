@@ -0,0 +1,45 @@
+use crate::{
+	auth::ApiResponse,
+	idp::{issuer, not_configured},
+	AppState,
+};
+use actix_web::{
+	get, http,
+	web::{Data, Json},
+	Either, HttpResponse,
+};
+use serde_json::json;
+
+/// The discovery document, see OpenID Connect Discovery 1.0 section 3
+#[get("/.well-known/openid-configuration")]
+pub async fn configuration_handler(
+	data: Data<AppState>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	if data.config.identity_provider.is_none() {
+		return Either::Left(not_configured());
+	}
+	let issuer = issuer(&data.config);
+
+	Either::Right(HttpResponse::Ok().json(json!({
+		"issuer": issuer,
+		"authorization_endpoint": format!("{issuer}/authorize"),
+		"token_endpoint": format!("{issuer}/token"),
+		"userinfo_endpoint": format!("{issuer}/userinfo"),
+		"jwks_uri": format!("{issuer}/.well-known/jwks.json"),
+		"response_types_supported": ["code"],
+		"grant_types_supported": ["authorization_code"],
+		"subject_types_supported": ["public"],
+		"id_token_signing_alg_values_supported": [data.config.signing.algorithm.as_str()],
+		"scopes_supported": ["openid", "email"],
+		"token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+		"code_challenge_methods_supported": ["S256"],
+		"claims_supported": ["iss", "sub", "aud", "exp", "iat", "nonce", "email", "email_verified"],
+	})))
+}
+
+/// The public keys used to verify access and ID tokens. This is available even when the identity
+/// provider is disabled, so other services can verify access tokens.
+#[get("/.well-known/jwks.json")]
+pub async fn jwks_handler(data: Data<AppState>) -> HttpResponse {
+	HttpResponse::Ok().json(data.key_ring.jwks())
+}
@@ -0,0 +1,47 @@
+//! TurboCore as an OpenID Connect identity provider, so other services can "Sign in with TurboCore".
+//!
+//! Only the authorization code flow with PKCE is supported. TurboCore does not render a login page:
+//! `/authorize` sends the user to the configured `login_url` with a signed `request`, and that page
+//! completes the request with the user's access token once they have logged in.
+
+use actix_web::{http, web, web::Json};
+
+use crate::{
+	auth::{api_error, ApiResponse},
+	Config, IdentityProviderConfig, OidcClient,
+};
+
+pub mod authorize;
+pub mod discovery;
+pub mod token;
+pub mod userinfo;
+
+pub fn add_routes(cfg: &mut web::ServiceConfig) {
+	cfg.service(crate::idp::discovery::configuration_handler)
+		.service(crate::idp::discovery::jwks_handler)
+		.service(crate::idp::authorize::authorize_handler)
+		.service(crate::idp::authorize::complete_handler)
+		.service(crate::idp::token::handler)
+		.service(crate::idp::userinfo::handler);
+}
+
+/// The issuer identifier, which is also the base of every endpoint
+pub fn issuer(config: &Config) -> &str {
+	config.base_url.trim_end_matches('/')
+}
+
+pub fn find_client<'a>(idp: &'a IdentityProviderConfig, client_id: &str) -> Option<&'a OidcClient> {
+	idp.clients
+		.iter()
+		.find(|client| client.client_id == client_id)
+}
+
+pub fn not_configured() -> (Json<ApiResponse>, http::StatusCode) {
+	(
+		Json(api_error(
+			"The server is not configured as an identity provider.".to_string(),
+			"IDP_NOT_CONFIGURED".to_string(),
+		)),
+		http::StatusCode::NOT_FOUND,
+	)
+}
@@ -0,0 +1,208 @@
+use crate::{
+	auth::util::{self, get_at_and_rt, Session},
+	idp::{find_client, issuer, not_configured},
+	AppState,
+};
+use actix_web::{
+	http::{self, header::HeaderMap},
+	post,
+	web::{Data, Form},
+	HttpResponse,
+};
+use base64::{
+	engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+	Engine,
+};
+use chrono::Utc;
+use entity::{oidc_auth_codes, users};
+use log::error;
+use sea_orm::EntityTrait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+	grant_type: String,
+	code: Option<String>,
+	redirect_uri: Option<String>,
+	client_id: Option<String>,
+	client_secret: Option<String>,
+	code_verifier: Option<String>,
+}
+
+/// Errors from the token endpoint use the format from RFC 6749 section 5.2, so that OAuth libraries understand them
+#[derive(Serialize)]
+struct TokenError {
+	error: &'static str,
+	error_description: &'static str,
+}
+
+/// Exchanges an authorization code for an access token and an ID token
+#[post("/token")]
+pub async fn handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+	form: Form<TokenRequest>,
+) -> HttpResponse {
+	let idp = match data.config.identity_provider {
+		Some(ref idp) => idp,
+		None => {
+			let (body, status) = not_configured();
+			return HttpResponse::build(status).json(body.into_inner());
+		}
+	};
+
+	if form.grant_type != "authorization_code" {
+		return token_error(
+			http::StatusCode::BAD_REQUEST,
+			"unsupported_grant_type",
+			"Only the authorization_code grant is supported.",
+		);
+	}
+
+	// Clients may authenticate with HTTP Basic or with form parameters
+	let (client_id, client_secret) = match basic_credentials(request.headers()) {
+		Some((id, secret)) => (Some(id), Some(secret)),
+		None => (form.client_id.clone(), form.client_secret.clone()),
+	};
+	let client = match client_id.as_ref().and_then(|id| find_client(idp, id)) {
+		Some(client) => client,
+		None => return invalid_client(),
+	};
+	if let Some(ref expected) = client.client_secret {
+		// Compare hashes so the comparison does not leak the secret's length or prefix
+		let matches = client_secret
+			.is_some_and(|secret| util::hash_secret(&secret) == util::hash_secret(expected));
+		if !matches {
+			return invalid_client();
+		}
+	}
+
+	let code_hash = match form.code {
+		Some(ref code) => util::hash_secret(code),
+		None => return invalid_grant(),
+	};
+	let auth_code = match oidc_auth_codes::Entity::find_by_id(code_hash.clone())
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(auth_code)) => auth_code,
+		Ok(None) => return invalid_grant(),
+		Err(e) => {
+			error!("Unable to find authorization code. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().finish();
+		}
+	};
+	// Codes can only be used once. If another request deleted it first, it wins.
+	match oidc_auth_codes::Entity::delete_by_id(code_hash)
+		.exec(&data.connection)
+		.await
+	{
+		Ok(res) if res.rows_affected == 1 => (),
+		Ok(_) => return invalid_grant(),
+		Err(e) => {
+			error!("Unable to delete authorization code. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().finish();
+		}
+	}
+
+	let verifier_matches = form.code_verifier.as_ref().is_some_and(|verifier| {
+		URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) == auth_code.code_challenge
+	});
+	if auth_code.expiry < Utc::now().naive_utc()
+		|| auth_code.client_id != client.client_id
+		|| form.redirect_uri.as_ref() != Some(&auth_code.redirect_uri)
+		|| !verifier_matches
+	{
+		return invalid_grant();
+	}
+
+	let user = match users::Entity::find_by_id(auth_code.uid)
+		.one(&data.connection)
+		.await
+	{
+		Ok(Some(user)) if user.active => user,
+		Ok(_) => return invalid_grant(),
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			return HttpResponse::InternalServerError().finish();
+		}
+	};
+
+	let uid_str = user.uid.to_string();
+	let (at, _rt, exp) = get_at_and_rt(
+		&data.connection,
+		&uid_str,
+		&data.key_ring,
+		&data.config.tokens,
+		false,
+		Session::new(&request, &data.ua_parser),
+	)
+	.await;
+
+	let now = Utc::now().timestamp();
+	let mut claims = json!({
+		"iss": issuer(&data.config),
+		"sub": uid_str,
+		"aud": client.client_id,
+		"iat": now,
+		"exp": exp,
+	});
+	if let Some(nonce) = auth_code.nonce {
+		claims["nonce"] = json!(nonce);
+	}
+	if auth_code.scope.split(' ').any(|scope| scope == "email") {
+		claims["email"] = json!(user.email);
+		claims["email_verified"] = json!(user.email_verified);
+	}
+
+	HttpResponse::Ok()
+		.insert_header((http::header::CACHE_CONTROL, "no-store"))
+		.json(json!({
+			"access_token": at,
+			"token_type": "Bearer",
+			"expires_in": exp - now,
+			"id_token": data.key_ring.sign(&claims),
+			"scope": auth_code.scope,
+		}))
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+	let value = headers.get("Authorization")?.to_str().ok()?;
+	let encoded = value
+		.strip_prefix("Basic ")
+		.or_else(|| value.strip_prefix("basic "))?;
+	let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+	let (id, secret) = decoded.split_once(':')?;
+	Some((id.to_string(), secret.to_string()))
+}
+
+fn token_error(
+	status: http::StatusCode,
+	error: &'static str,
+	error_description: &'static str,
+) -> HttpResponse {
+	HttpResponse::build(status)
+		.insert_header((http::header::CACHE_CONTROL, "no-store"))
+		.json(TokenError {
+			error,
+			error_description,
+		})
+}
+
+fn invalid_client() -> HttpResponse {
+	token_error(
+		http::StatusCode::UNAUTHORIZED,
+		"invalid_client",
+		"The client could not be authenticated.",
+	)
+}
+
+fn invalid_grant() -> HttpResponse {
+	token_error(
+		http::StatusCode::BAD_REQUEST,
+		"invalid_grant",
+		"The authorization code is invalid, expired, or was issued to another client.",
+	)
+}
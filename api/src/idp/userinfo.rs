@@ -0,0 +1,59 @@
+use crate::{
+	auth::{
+		api_error,
+		util::{self, HeaderResult},
+		ApiResponse,
+	},
+	idp::not_configured,
+	AppState,
+};
+use actix_web::{
+	http, route,
+	web::{Data, Json},
+	Either, HttpResponse,
+};
+use entity::users;
+use log::error;
+use sea_orm::EntityTrait;
+use serde_json::json;
+
+/// Returns claims about the user the access token was issued to
+#[route("/userinfo", method = "GET", method = "POST")]
+pub async fn handler(
+	request: actix_web::HttpRequest,
+	data: Data<AppState>,
+) -> Either<(Json<ApiResponse>, http::StatusCode), HttpResponse> {
+	if data.config.identity_provider.is_none() {
+		return Either::Left(not_configured());
+	}
+
+	let header_map = request.headers();
+	let authorization = header_map.get("Authorization");
+
+	let uid = match util::verify_header(authorization, &data.key_ring) {
+		HeaderResult::Error(r, s) => return Either::Left((r, s)),
+		HeaderResult::Uid(uid) => uid,
+	};
+
+	match users::Entity::find_by_id(uid).one(&data.connection).await {
+		Ok(Some(user)) => Either::Right(HttpResponse::Ok().json(json!({
+			"sub": user.uid.to_string(),
+			"email": user.email,
+			"email_verified": user.email_verified,
+		}))),
+		Ok(None) => Either::Left((
+			Json(api_error("The user was not found.".to_string(), "USER_NOT_FOUND".to_string())),
+			http::StatusCode::NOT_FOUND,
+		)),
+		Err(e) => {
+			error!("Unable to find user. Error: {}", e.to_string());
+			Either::Left((
+				Json(api_error(
+					"An internal server error occurred.".to_string(),
+					"INTERNAL_SERVER_ERROR".to_string(),
+				)),
+				http::StatusCode::INTERNAL_SERVER_ERROR,
+			))
+		}
+	}
+}
@@ -0,0 +1,374 @@
+//! The key ring used to sign access, refresh and ID tokens.
+//!
+//! Tokens are signed with an asymmetric key and carry its id in the `kid` header, so other services can
+//! verify them with the public keys from `/.well-known/jwks.json` instead of holding a shared secret.
+//! Keys are stored in the database and rotated by a scheduled job. A retired key stops signing, but keeps
+//! verifying until every token it signed has expired.
+
+use std::{
+	collections::HashSet,
+	fmt,
+	sync::{Arc, RwLock},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, NaiveDateTime, Utc};
+use entity::signing_keys;
+use jsonwebtoken::{jwk::Jwk, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use migration::DbErr;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rand::thread_rng;
+use rsa::{
+	pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+	traits::PublicKeyParts,
+	RsaPrivateKey,
+};
+use sea_orm::{
+	sea_query::Expr, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter,
+	QueryOrder, Set,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::SigningConfig;
+
+/// How long a new key is published before it starts signing, in minutes. Other instances reload the
+/// ring more often than this, so they can verify the new key's tokens before they see one.
+const PUBLISH_BEFORE_USE: i64 = 15;
+/// The size of generated RSA keys, in bits
+const RSA_KEY_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyAlgorithm {
+	RS256,
+	ES256,
+	EdDSA,
+}
+
+impl KeyAlgorithm {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			KeyAlgorithm::RS256 => "RS256",
+			KeyAlgorithm::ES256 => "ES256",
+			KeyAlgorithm::EdDSA => "EdDSA",
+		}
+	}
+
+	fn parse(value: &str) -> Option<Self> {
+		match value {
+			"RS256" => Some(KeyAlgorithm::RS256),
+			"ES256" => Some(KeyAlgorithm::ES256),
+			"EdDSA" => Some(KeyAlgorithm::EdDSA),
+			_ => None,
+		}
+	}
+
+	fn jwt_algorithm(&self) -> Algorithm {
+		match self {
+			KeyAlgorithm::RS256 => Algorithm::RS256,
+			KeyAlgorithm::ES256 => Algorithm::ES256,
+			KeyAlgorithm::EdDSA => Algorithm::EdDSA,
+		}
+	}
+}
+
+struct Key {
+	kid: String,
+	algorithm: KeyAlgorithm,
+	encoding: EncodingKey,
+	decoding: DecodingKey,
+	jwk: Value,
+	created_at: NaiveDateTime,
+}
+
+#[derive(Clone)]
+pub struct KeyRing {
+	/// Sorted from newest to oldest
+	keys: Arc<RwLock<Vec<Key>>>,
+}
+
+impl fmt::Debug for KeyRing {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let keys = self.keys.read().unwrap();
+		f.debug_struct("KeyRing")
+			.field("kids", &keys.iter().map(|key| &key.kid).collect::<Vec<_>>())
+			.finish()
+	}
+}
+
+impl KeyRing {
+	/// Creates a ring with a single key that is not stored anywhere
+	pub fn ephemeral(algorithm: KeyAlgorithm) -> Self {
+		let key = parse_key(
+			Uuid::new_v4().simple().to_string(),
+			algorithm,
+			&generate_private_key(algorithm),
+			Utc::now().naive_utc(),
+		)
+		.unwrap();
+		Self {
+			keys: Arc::new(RwLock::new(vec![key])),
+		}
+	}
+
+	/// Loads the keys from the database, creating the first key if needed
+	pub async fn load(
+		connection: &DatabaseConnection,
+		config: &SigningConfig,
+	) -> Result<Self, DbErr> {
+		let ring = Self {
+			keys: Arc::new(RwLock::new(vec![])),
+		};
+		ring.rotate(connection, config).await?;
+		Ok(ring)
+	}
+
+	/// Reloads the keys from the database, to pick up keys created by other instances. Retired keys are
+	/// loaded until the prune job deletes them, see `TokenConfig::retired_key_lifetime`.
+	pub async fn reload(&self, connection: &DatabaseConnection) -> Result<(), DbErr> {
+		let models = signing_keys::Entity::find()
+			.order_by_desc(signing_keys::Column::CreatedAt)
+			.all(connection)
+			.await?;
+
+		let keys = models
+			.into_iter()
+			.filter_map(|model| {
+				let algorithm = KeyAlgorithm::parse(&model.algorithm)?;
+				let key = parse_key(model.kid, algorithm, &model.private_key, model.created_at);
+				if key.is_none() {
+					log::error!("Unable to parse signing key with algorithm {}", model.algorithm);
+				}
+				key
+			})
+			.collect();
+		*self.keys.write().unwrap() = keys;
+		Ok(())
+	}
+
+	/// Creates a new key if the newest one is due for rotation or uses another algorithm, and retires the
+	/// older keys. Returns whether a key was created.
+	pub async fn rotate(
+		&self,
+		connection: &DatabaseConnection,
+		config: &SigningConfig,
+	) -> Result<bool, DbErr> {
+		self.reload(connection).await?;
+
+		let now = Utc::now().naive_utc();
+		let due = match self.keys.read().unwrap().first() {
+			Some(newest) => {
+				newest.algorithm != config.algorithm
+					|| (config.rotation_days > 0
+						&& newest.created_at + Duration::days(config.rotation_days.into()) <= now)
+			}
+			None => true,
+		};
+		if !due {
+			return Ok(false);
+		}
+
+		let kid = Uuid::new_v4().simple().to_string();
+		signing_keys::ActiveModel {
+			kid: Set(kid.to_owned()),
+			algorithm: Set(config.algorithm.as_str().to_string()),
+			private_key: Set(generate_private_key(config.algorithm)),
+			created_at: Set(now),
+			retired_at: Set(None),
+		}
+		.insert(connection)
+		.await?;
+
+		signing_keys::Entity::update_many()
+			.col_expr(signing_keys::Column::RetiredAt, Expr::value(now))
+			.filter(signing_keys::Column::RetiredAt.is_null())
+			.filter(signing_keys::Column::Kid.ne(kid))
+			.exec(connection)
+			.await?;
+
+		self.reload(connection).await?;
+		Ok(true)
+	}
+
+	/// Signs the claims with the current key
+	pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+		let keys = self.keys.read().unwrap();
+		let key = signing_key(&keys).expect("The key ring has no keys");
+
+		let mut header = Header::new(key.algorithm.jwt_algorithm());
+		header.kid = Some(key.kid.to_owned());
+		jsonwebtoken::encode(&header, claims, &key.encoding).unwrap()
+	}
+
+	/// Verifies the signature with the key named by the token's `kid`, and returns the claims.
+	/// Validating the claims, such as `exp`, is left to the caller.
+	pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+		let header = jsonwebtoken::decode_header(token).ok()?;
+		let kid = header.kid?;
+
+		let keys = self.keys.read().unwrap();
+		let key = keys.iter().find(|key| key.kid == kid)?;
+		// Never let the token choose the algorithm
+		if header.alg != key.algorithm.jwt_algorithm() {
+			return None;
+		}
+
+		let mut validation = Validation::new(header.alg);
+		validation.validate_exp = false;
+		validation.validate_aud = false;
+		validation.required_spec_claims = HashSet::new();
+		jsonwebtoken::decode::<T>(token, &key.decoding, &validation)
+			.ok()
+			.map(|data| data.claims)
+	}
+
+	/// The public keys, in the JWK Set format
+	pub fn jwks(&self) -> Value {
+		let keys = self.keys.read().unwrap();
+		json!({ "keys": keys.iter().map(|key| &key.jwk).collect::<Vec<_>>() })
+	}
+}
+
+/// The newest key that has been published long enough, or the newest key when there are none yet
+fn signing_key(keys: &[Key]) -> Option<&Key> {
+	let published_before = Utc::now().naive_utc() - Duration::minutes(PUBLISH_BEFORE_USE);
+	keys.iter()
+		.find(|key| key.created_at <= published_before)
+		.or_else(|| keys.first())
+}
+
+/// Generates a private key, encoded as PKCS#8 PEM
+fn generate_private_key(algorithm: KeyAlgorithm) -> String {
+	match algorithm {
+		KeyAlgorithm::RS256 => RsaPrivateKey::new(&mut thread_rng(), RSA_KEY_SIZE)
+			.unwrap()
+			.to_pkcs8_pem(LineEnding::LF)
+			.unwrap()
+			.to_string(),
+		KeyAlgorithm::ES256 => p256::SecretKey::random(&mut thread_rng())
+			.to_pkcs8_pem(LineEnding::LF)
+			.unwrap()
+			.to_string(),
+		KeyAlgorithm::EdDSA => ed25519_dalek::SigningKey::generate(&mut thread_rng())
+			.to_pkcs8_pem(LineEnding::LF)
+			.unwrap()
+			.to_string(),
+	}
+}
+
+fn parse_key(
+	kid: String,
+	algorithm: KeyAlgorithm,
+	pem: &str,
+	created_at: NaiveDateTime,
+) -> Option<Key> {
+	let (encoding, mut jwk) = match algorithm {
+		KeyAlgorithm::RS256 => {
+			let key = RsaPrivateKey::from_pkcs8_pem(pem).ok()?;
+			let jwk = json!({
+				"kty": "RSA",
+				"n": URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+				"e": URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+			});
+			(EncodingKey::from_rsa_pem(pem.as_bytes()).ok()?, jwk)
+		}
+		KeyAlgorithm::ES256 => {
+			let key = p256::SecretKey::from_pkcs8_pem(pem).ok()?;
+			let point = key.public_key().to_encoded_point(false);
+			let jwk = json!({
+				"kty": "EC",
+				"crv": "P-256",
+				"x": URL_SAFE_NO_PAD.encode(point.x()?),
+				"y": URL_SAFE_NO_PAD.encode(point.y()?),
+			});
+			(EncodingKey::from_ec_pem(pem.as_bytes()).ok()?, jwk)
+		}
+		KeyAlgorithm::EdDSA => {
+			let key = ed25519_dalek::SigningKey::from_pkcs8_pem(pem).ok()?;
+			let jwk = json!({
+				"kty": "OKP",
+				"crv": "Ed25519",
+				"x": URL_SAFE_NO_PAD.encode(key.verifying_key().as_bytes()),
+			});
+			(EncodingKey::from_ed_pem(pem.as_bytes()).ok()?, jwk)
+		}
+	};
+	jwk["kid"] = json!(kid);
+	jwk["alg"] = json!(algorithm.as_str());
+	jwk["use"] = json!("sig");
+
+	let decoding = DecodingKey::from_jwk(&serde_json::from_value::<Jwk>(jwk.clone()).ok()?).ok()?;
+
+	Some(Key {
+		kid,
+		algorithm,
+		encoding,
+		decoding,
+		jwk,
+		created_at,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use jsonwebtoken::jwk::JwkSet;
+	use std::collections::BTreeMap;
+
+	fn round_trip(algorithm: KeyAlgorithm) {
+		let ring = KeyRing::ephemeral(algorithm);
+		let mut claims = BTreeMap::new();
+		claims.insert("uid", "user");
+		let token = ring.sign(&claims);
+
+		let verified: BTreeMap<String, String> = ring.verify(&token).unwrap();
+		assert_eq!(verified["uid"], "user");
+
+		// Tokens verify with the published JWK alone
+		let jwks: JwkSet = serde_json::from_value(ring.jwks()).unwrap();
+		let kid = jsonwebtoken::decode_header(&token).unwrap().kid.unwrap();
+		let mut validation = Validation::new(algorithm.jwt_algorithm());
+		validation.required_spec_claims = HashSet::new();
+		let decoded = jsonwebtoken::decode::<BTreeMap<String, String>>(
+			&token,
+			&DecodingKey::from_jwk(jwks.find(&kid).unwrap()).unwrap(),
+			&validation,
+		);
+		assert!(decoded.is_ok());
+
+		// Another ring's keys are unknown
+		assert!(KeyRing::ephemeral(algorithm)
+			.verify::<BTreeMap<String, String>>(&token)
+			.is_none());
+	}
+
+	#[test]
+	fn test_es256() {
+		round_trip(KeyAlgorithm::ES256);
+	}
+
+	#[test]
+	fn test_eddsa() {
+		round_trip(KeyAlgorithm::EdDSA);
+	}
+
+	#[test]
+	fn test_rs256() {
+		round_trip(KeyAlgorithm::RS256);
+	}
+
+	#[test]
+	fn test_rejects_hmac_tokens() {
+		use hmac::{Hmac, Mac};
+		use jwt::SignWithKey;
+
+		let key: Hmac<sha2::Sha256> = Hmac::new_from_slice(b"a_very_long_secret_key").unwrap();
+		let mut claims = BTreeMap::new();
+		claims.insert("uid", "user");
+		let token = claims.sign_with_key(&key).unwrap();
+
+		let ring = KeyRing::ephemeral(KeyAlgorithm::ES256);
+		assert!(ring.verify::<BTreeMap<String, String>>(&token).is_none());
+	}
+}
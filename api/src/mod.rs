@@ -4,13 +4,26 @@ use actix_web::{
 	web::BytesMut,
 	HttpResponse, ResponseError,
 };
-use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use lettre::{
+	transport::smtp::{
+		authentication::Credentials,
+		client::{Tls, TlsParameters},
+	},
+	AsyncSmtpTransport, Tokio1Executor,
+};
+use hmac::{Hmac, Mac};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sms::{file::FileSender, http::HttpSender, SmsSender};
+use std::{net::IpAddr, path::PathBuf, sync::Arc};
 
 pub mod auth;
 pub mod health;
 pub mod admin;
+pub mod breached_passwords;
+pub mod idp;
+pub mod keys;
+pub mod projects;
 
 #[macro_use]
 extern crate lazy_static;
@@ -18,6 +31,8 @@ extern crate lazy_static;
 // Using lazy static to avoid compiling this regex every time we need it, as the computation is expensive
 lazy_static! {
 	pub static ref EMAIL_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*$").unwrap();
+	/// E.164, see `auth::phone::normalize`
+	pub static ref PHONE_REGEX: Regex = Regex::new(r"^\+[1-9][0-9]{6,14}$").unwrap();
 }
 
 #[derive(Debug, Clone)]
@@ -28,10 +43,101 @@ pub struct Config {
 	pub debug_level: String,
 	pub bind_addr: String,
 	pub argon2_config: Argon2Config,
+	pub password_hashing: PasswordHashConfig,
 	pub minimum_password_strength: u8,
+	pub password_policy: PasswordPolicyConfig,
 	pub mailer: Option<AsyncSmtpTransport<Tokio1Executor>>,
 	pub email: Option<EmailConfig>,
-    pub allowed_origins: Vec<String>
+	/// When set, users can sign up and log in with a code texted to their phone
+	pub sms: Option<SmsConfig>,
+    pub allowed_origins: Vec<String>,
+	pub webauthn: WebauthnConfig,
+	pub oauth_providers: Vec<OAuthProviderConfig>,
+	pub identity_provider: Option<IdentityProviderConfig>,
+	pub signing: SigningConfig,
+	pub tokens: TokenConfig,
+	pub lockout: LockoutConfig,
+	pub anonymous_users: AnonymousUserConfig,
+	pub rate_limit: RateLimitConfig,
+	/// The addresses of the proxies in front of TurboCore. Only requests from them are trusted to say
+	/// which client they were forwarded for, with `X-Forwarded-For`.
+	pub trusted_proxies: Vec<IpAddr>,
+	/// When set, passwords that appear in known breaches are rejected or flagged
+	pub breached_passwords: Option<BreachedPasswordConfig>,
+	/// Other projects served alongside the default one, see `projects`
+	pub projects: Vec<ProjectConfig>,
+}
+
+impl Config {
+	/// The config of a project, which is this config with the project's settings in place of the defaults
+	pub fn for_project(&self, project: &ProjectConfig) -> Config {
+		let mut config = self.clone();
+		config.connection_url = project.connection_url.to_owned();
+		config.secret_key = project_secret_key(&self.secret_key, &project.id);
+		if let Some(ref base_url) = project.base_url {
+			config.base_url = base_url.to_owned();
+			config.webauthn = WebauthnConfig::for_base_url(base_url);
+		}
+		if let Some(ref email) = project.email {
+			config.mailer = Some(email.mailer());
+			config.email = Some(email.to_owned());
+		}
+		if let Some(ref allowed_origins) = project.allowed_origins {
+			config.allowed_origins = allowed_origins.to_owned();
+		}
+		if let Some(ref webauthn) = project.webauthn {
+			config.webauthn = webauthn.to_owned();
+		}
+		if let Some(ref signing) = project.signing {
+			config.signing = signing.to_owned();
+		}
+		if let Some(ref tokens) = project.tokens {
+			config.tokens = tokens.to_owned();
+		}
+		if let Some(ref oauth_providers) = project.oauth_providers {
+			config.oauth_providers = oauth_providers.to_owned();
+		}
+		if let Some(ref identity_provider) = project.identity_provider {
+			config.identity_provider = Some(identity_provider.to_owned());
+		}
+		config.projects = vec![];
+		config
+	}
+}
+
+/// The key a project signs its own tokens with, such as MFA challenges and reset tokens, so that they are
+/// never valid in another project. It is derived from the top level key and the project's id, the same
+/// way HKDF-Expand derives a key from its pseudorandom key.
+fn project_secret_key(secret_key: &Hmac<sha2::Sha256>, id: &str) -> Hmac<sha2::Sha256> {
+	let mut mac = secret_key.clone();
+	mac.update(b"TurboCore project ");
+	mac.update(id.as_bytes());
+	mac.update(&[1]);
+	Hmac::new_from_slice(&mac.finalize().into_bytes()).unwrap()
+}
+
+/// A project with its own users, signing keys and settings. Settings that are not set are taken from the
+/// top level of the config, so a project without `oauth_providers` or `identity_provider` shares the
+/// default project's. Its `secret_key` is always its own, derived from the top level one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectConfig {
+	/// Used in the `X-Project` header and the `/projects/{id}` path prefix
+	pub id: String,
+	/// Host names that serve the project, e.g. "auth.example.com"
+	#[serde(default)]
+	pub hosts: Vec<String>,
+	/// The project's own database, which keeps its users apart from every other project's
+	pub connection_url: String,
+	/// Passkeys are scoped to the base URL's host, unless `webauthn` is set
+	pub base_url: Option<String>,
+	pub email: Option<EmailConfig>,
+	pub allowed_origins: Option<Vec<String>>,
+	pub webauthn: Option<WebauthnConfig>,
+	pub signing: Option<SigningConfig>,
+	pub tokens: Option<TokenConfig>,
+	/// The providers must allow the project's own callback URL, which is under its `base_url`
+	pub oauth_providers: Option<Vec<OAuthProviderConfig>>,
+	pub identity_provider: Option<IdentityProviderConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -70,12 +176,489 @@ pub struct EmailConfig {
 	pub magic_link_subject: String,
 	pub forgot_password_subject: String,
 	pub confirmation_subject: String,
+	/// When set, users are emailed with this subject when one of their sessions may have been stolen
+	#[serde(default)]
+	pub security_alert_subject: Option<String>,
+	/// When set, users are emailed with this subject when their account is locked after failed logins
+	#[serde(default)]
+	pub account_locked_subject: Option<String>,
+	/// When set, users are emailed with this subject when their password is reset
+	#[serde(default)]
+	pub password_changed_subject: Option<String>,
+	/// The subject of the notice sent to the old address when a user changes their email
+	#[serde(default = "default_email_change_subject")]
+	pub email_change_subject: String,
+}
+
+impl EmailConfig {
+	/// Panics if `smtp_encryption` is not supported
+	pub fn mailer(&self) -> AsyncSmtpTransport<Tokio1Executor> {
+		let mut mailer = AsyncSmtpTransport::<Tokio1Executor>::relay(&self.smtp_server)
+			.unwrap()
+			.port(self.smtp_port);
+		let tls = match self.smtp_encryption.as_str() {
+			"None" | "none" => Tls::None,
+			"TLS" | "tls" => Tls::Required(TlsParameters::new_native(self.smtp_server.clone()).unwrap()),
+			"STARTTLS" | "starttls" => {
+				Tls::Opportunistic(TlsParameters::new_native(self.smtp_server.clone()).unwrap())
+			}
+			_ => {
+				panic!("Provided TLS method is not supported")
+			}
+		};
+		if !"".eq(&self.smtp_username) {
+			mailer = mailer.credentials(Credentials::new(
+				self.smtp_username.clone(),
+				self.smtp_password.clone(),
+			));
+		}
+		mailer.tls(tls).build()
+	}
+}
+
+fn default_email_change_subject() -> String {
+	"Your email address is being changed".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebauthnConfig {
+	/// The relying party id, usually the domain of the site that registers passkeys
+	pub rp_id: String,
+	pub rp_name: String,
+	/// The origins allowed to perform ceremonies, e.g. "https://example.com"
+	pub origins: Vec<String>,
+}
+
+impl WebauthnConfig {
+	/// Scopes passkeys to the host that TurboCore is served from
+	pub fn for_base_url(base_url: &str) -> Self {
+		let host = base_url
+			.split("://")
+			.last()
+			.and_then(|rest| rest.split(['/', ':']).next())
+			.unwrap_or_default()
+			.to_string();
+		WebauthnConfig {
+			rp_id: host,
+			rp_name: "TurboCore".to_string(),
+			origins: vec![base_url.trim_end_matches('/').to_string()],
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthProviderConfig {
+	/// The name used in the URL, e.g. "google" for /api/auth/user/oauth/google/start
+	pub name: String,
+	/// The issuer URL, used to discover the provider's endpoints and to validate ID tokens
+	pub issuer: String,
+	pub client_id: String,
+	pub client_secret: String,
+	#[serde(default = "default_oauth_scopes")]
+	pub scopes: Vec<String>,
+}
+
+fn default_oauth_scopes() -> Vec<String> {
+	vec!["openid".to_string(), "email".to_string(), "profile".to_string()]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcClient {
+	pub client_id: String,
+	/// Confidential clients authenticate with a secret. Public clients, such as SPAs, only use PKCE.
+	pub client_secret: Option<String>,
+	pub redirect_uris: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityProviderConfig {
+	/// The page that logs the user in and completes the authorization request
+	pub login_url: String,
+	pub clients: Vec<OidcClient>,
+}
+
+/// How access, refresh and ID tokens are signed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningConfig {
+	#[serde(default = "default_signing_algorithm")]
+	pub algorithm: keys::KeyAlgorithm,
+	/// How often a new signing key is created, in days. 0 disables rotation.
+	#[serde(default = "default_rotation_days")]
+	pub rotation_days: u32,
+}
+
+impl Default for SigningConfig {
+	fn default() -> Self {
+		Self {
+			algorithm: default_signing_algorithm(),
+			rotation_days: default_rotation_days(),
+		}
+	}
+}
+
+fn default_signing_algorithm() -> keys::KeyAlgorithm {
+	keys::KeyAlgorithm::ES256
+}
+
+fn default_rotation_days() -> u32 {
+	30
+}
+
+/// The algorithm used to hash new passwords
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordHashAlgorithm {
+	/// Argon2id, with `argon2_params`
+	#[default]
+	Argon2,
+	Bcrypt,
+}
+
+/// The password hash settings of a Firebase project, from its exported hash parameters. Needed to verify
+/// the passwords of users imported from Firebase.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirebaseScryptConfig {
+	/// Base64 encoded
+	pub signer_key: String,
+	/// Base64 encoded
+	pub salt_separator: String,
+	pub rounds: u32,
+	pub mem_cost: u32,
+}
+
+/// How passwords are hashed. Hashes made by another algorithm, or with other parameters, are replaced
+/// when their user next logs in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashConfig {
+	pub algorithm: PasswordHashAlgorithm,
+	pub bcrypt_cost: u32,
+	pub firebase_scrypt: Option<FirebaseScryptConfig>,
+}
+
+impl Default for PasswordHashConfig {
+	fn default() -> Self {
+		Self {
+			algorithm: PasswordHashAlgorithm::Argon2,
+			bcrypt_cost: 12,
+			firebase_scrypt: None,
+		}
+	}
+}
+
+/// The rules new passwords must follow, on top of `minimum_password_strength`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicyConfig {
+	pub min_length: usize,
+	/// Longer passwords are refused, as they are expensive to hash
+	pub max_length: usize,
+	/// How many of the user's latest passwords, including the current one, cannot be used again. 0 allows
+	/// any password to be reused.
+	pub history: u32,
+	/// When set, users whose password is older than this many days must change it before they can log in
+	/// with it again
+	pub max_age_days: Option<i64>,
+}
+
+impl Default for PasswordPolicyConfig {
+	fn default() -> Self {
+		Self {
+			min_length: 8,
+			max_length: 128,
+			history: 0,
+			max_age_days: None,
+		}
+	}
+}
+
+/// How long tokens and sessions last. Lifetimes are in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TokenConfig {
+	pub access_token_lifetime: i64,
+	/// The refresh token lifetime for logins that ask to be remembered, which is the default
+	pub refresh_token_lifetime: i64,
+	/// The refresh token lifetime for logins with `remember_me` set to false
+	pub short_refresh_token_lifetime: i64,
+	/// When set, a refresh token expires after this much inactivity, and the refresh token lifetime caps
+	/// the whole session instead of each token
+	pub refresh_token_idle_timeout: Option<i64>,
+	pub magic_link_lifetime: i64,
+	/// How long a login code sent instead of a magic link lasts
+	pub login_code_lifetime: i64,
+	/// Wrong guesses allowed before a login code stops working
+	pub login_code_max_attempts: i32,
+	pub email_verify_lifetime: i64,
+	/// How long a pending email change can be confirmed or cancelled
+	pub email_change_lifetime: i64,
+	pub password_reset_lifetime: i64,
+	/// Linking a new way to log in needs a session that started at most this long ago, so the user has to
+	/// log in again if their session is older
+	pub reauthentication_window: i64,
+	/// The most JSON, in bytes, that custom claims can take up. Every access token carries them.
+	pub custom_claims_max_size: usize,
+	/// How long expired refresh tokens and sessions are kept before they are pruned, in days
+	pub prune_after_days: i64,
+	/// How long security events are kept before they are pruned, in days
+	pub security_event_retention_days: i64,
+}
+
+impl TokenConfig {
+	/// How long a retired signing key must keep verifying, which is as long as any token it signed lives
+	pub fn retired_key_lifetime(&self) -> chrono::Duration {
+		let longest = self
+			.refresh_token_lifetime
+			.max(self.short_refresh_token_lifetime)
+			.max(self.access_token_lifetime);
+		chrono::Duration::seconds(longest) + chrono::Duration::days(1)
+	}
+}
+
+impl Default for TokenConfig {
+	fn default() -> Self {
+		Self {
+			access_token_lifetime: 15 * 60,
+			refresh_token_lifetime: 30 * 24 * 60 * 60,
+			short_refresh_token_lifetime: 24 * 60 * 60,
+			refresh_token_idle_timeout: None,
+			magic_link_lifetime: 15 * 60,
+			login_code_lifetime: 10 * 60,
+			login_code_max_attempts: 5,
+			email_verify_lifetime: 15 * 60,
+			email_change_lifetime: 24 * 60 * 60,
+			password_reset_lifetime: 15 * 60,
+			reauthentication_window: 5 * 60,
+			custom_claims_max_size: 1024,
+			prune_after_days: 21,
+			security_event_retention_days: 90,
+		}
+	}
+}
+
+/// Where text messages are sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SmsConfig {
+	/// Messages are only logged, and appended to `path` as JSON lines when it is set. For development
+	/// and tests.
+	File { path: Option<String> },
+	/// Messages are posted as JSON to the provider's `url`
+	Http {
+		url: String,
+		/// The number or sender ID that messages come from
+		from: String,
+		auth_token: Option<String>,
+	},
+}
+
+impl SmsConfig {
+	pub fn sender(&self) -> Arc<dyn SmsSender> {
+		match self {
+			SmsConfig::File { path } => Arc::new(FileSender::new(path.as_ref().map(PathBuf::from))),
+			SmsConfig::Http {
+				url,
+				from,
+				auth_token,
+			} => Arc::new(HttpSender::new(url.to_owned(), from.to_owned(), auth_token.to_owned())),
+		}
+	}
+}
+
+/// How failed logins are throttled. Once an account or IP address reaches its limit, it is locked, and
+/// each further failure doubles the lock, up to the maximum. Durations are in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+	/// Failed logins to an account before it is locked
+	pub max_failures: i32,
+	/// Failed logins from an IP address, to any account, before it is locked
+	pub max_ip_failures: i32,
+	pub lockout_duration: i64,
+	pub max_lockout_duration: i64,
+	/// Failures are forgotten after this long without another one
+	pub reset_after: i64,
+}
+
+impl Default for LockoutConfig {
+	fn default() -> Self {
+		Self {
+			max_failures: 5,
+			max_ip_failures: 20,
+			lockout_duration: 60,
+			max_lockout_duration: 60 * 60,
+			reset_after: 24 * 60 * 60,
+		}
+	}
+}
+
+/// Guest accounts, created without an email or password, that can be upgraded to a full account later
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnonymousUserConfig {
+	pub enabled: bool,
+	/// Anonymous users are deleted once they have gone this long without a session, in days. Without
+	/// a session, there is no way back into the account.
+	pub prune_after_days: i64,
+}
+
+impl Default for AnonymousUserConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			prune_after_days: 30,
+		}
+	}
+}
+
+/// Which requests share a rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+	/// Requests from the same IP address
+	Ip,
+	/// Requests for the same `email` in the JSON body. Requests without one are not limited.
+	Email,
+	/// Requests for the same `phone` in the JSON body. Requests without a valid one are not limited.
+	Phone,
+	/// Requests with an access token for the same user. Requests without a valid one are not limited.
+	Uid,
+}
+
+/// Allows `limit` requests to a route per `window` seconds, for each IP address, email, phone number or user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitPolicy {
+	pub method: String,
+	/// The path of the route. A trailing `*` matches every path that starts with the rest.
+	pub path: String,
+	pub key: RateLimitKey,
+	pub limit: u32,
+	pub window: i64,
+}
+
+impl RateLimitPolicy {
+	fn new(method: &str, path: &str, key: RateLimitKey, limit: u32, window: i64) -> Self {
+		Self {
+			method: method.to_string(),
+			path: path.to_string(),
+			key,
+			limit,
+			window,
+		}
+	}
+}
+
+/// Where rate limits are counted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitStoreKind {
+	/// In each instance's memory
+	#[default]
+	Memory,
+	/// In the database, so that the limits hold across instances
+	Database,
+}
+
+/// How requests to the auth and email-sending endpoints are rate limited. An empty list of policies
+/// turns rate limiting off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+	pub store: RateLimitStoreKind,
+	pub policies: Vec<RateLimitPolicy>,
+}
+
+impl Default for RateLimitConfig {
+	fn default() -> Self {
+		use RateLimitKey::{Email, Ip, Phone, Uid};
+		const MINUTE: i64 = 60;
+		const HOUR: i64 = 60 * 60;
+
+		Self {
+			store: RateLimitStoreKind::default(),
+			policies: vec![
+				// Endpoints that send emails
+				RateLimitPolicy::new("POST", "/api/auth/user/magic-link", Ip, 10, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/magic-link", Email, 5, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/phone", Ip, 10, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/phone", Phone, 5, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/reset-password", Ip, 10, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/reset-password", Email, 5, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/verify-email", Ip, 10, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/verify-email", Uid, 5, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/create", Ip, 10, HOUR),
+				RateLimitPolicy::new("POST", "/api/auth/user/anonymous", Ip, 10, HOUR),
+				// Endpoints that check credentials
+				RateLimitPolicy::new("POST", "/api/auth/user/login", Ip, 30, MINUTE),
+				RateLimitPolicy::new("POST", "/api/auth/user/login/mfa", Ip, 30, MINUTE),
+				RateLimitPolicy::new("POST", "/api/admin/login", Ip, 30, MINUTE),
+				RateLimitPolicy::new("GET", "/api/auth/user/magic-link/*", Ip, 30, MINUTE),
+				RateLimitPolicy::new("POST", "/api/auth/user/magic-link/verify", Ip, 30, MINUTE),
+				RateLimitPolicy::new("POST", "/api/auth/user/phone/verify", Ip, 30, MINUTE),
+			],
+		}
+	}
+}
+
+/// Where the hashes of breached passwords come from. Local datasets use the format of the Have I Been
+/// Pwned downloads, with one uppercase SHA-1 `HASH:COUNT` per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BreachedPasswordSource {
+	/// A file of hashes, loaded into memory
+	HashList { path: String },
+	/// A file of hashes, loaded into a bloom filter. It uses far less memory than `hash_list`, but
+	/// rejects a few passwords that were never breached.
+	BloomFilter {
+		path: String,
+		#[serde(default = "default_false_positive_rate")]
+		false_positive_rate: f64,
+	},
+	/// A directory with a file of `SUFFIX:COUNT` lines for each 5 character hash prefix, like the range
+	/// API returns. Files are read when a password is checked.
+	RangeDirectory { path: String },
+	/// A k-anonymity range API, e.g. "https://api.pwnedpasswords.com/range/"
+	RangeApi { url: String },
+}
+
+fn default_false_positive_rate() -> f64 {
+	0.001
+}
+
+/// What happens to a password that appears in a breach
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreachedPasswordAction {
+	/// The password is refused with `BREACHED_PASSWORD`
+	#[default]
+	Reject,
+	/// The password is accepted, and a `breached_password` security event is recorded for the user
+	Warn,
+}
+
+/// How passwords are checked against known breaches. Passwords are still accepted when the check
+/// cannot be made, e.g. when the range API is down.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BreachedPasswordConfig {
+	pub source: BreachedPasswordSource,
+	#[serde(default)]
+	pub action: BreachedPasswordAction,
+	/// Passwords seen in fewer breaches than this are allowed
+	#[serde(default = "default_breach_min_count")]
+	pub min_count: u64,
+}
+
+fn default_breach_min_count() -> u64 {
+	1
 }
 
 pub struct AppState {
 	pub connection: sea_orm::DatabaseConnection,
 	pub config: Config,
 	pub ua_parser: uaparser::UserAgentParser,
+	pub key_ring: keys::KeyRing,
+	pub breached_passwords: Option<breached_passwords::BreachedPasswords>,
+	pub sms: Option<Arc<dyn SmsSender>>,
 }
 
 #[derive(Debug)]
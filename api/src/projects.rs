@@ -0,0 +1,83 @@
+//! Projects let one process serve several products, each with its own users, signing keys, email settings,
+//! allowed origins and token lifetimes.
+//!
+//! Every project has its own database, so the queries in `auth` and `admin` only ever see the users of the
+//! project a request is for. A request picks its project with a `/projects/{id}` path prefix, the
+//! `X-Project` header or one of the project's host names, in that order. Requests that pick none are for
+//! the default project, which is configured by the top level of the config.
+
+use std::collections::HashMap;
+
+use actix_web::web::Data;
+
+use crate::{AppState, ProjectConfig};
+
+pub const PROJECT_HEADER: &str = "X-Project";
+pub const PATH_PREFIX: &str = "/projects/";
+
+/// The id of the project a request is for. Requests for the default project have none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectId(pub String);
+
+/// The projects served alongside the default one, and the state their requests are handled with
+#[derive(Default)]
+pub struct Projects {
+	states: HashMap<String, Data<AppState>>,
+	/// Lower case host names, and the ids of the projects they serve
+	hosts: HashMap<String, String>,
+}
+
+pub enum Resolved<'a> {
+	/// The request is for the default project
+	Default,
+	/// The request is for the project with the id. The path is the request's path without the project
+	/// prefix, when it had one.
+	Project(&'a str, &'a Data<AppState>, Option<&'a str>),
+	/// The request names a project that does not exist
+	NotFound,
+}
+
+impl Projects {
+	pub fn add(&mut self, project: &ProjectConfig, state: AppState) {
+		for host in project.hosts.iter() {
+			self.hosts
+				.insert(host.to_lowercase(), project.id.to_owned());
+		}
+		self.states.insert(project.id.to_owned(), Data::new(state));
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.states.is_empty()
+	}
+
+	/// Finds the project a request is for. `host` may include a port.
+	pub fn resolve<'a>(&'a self, path: &'a str, header: Option<&str>, host: &str) -> Resolved<'a> {
+		if let Some(rest) = path.strip_prefix(PATH_PREFIX) {
+			let (id, path) = match rest.find('/') {
+				Some(i) => rest.split_at(i),
+				None => (rest, "/"),
+			};
+			return match self.states.get_key_value(id) {
+				Some((id, state)) => Resolved::Project(id, state, Some(path)),
+				None => Resolved::NotFound,
+			};
+		}
+
+		if let Some(id) = header {
+			return match self.states.get_key_value(id) {
+				Some((id, state)) => Resolved::Project(id, state, None),
+				None => Resolved::NotFound,
+			};
+		}
+
+		let host = host.split(':').next().unwrap_or_default().to_lowercase();
+		match self
+			.hosts
+			.get(&host)
+			.and_then(|id| self.states.get_key_value(id))
+		{
+			Some((id, state)) => Resolved::Project(id, state, None),
+			None => Resolved::Default,
+		}
+	}
+}
@@ -0,0 +1,130 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::{http::header::ContentType, test};
+use api::{
+	auth::{
+		action_tokens::{self, ActionKind},
+		claims::{self, EmailVerifyClaims, MagicLinkClaims, RegisteredClaims, TokenClaims},
+	},
+	Config,
+};
+use chrono::Duration;
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct SignupResponse {
+		uid: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	async fn create_user(
+		app: &impl actix_service::Service<
+			actix_http::Request,
+			Response = actix_web::dev::ServiceResponse,
+			Error = actix_web::Error,
+		>,
+		email: &str,
+	) -> Uuid {
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_json(serde_json::json!({
+				"email": email,
+				"password": "a_strong_password1111011",
+				"login": false,
+				"metadata": "",
+			}))
+			.to_request();
+		let user: SignupResponse = test::call_and_read_body_json(app, req).await;
+		Uuid::parse_str(&user.uid).unwrap()
+	}
+
+	/// Issues a magic link like the email would
+	async fn magic_link(config: &Config, uid: Uuid) -> String {
+		let connection = connect(config).await;
+		let claims = MagicLinkClaims {
+			registered: RegisteredClaims::new(MagicLinkClaims::AUDIENCE, Duration::minutes(15)),
+			sub: uid,
+			next: "http://turbocore/app".to_string(),
+			email: None,
+		};
+		action_tokens::issue(&connection, uid, ActionKind::MagicLink, &claims.registered)
+			.await
+			.unwrap();
+		claims::sign(&claims, &config.secret_key)
+	}
+
+	/// Issues an email verification link like the email would
+	async fn verify_link(config: &Config, uid: Uuid) -> String {
+		let connection = connect(config).await;
+		let claims = EmailVerifyClaims {
+			registered: RegisteredClaims::new(EmailVerifyClaims::AUDIENCE, Duration::minutes(15)),
+			sub: uid,
+			next: "http://turbocore/app".to_string(),
+		};
+		action_tokens::issue(&connection, uid, ActionKind::EmailVerify, &claims.registered)
+			.await
+			.unwrap();
+		claims::sign(&claims, &config.secret_key)
+	}
+
+	#[actix_web::test]
+	async fn test_magic_link_is_single_use() {
+		let config = test_config(None, None);
+		let app = create_app_with_config(config.clone()).await;
+		let uid = create_user(&app, "magic-once@example.com").await;
+
+		// Sending a new link revokes the one before it
+		let old = magic_link(&config, uid).await;
+		let new = magic_link(&config, uid).await;
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/auth/user/magic-link/{old}"))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "TOKEN_REVOKED");
+
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/auth/user/magic-link/{new}"))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 302);
+
+		// The link cannot log in twice
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/auth/user/magic-link/{new}"))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 400);
+		let resp: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "TOKEN_ALREADY_USED");
+	}
+
+	#[actix_web::test]
+	async fn test_email_verification_is_single_use() {
+		let config = test_config(None, None);
+		let app = create_app_with_config(config.clone()).await;
+		let uid = create_user(&app, "verify-once@example.com").await;
+
+		// Other kinds of tokens do not revoke it
+		let token = verify_link(&config, uid).await;
+		magic_link(&config, uid).await;
+
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/auth/user/verify-email/{token}"))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 302);
+
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/auth/user/verify-email/{token}"))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "TOKEN_ALREADY_USED");
+	}
+}
@@ -0,0 +1,304 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::{http::header, test};
+use api::{
+	auth::{
+		action_tokens::{self, ActionKind},
+		anonymous,
+		claims::{self, MagicLinkClaims, RegisteredClaims, TokenClaims},
+	},
+	AnonymousUserConfig, Config, EmailConfig,
+};
+use chrono::{Duration, Utc};
+use entity::{sessions, users};
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use sea_orm::{DatabaseConnection, EntityTrait, Set};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct UserResponse {
+		uid: String,
+		email: String,
+		email_verified: bool,
+		anonymous: bool,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	fn config() -> Config {
+		let mut config = test_config(None, None);
+		config.anonymous_users.enabled = true;
+		config
+	}
+
+	/// Emails go nowhere, so the tests issue the links themselves
+	fn email_config() -> EmailConfig {
+		EmailConfig {
+			smtp_server: "localhost".to_string(),
+			smtp_port: 25,
+			smtp_username: "".to_string(),
+			smtp_password: "".to_string(),
+			smtp_encryption: "none".to_string(),
+			from: "TurboCore <noreply@turbocore.org>".to_string(),
+			reply_to: "TurboCore <noreply@turbocore.org>".to_string(),
+			magic_link_subject: "".to_string(),
+			forgot_password_subject: "".to_string(),
+			confirmation_subject: "Your magic link".to_string(),
+			security_alert_subject: None,
+			account_locked_subject: None,
+			password_changed_subject: None,
+			email_change_subject: "".to_string(),
+		}
+	}
+
+	fn create_anonymous() -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/anonymous")
+			.to_request()
+	}
+
+	fn get_user(token: &str) -> actix_http::Request {
+		test::TestRequest::get()
+			.uri("/api/auth/user")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+			.to_request()
+	}
+
+	fn upgrade(token: &str, email: &str, password: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/anonymous/upgrade")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+			.set_json(serde_json::json!({ "email": email, "password": password }))
+			.to_request()
+	}
+
+	fn magic_link(token: &str, email: &str, mode: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/magic-link")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+			.set_json(serde_json::json!({
+				"email": email,
+				"sign_up": true,
+				"next_url": "http://turbocore/app",
+				"mode": mode,
+			}))
+			.to_request()
+	}
+
+	/// Adds an anonymous user that was created `age` ago
+	async fn insert_anonymous(connection: &DatabaseConnection, age: Duration) -> Uuid {
+		let uid = Uuid::new_v4();
+		let created_at = Utc::now().naive_utc() - age;
+		users::Entity::insert(users::ActiveModel {
+			uid: Set(uid),
+			email: Set(Some(anonymous::placeholder_email(uid))),
+			password: Set("0".to_string()),
+			created_at: Set(created_at),
+			updated_at: Set(created_at),
+			last_login: Set(None),
+			active: Set(true),
+			metadata: Set(None),
+			email_verified: Set(false),
+			password_changed_at: Set(None),
+			anonymous: Set(true),
+			phone: Set(None),
+			phone_verified: Set(false),
+		})
+		.exec(connection)
+		.await
+		.unwrap();
+		uid
+	}
+
+	async fn exists(connection: &DatabaseConnection, uid: Uuid) -> bool {
+		users::Entity::find_by_id(uid)
+			.one(connection)
+			.await
+			.unwrap()
+			.is_some()
+	}
+
+	#[actix_web::test]
+	async fn test_anonymous_users_disabled() {
+		let app = create_app_with_config(test_config(None, None)).await;
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, create_anonymous()).await;
+		assert_eq!(resp.error_code, "ANONYMOUS_USERS_DISABLED");
+	}
+
+	#[actix_web::test]
+	async fn test_upgrade_with_password() {
+		let app = create_app_with_config(config()).await;
+		let password = "anonymous_upgrade_password_8812";
+
+		let resp = test::call_service(&app, create_anonymous()).await;
+		assert_eq!(resp.status(), 201);
+		let guest: LoginResponse = test::read_body_json(resp).await;
+		let user: UserResponse = test::call_and_read_body_json(&app, get_user(&guest.token)).await;
+		assert!(user.anonymous);
+		assert!(user.email.is_empty());
+
+		// Anonymous users have no email to verify or change
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/verify-email")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {}", guest.token)))
+			.set_json(serde_json::json!({ "next_url": "http://turbocore/app" }))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "ANONYMOUS_USER");
+
+		let resp: ErrorResponse =
+			test::call_and_read_body_json(&app, upgrade(&guest.token, "not an email", password))
+				.await;
+		assert_eq!(resp.error_code, "INVALID_EMAIL");
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			upgrade(&guest.token, "anonymous-upgrade@example.com", "short"),
+		)
+		.await;
+		assert_eq!(resp.error_code, "INVALID_PASSWORD");
+
+		let resp = test::call_service(
+			&app,
+			upgrade(&guest.token, "anonymous-upgrade@example.com", password),
+		)
+		.await;
+		assert_eq!(resp.status(), 200);
+
+		// The session keeps working, and the user can now log in with their password
+		let user: UserResponse = test::call_and_read_body_json(&app, get_user(&guest.token)).await;
+		assert_eq!(user.uid, guest.uid);
+		assert_eq!(user.email, "anonymous-upgrade@example.com");
+		assert!(!user.anonymous);
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(serde_json::json!({
+				"email": "anonymous-upgrade@example.com",
+				"password": password,
+			}))
+			.to_request();
+		let login: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(login.uid, guest.uid);
+
+		// Users can only be upgraded once, and never to another user's email
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			upgrade(&guest.token, "anonymous-upgrade-2@example.com", password),
+		)
+		.await;
+		assert_eq!(resp.error_code, "USER_NOT_ANONYMOUS");
+		let other: LoginResponse = test::call_and_read_body_json(&app, create_anonymous()).await;
+		let resp = test::call_service(
+			&app,
+			upgrade(&other.token, "anonymous-upgrade@example.com", password),
+		)
+		.await;
+		assert_eq!(resp.status(), 409);
+	}
+
+	#[actix_web::test]
+	async fn test_upgrade_with_magic_link() {
+		let mut config = config();
+		config.mailer = Some(AsyncSmtpTransport::<Tokio1Executor>::unencrypted_localhost());
+		config.email = Some(email_config());
+		let app = create_app_with_config(config.clone()).await;
+
+		let guest: LoginResponse = test::call_and_read_body_json(&app, create_anonymous()).await;
+
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			magic_link(&guest.token, "anonymous-magic@example.com", "code"),
+		)
+		.await;
+		assert_eq!(resp.error_code, "INVALID_MODE");
+		let resp = test::call_service(
+			&app,
+			magic_link(&guest.token, "anonymous-magic@example.com", "link"),
+		)
+		.await;
+		assert_eq!(resp.status(), 200);
+
+		// Follow a link like the one that was emailed
+		let uid = Uuid::parse_str(&guest.uid).unwrap();
+		let claims = MagicLinkClaims {
+			registered: RegisteredClaims::new(MagicLinkClaims::AUDIENCE, Duration::minutes(15)),
+			sub: uid,
+			next: "http://turbocore/app".to_string(),
+			email: Some("anonymous-magic@example.com".to_string()),
+		};
+		action_tokens::issue(
+			&connect(&config).await,
+			uid,
+			ActionKind::MagicLink,
+			&claims.registered,
+		)
+		.await
+		.unwrap();
+		let link = claims::sign(&claims, &config.secret_key);
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/auth/user/magic-link/{link}"))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 302);
+
+		// Following the link proved the user owns the email
+		let user: UserResponse = test::call_and_read_body_json(&app, get_user(&guest.token)).await;
+		assert_eq!(user.email, "anonymous-magic@example.com");
+		assert!(user.email_verified);
+		assert!(!user.anonymous);
+
+		// Links can't be sent for an email that is already in use
+		let other: LoginResponse = test::call_and_read_body_json(&app, create_anonymous()).await;
+		let resp = test::call_service(
+			&app,
+			magic_link(&other.token, "anonymous-magic@example.com", "link"),
+		)
+		.await;
+		assert_eq!(resp.status(), 409);
+	}
+
+	#[actix_web::test]
+	async fn test_prune_stale_anonymous_users() {
+		let config = AnonymousUserConfig {
+			enabled: true,
+			prune_after_days: 30,
+		};
+		let connection = connect(&test_config(None, None)).await;
+		let stale = insert_anonymous(&connection, Duration::days(31)).await;
+		let recent = insert_anonymous(&connection, Duration::days(1)).await;
+		let active = insert_anonymous(&connection, Duration::days(31)).await;
+
+		// A session that is still alive keeps the user
+		let now = Utc::now().naive_utc();
+		sessions::Entity::insert(sessions::ActiveModel {
+			id: Set(Uuid::new_v4()),
+			uid: Set(active),
+			created_at: Set(now),
+			last_refresh: Set(now),
+			expiry: Set(now + Duration::days(1)),
+			ip: Set(None),
+			os: Set("Other".to_string()),
+			browser: Set("Other".to_string()),
+			device: Set("Other".to_string()),
+			remember_me: Set(true),
+		})
+		.exec(&connection)
+		.await
+		.unwrap();
+
+		anonymous::prune(&connection, &config).await.unwrap();
+		assert!(!exists(&connection, stale).await);
+		assert!(exists(&connection, recent).await);
+		assert!(exists(&connection, active).await);
+	}
+}
@@ -0,0 +1,108 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::test;
+use api::{BreachedPasswordAction, BreachedPasswordConfig, BreachedPasswordSource};
+use entity::security_events;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sha1::{Digest, Sha1};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	const BREACHED: &str = "a_breached_password_8437";
+
+	#[derive(serde::Deserialize, Debug)]
+	struct SignupResponse {
+		uid: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct Violation {
+		code: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+		violations: Vec<Violation>,
+	}
+
+	/// Writes a dataset in the format of the Have I Been Pwned downloads
+	fn hash_list(name: &str) -> String {
+		let hash: String = Sha1::digest(BREACHED.as_bytes())
+			.iter()
+			.map(|b| format!("{b:02X}"))
+			.collect();
+		let path = std::env::temp_dir().join(format!("{name}-{}.txt", Uuid::new_v4()));
+		std::fs::write(
+			&path,
+			format!("0000000000000000000000000000000000000000:5\r\n{hash}:12\r\n"),
+		)
+		.unwrap();
+		path.to_string_lossy().to_string()
+	}
+
+	fn create(email: &str, password: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": email,
+				"password": password,
+				"login": false,
+				"metadata": "",
+			}))
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_breached_password_rejected() {
+		let mut config = test_config(None, None);
+		config.breached_passwords = Some(BreachedPasswordConfig {
+			source: BreachedPasswordSource::HashList {
+				path: hash_list("reject"),
+			},
+			action: BreachedPasswordAction::Reject,
+			min_count: 1,
+		});
+		let app = create_app_with_config(config).await;
+
+		let resp = test::call_service(&app, create("breached@example.com", BREACHED)).await;
+		assert_eq!(resp.status(), 400);
+		let resp: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "INVALID_PASSWORD");
+		assert_eq!(resp.violations[0].code, "BREACHED_PASSWORD");
+
+		let resp =
+			test::call_service(&app, create("breached@example.com", "a_strong_password1111011"))
+				.await;
+		assert_eq!(resp.status(), 201);
+	}
+
+	#[actix_web::test]
+	async fn test_breached_password_warns() {
+		let mut config = test_config(None, None);
+		config.breached_passwords = Some(BreachedPasswordConfig {
+			source: BreachedPasswordSource::BloomFilter {
+				path: hash_list("warn"),
+				false_positive_rate: 0.001,
+			},
+			action: BreachedPasswordAction::Warn,
+			min_count: 1,
+		});
+		let app = create_app_with_config(config.clone()).await;
+
+		// The password is accepted, and the user is flagged
+		let resp = test::call_service(&app, create("breached-warn@example.com", BREACHED)).await;
+		assert_eq!(resp.status(), 201);
+		let user: SignupResponse = test::read_body_json(resp).await;
+
+		let connection = connect(&config).await;
+		let events = security_events::Entity::find()
+			.filter(security_events::Column::Uid.eq(Uuid::parse_str(&user.uid).unwrap()))
+			.filter(security_events::Column::Event.eq("breached_password"))
+			.all(&connection)
+			.await
+			.unwrap();
+		assert_eq!(events.len(), 1);
+	}
+}
@@ -1,13 +1,11 @@
-use crate::auth::create_app;
+use crate::auth::{create_app, verify_token};
 use actix_web::test;
-use hmac::{Hmac, Mac};
 use uuid::Uuid;
 
 mod tests {
-	use std::{collections::BTreeMap, str::FromStr};
+	use std::str::FromStr;
 
 	use actix_web::http::header::ContentType;
-	use jwt::VerifyWithKey;
 
 	use super::*;
 
@@ -28,10 +26,16 @@ mod tests {
 
 	#[actix_web::test]
 	async fn test_create_user_weak_password() {
+		#[derive(serde::Deserialize, Debug)]
+		struct Violation {
+			code: String,
+		}
+
 		#[derive(serde::Deserialize, Debug)]
 		struct ExpectedResponse {
 			message: String,
 			error_code: String,
+			violations: Vec<Violation>,
 		}
 
 		let app = create_app(None, None).await;
@@ -40,7 +44,9 @@ mod tests {
 			.set_payload(r##"{"email":"test1@example.com","password":"password","login":false,"email_verified":false,"metadata":""}"##).to_request();
 		// Notice that the password is too weak
 		let resp: ExpectedResponse = test::call_and_read_body_json(&app, req).await;
-		assert_eq!(resp.error_code, "WEAK_PASSWORD");
+		assert_eq!(resp.error_code, "INVALID_PASSWORD");
+		assert_eq!(resp.violations.len(), 1);
+		assert_eq!(resp.violations[0].code, "WEAK_PASSWORD");
 		assert!(resp.message.contains("too weak"));
 	}
 
@@ -114,24 +120,21 @@ mod tests {
 		Uuid::from_str(&resp.uid).unwrap(); // Valid UUID
 
 		// Confirm that email_verified is false
-		assert_eq!(resp.email_verified, false);
+		assert!(!resp.email_verified);
 
 		// Confirm that the metadata is empty
 		assert_eq!(resp.metadata, "");
 
 		// Confirm that the tokens are valid
-		let secret_key: Hmac<sha2::Sha256> =
-			Hmac::new_from_slice("a_secret_key".repeat(3).as_bytes()).unwrap();
-		let claims: BTreeMap<String, String> = resp.token.verify_with_key(&secret_key).unwrap();
+		let claims = verify_token(&app, &resp.token).await;
 		assert_eq!(claims["iss"], "TurboCore");
-		assert_eq!(claims["uid"], resp.uid);
-		assert_eq!(claims["type"], "at");
-		assert_eq!(claims["exp"].parse::<u64>().unwrap(), resp.expiry);
+		assert_eq!(claims["sub"], resp.uid);
+		assert_eq!(claims["aud"], "TurboCore/access");
+		assert_eq!(claims["exp"], resp.expiry);
 
-		let claims: BTreeMap<String, String> =
-			resp.refresh_token.verify_with_key(&secret_key).unwrap();
+		let claims = verify_token(&app, &resp.refresh_token).await;
 		assert_eq!(claims["iss"], "TurboCore");
-		assert_eq!(claims["uid"], resp.uid);
-		assert_eq!(claims["type"], "rt");
+		assert_eq!(claims["sub"], resp.uid);
+		assert_eq!(claims["aud"], "TurboCore/refresh");
 	}
 }
@@ -0,0 +1,105 @@
+use crate::auth::{create_app, verify_token};
+use actix_web::test;
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+		refresh_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct RefreshResponse {
+		access_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ClaimsResponse {
+		claims: serde_json::Value,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	fn put_claims(uid: &str, claims: serde_json::Value) -> actix_http::Request {
+		test::TestRequest::put()
+			.uri(&format!("/api/admin/user/{uid}/claims"))
+			.set_json(claims)
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_custom_claims() {
+		let app = create_app(None, None).await;
+		let email = format!("claims-{}@example.com", Uuid::new_v4());
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": email,
+				"password": "a_strong_password1111011",
+				"login": true,
+				"metadata": "",
+			}))
+			.to_request();
+		let user: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+		for (claims, error_code) in [
+			(serde_json::json!(["admin"]), "INVALID_CLAIMS"),
+			(serde_json::json!({ "plan": "pro", "sub": "someone-else" }), "RESERVED_CLAIM"),
+			(serde_json::json!({ "plan": "x".repeat(2000) }), "CLAIMS_TOO_LARGE"),
+		] {
+			let resp = test::call_service(&app, put_claims(&user.uid, claims)).await;
+			assert_eq!(resp.status(), 400);
+			let body: ErrorResponse = test::read_body_json(resp).await;
+			assert_eq!(body.error_code, error_code);
+		}
+
+		let resp = test::call_service(
+			&app,
+			put_claims(&Uuid::new_v4().to_string(), serde_json::json!({ "plan": "pro" })),
+		)
+		.await;
+		assert_eq!(resp.status(), 404);
+
+		let claims = serde_json::json!({ "plan": "pro", "org": { "id": 7, "roles": ["billing"] } });
+		let resp = test::call_service(&app, put_claims(&user.uid, claims.to_owned())).await;
+		assert_eq!(resp.status(), 200);
+
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/admin/user/{}/claims", user.uid))
+			.to_request();
+		let resp: ClaimsResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.claims, claims);
+
+		// Tokens issued before the claims were set don't have them, until they are refreshed
+		let token = verify_token(&app, &user.token).await;
+		assert!(token.get("plan").is_none());
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": user.refresh_token }))
+			.to_request();
+		let refreshed: RefreshResponse = test::call_and_read_body_json(&app, req).await;
+		let token = verify_token(&app, &refreshed.access_token).await;
+		assert_eq!(token["plan"], "pro");
+		assert_eq!(token["org"]["roles"][0], "billing");
+		assert_eq!(token["sub"], user.uid);
+
+		// An empty object removes the claims
+		let resp = test::call_service(&app, put_claims(&user.uid, serde_json::json!({}))).await;
+		assert_eq!(resp.status(), 200);
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(serde_json::json!({ "email": email, "password": "a_strong_password1111011" }))
+			.to_request();
+		let login: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		let token = verify_token(&app, &login.token).await;
+		assert!(token.get("plan").is_none());
+	}
+}
@@ -0,0 +1,203 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::test;
+use api::{
+	auth::{
+		action_tokens::{self, ActionKind},
+		claims::{self, EmailChangeCancelClaims, EmailChangeClaims, RegisteredClaims, TokenClaims},
+	},
+	Config, EmailConfig,
+};
+use chrono::Duration;
+use entity::users;
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use sea_orm::EntityTrait;
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	/// Emails go nowhere, so the tests issue the links themselves
+	fn email_config() -> EmailConfig {
+		EmailConfig {
+			smtp_server: "localhost".to_string(),
+			smtp_port: 25,
+			smtp_username: "".to_string(),
+			smtp_password: "".to_string(),
+			smtp_encryption: "none".to_string(),
+			from: "TurboCore <noreply@turbocore.org>".to_string(),
+			reply_to: "TurboCore <noreply@turbocore.org>".to_string(),
+			magic_link_subject: "".to_string(),
+			forgot_password_subject: "".to_string(),
+			confirmation_subject: "Confirm your email".to_string(),
+			security_alert_subject: None,
+			account_locked_subject: None,
+			password_changed_subject: None,
+			email_change_subject: "Your email address is being changed".to_string(),
+		}
+	}
+
+	/// Issues a confirmation link like the email to the new address would
+	async fn confirm_link(config: &Config, uid: Uuid) -> String {
+		let connection = connect(config).await;
+		let claims = EmailChangeClaims {
+			registered: RegisteredClaims::new(EmailChangeClaims::AUDIENCE, Duration::hours(1)),
+			sub: uid,
+			next: "http://turbocore/app".to_string(),
+		};
+		action_tokens::issue(&connection, uid, ActionKind::EmailChange, &claims.registered)
+			.await
+			.unwrap();
+		format!(
+			"/api/auth/user/email-change/confirm/{}",
+			claims::sign(&claims, &config.secret_key)
+		)
+	}
+
+	/// Issues a cancel link like the notice to the old address would
+	async fn cancel_link(config: &Config, uid: Uuid) -> String {
+		let connection = connect(config).await;
+		let claims = EmailChangeCancelClaims {
+			registered: RegisteredClaims::new(
+				EmailChangeCancelClaims::AUDIENCE,
+				Duration::hours(1),
+			),
+			sub: uid,
+			next: "http://turbocore/app".to_string(),
+		};
+		action_tokens::issue(&connection, uid, ActionKind::EmailChangeCancel, &claims.registered)
+			.await
+			.unwrap();
+		format!(
+			"/api/auth/user/email-change/cancel/{}",
+			claims::sign(&claims, &config.secret_key)
+		)
+	}
+
+	async fn find_user(config: &Config, uid: Uuid) -> users::Model {
+		let connection = connect(config).await;
+		users::Entity::find_by_id(uid)
+			.one(&connection)
+			.await
+			.unwrap()
+			.unwrap()
+	}
+
+	fn create(email: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": email,
+				"password": "a_strong_password1111011",
+				"login": false,
+				"metadata": "",
+			}))
+			.to_request()
+	}
+
+	fn login(email: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(serde_json::json!({ "email": email, "password": "a_strong_password1111011" }))
+			.to_request()
+	}
+
+	fn change_email(token: &str, email: &str) -> actix_http::Request {
+		test::TestRequest::put()
+			.uri("/api/auth/user")
+			.insert_header(("Authorization", format!("Bearer {token}")))
+			.set_json(serde_json::json!({ "email": email }))
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_email_change() {
+		let mailer = AsyncSmtpTransport::<Tokio1Executor>::unencrypted_localhost();
+		let config = test_config(Some(mailer), Some(email_config()));
+		let app = create_app_with_config(config.clone()).await;
+
+		for email in ["change-old@example.com", "change-taken@example.com"] {
+			let resp = test::call_service(&app, create(email)).await;
+			assert!(resp.status().is_success());
+		}
+		let session: LoginResponse =
+			test::call_and_read_body_json(&app, login("change-old@example.com")).await;
+		let uid = Uuid::parse_str(&session.uid).unwrap();
+
+		// Addresses that belong to someone else are refused
+		let resp =
+			test::call_service(&app, change_email(&session.token, "change-taken@example.com"))
+				.await;
+		assert_eq!(resp.status(), 409);
+		let resp: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "EMAIL_IN_USE");
+
+		// The email only changes once the new address is confirmed
+		let resp =
+			test::call_service(&app, change_email(&session.token, "change-new@example.com")).await;
+		assert_eq!(resp.status(), 202);
+		assert_eq!(find_user(&config, uid).await.email.as_deref(), Some("change-old@example.com"));
+
+		let link = confirm_link(&config, uid).await;
+		let req = test::TestRequest::get().uri(&link).to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 302);
+		assert_eq!(
+			resp.headers().get("Location").unwrap(),
+			"http://turbocore/app/?email_changed=true"
+		);
+
+		let user = find_user(&config, uid).await;
+		assert_eq!(user.email.as_deref(), Some("change-new@example.com"));
+		assert!(user.email_verified);
+		let resp = test::call_service(&app, login("change-new@example.com")).await;
+		assert_eq!(resp.status(), 200);
+		let resp = test::call_service(&app, login("change-old@example.com")).await;
+		assert_eq!(resp.status(), 401);
+
+		// The link can only be used once
+		let req = test::TestRequest::get().uri(&link).to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "TOKEN_ALREADY_USED");
+	}
+
+	#[actix_web::test]
+	async fn test_email_change_cancel() {
+		let mailer = AsyncSmtpTransport::<Tokio1Executor>::unencrypted_localhost();
+		let config = test_config(Some(mailer), Some(email_config()));
+		let app = create_app_with_config(config.clone()).await;
+
+		let resp = test::call_service(&app, create("cancel-old@example.com")).await;
+		assert!(resp.status().is_success());
+		let session: LoginResponse =
+			test::call_and_read_body_json(&app, login("cancel-old@example.com")).await;
+		let uid = Uuid::parse_str(&session.uid).unwrap();
+
+		let resp =
+			test::call_service(&app, change_email(&session.token, "cancel-new@example.com")).await;
+		assert_eq!(resp.status(), 202);
+		let confirm = confirm_link(&config, uid).await;
+
+		// Cancelling from the old address revokes the confirmation link
+		let req = test::TestRequest::get()
+			.uri(&cancel_link(&config, uid).await)
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 302);
+
+		let req = test::TestRequest::get().uri(&confirm).to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "TOKEN_REVOKED");
+		assert_eq!(find_user(&config, uid).await.email.as_deref(), Some("cancel-old@example.com"));
+	}
+}
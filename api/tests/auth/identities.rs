@@ -0,0 +1,221 @@
+use crate::auth::{create_app_with_config, test_config};
+use actix_web::{http::header, test};
+use api::SmsConfig;
+use rand::{thread_rng, Rng};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct Identity {
+		id: String,
+		provider: String,
+		provider_subject: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct IdentityListResponse {
+		identities: Vec<Identity>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	fn create_user(email: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": email,
+				"password": "a_strong_password1111011",
+				"login": false,
+				"metadata": "",
+			}))
+			.to_request()
+	}
+
+	fn login(email: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(serde_json::json!({ "email": email, "password": "a_strong_password1111011" }))
+			.to_request()
+	}
+
+	fn list(token: &str) -> actix_http::Request {
+		test::TestRequest::get()
+			.uri("/api/auth/user/identities")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+			.to_request()
+	}
+
+	fn unlink(token: &str, id: &str) -> actix_http::Request {
+		test::TestRequest::delete()
+			.uri(&format!("/api/auth/user/identities/{id}"))
+			.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+			.to_request()
+	}
+
+	fn link_magic_link(token: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/identities/magic-link")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+			.to_request()
+	}
+
+	fn link_password(token: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/identities/password")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+			.set_json(serde_json::json!({ "password": "a_strong_password1111011" }))
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_link_and_unlink_identities() {
+		let app = create_app_with_config(test_config(None, None)).await;
+		let email = format!("identities-{}@example.com", Uuid::new_v4());
+
+		let resp = test::call_service(&app, create_user(&email)).await;
+		assert!(resp.status().is_success());
+		let login_resp: LoginResponse = test::call_and_read_body_json(&app, login(&email)).await;
+		let token = login_resp.token;
+
+		// Users created with a password can log in with it
+		let resp: IdentityListResponse = test::call_and_read_body_json(&app, list(&token)).await;
+		assert_eq!(resp.identities.len(), 1);
+		let password = &resp.identities[0];
+		assert_eq!(password.provider, "password");
+		assert_eq!(password.provider_subject, email);
+
+		// The only identity can't be unlinked
+		let resp = test::call_service(&app, unlink(&token, &password.id)).await;
+		assert_eq!(resp.status(), 400);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "LAST_IDENTITY");
+
+		let resp = test::call_service(&app, link_password(&token)).await;
+		assert_eq!(resp.status(), 409);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "IDENTITY_ALREADY_LINKED");
+
+		let resp = test::call_service(&app, link_magic_link(&token)).await;
+		assert_eq!(resp.status(), 200);
+		let resp: IdentityListResponse = test::call_and_read_body_json(&app, list(&token)).await;
+		assert_eq!(resp.identities.len(), 2);
+		assert!(resp.identities.iter().any(|i| i.provider == "magic_link"));
+
+		// Identities of other users are not found
+		let resp = test::call_service(&app, unlink(&token, &Uuid::new_v4().to_string())).await;
+		assert_eq!(resp.status(), 404);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "IDENTITY_NOT_FOUND");
+
+		// Without the password identity, the password no longer works
+		let resp = test::call_service(&app, unlink(&token, &password.id)).await;
+		assert_eq!(resp.status(), 200);
+		let resp = test::call_service(&app, login(&email)).await;
+		assert_eq!(resp.status(), 401);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "INVALID_CREDENTIALS");
+
+		let resp = test::call_service(&app, link_password(&token)).await;
+		assert_eq!(resp.status(), 200);
+		let again: LoginResponse = test::call_and_read_body_json(&app, login(&email)).await;
+		assert_eq!(again.uid, login_resp.uid);
+	}
+
+	#[actix_web::test]
+	async fn test_link_requires_recent_login() {
+		let mut config = test_config(None, None);
+		config.tokens.reauthentication_window = 0;
+		let app = create_app_with_config(config).await;
+		let email = format!("identities-{}@example.com", Uuid::new_v4());
+
+		let resp = test::call_service(&app, create_user(&email)).await;
+		assert!(resp.status().is_success());
+		let login: LoginResponse = test::call_and_read_body_json(&app, login(&email)).await;
+
+		let resp = test::call_service(&app, link_magic_link(&login.token)).await;
+		assert_eq!(resp.status(), 401);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "REAUTHENTICATION_REQUIRED");
+
+		// Listing doesn't need a recent login
+		let resp: IdentityListResponse =
+			test::call_and_read_body_json(&app, list(&login.token)).await;
+		assert_eq!(resp.identities.len(), 1);
+	}
+
+	#[actix_web::test]
+	async fn test_link_phone() {
+		let path = std::env::temp_dir().join(format!("turbocore-sms-{}.jsonl", Uuid::new_v4()));
+		let mut config = test_config(None, None);
+		config.sms = Some(SmsConfig::File {
+			path: Some(path.to_str().unwrap().to_string()),
+		});
+		let app = create_app_with_config(config).await;
+		let email = format!("identities-{}@example.com", Uuid::new_v4());
+		let phone = format!("+1555{:07}", thread_rng().gen_range(0..10_000_000));
+
+		let resp = test::call_service(&app, create_user(&email)).await;
+		assert!(resp.status().is_success());
+		let login: LoginResponse = test::call_and_read_body_json(&app, login(&email)).await;
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/phone/link")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {}", login.token)))
+			.set_json(serde_json::json!({ "phone": phone }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 200);
+		let contents = std::fs::read_to_string(&path).unwrap();
+		let message: serde_json::Value =
+			serde_json::from_str(contents.lines().last().unwrap()).unwrap();
+		let code = message["body"].as_str().unwrap()[..6].to_string();
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/phone/link/verify")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {}", login.token)))
+			.set_json(serde_json::json!({ "phone": phone, "code": code }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 200);
+
+		let resp: IdentityListResponse =
+			test::call_and_read_body_json(&app, list(&login.token)).await;
+		assert!(resp
+			.identities
+			.iter()
+			.any(|i| i.provider == "phone" && i.provider_subject == phone));
+
+		// The number now logs in to the same user
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/phone")
+			.set_json(serde_json::json!({ "phone": phone, "sign_up": false }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 200);
+		let contents = std::fs::read_to_string(&path).unwrap();
+		let message: serde_json::Value =
+			serde_json::from_str(contents.lines().last().unwrap()).unwrap();
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/phone/verify")
+			.set_json(serde_json::json!({
+				"phone": phone,
+				"code": &message["body"].as_str().unwrap()[..6],
+			}))
+			.to_request();
+		let phone_login: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(phone_login.uid, login.uid);
+
+		std::fs::remove_file(&path).unwrap();
+	}
+}
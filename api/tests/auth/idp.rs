@@ -0,0 +1,243 @@
+use crate::auth::{create_app_with_config, test_config};
+use actix_web::{http::header::ContentType, test};
+use api::{IdentityProviderConfig, OidcClient};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+const CLIENT_ID: &str = "dashboard";
+const CLIENT_SECRET: &str = "dashboard-secret";
+const REDIRECT_URI: &str = "https://dashboard.example.com/callback";
+
+fn query_map(location: &str) -> HashMap<String, String> {
+	Url::parse(location)
+		.unwrap()
+		.query_pairs()
+		.into_owned()
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct RedirectResponse {
+		redirect_url: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct TokenResponse {
+		access_token: String,
+		token_type: String,
+		id_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct TokenError {
+		error: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct IdTokenClaims {
+		iss: String,
+		sub: String,
+		nonce: String,
+		email: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct UserInfo {
+		sub: String,
+		email: String,
+	}
+
+	#[actix_web::test]
+	async fn test_authorization_code_flow() {
+		let mut config = test_config(None, None);
+		config.identity_provider = Some(IdentityProviderConfig {
+			login_url: "http://turbocore/login".to_string(),
+			clients: vec![OidcClient {
+				client_id: CLIENT_ID.to_string(),
+				client_secret: Some(CLIENT_SECRET.to_string()),
+				redirect_uris: vec![REDIRECT_URI.to_string()],
+			}],
+		});
+		let app = create_app_with_config(config).await;
+
+		// Create a user and log in
+		let req = test::TestRequest::post().uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_payload(r##"{"email":"idp@example.com","password":"a_strong_password1111011","login":true,"metadata":""}"##).to_request();
+		let user: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+		// Unknown redirect URIs are rejected without redirecting
+		let req = test::TestRequest::get()
+			.uri("/authorize?response_type=code&client_id=dashboard&redirect_uri=https%3A%2F%2Fevil.example%2F&scope=openid")
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 400);
+
+		// PKCE is required
+		let req = test::TestRequest::get()
+			.uri("/authorize?response_type=code&client_id=dashboard&redirect_uri=https%3A%2F%2Fdashboard.example.com%2Fcallback&scope=openid&state=xyz")
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 302);
+		let params = query_map(resp.headers().get("Location").unwrap().to_str().unwrap());
+		assert_eq!(params["error"], "invalid_request");
+		assert_eq!(params["state"], "xyz");
+
+		// Start a valid request, which sends the user to the login page
+		let verifier = "a_code_verifier_that_is_long_enough_to_be_valid";
+		let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+		let authorize = Url::parse_with_params(
+			"http://turbocore/authorize",
+			&[
+				("response_type", "code"),
+				("client_id", CLIENT_ID),
+				("redirect_uri", REDIRECT_URI),
+				("scope", "openid email"),
+				("state", "abc"),
+				("nonce", "n-0S6_WzA2Mj"),
+				("code_challenge", &challenge),
+				("code_challenge_method", "S256"),
+			],
+		)
+		.unwrap();
+		let req = test::TestRequest::get()
+			.uri(&format!("/authorize?{}", authorize.query().unwrap()))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 302);
+		let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+		assert!(location.starts_with("http://turbocore/login?request="));
+		let request = query_map(location)["request"].clone();
+
+		// The login page completes the request with the user's access token
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/oidc/authorize")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.set_json(serde_json::json!({ "request": request }))
+			.to_request();
+		let resp: RedirectResponse = test::call_and_read_body_json(&app, req).await;
+		assert!(resp.redirect_url.starts_with(REDIRECT_URI));
+		let params = query_map(&resp.redirect_url);
+		assert_eq!(params["state"], "abc");
+		let code = params["code"].clone();
+
+		// A wrong verifier fails, and burns the code
+		let req = test::TestRequest::post()
+			.uri("/token")
+			.set_form([
+				("grant_type", "authorization_code"),
+				("code", code.as_str()),
+				("redirect_uri", REDIRECT_URI),
+				("client_id", CLIENT_ID),
+				("client_secret", CLIENT_SECRET),
+				("code_verifier", "wrong"),
+			])
+			.to_request();
+		let resp: TokenError = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error, "invalid_grant");
+
+		// Get a fresh code and exchange it, authenticating with HTTP Basic
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/oidc/authorize")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.set_json(serde_json::json!({ "request": request }))
+			.to_request();
+		let resp: RedirectResponse = test::call_and_read_body_json(&app, req).await;
+		let code = query_map(&resp.redirect_url)["code"].clone();
+
+		let basic = base64::engine::general_purpose::STANDARD
+			.encode(format!("{CLIENT_ID}:{CLIENT_SECRET}"));
+		let token_form = [
+			("grant_type", "authorization_code"),
+			("code", code.as_str()),
+			("redirect_uri", REDIRECT_URI),
+			("code_verifier", verifier),
+		];
+		let req = test::TestRequest::post()
+			.uri("/token")
+			.insert_header(("Authorization", format!("Basic {basic}")))
+			.set_form(token_form)
+			.to_request();
+		let tokens: TokenResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(tokens.token_type, "Bearer");
+
+		// The code can only be used once
+		let req = test::TestRequest::post()
+			.uri("/token")
+			.insert_header(("Authorization", format!("Basic {basic}")))
+			.set_form(token_form)
+			.to_request();
+		let resp: TokenError = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error, "invalid_grant");
+
+		// Verify the ID token with the published keys
+		let req = test::TestRequest::get()
+			.uri("/.well-known/openid-configuration")
+			.to_request();
+		let discovery: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(discovery["issuer"], "http://turbocore");
+		assert_eq!(discovery["jwks_uri"], "http://turbocore/.well-known/jwks.json");
+
+		let req = test::TestRequest::get()
+			.uri("/.well-known/jwks.json")
+			.to_request();
+		let jwks: JwkSet = test::call_and_read_body_json(&app, req).await;
+		let kid = jsonwebtoken::decode_header(&tokens.id_token)
+			.unwrap()
+			.kid
+			.unwrap();
+		let mut validation = Validation::new(Algorithm::ES256);
+		validation.set_audience(&[CLIENT_ID]);
+		validation.set_issuer(&["http://turbocore"]);
+		let claims = jsonwebtoken::decode::<IdTokenClaims>(
+			&tokens.id_token,
+			&DecodingKey::from_jwk(jwks.find(&kid).unwrap()).unwrap(),
+			&validation,
+		)
+		.unwrap()
+		.claims;
+		assert_eq!(claims.iss, "http://turbocore");
+		assert_eq!(claims.sub, user.uid);
+		assert_eq!(claims.nonce, "n-0S6_WzA2Mj");
+		assert_eq!(claims.email, "idp@example.com");
+
+		// Access tokens are signed with the same keys, so other services can verify them
+		let kid = jsonwebtoken::decode_header(&tokens.access_token)
+			.unwrap()
+			.kid
+			.unwrap();
+		let mut validation = Validation::new(Algorithm::ES256);
+		validation.set_audience(&["TurboCore/access"]);
+		let claims = jsonwebtoken::decode::<serde_json::Value>(
+			&tokens.access_token,
+			&DecodingKey::from_jwk(jwks.find(&kid).unwrap()).unwrap(),
+			&validation,
+		)
+		.unwrap()
+		.claims;
+		assert_eq!(claims["sub"], user.uid);
+		assert_eq!(claims["aud"], "TurboCore/access");
+
+		// The access token works with the userinfo endpoint
+		let req = test::TestRequest::get()
+			.uri("/userinfo")
+			.insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+			.to_request();
+		let info: UserInfo = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(info.sub, user.uid);
+		assert_eq!(info.email, "idp@example.com");
+	}
+}
@@ -0,0 +1,179 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::{http::header::ContentType, test};
+use api::LockoutConfig;
+use entity::security_events;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct SignupResponse {
+		uid: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	fn login(email: &str, password: &str, ip: &str) -> actix_http::Request {
+		login_request(email, password, ip).to_request()
+	}
+
+	fn login_request(email: &str, password: &str, ip: &str) -> test::TestRequest {
+		test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.peer_addr(format!("{ip}:40000").parse().unwrap())
+			.set_json(serde_json::json!({ "email": email, "password": password }))
+	}
+
+	#[actix_web::test]
+	async fn test_account_lockout() {
+		let mut config = test_config(None, None);
+		config.lockout = LockoutConfig {
+			max_failures: 3,
+			max_ip_failures: 100,
+			..LockoutConfig::default()
+		};
+		let app = create_app_with_config(config.clone()).await;
+		let ip = "203.0.113.7";
+
+		let req = test::TestRequest::post().uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_payload(r##"{"email":"lockout@example.com","password":"a_strong_password1111011","login":false,"metadata":""}"##).to_request();
+		let user: SignupResponse = test::call_and_read_body_json(&app, req).await;
+
+		// Wrong passwords are rejected until the limit, which locks the account
+		for _ in 0..2 {
+			let resp: ErrorResponse =
+				test::call_and_read_body_json(&app, login("lockout@example.com", "wrong", ip))
+					.await;
+			assert_eq!(resp.error_code, "INVALID_CREDENTIALS");
+		}
+		let resp = test::call_service(&app, login("lockout@example.com", "wrong", ip)).await;
+		assert_eq!(resp.status(), 429);
+		let resp: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "ACCOUNT_LOCKED");
+
+		// While locked, even the right password is rejected
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			login("lockout@example.com", "a_strong_password1111011", ip),
+		)
+		.await;
+		assert_eq!(resp.error_code, "ACCOUNT_LOCKED");
+
+		// Emails without an account lock the same way
+		for _ in 0..2 {
+			test::call_service(&app, login("nobody@example.com", "wrong", ip)).await;
+		}
+		let resp: ErrorResponse =
+			test::call_and_read_body_json(&app, login("nobody@example.com", "wrong", ip)).await;
+		assert_eq!(resp.error_code, "ACCOUNT_LOCKED");
+
+		// The lock was recorded
+		let connection = connect(&config).await;
+		let events = security_events::Entity::find()
+			.filter(security_events::Column::Uid.eq(Uuid::parse_str(&user.uid).unwrap()))
+			.all(&connection)
+			.await
+			.unwrap();
+		assert_eq!(events.len(), 1);
+		assert_eq!(events[0].event, "account_locked");
+
+		// An admin can lift the lock
+		let req = test::TestRequest::delete()
+			.uri(&format!("/api/admin/user/{}/lockout", user.uid))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		let resp =
+			test::call_service(&app, login("lockout@example.com", "a_strong_password1111011", ip))
+				.await;
+		assert!(resp.status().is_success());
+	}
+
+	#[actix_web::test]
+	async fn test_ip_lockout() {
+		let mut config = test_config(None, None);
+		config.lockout = LockoutConfig {
+			max_failures: 100,
+			max_ip_failures: 3,
+			..LockoutConfig::default()
+		};
+		let app = create_app_with_config(config).await;
+		let ip = "203.0.113.8";
+
+		// Guessing across many accounts locks the IP address
+		for i in 0..2 {
+			let resp: ErrorResponse = test::call_and_read_body_json(
+				&app,
+				login(&format!("ip-lockout-{i}@example.com"), "wrong", ip),
+			)
+			.await;
+			assert_eq!(resp.error_code, "INVALID_CREDENTIALS");
+		}
+		let resp: ErrorResponse =
+			test::call_and_read_body_json(&app, login("ip-lockout-2@example.com", "wrong", ip))
+				.await;
+		assert_eq!(resp.error_code, "ACCOUNT_LOCKED");
+
+		// Claiming to be forwarded for another address does not get around it
+		let req = login_request("ip-lockout-3@example.com", "wrong", ip)
+			.insert_header(("X-Forwarded-For", "203.0.113.10"))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "ACCOUNT_LOCKED");
+
+		// but other addresses are not locked
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			login("ip-lockout-3@example.com", "wrong", "203.0.113.9"),
+		)
+		.await;
+		assert_eq!(resp.error_code, "INVALID_CREDENTIALS");
+	}
+
+	#[actix_web::test]
+	async fn test_ip_lockout_behind_proxy() {
+		let mut config = test_config(None, None);
+		config.lockout = LockoutConfig {
+			max_failures: 100,
+			max_ip_failures: 2,
+			..LockoutConfig::default()
+		};
+		config.trusted_proxies = vec!["192.0.2.1".parse().unwrap()];
+		let app = create_app_with_config(config).await;
+		let proxy = "192.0.2.1";
+
+		// Behind a trusted proxy, the address it forwarded for is locked rather than the proxy's
+		let forwarded = |email: &str, client: &str| {
+			login_request(email, "wrong", proxy)
+				.insert_header(("X-Forwarded-For", format!("198.51.100.1, {client}")))
+				.to_request()
+		};
+		for i in 0..2 {
+			test::call_service(
+				&app,
+				forwarded(&format!("proxied-lockout-{i}@example.com"), "203.0.113.20"),
+			)
+			.await;
+		}
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			forwarded("proxied-lockout-2@example.com", "203.0.113.20"),
+		)
+		.await;
+		assert_eq!(resp.error_code, "ACCOUNT_LOCKED");
+
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			forwarded("proxied-lockout-2@example.com", "203.0.113.21"),
+		)
+		.await;
+		assert_eq!(resp.error_code, "INVALID_CREDENTIALS");
+	}
+}
@@ -0,0 +1,117 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::{http::header::ContentType, test};
+use api::{auth::util::hash_secret, Config, EmailConfig};
+use entity::login_codes;
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use sea_orm::{ActiveModelTrait, EntityTrait, Set};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct SignupResponse {
+		uid: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	/// Emails go nowhere, so the tests set the code themselves
+	fn email_config() -> EmailConfig {
+		EmailConfig {
+			smtp_server: "localhost".to_string(),
+			smtp_port: 25,
+			smtp_username: "".to_string(),
+			smtp_password: "".to_string(),
+			smtp_encryption: "none".to_string(),
+			from: "TurboCore <noreply@turbocore.org>".to_string(),
+			reply_to: "TurboCore <noreply@turbocore.org>".to_string(),
+			magic_link_subject: "Your login code".to_string(),
+			forgot_password_subject: "".to_string(),
+			confirmation_subject: "".to_string(),
+			security_alert_subject: None,
+			account_locked_subject: None,
+			password_changed_subject: None,
+			email_change_subject: "".to_string(),
+		}
+	}
+
+	async fn set_code(config: &Config, uid: Uuid, code: &str) {
+		let connection = connect(config).await;
+		let login_code = login_codes::Entity::find_by_id(uid)
+			.one(&connection)
+			.await
+			.unwrap()
+			.unwrap();
+		let mut login_code: login_codes::ActiveModel = login_code.into();
+		// Codes are bound to the address they were sent to
+		login_code.code_hash = Set(hash_secret(&format!("code@example.com:{code}")));
+		login_code.update(&connection).await.unwrap();
+	}
+
+	fn send_code() -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/magic-link")
+			.set_json(serde_json::json!({
+				"email": "code@example.com",
+				"sign_up": false,
+				"mode": "code",
+			}))
+			.to_request()
+	}
+
+	fn verify(code: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/magic-link/verify")
+			.set_json(serde_json::json!({ "email": "code@example.com", "code": code }))
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_login_code() {
+		let mailer = AsyncSmtpTransport::<Tokio1Executor>::unencrypted_localhost();
+		let config = test_config(Some(mailer), Some(email_config()));
+		let app = create_app_with_config(config.clone()).await;
+
+		let req = test::TestRequest::post().uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_payload(r##"{"email":"code@example.com","password":"a_strong_password1111011","login":false,"metadata":""}"##).to_request();
+		let user: SignupResponse = test::call_and_read_body_json(&app, req).await;
+		let uid = Uuid::parse_str(&user.uid).unwrap();
+
+		let resp = test::call_service(&app, send_code()).await;
+		assert_eq!(resp.status(), 200);
+		set_code(&config, uid, "123456").await;
+
+		// A wrong code is rejected, and the right one logs in
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, verify("000000")).await;
+		assert_eq!(resp.error_code, "INVALID_CODE");
+		let resp: LoginResponse = test::call_and_read_body_json(&app, verify("123456")).await;
+		assert_eq!(resp.uid, user.uid);
+		assert!(!resp.token.is_empty());
+
+		// The code can only be used once
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, verify("123456")).await;
+		assert_eq!(resp.error_code, "CODE_EXPIRED");
+
+		// Too many wrong guesses use up the code
+		let resp = test::call_service(&app, send_code()).await;
+		assert_eq!(resp.status(), 200);
+		set_code(&config, uid, "654321").await;
+		for _ in 0..5 {
+			let resp: ErrorResponse = test::call_and_read_body_json(&app, verify("000000")).await;
+			assert_eq!(resp.error_code, "INVALID_CODE");
+		}
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, verify("654321")).await;
+		assert_eq!(resp.error_code, "CODE_EXPIRED");
+	}
+}
@@ -0,0 +1,296 @@
+use crate::auth::{connect, create_app, create_app_with_config, test_config};
+use actix_web::{http::header::ContentType, test};
+use api::{
+	auth::{
+		action_tokens::{self, ActionKind},
+		claims::{self, MagicLinkClaims, RegisteredClaims, TokenClaims},
+		totp,
+	},
+	LockoutConfig,
+};
+use chrono::Duration;
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct EnrollResponse {
+		secret: String,
+		otpauth_uri: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct RecoveryCodesResponse {
+		recovery_codes: Vec<String>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ChallengeResponse {
+		uid: String,
+		mfa_required: bool,
+		mfa_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	#[actix_web::test]
+	async fn test_totp_login_flow() {
+		let app = create_app(None, None).await;
+
+		// Create a user and log in
+		let req = test::TestRequest::post().uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_payload(r##"{"email":"mfa@example.com","password":"a_strong_password1111011","login":true,"metadata":""}"##).to_request();
+		let user: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+		// Start enrollment
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.to_request();
+		let enroll: EnrollResponse = test::call_and_read_body_json(&app, req).await;
+		assert!(enroll
+			.otpauth_uri
+			.starts_with("otpauth://totp/TurboCore:mfa@example.com"));
+
+		// A wrong code does not confirm enrollment
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp/confirm")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.set_json(serde_json::json!({ "code": "abc123" }))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "INVALID_MFA_CODE");
+
+		// Confirm with the current code
+		let code = totp::code_at(&enroll.secret, totp::current_step()).unwrap();
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp/confirm")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.set_json(serde_json::json!({ "code": code }))
+			.to_request();
+		let codes: RecoveryCodesResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(codes.recovery_codes.len(), 10);
+
+		// Logging in now requires a second factor
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(
+				serde_json::json!({ "email": "mfa@example.com", "password": "a_strong_password1111011" }),
+			)
+			.to_request();
+		let challenge: ChallengeResponse = test::call_and_read_body_json(&app, req).await;
+		assert!(challenge.mfa_required);
+		assert_eq!(challenge.uid, user.uid);
+
+		// The code used for confirmation can't be replayed, but the next one is accepted
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login/mfa")
+			.set_json(serde_json::json!({ "mfa_token": challenge.mfa_token, "code": code }))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "INVALID_MFA_CODE");
+
+		let next_code = totp::code_at(&enroll.secret, totp::current_step() + 1).unwrap();
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login/mfa")
+			.set_json(serde_json::json!({ "mfa_token": challenge.mfa_token, "code": next_code }))
+			.to_request();
+		let resp: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.uid, user.uid);
+
+		// Recovery codes work exactly once
+		let recovery_code = &codes.recovery_codes[0];
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login/mfa")
+			.set_json(
+				serde_json::json!({ "mfa_token": challenge.mfa_token, "recovery_code": recovery_code }),
+			)
+			.to_request();
+		let resp: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.uid, user.uid);
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login/mfa")
+			.set_json(
+				serde_json::json!({ "mfa_token": challenge.mfa_token, "recovery_code": recovery_code }),
+			)
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "INVALID_MFA_CODE");
+
+		// Disable MFA with another recovery code
+		let req = test::TestRequest::delete()
+			.uri("/api/auth/user/mfa/totp")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.set_json(serde_json::json!({ "recovery_code": codes.recovery_codes[1] }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+	}
+
+	#[actix_web::test]
+	async fn test_magic_link_requires_second_factor() {
+		let config = test_config(None, None);
+		let app = create_app_with_config(config.clone()).await;
+		let email = format!("mfa-magic-{}@example.com", Uuid::new_v4());
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": email,
+				"password": "a_strong_password1111011",
+				"login": true,
+				"metadata": "",
+			}))
+			.to_request();
+		let user: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.to_request();
+		let enroll: EnrollResponse = test::call_and_read_body_json(&app, req).await;
+		let code = totp::code_at(&enroll.secret, totp::current_step()).unwrap();
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp/confirm")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.set_json(serde_json::json!({ "code": code }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		// Issue a magic link like the email would
+		let uid = Uuid::parse_str(&user.uid).unwrap();
+		let connection = connect(&config).await;
+		let link = MagicLinkClaims {
+			registered: RegisteredClaims::new(MagicLinkClaims::AUDIENCE, Duration::minutes(15)),
+			sub: uid,
+			next: "http://turbocore/app".to_string(),
+			email: None,
+		};
+		action_tokens::issue(&connection, uid, ActionKind::MagicLink, &link.registered)
+			.await
+			.unwrap();
+		let link = claims::sign(&link, &config.secret_key);
+
+		// The link only leads to a challenge, not to tokens
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/auth/user/magic-link/{link}"))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 302);
+		let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+		assert!(location.contains("mfa_required=true"));
+		assert!(!location.contains("&at="));
+		let mfa_token = location
+			.split(['?', '&'])
+			.find_map(|param| param.strip_prefix("mfa_token="))
+			.unwrap();
+
+		let next_code = totp::code_at(&enroll.secret, totp::current_step() + 1).unwrap();
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login/mfa")
+			.set_json(serde_json::json!({ "mfa_token": mfa_token, "code": next_code }))
+			.to_request();
+		let resp: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.uid, user.uid);
+	}
+
+	#[actix_web::test]
+	async fn test_wrong_codes_are_limited() {
+		let mut config = test_config(None, None);
+		config.lockout = LockoutConfig {
+			max_failures: 7,
+			..LockoutConfig::default()
+		};
+		let app = create_app_with_config(config).await;
+		let email = format!("mfa-limit-{}@example.com", Uuid::new_v4());
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": email,
+				"password": "a_strong_password1111011",
+				"login": true,
+				"metadata": "",
+			}))
+			.to_request();
+		let user: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.to_request();
+		let enroll: EnrollResponse = test::call_and_read_body_json(&app, req).await;
+		let code = totp::code_at(&enroll.secret, totp::current_step()).unwrap();
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp/confirm")
+			.insert_header(("Authorization", format!("Bearer {}", user.token)))
+			.set_json(serde_json::json!({ "code": code }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		let login = || {
+			test::TestRequest::post()
+				.uri("/api/auth/user/login")
+				.set_json(
+					serde_json::json!({ "email": email, "password": "a_strong_password1111011" }),
+				)
+				.to_request()
+		};
+		let challenge_request = |mfa_token: &str, code: &str| {
+			test::TestRequest::post()
+				.uri("/api/auth/user/login/mfa")
+				.set_json(serde_json::json!({ "mfa_token": mfa_token, "code": code }))
+				.to_request()
+		};
+		let next_code = totp::code_at(&enroll.secret, totp::current_step() + 1).unwrap();
+
+		// A challenge stops working after five codes, even the right one
+		let challenge: ChallengeResponse = test::call_and_read_body_json(&app, login()).await;
+		for _ in 0..5 {
+			let resp: ErrorResponse = test::call_and_read_body_json(
+				&app,
+				challenge_request(&challenge.mfa_token, "abc123"),
+			)
+			.await;
+			assert_eq!(resp.error_code, "INVALID_MFA_CODE");
+		}
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			challenge_request(&challenge.mfa_token, &next_code),
+		)
+		.await;
+		assert_eq!(resp.error_code, "EXPIRED_TOKEN");
+
+		// Logging in again gives a new challenge, but wrong codes are counted against the user too
+		let challenge: ChallengeResponse = test::call_and_read_body_json(&app, login()).await;
+		let resp: ErrorResponse =
+			test::call_and_read_body_json(&app, challenge_request(&challenge.mfa_token, "abc123"))
+				.await;
+		assert_eq!(resp.error_code, "INVALID_MFA_CODE");
+		let resp =
+			test::call_service(&app, challenge_request(&challenge.mfa_token, "abc123")).await;
+		assert_eq!(resp.status(), 429);
+		let resp: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "ACCOUNT_LOCKED");
+
+		let resp: ErrorResponse = test::call_and_read_body_json(
+			&app,
+			challenge_request(&challenge.mfa_token, &next_code),
+		)
+		.await;
+		assert_eq!(resp.error_code, "ACCOUNT_LOCKED");
+	}
+}
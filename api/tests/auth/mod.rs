@@ -6,38 +6,106 @@ use actix_web::{
 	web::{self, Data},
 	App,
 };
-use api::{AppState, Argon2Config, Config, EmailConfig, JsonError};
+use api::{
+	breached_passwords::BreachedPasswords, keys::KeyRing, AnonymousUserConfig, AppState, Argon2Config,
+	Config, EmailConfig, JsonError, LockoutConfig, PasswordHashConfig, PasswordPolicyConfig,
+	RateLimitConfig, SigningConfig, TokenConfig, WebauthnConfig,
+};
 use hmac::{Hmac, Mac};
 use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use migration::{Migrator, MigratorTrait};
-use std::path::Path;
+use jsonwebtoken::{jwk::JwkSet, DecodingKey, Validation};
+use sea_orm::DatabaseConnection;
 use uaparser::UserAgentParser;
+use uuid::Uuid;
 
+mod action_tokens;
+mod anonymous;
+mod breached_password;
 mod create_user;
+mod custom_claims;
+mod email_change;
+mod identities;
+mod idp;
+mod lockout;
+mod login_code;
+mod mfa;
+mod oauth;
+mod passkey;
+mod phone;
+mod password_hashing;
+mod password_policy;
+mod rbac;
+mod refresh;
+mod reset_password;
+mod sessions;
+mod user_transfer;
 
 pub async fn create_app(
 	mailer: Option<AsyncSmtpTransport<Tokio1Executor>>,
 	email: Option<EmailConfig>,
 ) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+	create_app_with_config(test_config(mailer, email)).await
+}
+
+/// The config used by `create_app`, for tests that need to change it
+pub fn test_config(
+	mailer: Option<AsyncSmtpTransport<Tokio1Executor>>,
+	email: Option<EmailConfig>,
+) -> Config {
 	// Create a new HMAC-SHA256 key
 	let secret_key = Hmac::new_from_slice("a_secret_key".repeat(3).as_bytes()).unwrap();
+	// Each config gets its own database, so tests don't see each other's data or the last run's
+	let connection_url = std::env::temp_dir().join(format!("turbocore-{}.sqlite", Uuid::new_v4()));
+
+	Config {
+		bind_addr: "not_used".to_string(),
+		connection_url: format!("sqlite://{}?mode=rwc", connection_url.display()),
+		base_url: "http://turbocore".to_string(),
+		secret_key,
+		debug_level: "debug".to_string(),
+		argon2_config: Argon2Config::default(),
+		password_hashing: PasswordHashConfig::default(),
+		minimum_password_strength: 1,
+		password_policy: PasswordPolicyConfig::default(),
+		mailer,
+		email,
+		sms: None,
+		allowed_origins: vec![],
+		webauthn: WebauthnConfig {
+			rp_id: "turbocore".to_string(),
+			rp_name: "TurboCore".to_string(),
+			origins: vec!["http://turbocore".to_string()],
+		},
+		oauth_providers: vec![],
+		identity_provider: None,
+		signing: SigningConfig::default(),
+		tokens: TokenConfig::default(),
+		lockout: LockoutConfig::default(),
+		anonymous_users: AnonymousUserConfig::default(),
+		rate_limit: RateLimitConfig::default(),
+		trusted_proxies: vec![],
+		breached_passwords: None,
+		projects: vec![],
+	}
+}
 
+pub async fn create_app_with_config(
+	config: Config,
+) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
 	// Load the regexes from the YAML file
 	let ua_parser = UserAgentParser::from_yaml("../regexes.yaml").unwrap();
 
 	// Create a connection to the database
-	let connection;
+	let connection = connect(&config).await;
 
-	if !Path::new("../test.sqlite").exists() { // Prevent migration from running twice in tests
-		connection = sea_orm::Database::connect("sqlite://../test.sqlite?mode=rwc".to_string())
-			.await
-			.unwrap();
-		Migrator::up(&connection, None).await.unwrap();
-	} else {
-		connection = sea_orm::Database::connect("sqlite://../test.sqlite".to_string())
-			.await
-			.unwrap();
-	}
+	let key_ring = KeyRing::load(&connection, &config.signing).await.unwrap();
+	let breached_passwords = config
+		.breached_passwords
+		.as_ref()
+		.map(|breached_config| BreachedPasswords::load(breached_config).unwrap());
+
+	let sms = config.sms.as_ref().map(|sms_config| sms_config.sender());
 
 	// Create the JSON config
 	let json_cfg = web::JsonConfig::default().error_handler(|err, _req| {
@@ -53,23 +121,47 @@ pub async fn create_app(
 	test::init_service(
 		App::new()
 			.app_data(Data::new(AppState {
-				config: Config {
-					bind_addr: "not_used".to_string(),
-					connection_url: "sqlite://../test.sqlite?mode=rwc".to_string(),
-					base_url: "http://turbocore".to_string(),
-					secret_key,
-					debug_level: "debug".to_string(),
-					argon2_config: Argon2Config::default(),
-					minimum_password_strength: 1,
-					mailer,
-					email,
-                    allowed_origins: vec![]
-				},
+				config,
 				connection,
 				ua_parser,
+				key_ring,
+				breached_passwords,
+				sms,
 			}))
 			.app_data(json_cfg)
-			.configure(api::auth::add_routes),
+			.configure(api::auth::add_routes)
+			.configure(|cfg| api::admin::add_routes(cfg, |route, _| route))
+			.configure(api::idp::add_routes),
 	)
 	.await
 }
+
+/// Connects to the database of apps made with `config`, migrating it if it's new. Tests use it to
+/// read or change the database directly.
+pub async fn connect(config: &Config) -> DatabaseConnection {
+	let connection = sea_orm::Database::connect(config.connection_url.to_owned())
+		.await
+		.unwrap();
+	Migrator::up(&connection, None).await.unwrap();
+	connection
+}
+
+/// Verifies a token with the keys published at `/.well-known/jwks.json`, and returns its claims
+pub async fn verify_token(
+	app: &impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+	token: &str,
+) -> serde_json::Value {
+	let req = test::TestRequest::get()
+		.uri("/.well-known/jwks.json")
+		.to_request();
+	let jwks: JwkSet = test::call_and_read_body_json(app, req).await;
+
+	let header = jsonwebtoken::decode_header(token).unwrap();
+	let jwk = jwks.find(&header.kid.unwrap()).unwrap();
+	let mut validation = Validation::new(header.alg);
+	// The audience depends on the kind of token, and is checked by the tests themselves
+	validation.validate_aud = false;
+	jsonwebtoken::decode(token, &DecodingKey::from_jwk(jwk).unwrap(), &validation)
+		.unwrap()
+		.claims
+}
@@ -0,0 +1,341 @@
+use crate::auth::{create_app_with_config, test_config};
+use actix_web::{
+	cookie::Cookie,
+	test,
+	web::{self, Data, Form},
+	App, HttpResponse, HttpServer,
+};
+use api::OAuthProviderConfig;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{EncodingKey, Header};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, net::TcpListener};
+
+const CLIENT_ID: &str = "turbocore-client";
+const CLIENT_SECRET: &str = "mock-client-secret";
+
+/// A minimal OpenID provider. Instead of showing a login page, the test builds the authorization
+/// code itself as "{code_challenge}.{nonce}.{subject}.{email}", which the token endpoint checks.
+fn start_mock_provider() -> String {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+	let server_issuer = issuer.clone();
+	let server = HttpServer::new(move || {
+		App::new()
+			.app_data(Data::new(server_issuer.clone()))
+			.route("/.well-known/openid-configuration", web::get().to(discovery))
+			.route("/token", web::post().to(token))
+	})
+	.workers(1)
+	.listen(listener)
+	.unwrap()
+	.run();
+	actix_web::rt::spawn(server);
+
+	issuer
+}
+
+async fn discovery(issuer: Data<String>) -> HttpResponse {
+	HttpResponse::Ok().json(serde_json::json!({
+		"issuer": issuer.as_str(),
+		"authorization_endpoint": format!("{}/authorize", issuer.as_str()),
+		"token_endpoint": format!("{}/token", issuer.as_str()),
+	}))
+}
+
+async fn token(issuer: Data<String>, form: Form<HashMap<String, String>>) -> HttpResponse {
+	let mut parts = form["code"].splitn(4, '.');
+	let (challenge, nonce, subject, email) = (
+		parts.next().unwrap(),
+		parts.next().unwrap(),
+		parts.next().unwrap(),
+		parts.next().unwrap(),
+	);
+
+	let verifier_hash = URL_SAFE_NO_PAD.encode(Sha256::digest(form["code_verifier"].as_bytes()));
+	if form["client_secret"] != CLIENT_SECRET || verifier_hash != challenge {
+		return HttpResponse::BadRequest().json(serde_json::json!({"error": "invalid_grant"}));
+	}
+
+	let now = chrono::Utc::now().timestamp();
+	let id_token = jsonwebtoken::encode(
+		&Header::default(),
+		&serde_json::json!({
+			"iss": issuer.as_str(),
+			"aud": CLIENT_ID,
+			"sub": subject,
+			"email": email,
+			"email_verified": true,
+			"nonce": nonce,
+			"iat": now,
+			"exp": now + 300,
+		}),
+		&EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+	)
+	.unwrap();
+
+	HttpResponse::Ok().json(serde_json::json!({
+		"access_token": "unused",
+		"token_type": "Bearer",
+		"id_token": id_token,
+	}))
+}
+
+fn location(resp: &actix_web::dev::ServiceResponse) -> Url {
+	assert_eq!(resp.status(), 302);
+	Url::parse(resp.headers().get("Location").unwrap().to_str().unwrap()).unwrap()
+}
+
+fn query_map(url: &Url) -> HashMap<String, String> {
+	url.query_pairs().into_owned().collect()
+}
+
+/// The results of a login, which are sent in the fragment so that they never reach a server
+fn fragment_map(url: &Url) -> HashMap<String, String> {
+	form_urlencoded::parse(url.fragment().unwrap_or_default().as_bytes())
+		.into_owned()
+		.collect()
+}
+
+/// The cookie that binds the login's state to the browser that started it
+fn state_cookie(resp: &actix_web::dev::ServiceResponse) -> Cookie<'static> {
+	resp.response()
+		.cookies()
+		.find(|cookie| cookie.name() == "turbocore_oauth_state")
+		.unwrap()
+		.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[actix_web::test]
+	async fn test_oauth_login() {
+		let issuer = start_mock_provider();
+		let mut config = test_config(None, None);
+		config.oauth_providers = vec![OAuthProviderConfig {
+			name: "mock".to_string(),
+			issuer: issuer.clone(),
+			client_id: CLIENT_ID.to_string(),
+			client_secret: CLIENT_SECRET.to_string(),
+			scopes: vec!["openid".to_string(), "email".to_string()],
+		}];
+		let app = create_app_with_config(config).await;
+
+		let subject = uuid::Uuid::new_v4().to_string();
+		let email = format!("oauth-{subject}@example.com");
+
+		// Tokens must not be sent to arbitrary origins
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user/oauth/mock/start?next_url=https%3A%2F%2Fevil.example%2F")
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 400);
+
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user/oauth/unknown/start?next_url=http%3A%2F%2Fturbocore%2Fdone")
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 404);
+
+		let mut uid = None;
+		for _ in 0..2 {
+			// Start the login and check the authorization request
+			let req = test::TestRequest::get()
+				.uri("/api/auth/user/oauth/mock/start?next_url=http%3A%2F%2Fturbocore%2Fdone")
+				.to_request();
+			let resp = test::call_service(&app, req).await;
+			let cookie = state_cookie(&resp);
+			assert_eq!(cookie.http_only(), Some(true));
+			assert_eq!(cookie.path(), Some("/api/auth/user/oauth"));
+			let authorize = location(&resp);
+			assert_eq!(
+				authorize.as_str().split('?').next().unwrap(),
+				format!("{issuer}/authorize")
+			);
+			let params = query_map(&authorize);
+			assert_eq!(params["client_id"], CLIENT_ID);
+			assert_eq!(params["code_challenge_method"], "S256");
+			assert_eq!(params["scope"], "openid email");
+			assert_eq!(
+				params["redirect_uri"],
+				"http://turbocore/api/auth/user/oauth/mock/callback"
+			);
+
+			let code =
+				format!("{}.{}.{}.{}", params["code_challenge"], params["nonce"], subject, email);
+			let callback = Url::parse_with_params(
+				"http://turbocore/api/auth/user/oauth/mock/callback",
+				&[("code", code.as_str()), ("state", params["state"].as_str())],
+			)
+			.unwrap();
+			let callback_uri = format!("{}?{}", callback.path(), callback.query().unwrap());
+
+			// The login can only be completed in the browser that started it
+			let req = test::TestRequest::get().uri(&callback_uri).to_request();
+			let resp = test::call_service(&app, req).await;
+			assert_eq!(resp.status(), 400);
+			let req = test::TestRequest::get()
+				.uri(&callback_uri)
+				.cookie(Cookie::new("turbocore_oauth_state", "another_state"))
+				.to_request();
+			let resp = test::call_service(&app, req).await;
+			assert_eq!(resp.status(), 400);
+
+			let req = test::TestRequest::get()
+				.uri(&callback_uri)
+				.cookie(cookie.clone())
+				.to_request();
+			let resp = test::call_service(&app, req).await;
+			assert_eq!(state_cookie(&resp).value(), "");
+			let done = location(&resp);
+			assert_eq!(done.as_str().split('#').next().unwrap(), "http://turbocore/done");
+			let result = fragment_map(&done);
+			assert!(!result.contains_key("error"), "{result:?}");
+			assert!(!result["at"].is_empty());
+			assert!(!result["rt"].is_empty());
+
+			// The same subject always maps to the same user
+			match uid {
+				None => uid = Some(result["uid"].clone()),
+				Some(ref uid) => assert_eq!(uid, &result["uid"]),
+			}
+
+			// The state can only be used once
+			let req = test::TestRequest::get()
+				.uri(&callback_uri)
+				.cookie(cookie)
+				.to_request();
+			let resp = test::call_service(&app, req).await;
+			assert_eq!(resp.status(), 400);
+		}
+
+		// A tampered code fails PKCE at the provider, and the user is sent back with an error
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user/oauth/mock/start?next_url=http%3A%2F%2Fturbocore%2Fdone")
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		let cookie = state_cookie(&resp);
+		let params = query_map(&location(&resp));
+		let code = format!("wrong.{}.{}.{}", params["nonce"], subject, email);
+		let callback = Url::parse_with_params(
+			"http://turbocore/api/auth/user/oauth/mock/callback",
+			&[("code", code.as_str()), ("state", params["state"].as_str())],
+		)
+		.unwrap();
+		let req = test::TestRequest::get()
+			.uri(&format!("{}?{}", callback.path(), callback.query().unwrap()))
+			.cookie(cookie)
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		let result = fragment_map(&location(&resp));
+		assert_eq!(result["error"], "OAUTH_PROVIDER_ERROR");
+	}
+
+	#[actix_web::test]
+	async fn test_oauth_link_anonymous_user() {
+		#[derive(serde::Deserialize)]
+		struct LoginResponse {
+			uid: String,
+			token: String,
+		}
+
+		#[derive(serde::Deserialize)]
+		struct RedirectResponse {
+			redirect_url: String,
+		}
+
+		#[derive(serde::Deserialize)]
+		struct UserResponse {
+			email: String,
+			anonymous: bool,
+		}
+
+		let issuer = start_mock_provider();
+		let mut config = test_config(None, None);
+		config.anonymous_users.enabled = true;
+		config.oauth_providers = vec![OAuthProviderConfig {
+			name: "mock".to_string(),
+			issuer,
+			client_id: CLIENT_ID.to_string(),
+			client_secret: CLIENT_SECRET.to_string(),
+			scopes: vec!["openid".to_string(), "email".to_string()],
+		}];
+		let app = create_app_with_config(config).await;
+
+		let subject = uuid::Uuid::new_v4().to_string();
+		let email = format!("oauth-anonymous-{subject}@example.com");
+
+		// Links the provider's identity to a new anonymous user, and returns where the user ends up
+		let link = || async {
+			let req = test::TestRequest::post()
+				.uri("/api/auth/user/anonymous")
+				.to_request();
+			let guest: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+			let req = test::TestRequest::post()
+				.uri("/api/auth/user/oauth/mock/link")
+				.insert_header(("Authorization", format!("Bearer {}", guest.token)))
+				.set_json(serde_json::json!({ "next_url": "http://turbocore/done" }))
+				.to_request();
+			let resp = test::call_service(&app, req).await;
+			let cookie = state_cookie(&resp);
+			let resp: RedirectResponse = test::read_body_json(resp).await;
+			let params = query_map(&Url::parse(&resp.redirect_url).unwrap());
+
+			let code =
+				format!("{}.{}.{}.{}", params["code_challenge"], params["nonce"], subject, email);
+			let callback = Url::parse_with_params(
+				"http://turbocore/api/auth/user/oauth/mock/callback",
+				&[("code", code.as_str()), ("state", params["state"].as_str())],
+			)
+			.unwrap();
+			let req = test::TestRequest::get()
+				.uri(&format!("{}?{}", callback.path(), callback.query().unwrap()))
+				.cookie(cookie)
+				.to_request();
+			let resp = test::call_service(&app, req).await;
+			(guest, fragment_map(&location(&resp)))
+		};
+
+		// The anonymous user keeps their uid and takes the provider's email
+		let (guest, result) = link().await;
+		assert!(!result.contains_key("error"), "{result:?}");
+		assert_eq!(result["uid"], guest.uid);
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user")
+			.insert_header(("Authorization", format!("Bearer {}", guest.token)))
+			.to_request();
+		let user: UserResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(user.email, email);
+		assert!(!user.anonymous);
+
+		// Logging in with the provider again finds the upgraded user
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user/oauth/mock/start?next_url=http%3A%2F%2Fturbocore%2Fdone")
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		let cookie = state_cookie(&resp);
+		let params = query_map(&location(&resp));
+		let code =
+			format!("{}.{}.{}.{}", params["code_challenge"], params["nonce"], subject, email);
+		let callback = Url::parse_with_params(
+			"http://turbocore/api/auth/user/oauth/mock/callback",
+			&[("code", code.as_str()), ("state", params["state"].as_str())],
+		)
+		.unwrap();
+		let req = test::TestRequest::get()
+			.uri(&format!("{}?{}", callback.path(), callback.query().unwrap()))
+			.cookie(cookie)
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(fragment_map(&location(&resp))["uid"], guest.uid);
+
+		// An identity can only belong to one user
+		let (_, result) = link().await;
+		assert_eq!(result["error"], "OAUTH_IDENTITY_IN_USE");
+	}
+}
@@ -0,0 +1,299 @@
+use crate::auth::create_app;
+use actix_web::{http::header::ContentType, test};
+use api::auth::webauthn::{decode_base64url, encode_base64url};
+use ciborium::value::Value;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use sha2::{Digest, Sha256};
+
+/// A minimal authenticator that keeps its key in memory, so the ceremonies can be tested without hardware
+struct SoftAuthenticator {
+	key: SigningKey,
+	credential_id: Vec<u8>,
+	sign_count: u32,
+	/// Many platform authenticators don't count their signatures, and always report 0
+	counts_signatures: bool,
+}
+
+impl SoftAuthenticator {
+	fn new() -> Self {
+		let secret: [u8; 32] = rand::random();
+		Self {
+			key: SigningKey::from_slice(&secret).unwrap(),
+			credential_id: rand::random::<[u8; 16]>().to_vec(),
+			sign_count: 0,
+			counts_signatures: true,
+		}
+	}
+
+	fn cose_key(&self) -> Vec<u8> {
+		let point = self.key.verifying_key().to_encoded_point(false);
+		let key = Value::Map(vec![
+			(Value::Integer(1.into()), Value::Integer(2.into())),
+			(Value::Integer(3.into()), Value::Integer((-7).into())),
+			(Value::Integer((-1).into()), Value::Integer(1.into())),
+			(Value::Integer((-2).into()), Value::Bytes(point.x().unwrap().to_vec())),
+			(Value::Integer((-3).into()), Value::Bytes(point.y().unwrap().to_vec())),
+		]);
+		let mut bytes = vec![];
+		ciborium::ser::into_writer(&key, &mut bytes).unwrap();
+		bytes
+	}
+
+	fn authenticator_data(&mut self, attested: bool) -> Vec<u8> {
+		if self.counts_signatures {
+			self.sign_count += 1;
+		}
+		let mut data = Sha256::digest(b"turbocore").to_vec();
+		data.push(if attested { 0x45 } else { 0x05 }); // UP, UV and optionally AT
+		data.extend_from_slice(&self.sign_count.to_be_bytes());
+		if attested {
+			data.extend_from_slice(&[0; 16]); // AAGUID
+			data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+			data.extend_from_slice(&self.credential_id);
+			data.extend_from_slice(&self.cose_key());
+		}
+		data
+	}
+
+	fn client_data(ceremony: &str, challenge: &str) -> Vec<u8> {
+		serde_json::to_vec(&serde_json::json!({
+			"type": ceremony,
+			"challenge": challenge,
+			"origin": "http://turbocore",
+			"crossOrigin": false,
+		}))
+		.unwrap()
+	}
+
+	fn create(&mut self, challenge: &str) -> serde_json::Value {
+		let client_data = Self::client_data("webauthn.create", challenge);
+		let attestation = Value::Map(vec![
+			(Value::Text("fmt".to_string()), Value::Text("none".to_string())),
+			(Value::Text("attStmt".to_string()), Value::Map(vec![])),
+			(Value::Text("authData".to_string()), Value::Bytes(self.authenticator_data(true))),
+		]);
+		let mut attestation_object = vec![];
+		ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+
+		serde_json::json!({
+			"id": encode_base64url(&self.credential_id),
+			"rawId": encode_base64url(&self.credential_id),
+			"type": "public-key",
+			"response": {
+				"clientDataJSON": encode_base64url(&client_data),
+				"attestationObject": encode_base64url(&attestation_object),
+			}
+		})
+	}
+
+	fn get(&mut self, challenge: &str, user_handle: &[u8]) -> serde_json::Value {
+		let client_data = Self::client_data("webauthn.get", challenge);
+		let authenticator_data = self.authenticator_data(false);
+
+		let mut message = authenticator_data.clone();
+		message.extend_from_slice(&Sha256::digest(&client_data));
+		let signature: Signature = self.key.sign(&message);
+
+		serde_json::json!({
+			"id": encode_base64url(&self.credential_id),
+			"rawId": encode_base64url(&self.credential_id),
+			"type": "public-key",
+			"response": {
+				"clientDataJSON": encode_base64url(&client_data),
+				"authenticatorData": encode_base64url(&authenticator_data),
+				"signature": encode_base64url(signature.to_der().as_bytes()),
+				"userHandle": encode_base64url(user_handle),
+			}
+		})
+	}
+}
+
+mod tests {
+	use std::str::FromStr;
+
+	use super::*;
+	use uuid::Uuid;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct OptionsResponse {
+		options: serde_json::Value,
+		state: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct Passkey {
+		id: String,
+		name: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ListResponse {
+		passkeys: Vec<Passkey>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	#[actix_web::test]
+	async fn test_passkey_register_and_login() {
+		let app = create_app(None, None).await;
+		let mut authenticator = SoftAuthenticator::new();
+
+		let req = test::TestRequest::post().uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_payload(r##"{"email":"passkey@example.com","password":"a_strong_password1111011","login":true,"metadata":""}"##).to_request();
+		let user: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		let auth = ("Authorization", format!("Bearer {}", user.token));
+
+		// Registration ceremony
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/register/start")
+			.insert_header(auth.clone())
+			.to_request();
+		let start: OptionsResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(start.options["rp"]["id"], "turbocore");
+		let user_handle = decode_base64url(start.options["user"]["id"].as_str().unwrap()).unwrap();
+		assert_eq!(Uuid::from_slice(&user_handle).unwrap(), Uuid::from_str(&user.uid).unwrap());
+
+		let credential = authenticator.create(start.options["challenge"].as_str().unwrap());
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/register/finish")
+			.insert_header(auth.clone())
+			.set_json(
+				serde_json::json!({ "state": start.state, "name": "Laptop", "credential": credential }),
+			)
+			.to_request();
+		let passkey: Passkey = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(passkey.name, "Laptop");
+		assert_eq!(passkey.id, encode_base64url(&authenticator.credential_id));
+
+		// The same credential can't be registered twice
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/register/finish")
+			.insert_header(auth.clone())
+			.set_json(serde_json::json!({ "state": start.state, "credential": credential }))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "PASSKEY_EXISTS");
+
+		// Authentication ceremony
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/login/start")
+			.set_json(serde_json::json!({ "email": "passkey@example.com" }))
+			.to_request();
+		let start: OptionsResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(start.options["allowCredentials"][0]["id"], passkey.id.as_str());
+
+		let assertion =
+			authenticator.get(start.options["challenge"].as_str().unwrap(), &user_handle);
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/login/finish")
+			.set_json(serde_json::json!({ "state": start.state, "credential": assertion }))
+			.to_request();
+		let resp: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.uid, user.uid);
+
+		// Replaying the assertion is caught by the signature counter
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/login/finish")
+			.set_json(serde_json::json!({ "state": start.state, "credential": assertion }))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "WEBAUTHN_VERIFICATION_FAILED");
+
+		// An assertion for another challenge is rejected
+		let assertion = authenticator.get("another_challenge", &user_handle);
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/login/finish")
+			.set_json(serde_json::json!({ "state": start.state, "credential": assertion }))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "WEBAUTHN_VERIFICATION_FAILED");
+
+		// List and delete
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user/passkeys")
+			.insert_header(auth.clone())
+			.to_request();
+		let list: ListResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(list.passkeys.len(), 1);
+
+		let req = test::TestRequest::delete()
+			.uri(&format!("/api/auth/user/passkeys/{}", passkey.id))
+			.insert_header(auth.clone())
+			.to_request();
+		assert!(test::call_service(&app, req).await.status().is_success());
+
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user/passkeys")
+			.insert_header(auth)
+			.to_request();
+		let list: ListResponse = test::call_and_read_body_json(&app, req).await;
+		assert!(list.passkeys.is_empty());
+	}
+
+	#[actix_web::test]
+	async fn test_passkey_login_state_is_used_once() {
+		let app = create_app(None, None).await;
+		let mut authenticator = SoftAuthenticator {
+			counts_signatures: false,
+			..SoftAuthenticator::new()
+		};
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": "passkey-uncounted@example.com",
+				"password": "a_strong_password1111011",
+				"login": true,
+				"metadata": "",
+			}))
+			.to_request();
+		let user: LoginResponse = test::call_and_read_body_json(&app, req).await;
+		let auth = ("Authorization", format!("Bearer {}", user.token));
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/register/start")
+			.insert_header(auth.clone())
+			.to_request();
+		let start: OptionsResponse = test::call_and_read_body_json(&app, req).await;
+		let user_handle = decode_base64url(start.options["user"]["id"].as_str().unwrap()).unwrap();
+		let credential = authenticator.create(start.options["challenge"].as_str().unwrap());
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/register/finish")
+			.insert_header(auth)
+			.set_json(serde_json::json!({ "state": start.state, "credential": credential }))
+			.to_request();
+		assert!(test::call_service(&app, req).await.status().is_success());
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/passkey/login/start")
+			.set_json(serde_json::json!({}))
+			.to_request();
+		let start: OptionsResponse = test::call_and_read_body_json(&app, req).await;
+		let assertion =
+			authenticator.get(start.options["challenge"].as_str().unwrap(), &user_handle);
+		let finish = || {
+			test::TestRequest::post()
+				.uri("/api/auth/user/passkey/login/finish")
+				.set_json(serde_json::json!({ "state": start.state, "credential": assertion }))
+				.to_request()
+		};
+		let resp: LoginResponse = test::call_and_read_body_json(&app, finish()).await;
+		assert_eq!(resp.uid, user.uid);
+
+		// The counter stays at 0, so only the state stops the assertion being replayed
+		let resp = test::call_service(&app, finish()).await;
+		assert_eq!(resp.status(), 400);
+		let resp: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "TOKEN_ALREADY_USED");
+	}
+}
@@ -0,0 +1,140 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::test;
+use api::{auth::identities, Config, FirebaseScryptConfig, PasswordHashAlgorithm};
+use chrono::Utc;
+use entity::users;
+use sea_orm::{EntityTrait, Set};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+	}
+
+	async fn find_password(config: &Config, uid: Uuid) -> String {
+		let connection = connect(config).await;
+		users::Entity::find_by_id(uid)
+			.one(&connection)
+			.await
+			.unwrap()
+			.unwrap()
+			.password
+	}
+
+	/// Adds a user like an import from another provider would
+	async fn import(config: &Config, email: &str, password_hash: String) -> Uuid {
+		let connection = connect(config).await;
+		let uid = Uuid::new_v4();
+		users::Entity::insert(users::ActiveModel {
+			uid: Set(uid),
+			email: Set(Some(email.to_owned())),
+			password: Set(password_hash),
+			created_at: Set(Utc::now().naive_utc()),
+			updated_at: Set(Utc::now().naive_utc()),
+			last_login: Set(None),
+			active: Set(true),
+			metadata: Set(None),
+			email_verified: Set(true),
+			password_changed_at: Set(None),
+			anonymous: Set(false),
+			phone: Set(None),
+			phone_verified: Set(false),
+		})
+		.exec(&connection)
+		.await
+		.unwrap();
+		entity::identities::Entity::insert(identities::new(
+			uid,
+			identities::PASSWORD,
+			email,
+			Some(email.to_owned()),
+		))
+		.exec(&connection)
+		.await
+		.unwrap();
+		uid
+	}
+
+	fn login(email: &str, password: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(serde_json::json!({ "email": email, "password": password }))
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_imported_hashes_rehashed() {
+		let mut config = test_config(None, None);
+		// The example from Firebase's scrypt documentation
+		config.password_hashing.firebase_scrypt = Some(FirebaseScryptConfig {
+			signer_key: "jxspr8Ki0RYycVU8zykbdLGjFQ3McFUH0uiiTvC8pVMXAn210wjLNmdZJzxUECKbm0QsEmYUSDzZvpjeJ9WmXA==".to_string(),
+			salt_separator: "Bw==".to_string(),
+			rounds: 8,
+			mem_cost: 14,
+		});
+		let app = create_app_with_config(config.clone()).await;
+
+		let imported = [
+			(
+				"imported-bcrypt@example.com",
+				"bcrypt_password_1384",
+				bcrypt::hash("bcrypt_password_1384", 4).unwrap(),
+			),
+			(
+				"imported-firebase@example.com",
+				"user1password",
+				"$firebase-scrypt$42xEC+ixf3L2lw==$lSrfV15cpx95/sZS2W9c9Kp6i/LVgQNDNC/qzrCnh1SAyZvqmZqAjTdn3aoItz+VHjoZilo78198JAdRuid5lQ==".to_string(),
+			),
+		];
+		for (email, password, hash) in imported {
+			let uid = import(&config, email, hash).await;
+
+			let resp = test::call_service(&app, login(email, "not_the_password")).await;
+			assert_eq!(resp.status(), 401);
+
+			let resp = test::call_service(&app, login(email, password)).await;
+			assert_eq!(resp.status(), 200);
+			assert!(find_password(&config, uid).await.starts_with("$argon2id$"));
+
+			// The new hash works too
+			let resp = test::call_service(&app, login(email, password)).await;
+			assert_eq!(resp.status(), 200);
+		}
+	}
+
+	#[actix_web::test]
+	async fn test_outdated_parameters_rehashed() {
+		let mut config = test_config(None, None);
+		config.password_hashing.algorithm = PasswordHashAlgorithm::Bcrypt;
+		config.password_hashing.bcrypt_cost = 4;
+		let app = create_app_with_config(config.clone()).await;
+
+		let password = "rehashed_password_5512";
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": "rehash@example.com",
+				"password": password,
+				"login": false,
+				"metadata": "",
+			}))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		// Hashes that are up to date are kept
+		let session: LoginResponse =
+			test::call_and_read_body_json(&app, login("rehash@example.com", password)).await;
+		let uid = Uuid::parse_str(&session.uid).unwrap();
+		assert!(find_password(&config, uid).await.starts_with("$2b$04$"));
+
+		config.password_hashing.bcrypt_cost = 5;
+		let app = create_app_with_config(config.clone()).await;
+		let resp = test::call_service(&app, login("rehash@example.com", password)).await;
+		assert_eq!(resp.status(), 200);
+		assert!(find_password(&config, uid).await.starts_with("$2b$05$"));
+	}
+}
@@ -0,0 +1,279 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::test;
+use api::{auth::totp, PasswordPolicyConfig};
+use chrono::{Duration, Utc};
+use entity::users;
+use sea_orm::{ActiveModelTrait, EntityTrait, Set};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct Violation {
+		code: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct PolicyError {
+		error_code: String,
+		violations: Vec<Violation>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ChallengeResponse {
+		mfa_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct EnrollResponse {
+		secret: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ExpiredResponse {
+		password_expired: bool,
+		reset_token: String,
+	}
+
+	fn create(email: &str, password: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/create")
+			.set_json(serde_json::json!({
+				"email": email,
+				"password": password,
+				"login": false,
+				"metadata": "",
+			}))
+			.to_request()
+	}
+
+	fn login(email: &str, password: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(serde_json::json!({ "email": email, "password": password }))
+			.to_request()
+	}
+
+	fn change_password(token: &str, old: &str, new: &str) -> actix_http::Request {
+		test::TestRequest::patch()
+			.uri("/api/auth/user/change-password")
+			.insert_header(("Authorization", format!("Bearer {token}")))
+			.set_json(serde_json::json!({ "old_password": old, "new_password": new }))
+			.to_request()
+	}
+
+	fn codes(resp: &PolicyError) -> Vec<&str> {
+		resp.violations.iter().map(|v| v.code.as_str()).collect()
+	}
+
+	#[actix_web::test]
+	async fn test_password_policy_violations() {
+		let mut config = test_config(None, None);
+		config.password_policy = PasswordPolicyConfig {
+			min_length: 12,
+			..PasswordPolicyConfig::default()
+		};
+		let app = create_app_with_config(config).await;
+
+		// Every broken rule is listed
+		let resp = test::call_service(&app, create("policy-many@example.com", "aaaa")).await;
+		assert_eq!(resp.status(), 400);
+		let resp: PolicyError = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "INVALID_PASSWORD");
+		assert_eq!(codes(&resp), ["PASSWORD_TOO_SHORT", "WEAK_PASSWORD"]);
+
+		// Passwords based on the email are weaker
+		let resp =
+			test::call_service(&app, create("zephyrquokka@example.com", "zephyrquokka")).await;
+		assert_eq!(resp.status(), 400);
+		let resp: PolicyError = test::read_body_json(resp).await;
+		assert_eq!(codes(&resp), ["WEAK_PASSWORD"]);
+
+		let resp =
+			test::call_service(&app, create("policy-long@example.com", &"Ab1_".repeat(40))).await;
+		let resp: PolicyError = test::read_body_json(resp).await;
+		assert_eq!(codes(&resp), ["PASSWORD_TOO_LONG"]);
+	}
+
+	#[actix_web::test]
+	async fn test_password_history() {
+		let mut config = test_config(None, None);
+		config.password_policy = PasswordPolicyConfig {
+			history: 3,
+			..PasswordPolicyConfig::default()
+		};
+		let app = create_app_with_config(config).await;
+
+		let passwords = [
+			"history_password_0493a",
+			"history_password_0493b",
+			"history_password_0493c",
+			"history_password_0493d",
+		];
+		let resp =
+			test::call_service(&app, create("policy-history@example.com", passwords[0])).await;
+		assert!(resp.status().is_success());
+		let session: LoginResponse =
+			test::call_and_read_body_json(&app, login("policy-history@example.com", passwords[0]))
+				.await;
+
+		// The current password can not be kept
+		let resp =
+			test::call_service(&app, change_password(&session.token, passwords[0], passwords[0]))
+				.await;
+		assert_eq!(resp.status(), 400);
+		let resp: PolicyError = test::read_body_json(resp).await;
+		assert_eq!(codes(&resp), ["PASSWORD_REUSED"]);
+
+		let resp =
+			test::call_service(&app, change_password(&session.token, passwords[0], passwords[1]))
+				.await;
+		assert_eq!(resp.status(), 200);
+
+		// Nor can one of the last 3
+		let resp =
+			test::call_service(&app, change_password(&session.token, passwords[1], passwords[0]))
+				.await;
+		assert_eq!(resp.status(), 400);
+
+		for pair in passwords[1..].windows(2) {
+			let resp =
+				test::call_service(&app, change_password(&session.token, pair[0], pair[1])).await;
+			assert_eq!(resp.status(), 200);
+		}
+
+		// The first password has been forgotten
+		let resp =
+			test::call_service(&app, change_password(&session.token, passwords[3], passwords[1]))
+				.await;
+		assert_eq!(resp.status(), 400);
+		let resp =
+			test::call_service(&app, change_password(&session.token, passwords[3], passwords[0]))
+				.await;
+		assert_eq!(resp.status(), 200);
+	}
+
+	#[actix_web::test]
+	async fn test_password_expiry() {
+		let mut config = test_config(None, None);
+		config.password_policy = PasswordPolicyConfig {
+			max_age_days: Some(90),
+			..PasswordPolicyConfig::default()
+		};
+		let app = create_app_with_config(config.clone()).await;
+
+		let password = "expiring_password_8831";
+		let resp = test::call_service(&app, create("policy-expiry@example.com", password)).await;
+		assert!(resp.status().is_success());
+		let session: LoginResponse =
+			test::call_and_read_body_json(&app, login("policy-expiry@example.com", password)).await;
+		assert!(!session.token.is_empty());
+
+		let connection = connect(&config).await;
+		let user = users::Entity::find_by_id(Uuid::parse_str(&session.uid).unwrap())
+			.one(&connection)
+			.await
+			.unwrap()
+			.unwrap();
+		let mut user: users::ActiveModel = user.into();
+		user.password_changed_at = Set(Some(Utc::now().naive_utc() - Duration::days(91)));
+		user.update(&connection).await.unwrap();
+
+		// Logging in only hands out a token to change the password
+		let resp: ExpiredResponse =
+			test::call_and_read_body_json(&app, login("policy-expiry@example.com", password)).await;
+		assert!(resp.password_expired);
+
+		let confirm = |new_password: &str| {
+			test::TestRequest::post()
+				.uri("/api/auth/user/reset-password/confirm")
+				.set_json(serde_json::json!({
+					"token": resp.reset_token,
+					"new_password": new_password,
+				}))
+				.to_request()
+		};
+
+		// The expired password must really be changed
+		let denied = test::call_service(&app, confirm(password)).await;
+		assert_eq!(denied.status(), 400);
+		let denied: PolicyError = test::read_body_json(denied).await;
+		assert_eq!(codes(&denied), ["PASSWORD_REUSED"]);
+
+		let changed = test::call_service(&app, confirm("renewed_password_8831")).await;
+		assert!(changed.status().is_success());
+
+		let session: LoginResponse = test::call_and_read_body_json(
+			&app,
+			login("policy-expiry@example.com", "renewed_password_8831"),
+		)
+		.await;
+		assert!(!session.token.is_empty());
+	}
+
+	#[actix_web::test]
+	async fn test_password_expiry_with_mfa() {
+		let mut config = test_config(None, None);
+		config.password_policy = PasswordPolicyConfig {
+			max_age_days: Some(90),
+			..PasswordPolicyConfig::default()
+		};
+		let app = create_app_with_config(config.clone()).await;
+
+		let email = format!("policy-expiry-mfa-{}@example.com", Uuid::new_v4());
+		let password = "expiring_password_8831";
+		let resp = test::call_service(&app, create(&email, password)).await;
+		assert!(resp.status().is_success());
+		let session: LoginResponse =
+			test::call_and_read_body_json(&app, login(&email, password)).await;
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp")
+			.insert_header(("Authorization", format!("Bearer {}", session.token)))
+			.to_request();
+		let enroll: EnrollResponse = test::call_and_read_body_json(&app, req).await;
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/mfa/totp/confirm")
+			.insert_header(("Authorization", format!("Bearer {}", session.token)))
+			.set_json(serde_json::json!({
+				"code": totp::code_at(&enroll.secret, totp::current_step()).unwrap(),
+			}))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		let connection = connect(&config).await;
+		let user = users::Entity::find_by_id(Uuid::parse_str(&session.uid).unwrap())
+			.one(&connection)
+			.await
+			.unwrap()
+			.unwrap();
+		let mut user: users::ActiveModel = user.into();
+		user.password_changed_at = Set(Some(Utc::now().naive_utc() - Duration::days(91)));
+		user.update(&connection).await.unwrap();
+
+		// The password alone doesn't hand out a reset token, which would get around the second factor
+		let resp = test::call_service(&app, login(&email, password)).await;
+		let body: serde_json::Value = test::read_body_json(resp).await;
+		assert!(body.get("reset_token").is_none());
+		let challenge: ChallengeResponse = serde_json::from_value(body).unwrap();
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login/mfa")
+			.set_json(serde_json::json!({
+				"mfa_token": challenge.mfa_token,
+				"code": totp::code_at(&enroll.secret, totp::current_step() + 1).unwrap(),
+			}))
+			.to_request();
+		let resp: ExpiredResponse = test::call_and_read_body_json(&app, req).await;
+		assert!(resp.password_expired);
+		assert!(!resp.reset_token.is_empty());
+	}
+}
@@ -0,0 +1,238 @@
+use crate::auth::{create_app_with_config, test_config};
+use actix_web::{
+	http::header,
+	test,
+	web::{self, Data, Json},
+	App, HttpRequest, HttpResponse, HttpServer,
+};
+use api::{Config, SmsConfig};
+use rand::{thread_rng, Rng};
+use std::{
+	net::TcpListener,
+	path::Path,
+	sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+type Messages = Arc<Mutex<Vec<serde_json::Value>>>;
+
+/// A minimal SMS provider, which keeps the messages posted to `/messages` and fails every message posted
+/// to `/fail`
+fn start_mock_provider(messages: Messages) -> String {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}", listener.local_addr().unwrap());
+
+	let server = HttpServer::new(move || {
+		App::new()
+			.app_data(Data::new(messages.clone()))
+			.route("/messages", web::post().to(receive))
+			.route("/fail", web::post().to(HttpResponse::ServiceUnavailable))
+	})
+	.workers(1)
+	.listen(listener)
+	.unwrap()
+	.run();
+	actix_web::rt::spawn(server);
+
+	url
+}
+
+async fn receive(
+	request: HttpRequest,
+	messages: Data<Messages>,
+	body: Json<serde_json::Value>,
+) -> HttpResponse {
+	match request.headers().get(header::AUTHORIZATION) {
+		Some(value) if value == "Bearer mock-token" => {
+			messages.lock().unwrap().push(body.into_inner());
+			HttpResponse::Ok().finish()
+		}
+		_ => HttpResponse::Unauthorized().finish(),
+	}
+}
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct UserResponse {
+		uid: String,
+		email: String,
+		phone: Option<String>,
+		phone_verified: bool,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	/// A number that no other test uses
+	fn new_phone() -> String {
+		format!("+1555{:07}", thread_rng().gen_range(0..10_000_000))
+	}
+
+	fn file_config(path: &Path) -> Config {
+		let mut config = test_config(None, None);
+		config.sms = Some(SmsConfig::File {
+			path: Some(path.to_str().unwrap().to_string()),
+		});
+		config
+	}
+
+	/// Reads the code from the last message in the file
+	fn code_from_file(path: &Path) -> String {
+		let contents = std::fs::read_to_string(path).unwrap();
+		let message: serde_json::Value =
+			serde_json::from_str(contents.lines().last().unwrap()).unwrap();
+		code_from(&message)
+	}
+
+	fn code_from(message: &serde_json::Value) -> String {
+		message["body"].as_str().unwrap()[..6].to_string()
+	}
+
+	fn send(phone: &str, sign_up: bool) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/phone")
+			.set_json(serde_json::json!({ "phone": phone, "sign_up": sign_up }))
+			.to_request()
+	}
+
+	fn verify(phone: &str, code: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/phone/verify")
+			.set_json(serde_json::json!({ "phone": phone, "code": code }))
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_phone_not_configured() {
+		let app = create_app_with_config(test_config(None, None)).await;
+
+		let resp = test::call_service(&app, send(&new_phone(), true)).await;
+		assert_eq!(resp.status(), 400);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "SMS_NOT_CONFIGURED");
+	}
+
+	#[actix_web::test]
+	async fn test_phone_sign_up_and_login() {
+		let path = std::env::temp_dir().join(format!("turbocore-sms-{}.jsonl", Uuid::new_v4()));
+		let app = create_app_with_config(file_config(&path)).await;
+		let phone = new_phone();
+
+		// Numbers must include the country code
+		let resp = test::call_service(&app, send(&phone[2..], true)).await;
+		assert_eq!(resp.status(), 400);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "INVALID_PHONE");
+
+		let resp = test::call_service(&app, send(&phone, false)).await;
+		assert_eq!(resp.status(), 400);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "USER_DOES_NOT_EXIST");
+
+		// Formatting is ignored
+		let formatted = format!("+1 ({}) {}-{}", &phone[2..5], &phone[5..8], &phone[8..]);
+		let resp = test::call_service(&app, send(&formatted, true)).await;
+		assert_eq!(resp.status(), 200);
+		let code = code_from_file(&path);
+
+		let resp = test::call_service(&app, send(&phone, true)).await;
+		assert_eq!(resp.status(), 400);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "USER_ALREADY_EXISTS");
+		// No new code was sent, so the first one still works
+		assert_eq!(code_from_file(&path), code);
+
+		let wrong_code = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+		let resp = test::call_service(&app, verify(&phone, &wrong_code)).await;
+		assert_eq!(resp.status(), 401);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "INVALID_CODE");
+
+		let resp = test::call_service(&app, verify(&phone, &code)).await;
+		assert_eq!(resp.status(), 200);
+		let login: LoginResponse = test::read_body_json(resp).await;
+
+		// Each code can only be used once
+		let resp = test::call_service(&app, verify(&phone, &code)).await;
+		assert_eq!(resp.status(), 400);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "CODE_EXPIRED");
+
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {}", login.token)))
+			.to_request();
+		let user: UserResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(user.uid, login.uid);
+		assert_eq!(user.email, "");
+		assert_eq!(user.phone, Some(phone.to_owned()));
+		assert!(user.phone_verified);
+
+		// The user has no email to verify
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/verify-email")
+			.insert_header((header::AUTHORIZATION, format!("Bearer {}", login.token)))
+			.set_json(serde_json::json!({ "next_url": "http://turbocore/app" }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 400);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "NO_EMAIL");
+
+		// Logging in again
+		let resp = test::call_service(&app, send(&phone, false)).await;
+		assert_eq!(resp.status(), 200);
+		let resp = test::call_service(&app, verify(&phone, &code_from_file(&path))).await;
+		assert_eq!(resp.status(), 200);
+		let again: LoginResponse = test::read_body_json(resp).await;
+		assert_eq!(again.uid, login.uid);
+
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[actix_web::test]
+	async fn test_phone_http_provider() {
+		let messages = Messages::default();
+		let url = start_mock_provider(messages.clone());
+		let phone = new_phone();
+
+		let mut config = test_config(None, None);
+		config.sms = Some(SmsConfig::Http {
+			url: format!("{url}/fail"),
+			from: "+15555550100".to_string(),
+			auth_token: Some("mock-token".to_string()),
+		});
+		let app = create_app_with_config(config.clone()).await;
+		let resp = test::call_service(&app, send(&phone, true)).await;
+		assert_eq!(resp.status(), 502);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "SMS_NOT_SENT");
+
+		config.sms = Some(SmsConfig::Http {
+			url: format!("{url}/messages"),
+			from: "+15555550100".to_string(),
+			auth_token: Some("mock-token".to_string()),
+		});
+		let app = create_app_with_config(config).await;
+		// The user was created by the failed request
+		let resp = test::call_service(&app, send(&phone, false)).await;
+		assert_eq!(resp.status(), 200);
+
+		let message = messages.lock().unwrap().last().unwrap().clone();
+		assert_eq!(message["from"], "+15555550100");
+		assert_eq!(message["to"], phone.as_str());
+
+		let resp = test::call_service(&app, verify(&phone, &code_from(&message))).await;
+		assert_eq!(resp.status(), 200);
+	}
+}
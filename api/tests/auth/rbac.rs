@@ -0,0 +1,228 @@
+use crate::auth::{connect, create_app_with_config, test_config, verify_token};
+use actix_web::test;
+use chrono::Utc;
+use entity::admins;
+use sea_orm::{EntityTrait, Set};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct Role {
+		name: String,
+		permissions: Vec<String>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct RoleListResponse {
+		roles: Vec<Role>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct UserPermissionsResponse {
+		roles: Vec<String>,
+		groups: Vec<String>,
+		permissions: Vec<String>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	fn post(uri: &str, body: serde_json::Value) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri(uri)
+			.set_json(body)
+			.to_request()
+	}
+
+	fn put(uri: &str) -> actix_http::Request {
+		test::TestRequest::put().uri(uri).to_request()
+	}
+
+	fn login(email: &str) -> actix_http::Request {
+		post(
+			"/api/auth/user/login",
+			serde_json::json!({ "email": email, "password": "a_strong_password1111011" }),
+		)
+	}
+
+	#[actix_web::test]
+	async fn test_roles_and_groups() {
+		let config = test_config(None, None);
+		let app = create_app_with_config(config.clone()).await;
+		// Names are unique, so that the test can run against the same database again
+		let suffix = Uuid::new_v4().simple().to_string();
+		let (read, write) = (format!("users:read:{suffix}"), format!("users:write:{suffix}"));
+		let (viewer, editor) = (format!("viewer-{suffix}"), format!("editor-{suffix}"));
+		let support = format!("support-{suffix}");
+
+		let resp = test::call_service(
+			&app,
+			post("/api/admin/permissions", serde_json::json!({ "name": "users read" })),
+		)
+		.await;
+		assert_eq!(resp.status(), 400);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "INVALID_NAME");
+
+		for permission in [&read, &write] {
+			let resp = test::call_service(
+				&app,
+				post("/api/admin/permissions", serde_json::json!({ "name": permission })),
+			)
+			.await;
+			assert_eq!(resp.status(), 201);
+		}
+		let resp = test::call_service(
+			&app,
+			post("/api/admin/permissions", serde_json::json!({ "name": read })),
+		)
+		.await;
+		assert_eq!(resp.status(), 409);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "PERMISSION_EXISTS");
+
+		// Roles can only grant permissions that exist
+		let resp = test::call_service(
+			&app,
+			post(
+				"/api/admin/roles",
+				serde_json::json!({ "name": viewer, "permissions": [read, "missing"] }),
+			),
+		)
+		.await;
+		assert_eq!(resp.status(), 404);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "PERMISSION_NOT_FOUND");
+
+		let resp = test::call_service(
+			&app,
+			post("/api/admin/roles", serde_json::json!({ "name": viewer, "permissions": [read] })),
+		)
+		.await;
+		assert_eq!(resp.status(), 201);
+		let resp = test::call_service(
+			&app,
+			post(
+				"/api/admin/roles",
+				serde_json::json!({ "name": editor, "permissions": [read, write] }),
+			),
+		)
+		.await;
+		assert_eq!(resp.status(), 201);
+		let resp = test::call_service(
+			&app,
+			post("/api/admin/groups", serde_json::json!({ "name": support, "roles": [editor] })),
+		)
+		.await;
+		assert_eq!(resp.status(), 201);
+
+		let req = test::TestRequest::get()
+			.uri("/api/admin/roles")
+			.to_request();
+		let resp: RoleListResponse = test::call_and_read_body_json(&app, req).await;
+		let role = resp.roles.iter().find(|role| role.name == editor).unwrap();
+		assert_eq!(role.permissions, vec![read.to_owned(), write.to_owned()]);
+
+		// A user in the viewer role and the support group
+		let email = format!("rbac-{suffix}@example.com");
+		let req = post(
+			"/api/auth/user/create",
+			serde_json::json!({
+				"email": email,
+				"password": "a_strong_password1111011",
+				"login": false,
+				"metadata": "",
+			}),
+		);
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+		let user: LoginResponse = test::call_and_read_body_json(&app, login(&email)).await;
+		let token = verify_token(&app, &user.token).await;
+		assert!(token.get("permissions").is_none());
+
+		let resp = test::call_service(
+			&app,
+			put(&format!("/api/admin/roles/{viewer}/members/{}", Uuid::new_v4())),
+		)
+		.await;
+		assert_eq!(resp.status(), 404);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "USER_NOT_FOUND");
+
+		for uri in [
+			format!("/api/admin/roles/{viewer}/members/{}", user.uid),
+			format!("/api/admin/groups/{support}/members/{}", user.uid),
+		] {
+			let resp = test::call_service(&app, put(&uri)).await;
+			assert_eq!(resp.status(), 200);
+		}
+
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/admin/user/{}/permissions", user.uid))
+			.to_request();
+		let resp: UserPermissionsResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.roles, vec![editor.to_owned(), viewer.to_owned()]);
+		assert_eq!(resp.groups, vec![support.to_owned()]);
+		assert_eq!(resp.permissions, vec![read.to_owned(), write.to_owned()]);
+
+		let user: LoginResponse = test::call_and_read_body_json(&app, login(&email)).await;
+		let token = verify_token(&app, &user.token).await;
+		assert_eq!(token["permissions"], serde_json::json!([read, write]));
+
+		// Deleting the editor role takes its permissions away from the group
+		let req = test::TestRequest::delete()
+			.uri(&format!("/api/admin/roles/{editor}"))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 200);
+		let user: LoginResponse = test::call_and_read_body_json(&app, login(&email)).await;
+		let token = verify_token(&app, &user.token).await;
+		assert_eq!(token["permissions"], serde_json::json!([read]));
+
+		let req = test::TestRequest::delete()
+			.uri(&format!("/api/admin/roles/{viewer}/members/{}", user.uid))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 200);
+		let user: LoginResponse = test::call_and_read_body_json(&app, login(&email)).await;
+		let token = verify_token(&app, &user.token).await;
+		assert!(token.get("permissions").is_none());
+
+		// Admins can be members too
+		let connection = connect(&config).await;
+		let uid = Uuid::new_v4();
+		admins::Entity::insert(admins::ActiveModel {
+			uid: Set(uid),
+			email: Set(format!("rbac-admin-{suffix}@example.com")),
+			password: Set(String::new()),
+			created_at: Set(Utc::now().naive_utc()),
+			updated_at: Set(Utc::now().naive_utc()),
+			last_login: Set(None),
+			active: Set(true),
+			metadata: Set(None),
+			email_verified: Set(true),
+		})
+		.exec(&connection)
+		.await
+		.unwrap();
+		let resp =
+			test::call_service(&app, put(&format!("/api/admin/roles/{viewer}/members/{uid}")))
+				.await;
+		assert_eq!(resp.status(), 200);
+		let req = test::TestRequest::get()
+			.uri(&format!("/api/admin/user/{uid}/permissions"))
+			.to_request();
+		let resp: UserPermissionsResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.permissions, vec![read.to_owned()]);
+	}
+}
@@ -0,0 +1,85 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::{http::header::ContentType, test};
+use entity::security_events;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		refresh_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct RefreshResponse {
+		refresh_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	#[actix_web::test]
+	async fn test_refresh_token_reuse() {
+		let config = test_config(None, None);
+		let app = create_app_with_config(config.clone()).await;
+
+		// Create a user, and log in on two devices
+		let req = test::TestRequest::post().uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_payload(r##"{"email":"refresh@example.com","password":"a_strong_password1111011","login":true,"metadata":""}"##).to_request();
+		let first: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(
+				serde_json::json!({ "email": "refresh@example.com", "password": "a_strong_password1111011" }),
+			)
+			.to_request();
+		let second: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+		// Rotate the first session's token
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": first.refresh_token }))
+			.to_request();
+		let rotated: RefreshResponse = test::call_and_read_body_json(&app, req).await;
+
+		// Using the old token again revokes its family, including the token it was rotated into
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": first.refresh_token }))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "EXPIRED_JWT");
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": rotated.refresh_token }))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "EXPIRED_JWT");
+
+		// The other session is not affected
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": second.refresh_token }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		// The reuse was recorded
+		let connection = connect(&config).await;
+		let events = security_events::Entity::find()
+			.filter(security_events::Column::Uid.eq(Uuid::parse_str(&first.uid).unwrap()))
+			.all(&connection)
+			.await
+			.unwrap();
+		assert_eq!(events.len(), 1);
+		assert_eq!(events[0].event, "refresh_token_reuse");
+	}
+}
@@ -0,0 +1,112 @@
+use crate::auth::{connect, create_app_with_config, test_config};
+use actix_web::{http::header::ContentType, test};
+use api::{
+	auth::{
+		action_tokens::{self, ActionKind},
+		claims::{self, PasswordResetClaims, RegisteredClaims, TokenClaims},
+	},
+	Config,
+};
+use chrono::Duration;
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		uid: String,
+		refresh_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	/// Issues a reset token like the reset email would
+	async fn reset_token(config: &Config, uid: Uuid) -> String {
+		let connection = connect(config).await;
+		let claims = PasswordResetClaims {
+			registered: RegisteredClaims::new(PasswordResetClaims::AUDIENCE, Duration::minutes(15)),
+			sub: uid,
+		};
+		action_tokens::issue(&connection, uid, ActionKind::PasswordReset, &claims.registered)
+			.await
+			.unwrap();
+		claims::sign(&claims, &config.secret_key)
+	}
+
+	fn confirm(token: &str, new_password: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/reset-password/confirm")
+			.set_json(serde_json::json!({ "token": token, "new_password": new_password }))
+			.to_request()
+	}
+
+	fn login(password: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(serde_json::json!({ "email": "reset@example.com", "password": password }))
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_reset_password() {
+		let config = test_config(None, None);
+		let app = create_app_with_config(config.clone()).await;
+
+		let req = test::TestRequest::post().uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_payload(r##"{"email":"reset@example.com","password":"a_strong_password1111011","login":false,"metadata":""}"##).to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+		let session: LoginResponse =
+			test::call_and_read_body_json(&app, login("a_strong_password1111011")).await;
+		let token = reset_token(&config, Uuid::parse_str(&session.uid).unwrap()).await;
+
+		// A weak password does not use up the token
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, confirm(&token, "")).await;
+		assert_eq!(resp.error_code, "INVALID_PASSWORD");
+
+		let resp = test::call_service(&app, confirm(&token, "another_strong_password2222")).await;
+		assert_eq!(resp.status(), 200);
+
+		// The new password works, and the old one does not
+		let resp = test::call_service(&app, login("another_strong_password2222")).await;
+		assert_eq!(resp.status(), 200);
+		let resp: ErrorResponse =
+			test::call_and_read_body_json(&app, login("a_strong_password1111011")).await;
+		assert_eq!(resp.error_code, "INVALID_CREDENTIALS");
+
+		// Sessions from before the reset were ended
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": session.refresh_token }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 401);
+
+		// The token can only be used once
+		let resp = test::call_service(&app, confirm(&token, "a_third_strong_password3333")).await;
+		assert_eq!(resp.status(), 400);
+		let resp: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "TOKEN_ALREADY_USED");
+
+		// Tokens that were never issued are rejected
+		let forged = claims::sign(
+			&PasswordResetClaims {
+				registered: RegisteredClaims::new(
+					PasswordResetClaims::AUDIENCE,
+					Duration::minutes(15),
+				),
+				sub: Uuid::parse_str(&session.uid).unwrap(),
+			},
+			&config.secret_key,
+		);
+		let resp: ErrorResponse =
+			test::call_and_read_body_json(&app, confirm(&forged, "a_third_strong_password3333"))
+				.await;
+		assert_eq!(resp.error_code, "TOKEN_REVOKED");
+	}
+}
@@ -0,0 +1,139 @@
+use crate::auth::create_app;
+use actix_web::{http::header::ContentType, test};
+
+mod tests {
+	use super::*;
+
+	const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/116.0";
+	const SAFARI: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.6 Mobile/15E148 Safari/604.1";
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		token: String,
+		refresh_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct RefreshResponse {
+		access_token: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct Session {
+		id: String,
+		os: String,
+		browser: String,
+		current: bool,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct SessionListResponse {
+		sessions: Vec<Session>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	#[actix_web::test]
+	async fn test_list_and_revoke_sessions() {
+		let app = create_app(None, None).await;
+
+		// Create a user, and log in from two more devices
+		let req = test::TestRequest::post().uri("/api/auth/user/create")
+			.insert_header(ContentType::json())
+			.set_payload(r##"{"email":"sessions@example.com","password":"a_strong_password1111011","login":false,"metadata":""}"##).to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		let mut logins = vec![];
+		for user_agent in [FIREFOX, SAFARI] {
+			let req = test::TestRequest::post()
+				.uri("/api/auth/user/login")
+				.insert_header(("User-Agent", user_agent))
+				.set_json(
+					serde_json::json!({ "email": "sessions@example.com", "password": "a_strong_password1111011" }),
+				)
+				.to_request();
+			let login: LoginResponse = test::call_and_read_body_json(&app, req).await;
+			logins.push(login);
+		}
+		let (laptop, phone) = (&logins[0], &logins[1]);
+
+		// Both sessions are listed, with the device they are on
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user/sessions")
+			.insert_header(("Authorization", format!("Bearer {}", laptop.token)))
+			.to_request();
+		let resp: SessionListResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.sessions.len(), 2);
+		let current = resp.sessions.iter().find(|s| s.current).unwrap();
+		assert_eq!(current.browser, "Firefox");
+		assert_eq!(current.os, "Linux");
+		let other = resp.sessions.iter().find(|s| !s.current).unwrap();
+		assert_eq!(other.os, "iOS");
+
+		// Sessions of other users cannot be ended
+		let req = test::TestRequest::delete()
+			.uri("/api/auth/user/sessions/6755d7b1-38f2-4a3a-b872-98d0e7bbd1ee")
+			.insert_header(("Authorization", format!("Bearer {}", laptop.token)))
+			.to_request();
+		let resp: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.error_code, "SESSION_NOT_FOUND");
+
+		// End the phone's session, after which it can no longer be refreshed
+		let req = test::TestRequest::delete()
+			.uri(&format!("/api/auth/user/sessions/{}", other.id))
+			.insert_header(("Authorization", format!("Bearer {}", laptop.token)))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": phone.refresh_token }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_client_error());
+
+		// Ending the phone's session did not affect the laptop
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": laptop.refresh_token }))
+			.to_request();
+		let laptop: RefreshResponse = test::call_and_read_body_json(&app, req).await;
+
+		// Log in again on the phone, then sign out everywhere else from the laptop
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.insert_header(("User-Agent", SAFARI))
+			.set_json(
+				serde_json::json!({ "email": "sessions@example.com", "password": "a_strong_password1111011" }),
+			)
+			.to_request();
+		let phone: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+		let req = test::TestRequest::delete()
+			.uri("/api/auth/user/sessions")
+			.insert_header(("Authorization", format!("Bearer {}", laptop.access_token)))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_success());
+
+		let req = test::TestRequest::get()
+			.uri("/api/auth/user/sessions")
+			.insert_header(("Authorization", format!("Bearer {}", laptop.access_token)))
+			.to_request();
+		let resp: SessionListResponse = test::call_and_read_body_json(&app, req).await;
+		assert_eq!(resp.sessions.len(), 1);
+		assert!(resp.sessions[0].current);
+
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/refresh")
+			.set_json(serde_json::json!({ "refresh_token": phone.refresh_token }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert!(resp.status().is_client_error());
+	}
+}
@@ -0,0 +1,194 @@
+use crate::auth::{create_app_with_config, test_config};
+use actix_web::test;
+use api::FirebaseScryptConfig;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct RowError {
+		row: usize,
+		error_code: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ImportResponse {
+		imported: usize,
+		errors: Vec<RowError>,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ExportedUser {
+		email: Option<String>,
+		password_hash: Option<String>,
+		email_verified: bool,
+		metadata: Option<String>,
+		active: bool,
+		phone: Option<String>,
+		phone_verified: bool,
+	}
+
+	fn import(format: &str, body: String) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri(&format!("/api/admin/users/import?format={format}"))
+			.set_payload(body)
+			.to_request()
+	}
+
+	fn login(email: &str, password: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.set_json(serde_json::json!({ "email": email, "password": password }))
+			.to_request()
+	}
+
+	fn errors(resp: &ImportResponse) -> Vec<(usize, &str)> {
+		resp.errors
+			.iter()
+			.map(|e| (e.row, e.error_code.as_str()))
+			.collect()
+	}
+
+	#[actix_web::test]
+	async fn test_import_rows() {
+		let app = create_app_with_config(test_config(None, None)).await;
+
+		let hash = bcrypt::hash("imported_password_2291", 4).unwrap();
+		let rows = [
+			serde_json::json!({ "email": "import-1@example.com", "password_hash": hash, "email_verified": true, "metadata": "{\"plan\":\"pro\"}" }).to_string(),
+			serde_json::json!({ "email": "not an email" }).to_string(),
+			serde_json::json!({ "email": "import-1@example.com" }).to_string(),
+			serde_json::json!({ "email": "import-2@example.com", "password_hash": "md5$abc" }).to_string(),
+			"{ not json".to_string(),
+			serde_json::json!({ "email": "import-3@example.com", "active": false }).to_string(),
+		];
+		let resp: ImportResponse =
+			test::call_and_read_body_json(&app, import("json", rows.join("\n"))).await;
+		assert_eq!(resp.imported, 2);
+		assert_eq!(
+			errors(&resp),
+			[
+				(2, "INVALID_EMAIL"),
+				(3, "DUPLICATE_USER"),
+				(4, "UNSUPPORTED_PASSWORD_HASH"),
+				(5, "INVALID_ROW"),
+			]
+		);
+
+		let resp =
+			test::call_service(&app, login("import-1@example.com", "imported_password_2291")).await;
+		assert_eq!(resp.status(), 200);
+
+		// Users are never overwritten
+		let resp: ImportResponse = test::call_and_read_body_json(
+			&app,
+			import(
+				"csv",
+				"email,email_verified\nimport-3@example.com,true\nimport-4@example.com,false\n"
+					.to_string(),
+			),
+		)
+		.await;
+		assert_eq!(resp.imported, 1);
+		assert_eq!(errors(&resp), [(1, "EMAIL_IN_USE")]);
+
+		// An import that can't be read at all is refused
+		let resp = test::call_service(&app, import("firebase", "[]".to_string())).await;
+		assert_eq!(resp.status(), 400);
+	}
+
+	#[actix_web::test]
+	async fn test_import_providers() {
+		let mut config = test_config(None, None);
+		// The example from Firebase's scrypt documentation
+		config.password_hashing.firebase_scrypt = Some(FirebaseScryptConfig {
+			signer_key: "jxspr8Ki0RYycVU8zykbdLGjFQ3McFUH0uiiTvC8pVMXAn210wjLNmdZJzxUECKbm0QsEmYUSDzZvpjeJ9WmXA==".to_string(),
+			salt_separator: "Bw==".to_string(),
+			rounds: 8,
+			mem_cost: 14,
+		});
+		let app = create_app_with_config(config).await;
+
+		let firebase = serde_json::json!({
+			"users": [
+				{
+					"localId": "Ab1Cd2",
+					"email": "firebase-import@example.com",
+					"emailVerified": true,
+					"passwordHash": "lSrfV15cpx95/sZS2W9c9Kp6i/LVgQNDNC/qzrCnh1SAyZvqmZqAjTdn3aoItz+VHjoZilo78198JAdRuid5lQ==",
+					"salt": "42xEC+ixf3L2lw==",
+					"displayName": "Firebase User",
+					"customAttributes": "{\"role\":\"editor\"}",
+					"createdAt": "1486324027000",
+				},
+				{ "localId": "Ef3Gh4", "phoneNumber": "+15555550100" },
+			]
+		});
+		let resp: ImportResponse =
+			test::call_and_read_body_json(&app, import("firebase", firebase.to_string())).await;
+		assert_eq!(resp.imported, 2);
+		assert!(resp.errors.is_empty());
+		let resp =
+			test::call_service(&app, login("firebase-import@example.com", "user1password")).await;
+		assert_eq!(resp.status(), 200);
+
+		let auth0 = serde_json::json!({
+			"_id": { "$oid": "60425dc43519d90068f82973" },
+			"email": "auth0-import@example.com",
+			"email_verified": false,
+			"passwordHash": bcrypt::hash("auth0_password_7720", 4).unwrap(),
+			"user_metadata": { "theme": "dark" },
+			"created_at": { "$date": "2021-03-05T16:36:52.123Z" },
+		});
+		let resp: ImportResponse =
+			test::call_and_read_body_json(&app, import("auth0", auth0.to_string())).await;
+		assert_eq!(resp.imported, 1);
+		let resp =
+			test::call_service(&app, login("auth0-import@example.com", "auth0_password_7720"))
+				.await;
+		assert_eq!(resp.status(), 200);
+
+		// Exports can be imported again
+		let req = test::TestRequest::get()
+			.uri("/api/admin/users/export")
+			.to_request();
+		let body = test::call_and_read_body(&app, req).await;
+		let body = std::str::from_utf8(&body).unwrap();
+		let users: Vec<ExportedUser> = body
+			.lines()
+			.map(|line| serde_json::from_str(line).unwrap())
+			.collect();
+		let firebase_user = users
+			.iter()
+			.find(|user| user.email.as_deref() == Some("firebase-import@example.com"))
+			.unwrap();
+		assert!(firebase_user.email_verified);
+		assert!(firebase_user.active);
+		assert!(firebase_user.password_hash.is_some());
+		let metadata: serde_json::Value =
+			serde_json::from_str(firebase_user.metadata.as_ref().unwrap()).unwrap();
+		assert_eq!(metadata["role"], "editor");
+		assert_eq!(metadata["displayName"], "Firebase User");
+
+		// Users who signed up with a phone number are exported without an email
+		let phone_user = users
+			.iter()
+			.find(|user| user.phone.as_deref() == Some("+15555550100"))
+			.unwrap();
+		assert!(phone_user.email.is_none());
+		assert!(phone_user.phone_verified);
+
+		// Other tests add users at the same time, so only the ones from this test are imported again
+		let exported: Vec<&str> = body
+			.lines()
+			.filter(|line| line.contains("-import@example.com") || line.contains("+15555550100"))
+			.collect();
+		assert_eq!(exported.len(), 3);
+		let resp: ImportResponse =
+			test::call_and_read_body_json(&app, import("json", exported.join("\n"))).await;
+		assert_eq!(resp.imported, 0);
+		let mut codes: Vec<&str> = resp.errors.iter().map(|e| e.error_code.as_str()).collect();
+		codes.sort();
+		assert_eq!(codes, ["EMAIL_IN_USE", "EMAIL_IN_USE", "PHONE_IN_USE"]);
+	}
+}
@@ -0,0 +1,88 @@
+use lettre::message::header::ContentType;
+use lettre::message::{MultiPart, SinglePart};
+use lettre::AsyncTransport;
+use lettre::Message;
+use log::error;
+use sailfish::TemplateOnce;
+
+use crate::EmailParams;
+
+#[derive(TemplateOnce)]
+#[template(path = "email_change.stpl")]
+struct EmailChangeTemplateHtml {
+	name: String,
+	action_url: String,
+	new_email: String,
+	operating_system: String,
+	device: String,
+}
+
+#[derive(TemplateOnce)]
+#[template(path = "email_change.txt")]
+struct EmailChangeTemplateTxt {
+	name: String,
+	action_url: String,
+	new_email: String,
+	operating_system: String,
+	device: String,
+}
+
+/// Tells the old address that the email is being changed. The `action_url` of the params cancels the change.
+pub async fn send(params: EmailParams<'_>, new_email: &str) {
+	let html = EmailChangeTemplateHtml {
+		action_url: params.action_url.clone(),
+		new_email: new_email.to_owned(),
+		name: params.name.clone(),
+		operating_system: params.os.clone(),
+		device: params.device.clone(),
+	}
+	.render_once()
+	.unwrap();
+
+	let txt = EmailChangeTemplateTxt {
+		action_url: params.action_url,
+		new_email: new_email.to_owned(),
+		name: params.name,
+		operating_system: params.os,
+		device: params.device,
+	}
+	.render_once()
+	.unwrap();
+
+	let to = match crate::recipient(&params.to) {
+		Some(to) => to,
+		None => return,
+	};
+
+	let email = Message::builder()
+		.from(params.from.parse().unwrap())
+		.reply_to(params.reply_to.parse().unwrap())
+		.to(to)
+		.subject(params.subject)
+		.multipart(
+			MultiPart::alternative()
+				.singlepart(
+					SinglePart::builder()
+						.header(ContentType::TEXT_PLAIN)
+						.body(txt),
+				)
+				.singlepart(
+					SinglePart::builder()
+						.header(ContentType::TEXT_HTML)
+						.body(html),
+				),
+		);
+
+	let email = match email {
+		Ok(email) => email,
+		Err(err) => {
+			error!("Failed to build email: {err}");
+			return;
+		}
+	};
+
+	match params.mailer.send(email).await {
+		Ok(_) => (),
+		Err(err) => error!("Failed to send email: {err}"),
+	}
+}
@@ -44,10 +44,15 @@ pub async fn send(params: EmailParams<'_>) {
 	.render_once()
 	.unwrap();
 
+	let to = match crate::recipient(&params.to) {
+		Some(to) => to,
+		None => return,
+	};
+
 	let email = Message::builder()
 		.from(params.from.parse().unwrap())
 		.reply_to(params.reply_to.parse().unwrap())
-		.to(params.to.parse().unwrap())
+		.to(to)
 		.subject(params.subject)
 		.multipart(
 			MultiPart::alternative()
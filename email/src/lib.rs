@@ -1,8 +1,14 @@
-use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use lettre::{message::Mailbox, AsyncSmtpTransport, Tokio1Executor};
+use log::error;
 
+pub mod account_locked;
+pub mod email_change;
 pub mod forgot_password;
+pub mod login_code;
 pub mod magic;
 pub mod manual;
+pub mod password_changed;
+pub mod security_alert;
 pub mod verification;
 
 pub struct EmailParams<'a> {
@@ -16,3 +22,14 @@ pub struct EmailParams<'a> {
 	pub device: String,
 	pub mailer: &'a AsyncSmtpTransport<Tokio1Executor>,
 }
+
+/// Users without an email, such as anonymous users, only have a placeholder that can't be sent to
+fn recipient(to: &str) -> Option<Mailbox> {
+	match to.parse() {
+		Ok(mailbox) => Some(mailbox),
+		Err(err) => {
+			error!("Not sending email to {to}: {err}");
+			None
+		}
+	}
+}
@@ -2,6 +2,30 @@
 
 pub mod prelude;
 
+pub mod action_tokens;
 pub mod admins;
+pub mod custom_claims;
+pub mod email_changes;
+pub mod group_members;
+pub mod group_roles;
+pub mod groups;
+pub mod identities;
+pub mod login_codes;
+pub mod login_failures;
+pub mod mfa_challenges;
+pub mod oauth_states;
+pub mod oidc_auth_codes;
+pub mod password_history;
+pub mod permissions;
+pub mod rate_limits;
+pub mod recovery_codes;
 pub mod refresh_tokens;
+pub mod role_members;
+pub mod role_permissions;
+pub mod roles;
+pub mod security_events;
+pub mod sessions;
+pub mod signing_keys;
+pub mod totp_secrets;
 pub mod users;
+pub mod webauthn_credentials;
@@ -1,5 +1,29 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.0
 
+pub use super::action_tokens::Entity as ActionTokens;
 pub use super::admins::Entity as Admins;
+pub use super::custom_claims::Entity as CustomClaims;
+pub use super::email_changes::Entity as EmailChanges;
+pub use super::group_members::Entity as GroupMembers;
+pub use super::group_roles::Entity as GroupRoles;
+pub use super::groups::Entity as Groups;
+pub use super::identities::Entity as Identities;
+pub use super::login_codes::Entity as LoginCodes;
+pub use super::login_failures::Entity as LoginFailures;
+pub use super::mfa_challenges::Entity as MfaChallenges;
+pub use super::oauth_states::Entity as OauthStates;
+pub use super::oidc_auth_codes::Entity as OidcAuthCodes;
+pub use super::password_history::Entity as PasswordHistory;
+pub use super::permissions::Entity as Permissions;
+pub use super::rate_limits::Entity as RateLimits;
+pub use super::recovery_codes::Entity as RecoveryCodes;
 pub use super::refresh_tokens::Entity as RefreshTokens;
+pub use super::role_members::Entity as RoleMembers;
+pub use super::role_permissions::Entity as RolePermissions;
+pub use super::roles::Entity as Roles;
+pub use super::security_events::Entity as SecurityEvents;
+pub use super::sessions::Entity as Sessions;
+pub use super::signing_keys::Entity as SigningKeys;
+pub use super::totp_secrets::Entity as TotpSecrets;
 pub use super::users::Entity as Users;
+pub use super::webauthn_credentials::Entity as WebauthnCredentials;
@@ -5,9 +5,10 @@ use sea_orm::entity::prelude::*;
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
 #[sea_orm(table_name = "refresh_tokens")]
 pub struct Model {
-	pub uid: Uuid,
 	#[sea_orm(primary_key, auto_increment = false)]
-	pub refresh_token: String,
+	pub token_hash: String,
+	pub uid: Uuid,
+	pub session_id: Uuid,
 	pub expiry: DateTime,
 	pub used: bool,
 }
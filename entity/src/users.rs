@@ -7,7 +7,7 @@ use sea_orm::entity::prelude::*;
 pub struct Model {
 	#[sea_orm(primary_key, auto_increment = false)]
 	pub uid: Uuid,
-	pub email: String,
+	pub email: Option<String>,
 	pub password: String,
 	pub created_at: DateTime,
 	pub updated_at: DateTime,
@@ -15,6 +15,10 @@ pub struct Model {
 	pub active: bool,
 	pub metadata: Option<String>,
 	pub email_verified: bool,
+	pub password_changed_at: Option<DateTime>,
+	pub anonymous: bool,
+	pub phone: Option<String>,
+	pub phone_verified: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -1,8 +1,8 @@
-use std::{collections::BTreeMap, rc::Rc};
+use std::rc::Rc;
 
 use actix_web::{
 	body::{EitherBody, MessageBody},
-	dev::{Service, ServiceRequest, ServiceResponse, Transform},
+	dev::{ResourceDef, Service, ServiceRequest, ServiceResponse, Transform},
 	error::ErrorUnauthorized,
 	Error,
 };
@@ -11,18 +11,28 @@ use futures::{
 	FutureExt,
 };
 use futures_util::future::Ready;
-use hmac::Hmac;
-use jwt::VerifyWithKey;
-use sha2::Sha256;
+use api::{
+	admin::PERMISSION_ROUTES,
+	auth::claims::{self, AccessClaims},
+	keys::KeyRing,
+};
+
+use crate::projects;
 
 pub struct AdminMiddlewareFactory {
-	key: Hmac<Sha256>,
+	key_ring: KeyRing,
 	db_conn: sea_orm::DatabaseConnection,
+	/// Admin routes that check the token's permissions themselves, with `permissions::requires`
+	permission_routes: Rc<ResourceDef>,
 }
 
 impl AdminMiddlewareFactory {
-	pub fn new(key: Hmac<Sha256>, db_conn: sea_orm::DatabaseConnection) -> Self {
-		Self { key, db_conn }
+	pub fn new(key_ring: KeyRing, db_conn: sea_orm::DatabaseConnection) -> Self {
+		Self {
+			key_ring,
+			db_conn,
+			permission_routes: Rc::new(ResourceDef::new(PERMISSION_ROUTES.to_vec())),
+		}
 	}
 }
 
@@ -41,16 +51,19 @@ where
 	fn new_transform(&self, service: S) -> Self::Future {
 		ok(AdminMiddleware {
 			service: Rc::new(service),
-			key: self.key.clone(),
+			key_ring: self.key_ring.clone(),
 			db_conn: self.db_conn.clone(),
+			permission_routes: self.permission_routes.clone(),
 		})
 	}
 }
 
 pub struct AdminMiddleware<S> {
 	service: Rc<S>,
-	key: Hmac<Sha256>,
+	key_ring: KeyRing,
+	#[allow(dead_code)] // Will be used to look up API keys
 	db_conn: sea_orm::DatabaseConnection,
+	permission_routes: Rc<ResourceDef>,
 }
 
 impl<S, B> Service<ServiceRequest> for AdminMiddleware<S>
@@ -74,7 +87,12 @@ where
 					.map_into_right_body()))
 			};
 		}
-		if req.path().starts_with("/api/admin") && req.path() != "/api/admin/login" {
+		// Routes match the percent-decoded path, so it must be checked rather than the raw one
+		let path = req.match_info().as_str();
+		if path.starts_with("/api/admin")
+			&& path != "/api/admin/login"
+			&& !self.permission_routes.is_match(path)
+		{
 			let token = match req.headers().get("Authorization") {
 				Some(token) => match token.to_str() {
 					Ok(token) => token,
@@ -91,7 +109,7 @@ where
 
 			let parts: Vec<&str> = token.split_whitespace().collect();
 
-			if parts[0] == "bearer" || parts[0] == "Bearer" {
+			if matches!(parts.first(), Some(&"bearer") | Some(&"Bearer")) {
 				let token = match parts.get(1) {
 					Some(token) => *token,
 					None => {
@@ -99,25 +117,15 @@ where
 					}
 				};
 
-				let claims: BTreeMap<String, String> = match token.verify_with_key(&self.key) {
+				let claims: AccessClaims = match claims::verify(token, &projects::key_ring(&req, &self.key_ring)) {
 					Ok(claims) => claims,
 					Err(_) => {
 						return unauthorizedBoxPin!();
 					}
 				};
-				if claims["type"] != "at" {
+				if claims.role.as_deref() != Some("admin") {
 					return unauthorizedBoxPin!();
 				}
-				match claims.get("role") {
-					Some(role) => {
-						if role != "admin" {
-							return unauthorizedBoxPin!();
-						}
-					}
-					None => {
-						return unauthorizedBoxPin!();
-					}
-				};
 			} 
             // TODO: Add api keys here
             else {
@@ -1,2 +1,5 @@
 // TODO: Add middleware for sanitizing requests
-pub mod admin_middleware;
\ No newline at end of file
+pub mod admin_middleware;
+pub mod permissions;
+pub mod projects;
+pub mod rate_limit;
\ No newline at end of file
@@ -0,0 +1,117 @@
+//! Requires a permission for the routes it wraps, see `admin::rbac`. Requests need an access token that
+//! lists the permission, or an admin's access token, which has every permission. Requests without a valid
+//! access token are rejected with `401 Unauthorized`, and ones without the permission with `403 Forbidden`.
+//!
+//! ```ignore
+//! web::resource("/reports")
+//!     .route(web::get().to(reports))
+//!     .wrap(requires("reports:read", key_ring.clone()))
+//! ```
+
+use std::rc::Rc;
+
+use actix_web::{
+	body::{EitherBody, MessageBody},
+	dev::{Service, ServiceRequest, ServiceResponse, Transform},
+	http::header::AUTHORIZATION,
+	Error, HttpResponse,
+};
+use api::{
+	auth::{
+		api_error,
+		util::{self, ClaimsResult},
+	},
+	keys::KeyRing,
+};
+use futures::{
+	future::{ok, LocalBoxFuture},
+	FutureExt,
+};
+use futures_util::future::Ready;
+
+use crate::projects;
+
+/// Rejects requests whose access token does not have `permission`
+pub fn requires(permission: &str, key_ring: KeyRing) -> RequiresPermission {
+	RequiresPermission {
+		permission: Rc::from(permission),
+		key_ring,
+	}
+}
+
+pub struct RequiresPermission {
+	permission: Rc<str>,
+	key_ring: KeyRing,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequiresPermission
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+	S::Future: 'static,
+	B: MessageBody + 'static,
+{
+	type Response = ServiceResponse<EitherBody<B>>;
+	type Error = Error;
+	type Transform = RequiresPermissionMiddleware<S>;
+	type InitError = ();
+	type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ok(RequiresPermissionMiddleware {
+			service: Rc::new(service),
+			permission: self.permission.clone(),
+			key_ring: self.key_ring.clone(),
+		})
+	}
+}
+
+pub struct RequiresPermissionMiddleware<S> {
+	service: Rc<S>,
+	permission: Rc<str>,
+	key_ring: KeyRing,
+}
+
+impl<S, B> Service<ServiceRequest> for RequiresPermissionMiddleware<S>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+	S::Future: 'static,
+	B: MessageBody + 'static,
+{
+	type Response = ServiceResponse<EitherBody<B>>;
+	type Error = Error;
+	type Future = LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>;
+
+	actix_service::forward_ready!(service);
+
+	fn call(&self, req: ServiceRequest) -> Self::Future {
+		let key_ring = projects::key_ring(&req, &self.key_ring);
+		let allowed = match util::verify_header_claims(req.headers().get(AUTHORIZATION), &key_ring)
+		{
+			ClaimsResult::Error(body, status) => {
+				Err(HttpResponse::build(status).json(body.into_inner()))
+			}
+			ClaimsResult::Claims(claims) => {
+				// Admins have every permission
+				if claims.role.as_deref() == Some("admin")
+					|| claims.permissions.iter().any(|p| **p == *self.permission)
+				{
+					Ok(())
+				} else {
+					Err(HttpResponse::Forbidden().json(api_error(
+						format!("The {} permission is required.", self.permission),
+						"MISSING_PERMISSION".to_string(),
+					)))
+				}
+			}
+		};
+
+		match allowed {
+			Ok(()) => {
+				let service = Rc::clone(&self.service);
+				async move { service.call(req).await.map(|res| res.map_into_left_body()) }
+					.boxed_local()
+			}
+			Err(response) => Box::pin(ok(req.into_response(response).map_into_right_body())),
+		}
+	}
+}
@@ -0,0 +1,135 @@
+//! Resolves the project a request is for, see `api::projects`. Handlers, and the middlewares inside this
+//! one, get the project's `AppState` from `Data<AppState>` in place of the default project's, and its id
+//! from `ProjectId`. A project path prefix is removed before routing, so
+//! `/projects/acme/api/auth/user/login` is handled by the login route. Requests for a project that does
+//! not exist are rejected with `404 Not Found`.
+//!
+//! It must wrap the other middlewares, so that they see the project and the path without its prefix.
+
+use std::rc::Rc;
+
+use actix_web::{
+	body::{EitherBody, MessageBody},
+	dev::{Extensions, Service, ServiceRequest, ServiceResponse, Transform},
+	http::Uri,
+	web::Data,
+	Error, HttpResponse,
+};
+use api::{
+	auth::api_error,
+	keys::KeyRing,
+	projects::{ProjectId, Projects, Resolved, PROJECT_HEADER},
+	AppState,
+};
+use futures::{
+	future::{ok, LocalBoxFuture},
+	FutureExt,
+};
+use futures_util::future::Ready;
+
+pub struct ProjectMiddlewareFactory {
+	projects: Rc<Projects>,
+}
+
+impl ProjectMiddlewareFactory {
+	pub fn new(projects: Projects) -> Self {
+		Self {
+			projects: Rc::new(projects),
+		}
+	}
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ProjectMiddlewareFactory
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+	S::Future: 'static,
+	B: MessageBody + 'static,
+{
+	type Response = ServiceResponse<EitherBody<B>>;
+	type Error = Error;
+	type Transform = ProjectMiddleware<S>;
+	type InitError = ();
+	type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ok(ProjectMiddleware {
+			service: Rc::new(service),
+			projects: self.projects.clone(),
+		})
+	}
+}
+
+pub struct ProjectMiddleware<S> {
+	service: Rc<S>,
+	projects: Rc<Projects>,
+}
+
+impl<S, B> Service<ServiceRequest> for ProjectMiddleware<S>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+	S::Future: 'static,
+	B: MessageBody + 'static,
+{
+	type Response = ServiceResponse<EitherBody<B>>;
+	type Error = Error;
+	type Future = LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>;
+
+	actix_service::forward_ready!(service);
+
+	fn call(&self, mut req: ServiceRequest) -> Self::Future {
+		let service = Rc::clone(&self.service);
+		if self.projects.is_empty() {
+			return async move { service.call(req).await.map(|res| res.map_into_left_body()) }
+				.boxed_local();
+		}
+
+		let header = req
+			.headers()
+			.get(PROJECT_HEADER)
+			.map(|id| id.to_str().unwrap_or_default().to_string());
+		let host = req.connection_info().host().to_string();
+
+		let (project, path) = match self.projects.resolve(req.path(), header.as_deref(), &host) {
+			Resolved::Default => (None, None),
+			Resolved::Project(id, state, path) => {
+				(Some((ProjectId(id.to_string()), state.clone())), path.map(str::to_string))
+			}
+			Resolved::NotFound => {
+				let response = HttpResponse::NotFound().json(api_error(
+					"The project does not exist.".to_string(),
+					"PROJECT_NOT_FOUND".to_string(),
+				));
+				return Box::pin(ok(req.into_response(response).map_into_right_body()));
+			}
+		};
+
+		if let Some(path) = path {
+			let path_and_query = match req.query_string() {
+				"" => path,
+				query => format!("{path}?{query}"),
+			};
+			let mut parts = req.head().uri.clone().into_parts();
+			parts.path_and_query = Some(path_and_query.parse().unwrap());
+			let uri = Uri::from_parts(parts).unwrap();
+			req.match_info_mut().get_mut().update(&uri);
+			req.head_mut().uri = uri;
+		}
+
+		if let Some((id, state)) = project {
+			let mut data = Extensions::new();
+			data.insert(id);
+			data.insert(state);
+			req.add_data_container(Rc::new(data));
+		}
+
+		async move { service.call(req).await.map(|res| res.map_into_left_body()) }.boxed_local()
+	}
+}
+
+/// The key ring of the project a request is for, or `default` when the app has no `AppState`
+pub fn key_ring(req: &ServiceRequest, default: &KeyRing) -> KeyRing {
+	match req.app_data::<Data<AppState>>() {
+		Some(state) => state.key_ring.clone(),
+		None => default.clone(),
+	}
+}
@@ -0,0 +1,391 @@
+//! Rate limits requests to the auth and email-sending endpoints, see `RateLimitConfig`. Each policy counts
+//! the requests to a route in fixed windows, separately for each IP address, email, phone number or user, and
+//! requests over its limit are rejected with `429 Too Many Requests` until the window resets.
+//!
+//! IP addresses are read like `Client::address`, which only trusts `X-Forwarded-For` from the configured
+//! proxies, so clients cannot pick a new address for each request.
+//!
+//! Each project's requests are counted separately, and the database store counts them in the project's own
+//! database. The middleware must be wrapped by the project middleware for this.
+
+use std::{
+	collections::HashMap,
+	net::IpAddr,
+	pin::Pin,
+	rc::Rc,
+	sync::{Arc, Mutex},
+};
+
+use actix_web::{
+	body::{EitherBody, MessageBody},
+	dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
+	error::PayloadError,
+	http::header::RETRY_AFTER,
+	web::{Bytes, Data},
+	Error, HttpResponse,
+};
+use api::{
+	auth::{
+		api_error, phone,
+		sessions::Client,
+		util::{self, HeaderResult},
+	},
+	keys::KeyRing,
+	projects::ProjectId,
+	AppState, RateLimitKey, RateLimitPolicy, RateLimitStoreKind,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use entity::rate_limits;
+use futures::{
+	future::{ok, LocalBoxFuture},
+	stream, FutureExt, Stream,
+};
+use futures_util::future::Ready;
+use log::error;
+use sea_orm::{
+	sea_query::{Expr, OnConflict},
+	ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, Set,
+};
+use sha2::{Digest, Sha256};
+
+use crate::projects;
+
+/// Where requests are counted
+#[derive(Clone)]
+pub enum RateLimitStore {
+	/// In this instance's memory
+	Memory(MemoryStore),
+	/// In the `rate_limits` table, which every instance shares
+	Database(DatabaseConnection),
+}
+
+impl RateLimitStore {
+	pub fn new(kind: RateLimitStoreKind, connection: DatabaseConnection) -> Self {
+		match kind {
+			RateLimitStoreKind::Memory => RateLimitStore::Memory(MemoryStore::default()),
+			RateLimitStoreKind::Database => RateLimitStore::Database(connection),
+		}
+	}
+
+	/// The store a request is counted in, which for the database store is the database of the project the
+	/// request is for
+	fn for_request(&self, req: &ServiceRequest) -> RateLimitStore {
+		match (self, req.app_data::<ProjectId>(), req.app_data::<Data<AppState>>()) {
+			(RateLimitStore::Database(_), Some(_), Some(state)) => {
+				RateLimitStore::Database(state.connection.to_owned())
+			}
+			_ => self.clone(),
+		}
+	}
+
+	/// Counts a request against the key. Returns how many seconds are left in its window, if the key is
+	/// over the limit.
+	pub async fn hit(&self, key: &str, limit: u32, window: i64) -> Result<Option<i64>, DbErr> {
+		let now = Utc::now().naive_utc();
+		let (hits, resets_at) = match self {
+			RateLimitStore::Memory(store) => store.hit(key, window, now),
+			RateLimitStore::Database(connection) => {
+				hit_database(connection, key, window, now).await?
+			}
+		};
+		let seconds = ((resets_at - now).num_milliseconds() + 999) / 1000;
+		Ok((hits > limit).then_some(seconds.max(1)))
+	}
+}
+
+/// The requests counted for a key in its current window
+struct Window {
+	hits: u32,
+	resets_at: NaiveDateTime,
+}
+
+#[derive(Default)]
+struct Windows {
+	windows: HashMap<String, Window>,
+	next_purge: Option<NaiveDateTime>,
+}
+
+/// Counts requests in memory. Clones share their counts, so one store must be shared by every worker.
+#[derive(Clone, Default)]
+pub struct MemoryStore(Arc<Mutex<Windows>>);
+
+impl MemoryStore {
+	fn hit(&self, key: &str, window: i64, now: NaiveDateTime) -> (u32, NaiveDateTime) {
+		let mut store = self
+			.0
+			.lock()
+			.unwrap_or_else(|poisoned| poisoned.into_inner());
+
+		// Forget the windows that have reset every minute, so that the store does not grow forever
+		if store.next_purge.is_none_or(|next_purge| next_purge <= now) {
+			store.windows.retain(|_, window| window.resets_at > now);
+			store.next_purge = Some(now + Duration::minutes(1));
+		}
+
+		let entry = store.windows.entry(key.to_string()).or_insert(Window {
+			hits: 0,
+			resets_at: now,
+		});
+		if entry.resets_at <= now {
+			*entry = Window {
+				hits: 0,
+				resets_at: now + Duration::seconds(window),
+			};
+		}
+		entry.hits = entry.hits.saturating_add(1);
+		(entry.hits, entry.resets_at)
+	}
+}
+
+async fn hit_database(
+	connection: &DatabaseConnection,
+	key: &str,
+	window: i64,
+	now: NaiveDateTime,
+) -> Result<(u32, NaiveDateTime), DbErr> {
+	// Keys can hold an email, so they are hashed to fit the column
+	let key = format!("{:x}", Sha256::digest(key.as_bytes()));
+
+	let counted = count_hit(connection, &key, now).await?;
+	if counted == 0 {
+		// Start a new window. If another instance starts it first, the insert does nothing, and the hit
+		// is counted in that window instead.
+		rate_limits::Entity::delete_many()
+			.filter(rate_limits::Column::Key.eq(key.to_owned()))
+			.filter(rate_limits::Column::ResetsAt.lte(now))
+			.exec(connection)
+			.await?;
+		let started = rate_limits::Entity::insert(rate_limits::ActiveModel {
+			key: Set(key.to_owned()),
+			hits: Set(1),
+			resets_at: Set(now + Duration::seconds(window)),
+		})
+		.on_conflict(
+			OnConflict::column(rate_limits::Column::Key)
+				.do_nothing()
+				.to_owned(),
+		)
+		.exec_without_returning(connection)
+		.await?;
+		if started == 0 {
+			count_hit(connection, &key, now).await?;
+		}
+	}
+
+	let window = rate_limits::Entity::find_by_id(key)
+		.one(connection)
+		.await?
+		.ok_or_else(|| DbErr::RecordNotFound("The rate limit window was not found.".to_string()))?;
+	Ok((window.hits.max(0) as u32, window.resets_at))
+}
+
+/// Counts a hit in the key's current window. Returns 0 if it has none.
+async fn count_hit(
+	connection: &DatabaseConnection,
+	key: &str,
+	now: NaiveDateTime,
+) -> Result<u64, DbErr> {
+	rate_limits::Entity::update_many()
+		.col_expr(rate_limits::Column::Hits, Expr::col(rate_limits::Column::Hits).add(1))
+		.filter(rate_limits::Column::Key.eq(key))
+		.filter(rate_limits::Column::ResetsAt.gt(now))
+		.exec(connection)
+		.await
+		.map(|res| res.rows_affected)
+}
+
+pub struct RateLimitMiddlewareFactory {
+	policies: Rc<Vec<RateLimitPolicy>>,
+	store: RateLimitStore,
+	key_ring: KeyRing,
+	trusted_proxies: Rc<Vec<IpAddr>>,
+}
+
+impl RateLimitMiddlewareFactory {
+	pub fn new(
+		policies: Vec<RateLimitPolicy>,
+		store: RateLimitStore,
+		key_ring: KeyRing,
+		trusted_proxies: Vec<IpAddr>,
+	) -> Self {
+		Self {
+			policies: Rc::new(policies),
+			store,
+			key_ring,
+			trusted_proxies: Rc::new(trusted_proxies),
+		}
+	}
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimitMiddlewareFactory
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+	S::Future: 'static,
+	B: MessageBody + 'static,
+{
+	type Response = ServiceResponse<EitherBody<B>>;
+	type Error = Error;
+	type Transform = RateLimitMiddleware<S>;
+	type InitError = ();
+	type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ok(RateLimitMiddleware {
+			service: Rc::new(service),
+			policies: Rc::clone(&self.policies),
+			store: self.store.clone(),
+			key_ring: self.key_ring.clone(),
+			trusted_proxies: Rc::clone(&self.trusted_proxies),
+		})
+	}
+}
+
+pub struct RateLimitMiddleware<S> {
+	service: Rc<S>,
+	policies: Rc<Vec<RateLimitPolicy>>,
+	store: RateLimitStore,
+	key_ring: KeyRing,
+	trusted_proxies: Rc<Vec<IpAddr>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+	S::Future: 'static,
+	B: MessageBody + 'static,
+{
+	type Response = ServiceResponse<EitherBody<B>>;
+	type Error = Error;
+	type Future = LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>;
+
+	actix_service::forward_ready!(service);
+
+	fn call(&self, mut req: ServiceRequest) -> Self::Future {
+		let service = Rc::clone(&self.service);
+		let policies: Vec<RateLimitPolicy> = self
+			.policies
+			.iter()
+			.filter(|policy| matches(policy, &req))
+			.cloned()
+			.collect();
+		if policies.is_empty() {
+			return async move { service.call(req).await.map(|res| res.map_into_left_body()) }
+				.boxed_local();
+		}
+
+		let store = self.store.for_request(&req);
+		let project = req
+			.app_data::<ProjectId>()
+			.map(|project| project.0.to_owned());
+		let key_ring = projects::key_ring(&req, &self.key_ring);
+		let trusted_proxies = Rc::clone(&self.trusted_proxies);
+		async move {
+			let mut body = None;
+			let mut retry_after: Option<i64> = None;
+			for policy in &policies {
+				let value = match policy.key {
+					RateLimitKey::Ip => Client::address(req.request(), &trusted_proxies),
+					RateLimitKey::Email => {
+						if body.is_none() {
+							body = Some(read_body(&mut req).await);
+						}
+						body_field(&body, "email").map(|email| email.trim().to_lowercase())
+					}
+					RateLimitKey::Phone => {
+						if body.is_none() {
+							body = Some(read_body(&mut req).await);
+						}
+						body_field(&body, "phone").and_then(|phone| phone::normalize(&phone))
+					}
+					RateLimitKey::Uid => {
+						match util::verify_header(req.headers().get("Authorization"), &key_ring) {
+							HeaderResult::Uid(uid) => Some(uid.to_string()),
+							HeaderResult::Error(..) => None,
+						}
+					}
+				};
+				let value = match value {
+					Some(value) => value,
+					None => continue,
+				};
+
+				let mut key = format!(
+					"{} {} {}:{}",
+					policy.method.to_uppercase(),
+					policy.path,
+					key_name(policy.key),
+					value
+				);
+				if let Some(project) = &project {
+					key = format!("project:{project} {key}");
+				}
+				match store.hit(&key, policy.limit, policy.window).await {
+					Ok(Some(seconds)) => {
+						retry_after =
+							Some(retry_after.map_or(seconds, |longest| longest.max(seconds)))
+					}
+					Ok(None) => {}
+					// Requests are let through rather than failing every auth endpoint
+					Err(e) => {
+						error!("Unable to count request for rate limit. Error: {}", e.to_string())
+					}
+				}
+			}
+
+			if let Some(seconds) = retry_after {
+				let response = HttpResponse::TooManyRequests()
+					.insert_header((RETRY_AFTER, seconds.to_string()))
+					.json(api_error(
+						format!("Too many requests. Try again in {seconds} seconds."),
+						"RATE_LIMITED".to_string(),
+					));
+				return Ok(req.into_response(response).map_into_right_body());
+			}
+
+			service.call(req).await.map(|res| res.map_into_left_body())
+		}
+		.boxed_local()
+	}
+}
+
+fn matches(policy: &RateLimitPolicy, req: &ServiceRequest) -> bool {
+	if !policy.method.eq_ignore_ascii_case(req.method().as_str()) {
+		return false;
+	}
+	// Routes match the percent-decoded path, so policies must too, or encoding a character would skip them
+	let path = req.match_info().as_str();
+	match policy.path.strip_suffix('*') {
+		Some(prefix) => path.starts_with(prefix),
+		None => path == policy.path,
+	}
+}
+
+fn key_name(key: RateLimitKey) -> &'static str {
+	match key {
+		RateLimitKey::Ip => "ip",
+		RateLimitKey::Email => "email",
+		RateLimitKey::Phone => "phone",
+		RateLimitKey::Uid => "uid",
+	}
+}
+
+/// Reads the JSON body, and puts it back for the handler
+async fn read_body(req: &mut ServiceRequest) -> Option<serde_json::Value> {
+	let body = match req.extract::<Bytes>().await {
+		Ok(body) => body,
+		Err(_) => return None,
+	};
+	let json = serde_json::from_slice(&body).ok();
+
+	let payload: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+		Box::pin(stream::once(async move { Ok(body) }));
+	req.set_payload(Payload::from(payload));
+	json
+}
+
+fn body_field(body: &Option<Option<serde_json::Value>>, name: &str) -> Option<String> {
+	match body {
+		Some(Some(json)) => json.get(name)?.as_str().map(str::to_string),
+		_ => None,
+	}
+}
@@ -0,0 +1,238 @@
+use actix_web::{test, web, web::Data, App, HttpResponse};
+use api::{
+	auth::claims::{self, AccessClaims, RegisteredClaims, TokenClaims},
+	keys::{KeyAlgorithm, KeyRing},
+	AnonymousUserConfig, AppState, Argon2Config, Config, LockoutConfig, PasswordHashConfig,
+	PasswordPolicyConfig, RateLimitConfig, SigningConfig, TokenConfig, WebauthnConfig,
+};
+use chrono::Duration;
+use hmac::{Hmac, Mac};
+use middlewares::{admin_middleware::AdminMiddlewareFactory, permissions::requires};
+use migration::{Migrator, MigratorTrait};
+use uaparser::UserAgentParser;
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	fn token(key_ring: &KeyRing, role: Option<&str>, permissions: &[&str]) -> String {
+		claims::sign(
+			&AccessClaims {
+				registered: RegisteredClaims::new(AccessClaims::AUDIENCE, Duration::minutes(5)),
+				sub: Uuid::new_v4(),
+				sid: Uuid::new_v4(),
+				role: role.map(str::to_string),
+				permissions: permissions.iter().map(|p| p.to_string()).collect(),
+				custom: Default::default(),
+			},
+			key_ring,
+		)
+	}
+
+	fn get(token: Option<String>) -> actix_http::Request {
+		let mut req = test::TestRequest::get().uri("/users");
+		if let Some(token) = token {
+			req = req.insert_header(("Authorization", format!("Bearer {token}")));
+		}
+		req.to_request()
+	}
+
+	fn config() -> Config {
+		let connection_url =
+			std::env::temp_dir().join(format!("turbocore-{}.sqlite", Uuid::new_v4()));
+		Config {
+			bind_addr: "not_used".to_string(),
+			connection_url: format!("sqlite://{}?mode=rwc", connection_url.display()),
+			base_url: "http://turbocore".to_string(),
+			secret_key: Hmac::new_from_slice("a_secret_key".repeat(3).as_bytes()).unwrap(),
+			debug_level: "debug".to_string(),
+			argon2_config: Argon2Config::default(),
+			password_hashing: PasswordHashConfig::default(),
+			minimum_password_strength: 1,
+			password_policy: PasswordPolicyConfig::default(),
+			mailer: None,
+			email: None,
+			sms: None,
+			allowed_origins: vec![],
+			webauthn: WebauthnConfig {
+				rp_id: "turbocore".to_string(),
+				rp_name: "TurboCore".to_string(),
+				origins: vec!["http://turbocore".to_string()],
+			},
+			oauth_providers: vec![],
+			identity_provider: None,
+			signing: SigningConfig::default(),
+			tokens: TokenConfig::default(),
+			lockout: LockoutConfig::default(),
+			anonymous_users: AnonymousUserConfig::default(),
+			rate_limit: RateLimitConfig::default(),
+			trusted_proxies: vec![],
+			breached_passwords: None,
+			projects: vec![],
+		}
+	}
+
+	#[actix_web::test]
+	async fn test_requires_permission() {
+		let key_ring = KeyRing::ephemeral(KeyAlgorithm::ES256);
+		let app = test::init_service(
+			App::new().service(
+				web::resource("/users")
+					.route(web::get().to(HttpResponse::Ok))
+					.wrap(requires("users:read", key_ring.clone())),
+			),
+		)
+		.await;
+
+		let resp = test::call_service(&app, get(None)).await;
+		assert_eq!(resp.status(), 401);
+
+		// Tokens signed by another key ring are rejected
+		let other = KeyRing::ephemeral(KeyAlgorithm::ES256);
+		let resp = test::call_service(&app, get(Some(token(&other, None, &["users:read"])))).await;
+		assert_eq!(resp.status(), 401);
+
+		let resp =
+			test::call_service(&app, get(Some(token(&key_ring, None, &["users:write"])))).await;
+		assert_eq!(resp.status(), 403);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "MISSING_PERMISSION");
+
+		let token_with_permission = token(&key_ring, None, &["users:write", "users:read"]);
+		let resp = test::call_service(&app, get(Some(token_with_permission))).await;
+		assert_eq!(resp.status(), 200);
+
+		// Admins have every permission
+		let resp = test::call_service(&app, get(Some(token(&key_ring, Some("admin"), &[])))).await;
+		assert_eq!(resp.status(), 200);
+	}
+
+	#[actix_web::test]
+	async fn test_admin_routes_require_permissions() {
+		let config = config();
+		let connection = sea_orm::Database::connect(config.connection_url.to_owned())
+			.await
+			.unwrap();
+		Migrator::up(&connection, None).await.unwrap();
+		let key_ring = KeyRing::load(&connection, &config.signing).await.unwrap();
+		let routes_key_ring = key_ring.clone();
+		let app = test::init_service(
+			App::new()
+				.app_data(Data::new(AppState {
+					connection: connection.to_owned(),
+					config,
+					ua_parser: UserAgentParser::from_yaml("../regexes.yaml").unwrap(),
+					key_ring: key_ring.to_owned(),
+					breached_passwords: None,
+					sms: None,
+				}))
+				.configure(|cfg| {
+					api::admin::add_routes(cfg, |route, permission| {
+						route.wrap(requires(permission, routes_key_ring.clone()))
+					})
+				})
+				.wrap(AdminMiddlewareFactory::new(key_ring.to_owned(), connection)),
+		)
+		.await;
+		let request = |req: test::TestRequest, token: Option<String>| match token {
+			Some(token) => req
+				.insert_header(("Authorization", format!("Bearer {token}")))
+				.to_request(),
+			None => req.to_request(),
+		};
+		let create_role = |token: String| {
+			request(
+				test::TestRequest::post()
+					.uri("/api/admin/roles")
+					.set_json(serde_json::json!({ "name": "viewer", "permissions": [] })),
+				Some(token),
+			)
+		};
+		let list_roles = |token: Option<String>| {
+			request(test::TestRequest::get().uri("/api/admin/roles"), token)
+		};
+
+		let resp = test::call_service(&app, list_roles(None)).await;
+		assert_eq!(resp.status(), 401);
+		// Empty headers are rejected rather than taking the server down
+		for uri in [
+			"/api/admin/roles",
+			"/api/admin/users/export",
+			"/api/admin/user/uid/lockout",
+		] {
+			let req = test::TestRequest::get()
+				.uri(uri)
+				.insert_header(("Authorization", " "))
+				.to_request();
+			let resp = test::call_service(&app, req).await;
+			assert!(resp.status().is_client_error());
+		}
+
+		let resp =
+			test::call_service(&app, list_roles(Some(token(&key_ring, None, &["users:read"]))))
+				.await;
+		assert_eq!(resp.status(), 403);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "MISSING_PERMISSION");
+
+		let reader = token(&key_ring, None, &["rbac:read"]);
+		let resp = test::call_service(&app, list_roles(Some(reader.to_owned()))).await;
+		assert_eq!(resp.status(), 200);
+		// Whatever the path's encoding
+		let req =
+			request(test::TestRequest::get().uri("/api/admin/r%6Fles"), Some(reader.to_owned()));
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 200);
+		let req = request(
+			test::TestRequest::get().uri("/api/%61dmin/roles"),
+			Some(token(&key_ring, None, &["users:read"])),
+		);
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 403);
+		let resp = test::call_service(&app, create_role(reader.to_owned())).await;
+		assert_eq!(resp.status(), 403);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "MISSING_PERMISSION");
+
+		let req = request(
+			test::TestRequest::put().uri(&format!("/api/admin/user/{}/claims", Uuid::new_v4())),
+			Some(token(&key_ring, None, &["users:read"])),
+		);
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 403);
+
+		// Exports include password hashes
+		let export =
+			|uri: &str, token: Option<String>| request(test::TestRequest::get().uri(uri), token);
+		let resp =
+			test::call_service(&app, export("/api/admin/users/export", Some(reader.to_owned())))
+				.await;
+		assert_eq!(resp.status(), 403);
+		let exporter = token(&key_ring, None, &["users:read"]);
+		let resp =
+			test::call_service(&app, export("/api/admin/users/export", Some(exporter))).await;
+		assert_eq!(resp.status(), 200);
+		for uri in ["/api/admin/users/export", "/api/%61dmin/users/export"] {
+			let resp = test::call_service(&app, export(uri, None)).await;
+			assert_eq!(resp.status(), 401);
+		}
+
+		// The other admin routes still need the admin role
+		let lockout = format!("/api/admin/user/{}/lockout", Uuid::new_v4());
+		let req = request(test::TestRequest::delete().uri(&lockout), Some(reader));
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 401);
+
+		let resp =
+			test::call_service(&app, create_role(token(&key_ring, None, &["rbac:write"]))).await;
+		assert_eq!(resp.status(), 201);
+		let resp =
+			test::call_service(&app, create_role(token(&key_ring, Some("admin"), &[]))).await;
+		assert_eq!(resp.status(), 409);
+	}
+}
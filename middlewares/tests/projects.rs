@@ -0,0 +1,321 @@
+use actix_web::{test, web::Data, App};
+use api::{
+	auth::mfa, keys::KeyRing, projects::Projects, AnonymousUserConfig, AppState, Argon2Config,
+	Config, LockoutConfig, PasswordHashConfig, PasswordPolicyConfig, ProjectConfig,
+	RateLimitConfig, RateLimitKey, RateLimitPolicy, SigningConfig, TokenConfig, WebauthnConfig,
+};
+use entity::rate_limits;
+use hmac::{Hmac, Mac};
+use middlewares::{
+	projects::ProjectMiddlewareFactory,
+	rate_limit::{RateLimitMiddlewareFactory, RateLimitStore},
+};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::EntityTrait;
+use uaparser::UserAgentParser;
+use uuid::Uuid;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Debug)]
+	struct LoginResponse {
+		token: String,
+		expiry: i64,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct Jwks {
+		keys: Vec<serde_json::Value>,
+	}
+
+	fn config() -> Config {
+		let connection_url =
+			std::env::temp_dir().join(format!("turbocore-{}.sqlite", Uuid::new_v4()));
+		Config {
+			bind_addr: "not_used".to_string(),
+			connection_url: format!("sqlite://{}?mode=rwc", connection_url.display()),
+			base_url: "http://turbocore".to_string(),
+			secret_key: Hmac::new_from_slice("a_secret_key".repeat(3).as_bytes()).unwrap(),
+			debug_level: "debug".to_string(),
+			argon2_config: Argon2Config::default(),
+			password_hashing: PasswordHashConfig::default(),
+			minimum_password_strength: 1,
+			password_policy: PasswordPolicyConfig::default(),
+			mailer: None,
+			email: None,
+			sms: None,
+			allowed_origins: vec![],
+			webauthn: WebauthnConfig {
+				rp_id: "turbocore".to_string(),
+				rp_name: "TurboCore".to_string(),
+				origins: vec!["http://turbocore".to_string()],
+			},
+			oauth_providers: vec![],
+			identity_provider: None,
+			signing: SigningConfig::default(),
+			tokens: TokenConfig::default(),
+			lockout: LockoutConfig::default(),
+			anonymous_users: AnonymousUserConfig::default(),
+			rate_limit: RateLimitConfig::default(),
+			trusted_proxies: vec![],
+			breached_passwords: None,
+			projects: vec![],
+		}
+	}
+
+	async fn state(config: Config) -> AppState {
+		let connection = sea_orm::Database::connect(config.connection_url.to_owned())
+			.await
+			.unwrap();
+		Migrator::up(&connection, None).await.unwrap();
+		let key_ring = KeyRing::load(&connection, &config.signing).await.unwrap();
+		AppState {
+			connection,
+			config,
+			ua_parser: UserAgentParser::from_yaml("../regexes.yaml").unwrap(),
+			key_ring,
+			breached_passwords: None,
+			sms: None,
+		}
+	}
+
+	fn create_user(uri: &str, project: Option<&str>) -> actix_http::Request {
+		let mut req = test::TestRequest::post()
+			.uri(uri)
+			.set_json(serde_json::json!({
+				"email": "someone@example.com",
+				"password": "a_strong_password1111011",
+				"login": true,
+				"metadata": "",
+			}));
+		if let Some(project) = project {
+			req = req.insert_header(("X-Project", project));
+		}
+		req.to_request()
+	}
+
+	fn kid(token: &str) -> String {
+		jsonwebtoken::decode_header(token).unwrap().kid.unwrap()
+	}
+
+	#[actix_web::test]
+	async fn test_projects_have_their_own_users_and_keys() {
+		let default_config = config();
+		let acme = ProjectConfig {
+			id: "acme".to_string(),
+			hosts: vec!["auth.acme.test".to_string()],
+			connection_url: config().connection_url,
+			base_url: Some("https://auth.acme.test".to_string()),
+			email: None,
+			allowed_origins: None,
+			webauthn: None,
+			signing: None,
+			tokens: Some(TokenConfig {
+				access_token_lifetime: 60,
+				..TokenConfig::default()
+			}),
+			oauth_providers: None,
+			identity_provider: None,
+		};
+		let mut projects = Projects::default();
+		projects.add(&acme, state(default_config.for_project(&acme)).await);
+
+		let app = test::init_service(
+			App::new()
+				.app_data(Data::new(state(default_config).await))
+				.configure(api::auth::add_routes)
+				.configure(api::idp::add_routes)
+				.wrap(ProjectMiddlewareFactory::new(projects)),
+		)
+		.await;
+
+		// The same email can sign up to each project
+		let resp = test::call_service(&app, create_user("/api/auth/user/create", None)).await;
+		assert_eq!(resp.status(), 201);
+		let default_user: LoginResponse = test::read_body_json(resp).await;
+		let resp =
+			test::call_service(&app, create_user("/api/auth/user/create", Some("acme"))).await;
+		assert_eq!(resp.status(), 201);
+		let acme_user: LoginResponse = test::read_body_json(resp).await;
+
+		// The path prefix picks the same project as the header
+		let resp =
+			test::call_service(&app, create_user("/projects/acme/api/auth/user/create", None))
+				.await;
+		assert_eq!(resp.status(), 409);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "EMAIL_IN_USE");
+
+		// So does the host name
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/login")
+			.insert_header(("Host", "Auth.Acme.test:8080"))
+			.set_json(serde_json::json!({
+				"email": "someone@example.com",
+				"password": "a_strong_password1111011",
+			}))
+			.to_request();
+		let login: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+		// Each project signs with its own keys, and has its own token lifetimes
+		let req = test::TestRequest::get()
+			.uri("/.well-known/jwks.json")
+			.to_request();
+		let default_jwks: Jwks = test::call_and_read_body_json(&app, req).await;
+		let req = test::TestRequest::get()
+			.uri("/projects/acme/.well-known/jwks.json")
+			.to_request();
+		let acme_jwks: Jwks = test::call_and_read_body_json(&app, req).await;
+		let has_key = |jwks: &Jwks, kid: String| jwks.keys.iter().any(|key| key["kid"] == kid);
+		assert!(has_key(&default_jwks, kid(&default_user.token)));
+		assert!(!has_key(&default_jwks, kid(&acme_user.token)));
+		assert!(has_key(&acme_jwks, kid(&acme_user.token)));
+		assert!(has_key(&acme_jwks, kid(&login.token)));
+		// Access tokens expire 15 seconds after their lifetime, as leeway
+		assert!(login.expiry - chrono::Utc::now().timestamp() <= 60 + 15);
+		assert!(default_user.expiry - chrono::Utc::now().timestamp() > 60 + 15);
+
+		for req in [
+			create_user("/api/auth/user/create", Some("unknown")),
+			create_user("/projects/unknown/api/auth/user/create", None),
+		] {
+			let resp = test::call_service(&app, req).await;
+			assert_eq!(resp.status(), 404);
+			let body: ErrorResponse = test::read_body_json(resp).await;
+			assert_eq!(body.error_code, "PROJECT_NOT_FOUND");
+		}
+	}
+
+	#[actix_web::test]
+	async fn test_projects_are_rate_limited_separately() {
+		let default_config = config();
+		let acme = ProjectConfig {
+			id: "acme".to_string(),
+			hosts: vec![],
+			connection_url: config().connection_url,
+			base_url: None,
+			email: None,
+			allowed_origins: None,
+			webauthn: None,
+			signing: None,
+			tokens: None,
+			oauth_providers: None,
+			identity_provider: None,
+		};
+		let acme_state = state(default_config.for_project(&acme)).await;
+		let acme_connection = acme_state.connection.to_owned();
+		let mut projects = Projects::default();
+		projects.add(&acme, acme_state);
+		let default_state = state(default_config).await;
+		let default_connection = default_state.connection.to_owned();
+		let key_ring = default_state.key_ring.to_owned();
+
+		let policy = RateLimitPolicy {
+			method: "POST".to_string(),
+			path: "/api/auth/user/login".to_string(),
+			key: RateLimitKey::Ip,
+			limit: 1,
+			window: 60,
+		};
+		let app = test::init_service(
+			App::new()
+				.app_data(Data::new(default_state))
+				.configure(api::auth::add_routes)
+				.wrap(RateLimitMiddlewareFactory::new(
+					vec![policy],
+					RateLimitStore::Database(default_connection.to_owned()),
+					key_ring,
+					vec![],
+				))
+				.wrap(ProjectMiddlewareFactory::new(projects)),
+		)
+		.await;
+		let login = |project: Option<&str>| {
+			let mut req = test::TestRequest::post()
+				.uri("/api/auth/user/login")
+				.peer_addr("203.0.113.30:40000".parse().unwrap())
+				.set_json(serde_json::json!({
+					"email": "someone@example.com",
+					"password": "a_strong_password1111011",
+				}));
+			if let Some(project) = project {
+				req = req.insert_header(("X-Project", project));
+			}
+			req.to_request()
+		};
+
+		let resp = test::call_service(&app, login(None)).await;
+		assert_eq!(resp.status(), 401);
+		let resp = test::call_service(&app, login(None)).await;
+		assert_eq!(resp.status(), 429);
+
+		// The same address has its own limit in each project
+		let resp = test::call_service(&app, login(Some("acme"))).await;
+		assert_eq!(resp.status(), 401);
+		let resp = test::call_service(&app, login(Some("acme"))).await;
+		assert_eq!(resp.status(), 429);
+
+		// Which is counted in the project's database
+		for connection in [default_connection, acme_connection] {
+			let windows = rate_limits::Entity::find().all(&connection).await.unwrap();
+			assert_eq!(windows.len(), 1);
+			assert_eq!(windows[0].hits, 2);
+		}
+	}
+
+	#[actix_web::test]
+	async fn test_projects_do_not_accept_each_others_tokens() {
+		let default_config = config();
+		let acme = ProjectConfig {
+			id: "acme".to_string(),
+			hosts: vec![],
+			connection_url: config().connection_url,
+			base_url: None,
+			email: None,
+			allowed_origins: None,
+			webauthn: None,
+			signing: None,
+			tokens: None,
+			oauth_providers: None,
+			identity_provider: None,
+		};
+		let mut projects = Projects::default();
+		projects.add(&acme, state(default_config.for_project(&acme)).await);
+		let (mfa_token, _) =
+			mfa::create_challenge_token(Uuid::new_v4(), false, &default_config.secret_key);
+
+		let app = test::init_service(
+			App::new()
+				.app_data(Data::new(state(default_config).await))
+				.configure(api::auth::add_routes)
+				.wrap(ProjectMiddlewareFactory::new(projects)),
+		)
+		.await;
+		let challenge = |project: Option<&str>| {
+			let mut req = test::TestRequest::post()
+				.uri("/api/auth/user/login/mfa")
+				.set_json(serde_json::json!({ "mfa_token": mfa_token, "code": "000000" }));
+			if let Some(project) = project {
+				req = req.insert_header(("X-Project", project));
+			}
+			req.to_request()
+		};
+
+		// The default project accepts its own token, whose user does not exist
+		let resp = test::call_service(&app, challenge(None)).await;
+		assert_eq!(resp.status(), 404);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "USER_NOT_FOUND");
+
+		let resp = test::call_service(&app, challenge(Some("acme"))).await;
+		assert_eq!(resp.status(), 401);
+		let body: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(body.error_code, "INVALID_TOKEN");
+	}
+}
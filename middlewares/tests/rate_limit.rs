@@ -0,0 +1,157 @@
+use actix_web::{
+	http::header::RETRY_AFTER,
+	test,
+	web::{self, Json},
+	App, HttpResponse,
+};
+use api::{
+	keys::{KeyAlgorithm, KeyRing},
+	RateLimitKey, RateLimitPolicy,
+};
+use middlewares::rate_limit::{RateLimitMiddlewareFactory, RateLimitStore};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::ConnectOptions;
+
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, serde::Serialize)]
+	struct MagicBody {
+		email: String,
+	}
+
+	#[derive(serde::Deserialize, Debug)]
+	struct ErrorResponse {
+		error_code: String,
+	}
+
+	/// Echoes the body, to check that it still reaches the handler
+	async fn echo(body: Json<MagicBody>) -> HttpResponse {
+		HttpResponse::Ok().json(body.into_inner())
+	}
+
+	fn policy(key: RateLimitKey, limit: u32) -> RateLimitPolicy {
+		RateLimitPolicy {
+			method: "POST".to_string(),
+			path: "/api/auth/user/magic-link".to_string(),
+			key,
+			limit,
+			window: 60,
+		}
+	}
+
+	fn magic_link(email: &str, ip: &str) -> actix_http::Request {
+		test::TestRequest::post()
+			.uri("/api/auth/user/magic-link")
+			.peer_addr(format!("{ip}:40000").parse().unwrap())
+			.set_json(serde_json::json!({ "email": email }))
+			.to_request()
+	}
+
+	#[actix_web::test]
+	async fn test_rate_limit_by_email() {
+		let store = RateLimitStore::Memory(Default::default());
+		let app = test::init_service(
+			App::new()
+				.route("/api/auth/user/magic-link", web::post().to(echo))
+				.wrap(RateLimitMiddlewareFactory::new(
+					vec![policy(RateLimitKey::Email, 2)],
+					store,
+					KeyRing::ephemeral(KeyAlgorithm::ES256),
+					vec![],
+				)),
+		)
+		.await;
+
+		// The body is put back for the handler
+		for ip in ["203.0.113.1", "203.0.113.2"] {
+			let resp: MagicBody =
+				test::call_and_read_body_json(&app, magic_link("limited@example.com", ip)).await;
+			assert_eq!(resp.email, "limited@example.com");
+		}
+
+		// The email is limited whatever its case or IP address
+		let resp = test::call_service(&app, magic_link("Limited@Example.com", "203.0.113.3")).await;
+		assert_eq!(resp.status(), 429);
+		let retry_after: i64 = resp
+			.headers()
+			.get(RETRY_AFTER)
+			.unwrap()
+			.to_str()
+			.unwrap()
+			.parse()
+			.unwrap();
+		assert!(retry_after > 0 && retry_after <= 60);
+		let resp: ErrorResponse = test::read_body_json(resp).await;
+		assert_eq!(resp.error_code, "RATE_LIMITED");
+
+		// Nor does percent-encoding the path get around it
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/magic-lin%6B")
+			.peer_addr("203.0.113.3:40000".parse().unwrap())
+			.set_json(serde_json::json!({ "email": "limited@example.com" }))
+			.to_request();
+		let resp = test::call_service(&app, req).await;
+		assert_eq!(resp.status(), 429);
+
+		// Other emails are not
+		let resp = test::call_service(&app, magic_link("other@example.com", "203.0.113.3")).await;
+		assert_eq!(resp.status(), 200);
+	}
+
+	#[actix_web::test]
+	async fn test_database_store_is_shared() {
+		let mut options = ConnectOptions::new("sqlite::memory:".to_string());
+		options.max_connections(1);
+		let connection = sea_orm::Database::connect(options).await.unwrap();
+		Migrator::up(&connection, None).await.unwrap();
+
+		// Two instances sharing the database
+		let key_ring = KeyRing::ephemeral(KeyAlgorithm::ES256);
+		let first = test::init_service(
+			App::new()
+				.route("/api/auth/user/magic-link", web::post().to(echo))
+				.wrap(RateLimitMiddlewareFactory::new(
+					vec![policy(RateLimitKey::Ip, 3)],
+					RateLimitStore::Database(connection.clone()),
+					key_ring.clone(),
+					vec![],
+				)),
+		)
+		.await;
+		let second = test::init_service(
+			App::new()
+				.route("/api/auth/user/magic-link", web::post().to(echo))
+				.wrap(RateLimitMiddlewareFactory::new(
+					vec![policy(RateLimitKey::Ip, 3)],
+					RateLimitStore::Database(connection),
+					key_ring,
+					vec![],
+				)),
+		)
+		.await;
+
+		let ip = "198.51.100.9";
+		for app in [&first, &second, &first] {
+			let resp = test::call_service(app, magic_link("db@example.com", ip)).await;
+			assert_eq!(resp.status(), 200);
+		}
+		let resp = test::call_service(&second, magic_link("db@example.com", ip)).await;
+		assert_eq!(resp.status(), 429);
+		assert!(resp.headers().contains_key(RETRY_AFTER));
+
+		// Claiming to be forwarded for another address does not reset the count
+		let req = test::TestRequest::post()
+			.uri("/api/auth/user/magic-link")
+			.peer_addr(format!("{ip}:40000").parse().unwrap())
+			.insert_header(("X-Forwarded-For", "198.51.100.11"))
+			.set_json(serde_json::json!({ "email": "db@example.com" }))
+			.to_request();
+		let resp = test::call_service(&first, req).await;
+		assert_eq!(resp.status(), 429);
+
+		// Other IP addresses are counted separately
+		let resp = test::call_service(&first, magic_link("db@example.com", "198.51.100.10")).await;
+		assert_eq!(resp.status(), 200);
+	}
+}
@@ -1,12 +1,58 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20230601_000001_create_mfa_tables;
+mod m20230615_000001_create_webauthn_credentials;
+mod m20230701_000001_create_oauth_tables;
+mod m20230715_000001_create_oidc_auth_codes;
+mod m20230801_000001_create_signing_keys;
+mod m20230815_000001_create_sessions;
+mod m20230901_000001_hash_refresh_tokens;
+mod m20230901_000002_create_security_events;
+mod m20230915_000001_add_session_remember_me;
+mod m20231001_000001_create_login_failures;
+mod m20231015_000001_create_rate_limits;
+mod m20231101_000001_create_action_tokens;
+mod m20231115_000001_create_login_codes;
+mod m20231201_000001_create_email_changes;
+mod m20231215_000001_create_password_history;
+mod m20240101_000001_add_anonymous_users;
+mod m20240115_000001_add_user_phone;
+mod m20240201_000001_create_identities;
+mod m20240215_000001_create_custom_claims;
+mod m20240301_000001_create_rbac;
+mod m20240315_000001_create_mfa_challenges;
+mod m20240401_000001_make_user_email_optional;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
 	fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-		vec![Box::new(m20220101_000001_create_table::Migration)]
+		vec![
+			Box::new(m20220101_000001_create_table::Migration),
+			Box::new(m20230601_000001_create_mfa_tables::Migration),
+			Box::new(m20230615_000001_create_webauthn_credentials::Migration),
+			Box::new(m20230701_000001_create_oauth_tables::Migration),
+			Box::new(m20230715_000001_create_oidc_auth_codes::Migration),
+			Box::new(m20230801_000001_create_signing_keys::Migration),
+			Box::new(m20230815_000001_create_sessions::Migration),
+			Box::new(m20230901_000001_hash_refresh_tokens::Migration),
+			Box::new(m20230901_000002_create_security_events::Migration),
+			Box::new(m20230915_000001_add_session_remember_me::Migration),
+			Box::new(m20231001_000001_create_login_failures::Migration),
+			Box::new(m20231015_000001_create_rate_limits::Migration),
+			Box::new(m20231101_000001_create_action_tokens::Migration),
+			Box::new(m20231115_000001_create_login_codes::Migration),
+			Box::new(m20231201_000001_create_email_changes::Migration),
+			Box::new(m20231215_000001_create_password_history::Migration),
+			Box::new(m20240101_000001_add_anonymous_users::Migration),
+			Box::new(m20240115_000001_add_user_phone::Migration),
+			Box::new(m20240201_000001_create_identities::Migration),
+			Box::new(m20240215_000001_create_custom_claims::Migration),
+			Box::new(m20240301_000001_create_rbac::Migration),
+			Box::new(m20240315_000001_create_mfa_challenges::Migration),
+			Box::new(m20240401_000001_make_user_email_optional::Migration),
+		]
 	}
 }
@@ -0,0 +1,99 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(TotpSecret::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(TotpSecret::Uid)
+							.uuid()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(TotpSecret::Secret).string().not_null())
+					.col(ColumnDef::new(TotpSecret::Confirmed).boolean().not_null())
+					.col(ColumnDef::new(TotpSecret::LastUsedStep).big_integer())
+					.col(ColumnDef::new(TotpSecret::CreatedAt).date_time().not_null())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_table(
+				Table::create()
+					.table(RecoveryCode::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(RecoveryCode::CodeHash)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(RecoveryCode::Uid).uuid().not_null())
+					.col(ColumnDef::new(RecoveryCode::Used).boolean().not_null())
+					.col(
+						ColumnDef::new(RecoveryCode::CreatedAt)
+							.date_time()
+							.not_null(),
+					)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				sea_query::Index::create()
+					.if_not_exists()
+					.name("recovery_codes_uid")
+					.table(RecoveryCode::Table)
+					.col(RecoveryCode::Uid)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(TotpSecret::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.drop_table(
+				Table::drop()
+					.table(RecoveryCode::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum TotpSecret {
+	#[iden = "totp_secrets"]
+	Table,
+	Uid,
+	Secret,
+	Confirmed,
+	LastUsedStep,
+	CreatedAt,
+}
+
+#[derive(Iden)]
+enum RecoveryCode {
+	#[iden = "recovery_codes"]
+	Table,
+	CodeHash,
+	Uid,
+	Used,
+	CreatedAt,
+}
@@ -0,0 +1,76 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(WebauthnCredential::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(WebauthnCredential::CredentialId)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(WebauthnCredential::Uid).uuid().not_null())
+					.col(
+						ColumnDef::new(WebauthnCredential::PublicKey)
+							.text()
+							.not_null(),
+					)
+					.col(
+						ColumnDef::new(WebauthnCredential::SignCount)
+							.big_integer()
+							.not_null(),
+					)
+					.col(ColumnDef::new(WebauthnCredential::Name).string().not_null())
+					.col(
+						ColumnDef::new(WebauthnCredential::CreatedAt)
+							.date_time()
+							.not_null(),
+					)
+					.col(ColumnDef::new(WebauthnCredential::LastUsed).date_time())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				sea_query::Index::create()
+					.if_not_exists()
+					.name("webauthn_credentials_uid")
+					.table(WebauthnCredential::Table)
+					.col(WebauthnCredential::Uid)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(WebauthnCredential::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum WebauthnCredential {
+	#[iden = "webauthn_credentials"]
+	Table,
+	CredentialId,
+	Uid,
+	PublicKey,
+	SignCount,
+	Name,
+	CreatedAt,
+	LastUsed,
+}
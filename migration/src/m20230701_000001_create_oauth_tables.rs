@@ -0,0 +1,120 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(OauthState::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(OauthState::State)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(OauthState::Provider).string().not_null())
+					.col(ColumnDef::new(OauthState::CodeVerifier).string().not_null())
+					.col(ColumnDef::new(OauthState::Nonce).string().not_null())
+					.col(ColumnDef::new(OauthState::NextUrl).text().not_null())
+					.col(ColumnDef::new(OauthState::Expiry).date_time().not_null())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_table(
+				Table::create()
+					.table(OauthIdentity::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(OauthIdentity::Id)
+							.uuid()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(OauthIdentity::Uid).uuid().not_null())
+					.col(ColumnDef::new(OauthIdentity::Provider).string().not_null())
+					.col(ColumnDef::new(OauthIdentity::Subject).string().not_null())
+					.col(ColumnDef::new(OauthIdentity::Email).string())
+					.col(
+						ColumnDef::new(OauthIdentity::CreatedAt)
+							.date_time()
+							.not_null(),
+					)
+					.col(ColumnDef::new(OauthIdentity::LastLogin).date_time())
+					.to_owned(),
+			)
+			.await?;
+		// A subject is only unique within the provider that issued it
+		manager
+			.create_index(
+				sea_query::Index::create()
+					.if_not_exists()
+					.name("oauth_identities_provider_subject")
+					.table(OauthIdentity::Table)
+					.col(OauthIdentity::Provider)
+					.col(OauthIdentity::Subject)
+					.unique()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				sea_query::Index::create()
+					.if_not_exists()
+					.name("oauth_identities_uid")
+					.table(OauthIdentity::Table)
+					.col(OauthIdentity::Uid)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(OauthState::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.drop_table(
+				Table::drop()
+					.table(OauthIdentity::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum OauthState {
+	#[iden = "oauth_states"]
+	Table,
+	State,
+	Provider,
+	CodeVerifier,
+	Nonce,
+	NextUrl,
+	Expiry,
+}
+
+#[derive(Iden)]
+enum OauthIdentity {
+	#[iden = "oauth_identities"]
+	Table,
+	Id,
+	Uid,
+	Provider,
+	Subject,
+	Email,
+	CreatedAt,
+	LastLogin,
+}
@@ -0,0 +1,60 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(OidcAuthCode::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(OidcAuthCode::CodeHash)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(OidcAuthCode::ClientId).string().not_null())
+					.col(ColumnDef::new(OidcAuthCode::Uid).uuid().not_null())
+					.col(ColumnDef::new(OidcAuthCode::RedirectUri).text().not_null())
+					.col(ColumnDef::new(OidcAuthCode::Scope).string().not_null())
+					.col(ColumnDef::new(OidcAuthCode::Nonce).string())
+					.col(
+						ColumnDef::new(OidcAuthCode::CodeChallenge)
+							.string()
+							.not_null(),
+					)
+					.col(ColumnDef::new(OidcAuthCode::Expiry).date_time().not_null())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(OidcAuthCode::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum OidcAuthCode {
+	#[iden = "oidc_auth_codes"]
+	Table,
+	CodeHash,
+	ClientId,
+	Uid,
+	RedirectUri,
+	Scope,
+	Nonce,
+	CodeChallenge,
+	Expiry,
+}
@@ -0,0 +1,50 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(SigningKey::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(SigningKey::Kid)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(SigningKey::Algorithm).string().not_null())
+					.col(ColumnDef::new(SigningKey::PrivateKey).text().not_null())
+					.col(ColumnDef::new(SigningKey::CreatedAt).date_time().not_null())
+					.col(ColumnDef::new(SigningKey::RetiredAt).date_time())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(SigningKey::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum SigningKey {
+	#[iden = "signing_keys"]
+	Table,
+	Kid,
+	Algorithm,
+	PrivateKey,
+	CreatedAt,
+	RetiredAt,
+}
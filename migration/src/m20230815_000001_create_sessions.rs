@@ -0,0 +1,85 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Session::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(Session::Id).uuid().not_null().primary_key())
+					.col(ColumnDef::new(Session::Uid).uuid().not_null())
+					.col(ColumnDef::new(Session::CreatedAt).date_time().not_null())
+					.col(ColumnDef::new(Session::LastRefresh).date_time().not_null())
+					.col(ColumnDef::new(Session::Expiry).date_time().not_null())
+					.col(ColumnDef::new(Session::Ip).string())
+					.col(ColumnDef::new(Session::Os).string().not_null())
+					.col(ColumnDef::new(Session::Browser).string().not_null())
+					.col(ColumnDef::new(Session::Device).string().not_null())
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("sessions_uid_index")
+					.if_not_exists()
+					.table(Session::Table)
+					.col(Session::Uid)
+					.to_owned(),
+			)
+			.await?;
+
+		// Refresh tokens issued before sessions existed have no session
+		manager
+			.alter_table(
+				Table::alter()
+					.table(RefreshToken::Table)
+					.add_column(ColumnDef::new(RefreshToken::SessionId).uuid())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(RefreshToken::Table)
+					.drop_column(RefreshToken::SessionId)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.drop_table(Table::drop().table(Session::Table).if_exists().to_owned())
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum Session {
+	#[iden = "sessions"]
+	Table,
+	Id,
+	Uid,
+	CreatedAt,
+	LastRefresh,
+	Expiry,
+	Ip,
+	Os,
+	Browser,
+	Device,
+}
+
+#[derive(Iden)]
+enum RefreshToken {
+	#[iden = "refresh_tokens"]
+	Table,
+	SessionId,
+}
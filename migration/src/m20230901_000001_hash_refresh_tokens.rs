@@ -0,0 +1,117 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Stores refresh tokens as hashes, keyed by the session (the token family) they were issued for.
+///
+/// The table is recreated rather than altered, since SQLite cannot change a primary key. Existing refresh
+/// tokens are dropped with it: they predate typed claims, so they can no longer be used anyway.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(RefreshTokenEntry::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(RefreshTokenEntry::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(RefreshTokenEntry::TokenHash)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(RefreshTokenEntry::Uid).uuid().not_null())
+					.col(
+						ColumnDef::new(RefreshTokenEntry::SessionId)
+							.uuid()
+							.not_null(),
+					)
+					.col(
+						ColumnDef::new(RefreshTokenEntry::Expiry)
+							.date_time()
+							.not_null(),
+					)
+					.col(ColumnDef::new(RefreshTokenEntry::Used).boolean().not_null())
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("refresh_tokens_uid_index")
+					.if_not_exists()
+					.table(RefreshTokenEntry::Table)
+					.col(RefreshTokenEntry::Uid)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("refresh_tokens_session_id_index")
+					.if_not_exists()
+					.table(RefreshTokenEntry::Table)
+					.col(RefreshTokenEntry::SessionId)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(RefreshTokenEntry::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(RefreshTokenEntry::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(RefreshTokenEntry::Uid).uuid().not_null())
+					.col(
+						ColumnDef::new(RefreshTokenEntry::RefreshToken)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(
+						ColumnDef::new(RefreshTokenEntry::Expiry)
+							.date_time()
+							.not_null(),
+					)
+					.col(ColumnDef::new(RefreshTokenEntry::Used).boolean().not_null())
+					.col(ColumnDef::new(RefreshTokenEntry::SessionId).uuid())
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum RefreshTokenEntry {
+	#[iden = "refresh_tokens"]
+	Table,
+	TokenHash,
+	RefreshToken,
+	Uid,
+	SessionId,
+	Expiry,
+	Used,
+}
@@ -0,0 +1,67 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(SecurityEvent::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(SecurityEvent::Id)
+							.uuid()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(SecurityEvent::Uid).uuid().not_null())
+					.col(ColumnDef::new(SecurityEvent::Event).string().not_null())
+					.col(ColumnDef::new(SecurityEvent::SessionId).uuid())
+					.col(ColumnDef::new(SecurityEvent::Ip).string())
+					.col(
+						ColumnDef::new(SecurityEvent::CreatedAt)
+							.date_time()
+							.not_null(),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("security_events_uid_index")
+					.if_not_exists()
+					.table(SecurityEvent::Table)
+					.col(SecurityEvent::Uid)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(SecurityEvent::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum SecurityEvent {
+	#[iden = "security_events"]
+	Table,
+	Id,
+	Uid,
+	Event,
+	SessionId,
+	Ip,
+	CreatedAt,
+}
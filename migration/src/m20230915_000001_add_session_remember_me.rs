@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// Sessions started before this column existed used the long refresh token lifetime
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Session::Table)
+					.add_column(
+						ColumnDef::new(Session::RememberMe)
+							.boolean()
+							.not_null()
+							.default(true),
+					)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(Session::Table)
+					.drop_column(Session::RememberMe)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum Session {
+	#[iden = "sessions"]
+	Table,
+	RememberMe,
+}
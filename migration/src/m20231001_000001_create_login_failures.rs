@@ -0,0 +1,52 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(LoginFailure::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(LoginFailure::Key)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(LoginFailure::Failures).integer().not_null())
+					.col(
+						ColumnDef::new(LoginFailure::LastFailure)
+							.date_time()
+							.not_null(),
+					)
+					.col(ColumnDef::new(LoginFailure::LockedUntil).date_time())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(LoginFailure::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum LoginFailure {
+	#[iden = "login_failures"]
+	Table,
+	Key,
+	Failures,
+	LastFailure,
+	LockedUntil,
+}
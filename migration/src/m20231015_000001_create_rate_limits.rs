@@ -0,0 +1,41 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(RateLimit::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(RateLimit::Key)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(RateLimit::Hits).integer().not_null())
+					.col(ColumnDef::new(RateLimit::ResetsAt).date_time().not_null())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(RateLimit::Table).if_exists().to_owned())
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum RateLimit {
+	#[iden = "rate_limits"]
+	Table,
+	Key,
+	Hits,
+	ResetsAt,
+}
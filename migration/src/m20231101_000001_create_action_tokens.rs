@@ -0,0 +1,61 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(ActionToken::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(ActionToken::Jti)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(ActionToken::Uid).uuid().not_null())
+					.col(ColumnDef::new(ActionToken::Kind).string().not_null())
+					.col(ColumnDef::new(ActionToken::Expiry).date_time().not_null())
+					.col(ColumnDef::new(ActionToken::ConsumedAt).date_time())
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("action_tokens_uid_index")
+					.if_not_exists()
+					.table(ActionToken::Table)
+					.col(ActionToken::Uid)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(ActionToken::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum ActionToken {
+	#[iden = "action_tokens"]
+	Table,
+	Jti,
+	Uid,
+	Kind,
+	Expiry,
+	ConsumedAt,
+}
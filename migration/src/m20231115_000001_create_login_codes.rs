@@ -0,0 +1,43 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(LoginCode::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(LoginCode::Uid)
+							.uuid()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(LoginCode::CodeHash).string().not_null())
+					.col(ColumnDef::new(LoginCode::Attempts).integer().not_null())
+					.col(ColumnDef::new(LoginCode::Expiry).date_time().not_null())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(LoginCode::Table).if_exists().to_owned())
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum LoginCode {
+	#[iden = "login_codes"]
+	Table,
+	Uid,
+	CodeHash,
+	Attempts,
+	Expiry,
+}
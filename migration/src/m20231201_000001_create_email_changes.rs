@@ -0,0 +1,46 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(EmailChange::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(EmailChange::Uid)
+							.uuid()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(EmailChange::NewEmail).string().not_null())
+					.col(ColumnDef::new(EmailChange::Expiry).date_time().not_null())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(EmailChange::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum EmailChange {
+	#[iden = "email_changes"]
+	Table,
+	Uid,
+	NewEmail,
+	Expiry,
+}
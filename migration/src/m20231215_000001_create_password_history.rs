@@ -0,0 +1,93 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(PasswordHistory::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(PasswordHistory::Id)
+							.uuid()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(PasswordHistory::Uid).uuid().not_null())
+					.col(
+						ColumnDef::new(PasswordHistory::Password)
+							.string()
+							.not_null(),
+					)
+					.col(
+						ColumnDef::new(PasswordHistory::CreatedAt)
+							.date_time()
+							.not_null(),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("password_history_uid_index")
+					.if_not_exists()
+					.table(PasswordHistory::Table)
+					.col(PasswordHistory::Uid)
+					.to_owned(),
+			)
+			.await?;
+
+		// Users from before this column existed are treated as having set their password when they signed up
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.add_column(ColumnDef::new(User::PasswordChangedAt).date_time())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.drop_column(User::PasswordChangedAt)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.drop_table(
+				Table::drop()
+					.table(PasswordHistory::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum PasswordHistory {
+	#[iden = "password_history"]
+	Table,
+	Id,
+	Uid,
+	Password,
+	CreatedAt,
+}
+
+#[derive(Iden)]
+enum User {
+	#[iden = "users"]
+	Table,
+	PasswordChangedAt,
+}
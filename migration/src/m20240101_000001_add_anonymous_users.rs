@@ -0,0 +1,67 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.add_column(
+						ColumnDef::new(User::Anonymous)
+							.boolean()
+							.not_null()
+							.default(false),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		// Set when an anonymous user starts an OAuth login to link the identity to their account
+		manager
+			.alter_table(
+				Table::alter()
+					.table(OAuthState::Table)
+					.add_column(ColumnDef::new(OAuthState::Uid).uuid())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(OAuthState::Table)
+					.drop_column(OAuthState::Uid)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.drop_column(User::Anonymous)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum User {
+	#[iden = "users"]
+	Table,
+	Anonymous,
+}
+
+#[derive(Iden)]
+enum OAuthState {
+	#[iden = "oauth_states"]
+	Table,
+	Uid,
+}
@@ -0,0 +1,82 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.add_column(ColumnDef::new(User::Phone).string())
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.add_column(
+						ColumnDef::new(User::PhoneVerified)
+							.boolean()
+							.not_null()
+							.default(false),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		// Users without a phone number are NULL, which the unique index allows any number of
+		manager
+			.create_index(
+				Index::create()
+					.name("users_phone")
+					.if_not_exists()
+					.table(User::Table)
+					.col(User::Phone)
+					.unique()
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_index(
+				Index::drop()
+					.name("users_phone")
+					.table(User::Table)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.drop_column(User::PhoneVerified)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.drop_column(User::Phone)
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum User {
+	#[iden = "users"]
+	Table,
+	Phone,
+	PhoneVerified,
+}
@@ -0,0 +1,304 @@
+use sea_orm_migration::{
+	prelude::*,
+	sea_orm::{
+		prelude::{DateTime, Uuid},
+		ConnectionTrait,
+	},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Replaces `oauth_identities` with `identities`, which holds every way a user can log in.
+///
+/// OAuth identities are copied over. Every other user gets the identities they could already log in with:
+/// a password identity when they have a password, or a magic link identity for their email otherwise, and
+/// a phone identity when they have a phone number.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Identity::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(Identity::Id).uuid().not_null().primary_key())
+					.col(ColumnDef::new(Identity::Uid).uuid().not_null())
+					.col(ColumnDef::new(Identity::Provider).string().not_null())
+					.col(
+						ColumnDef::new(Identity::ProviderSubject)
+							.string()
+							.not_null(),
+					)
+					.col(ColumnDef::new(Identity::Email).string())
+					.col(ColumnDef::new(Identity::LinkedAt).date_time().not_null())
+					.col(ColumnDef::new(Identity::LastLogin).date_time())
+					.to_owned(),
+			)
+			.await?;
+		// A subject is only unique within the provider that issued it
+		manager
+			.create_index(
+				Index::create()
+					.if_not_exists()
+					.name("identities_provider_subject")
+					.table(Identity::Table)
+					.col(Identity::Provider)
+					.col(Identity::ProviderSubject)
+					.unique()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.if_not_exists()
+					.name("identities_uid")
+					.table(Identity::Table)
+					.col(Identity::Uid)
+					.to_owned(),
+			)
+			.await?;
+
+		let connection = manager.get_connection();
+		let backend = manager.get_database_backend();
+
+		connection
+			.execute(
+				backend.build(
+					Query::insert()
+						.into_table(Identity::Table)
+						.columns([
+							Identity::Id,
+							Identity::Uid,
+							Identity::Provider,
+							Identity::ProviderSubject,
+							Identity::Email,
+							Identity::LinkedAt,
+							Identity::LastLogin,
+						])
+						.select_from(
+							Query::select()
+								.columns([
+									OauthIdentity::Id,
+									OauthIdentity::Uid,
+									OauthIdentity::Provider,
+									OauthIdentity::Subject,
+									OauthIdentity::Email,
+									OauthIdentity::CreatedAt,
+									OauthIdentity::LastLogin,
+								])
+								.from(OauthIdentity::Table)
+								.to_owned(),
+						)
+						.map_err(|e| DbErr::Migration(e.to_string()))?,
+				),
+			)
+			.await?;
+
+		let users = connection
+			.query_all(
+				backend.build(
+					Query::select()
+						.columns([
+							User::Uid,
+							User::Email,
+							User::Password,
+							User::Phone,
+							User::CreatedAt,
+						])
+						.from(User::Table)
+						.and_where(Expr::col(User::Anonymous).eq(false)),
+				),
+			)
+			.await?;
+		for user in users {
+			let uid: Uuid = user.try_get("", "uid")?;
+			let email: String = user.try_get("", "email")?;
+			let password: String = user.try_get("", "password")?;
+			let phone: Option<String> = user.try_get("", "phone")?;
+			let created_at: DateTime = user.try_get("", "created_at")?;
+
+			let mut identities = vec![];
+			// Users who signed up with a phone number have a placeholder instead of an email
+			if !email.starts_with("phone:") {
+				let provider = match password.as_str() {
+					"0" => "magic_link",
+					_ => "password",
+				};
+				identities.push((provider, email.to_owned(), Some(email)));
+			}
+			if let Some(phone) = phone {
+				identities.push(("phone", phone, None));
+			}
+
+			for (provider, subject, email) in identities {
+				connection
+					.execute(
+						backend.build(
+							Query::insert()
+								.into_table(Identity::Table)
+								.columns([
+									Identity::Id,
+									Identity::Uid,
+									Identity::Provider,
+									Identity::ProviderSubject,
+									Identity::Email,
+									Identity::LinkedAt,
+								])
+								.values_panic([
+									Uuid::new_v4().into(),
+									uid.into(),
+									provider.into(),
+									subject.into(),
+									email.into(),
+									created_at.into(),
+								]),
+						),
+					)
+					.await?;
+			}
+		}
+
+		manager
+			.drop_table(
+				Table::drop()
+					.table(OauthIdentity::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(OauthIdentity::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(OauthIdentity::Id)
+							.uuid()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(OauthIdentity::Uid).uuid().not_null())
+					.col(ColumnDef::new(OauthIdentity::Provider).string().not_null())
+					.col(ColumnDef::new(OauthIdentity::Subject).string().not_null())
+					.col(ColumnDef::new(OauthIdentity::Email).string())
+					.col(
+						ColumnDef::new(OauthIdentity::CreatedAt)
+							.date_time()
+							.not_null(),
+					)
+					.col(ColumnDef::new(OauthIdentity::LastLogin).date_time())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.if_not_exists()
+					.name("oauth_identities_provider_subject")
+					.table(OauthIdentity::Table)
+					.col(OauthIdentity::Provider)
+					.col(OauthIdentity::Subject)
+					.unique()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.if_not_exists()
+					.name("oauth_identities_uid")
+					.table(OauthIdentity::Table)
+					.col(OauthIdentity::Uid)
+					.to_owned(),
+			)
+			.await?;
+
+		// Only OAuth identities have somewhere to go. The others are implied by the user's columns again.
+		let backend = manager.get_database_backend();
+		manager
+			.get_connection()
+			.execute(
+				backend.build(
+					Query::insert()
+						.into_table(OauthIdentity::Table)
+						.columns([
+							OauthIdentity::Id,
+							OauthIdentity::Uid,
+							OauthIdentity::Provider,
+							OauthIdentity::Subject,
+							OauthIdentity::Email,
+							OauthIdentity::CreatedAt,
+							OauthIdentity::LastLogin,
+						])
+						.select_from(
+							Query::select()
+								.columns([
+									Identity::Id,
+									Identity::Uid,
+									Identity::Provider,
+									Identity::ProviderSubject,
+									Identity::Email,
+									Identity::LinkedAt,
+									Identity::LastLogin,
+								])
+								.from(Identity::Table)
+								.and_where(Expr::col(Identity::Provider).is_not_in([
+									"password",
+									"magic_link",
+									"phone",
+								]))
+								.to_owned(),
+						)
+						.map_err(|e| DbErr::Migration(e.to_string()))?,
+				),
+			)
+			.await?;
+
+		manager
+			.drop_table(Table::drop().table(Identity::Table).if_exists().to_owned())
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum Identity {
+	#[iden = "identities"]
+	Table,
+	Id,
+	Uid,
+	Provider,
+	ProviderSubject,
+	Email,
+	LinkedAt,
+	LastLogin,
+}
+
+#[derive(Iden)]
+enum OauthIdentity {
+	#[iden = "oauth_identities"]
+	Table,
+	Id,
+	Uid,
+	Provider,
+	Subject,
+	Email,
+	CreatedAt,
+	LastLogin,
+}
+
+#[derive(Iden)]
+enum User {
+	#[iden = "users"]
+	Table,
+	Uid,
+	Email,
+	Password,
+	Phone,
+	CreatedAt,
+	Anonymous,
+}
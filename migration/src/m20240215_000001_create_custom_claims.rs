@@ -0,0 +1,49 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(CustomClaims::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(CustomClaims::Uid)
+							.uuid()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(CustomClaims::Claims).text().not_null())
+					.col(
+						ColumnDef::new(CustomClaims::UpdatedAt)
+							.date_time()
+							.not_null(),
+					)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(CustomClaims::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum CustomClaims {
+	Table,
+	Uid,
+	Claims,
+	UpdatedAt,
+}
@@ -0,0 +1,234 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Roles group permissions, and groups group roles. Users and admins are members of roles and groups,
+/// and have every permission of their roles and their groups' roles.
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(Permission::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(Permission::Name)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(Permission::Description).string())
+					.col(ColumnDef::new(Permission::CreatedAt).date_time().not_null())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_table(
+				Table::create()
+					.table(Role::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(Role::Name).string().not_null().primary_key())
+					.col(ColumnDef::new(Role::Description).string())
+					.col(ColumnDef::new(Role::CreatedAt).date_time().not_null())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_table(
+				Table::create()
+					.table(Group::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(Group::Name)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(Group::Description).string())
+					.col(ColumnDef::new(Group::CreatedAt).date_time().not_null())
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_table(
+				Table::create()
+					.table(RolePermission::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(RolePermission::Role).string().not_null())
+					.col(
+						ColumnDef::new(RolePermission::Permission)
+							.string()
+							.not_null(),
+					)
+					.primary_key(
+						Index::create()
+							.col(RolePermission::Role)
+							.col(RolePermission::Permission),
+					)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_table(
+				Table::create()
+					.table(GroupRole::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(GroupRole::Group).string().not_null())
+					.col(ColumnDef::new(GroupRole::Role).string().not_null())
+					.primary_key(Index::create().col(GroupRole::Group).col(GroupRole::Role))
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_table(
+				Table::create()
+					.table(RoleMember::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(RoleMember::Role).string().not_null())
+					.col(ColumnDef::new(RoleMember::Uid).uuid().not_null())
+					.primary_key(Index::create().col(RoleMember::Role).col(RoleMember::Uid))
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_table(
+				Table::create()
+					.table(GroupMember::Table)
+					.if_not_exists()
+					.col(ColumnDef::new(GroupMember::Group).string().not_null())
+					.col(ColumnDef::new(GroupMember::Uid).uuid().not_null())
+					.primary_key(
+						Index::create()
+							.col(GroupMember::Group)
+							.col(GroupMember::Uid),
+					)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.if_not_exists()
+					.name("role_members_uid")
+					.table(RoleMember::Table)
+					.col(RoleMember::Uid)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.if_not_exists()
+					.name("group_members_uid")
+					.table(GroupMember::Table)
+					.col(GroupMember::Uid)
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(RolePermission::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.drop_table(Table::drop().table(GroupRole::Table).if_exists().to_owned())
+			.await?;
+		manager
+			.drop_table(
+				Table::drop()
+					.table(RoleMember::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.drop_table(
+				Table::drop()
+					.table(GroupMember::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.drop_table(
+				Table::drop()
+					.table(Permission::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.drop_table(Table::drop().table(Role::Table).if_exists().to_owned())
+			.await?;
+		manager
+			.drop_table(Table::drop().table(Group::Table).if_exists().to_owned())
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum Permission {
+	#[iden = "permissions"]
+	Table,
+	Name,
+	Description,
+	CreatedAt,
+}
+
+#[derive(Iden)]
+enum Role {
+	#[iden = "roles"]
+	Table,
+	Name,
+	Description,
+	CreatedAt,
+}
+
+#[derive(Iden)]
+enum Group {
+	#[iden = "groups"]
+	Table,
+	Name,
+	Description,
+	CreatedAt,
+}
+
+#[derive(Iden)]
+enum RolePermission {
+	#[iden = "role_permissions"]
+	Table,
+	Role,
+	Permission,
+}
+
+#[derive(Iden)]
+enum GroupRole {
+	#[iden = "group_roles"]
+	Table,
+	Group,
+	Role,
+}
+
+#[derive(Iden)]
+enum RoleMember {
+	#[iden = "role_members"]
+	Table,
+	Role,
+	Uid,
+}
+
+#[derive(Iden)]
+enum GroupMember {
+	#[iden = "group_members"]
+	Table,
+	Group,
+	Uid,
+}
@@ -0,0 +1,46 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(MfaChallenge::Table)
+					.if_not_exists()
+					.col(
+						ColumnDef::new(MfaChallenge::Jti)
+							.string()
+							.not_null()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(MfaChallenge::Attempts).integer().not_null())
+					.col(ColumnDef::new(MfaChallenge::Expiry).date_time().not_null())
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(
+				Table::drop()
+					.table(MfaChallenge::Table)
+					.if_exists()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum MfaChallenge {
+	#[iden = "mfa_challenges"]
+	Table,
+	Jti,
+	Attempts,
+	Expiry,
+}
@@ -0,0 +1,167 @@
+use sea_orm_migration::{
+	prelude::*,
+	sea_orm::{prelude::Uuid, ConnectionTrait},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+// SQLite can't change a column to allow NULL, so the email moves to a new column instead
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		let connection = manager.get_connection();
+		let backend = manager.get_database_backend();
+
+		manager
+			.drop_index(
+				Index::drop()
+					.name("users_email")
+					.table(User::Table)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.rename_column(User::Email, User::EmailOld)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.add_column(ColumnDef::new(User::Email).string())
+					.to_owned(),
+			)
+			.await?;
+
+		// Users who signed up with a phone number had a placeholder, which can't be a real email
+		connection
+			.execute(
+				backend.build(
+					Query::update()
+						.table(User::Table)
+						.value(User::Email, Expr::col(User::EmailOld))
+						.and_where(Expr::col(User::EmailOld).not_like("phone:%")),
+				),
+			)
+			.await?;
+
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.drop_column(User::EmailOld)
+					.to_owned(),
+			)
+			.await?;
+
+		// Users without an email are NULL, which the unique index allows any number of
+		manager
+			.create_index(
+				Index::create()
+					.name("users_email")
+					.if_not_exists()
+					.table(User::Table)
+					.col(User::Email)
+					.unique()
+					.to_owned(),
+			)
+			.await
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		let connection = manager.get_connection();
+		let backend = manager.get_database_backend();
+
+		let users = connection
+			.query_all(
+				backend.build(
+					Query::select()
+						.column(User::Uid)
+						.from(User::Table)
+						.and_where(Expr::col(User::Email).is_null()),
+				),
+			)
+			.await?;
+		for user in users {
+			let uid: Uuid = user.try_get("", "uid")?;
+			connection
+				.execute(
+					backend.build(
+						Query::update()
+							.table(User::Table)
+							.value(User::Email, format!("phone:{uid}"))
+							.and_where(Expr::col(User::Uid).eq(uid)),
+					),
+				)
+				.await?;
+		}
+
+		manager
+			.drop_index(
+				Index::drop()
+					.name("users_email")
+					.table(User::Table)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.rename_column(User::Email, User::EmailOld)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.add_column(ColumnDef::new(User::Email).string().not_null().default(""))
+					.to_owned(),
+			)
+			.await?;
+		connection
+			.execute(
+				backend.build(
+					Query::update()
+						.table(User::Table)
+						.value(User::Email, Expr::col(User::EmailOld)),
+				),
+			)
+			.await?;
+		manager
+			.alter_table(
+				Table::alter()
+					.table(User::Table)
+					.drop_column(User::EmailOld)
+					.to_owned(),
+			)
+			.await?;
+
+		manager
+			.create_index(
+				Index::create()
+					.name("users_email")
+					.if_not_exists()
+					.table(User::Table)
+					.col(User::Email)
+					.unique()
+					.to_owned(),
+			)
+			.await
+	}
+}
+
+#[derive(Iden)]
+enum User {
+	#[iden = "users"]
+	Table,
+	Uid,
+	Email,
+	EmailOld,
+}
@@ -0,0 +1,43 @@
+use async_trait::async_trait;
+use log::info;
+use std::{fs::OpenOptions, io::Write, path::PathBuf, sync::Mutex};
+
+use crate::{SmsError, SmsSender};
+
+/// Logs messages instead of sending them, for development and tests. When a path is given, each
+/// message is also appended to it as a line of JSON with `to` and `body`.
+#[derive(Debug)]
+pub struct FileSender {
+	path: Option<PathBuf>,
+	// Keeps lines from different requests apart
+	lock: Mutex<()>,
+}
+
+impl FileSender {
+	pub fn new(path: Option<PathBuf>) -> Self {
+		Self {
+			path,
+			lock: Mutex::new(()),
+		}
+	}
+}
+
+#[async_trait]
+impl SmsSender for FileSender {
+	async fn send(&self, to: &str, body: &str) -> Result<(), SmsError> {
+		info!("SMS to {to}: {body}");
+
+		let path = match self.path {
+			Some(ref path) => path,
+			None => return Ok(()),
+		};
+		let line = serde_json::json!({ "to": to, "body": body }).to_string() + "\n";
+		let _lock = self.lock.lock().unwrap();
+		OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.and_then(|mut file| file.write_all(line.as_bytes()))
+			.map_err(|e| SmsError(format!("Unable to write to {}: {e}", path.display())))
+	}
+}
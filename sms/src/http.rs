@@ -0,0 +1,48 @@
+use async_trait::async_trait;
+
+use crate::{SmsError, SmsSender};
+
+/// Sends messages by posting `{"from", "to", "body"}` as JSON to a provider's endpoint, or to a gateway
+/// that forwards them to one
+#[derive(Debug)]
+pub struct HttpSender {
+	url: String,
+	from: String,
+	/// Sent as a bearer token when set
+	auth_token: Option<String>,
+	client: reqwest::Client,
+}
+
+impl HttpSender {
+	pub fn new(url: String, from: String, auth_token: Option<String>) -> Self {
+		Self {
+			url,
+			from,
+			auth_token,
+			client: reqwest::Client::new(),
+		}
+	}
+}
+
+#[async_trait]
+impl SmsSender for HttpSender {
+	async fn send(&self, to: &str, body: &str) -> Result<(), SmsError> {
+		let mut request = self.client.post(&self.url).json(&serde_json::json!({
+			"from": self.from,
+			"to": to,
+			"body": body,
+		}));
+		if let Some(ref token) = self.auth_token {
+			request = request.bearer_auth(token);
+		}
+
+		let response = request
+			.send()
+			.await
+			.map_err(|e| SmsError(format!("Unable to reach the SMS provider: {e}")))?;
+		match response.status() {
+			status if status.is_success() => Ok(()),
+			status => Err(SmsError(format!("The SMS provider responded with {status}"))),
+		}
+	}
+}
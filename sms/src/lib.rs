@@ -0,0 +1,23 @@
+use async_trait::async_trait;
+use std::fmt;
+
+pub mod file;
+pub mod http;
+pub mod otp;
+
+/// Delivers text messages. Numbers are in E.164 format, such as `+15555550100`.
+#[async_trait]
+pub trait SmsSender: fmt::Debug + Send + Sync {
+	async fn send(&self, to: &str, body: &str) -> Result<(), SmsError>;
+}
+
+#[derive(Debug)]
+pub struct SmsError(pub String);
+
+impl fmt::Display for SmsError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+
+impl std::error::Error for SmsError {}
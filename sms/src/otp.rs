@@ -0,0 +1,8 @@
+use crate::{SmsError, SmsSender};
+
+/// Sends a one-time login code
+pub async fn send(sender: &dyn SmsSender, to: &str, code: &str) -> Result<(), SmsError> {
+	sender
+		.send(to, &format!("{code} is your login code. Don't share it with anyone."))
+		.await
+}
@@ -9,17 +9,25 @@ use actix_web::{
 	App, HttpServer,
 };
 use actix_cors::Cors;
-use api::{health::ws::WSData, AppState, JsonError};
+use api::{
+	breached_passwords::BreachedPasswords, health::ws::WSData, keys::KeyRing, projects::Projects,
+	AppState, Config, JsonError,
+};
 use clokwerk::{AsyncScheduler, TimeUnits};
+use middlewares::{
+	permissions::requires,
+	projects::ProjectMiddlewareFactory,
+	rate_limit::{RateLimitMiddlewareFactory, RateLimitStore},
+};
 use migration::{Migrator, MigratorTrait};
+use sea_orm::DatabaseConnection;
 use sysinfo::{System, SystemExt};
 use tokio::{
 	spawn,
 	time::{sleep, Duration},
 };
 use uaparser::UserAgentParser;
-use util::{load_config::load_config, prune_database};
-use log::debug;
+use util::{load_config::load_config, prune_database, rotate_keys, transfer_users};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -36,15 +44,49 @@ async fn main() -> std::io::Result<()> {
 
 	Migrator::up(&connection, None).await.unwrap();
 
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	if let Some(result) = transfer_users::run(&connection, &config, &args).await {
+		return result;
+	}
+
+	let key_ring = KeyRing::load(&connection, &config.signing)
+		.await
+		.expect("Unable to load the signing keys");
+
+	let breached_passwords = config.breached_passwords.as_ref().map(|breached_config| {
+		BreachedPasswords::load(breached_config)
+			.expect("Unable to load the breached password dataset")
+	});
+
+	let sms = config.sms.as_ref().map(|sms_config| sms_config.sender());
+
 	let connection2 = sea_orm::Database::connect(config.connection_url.to_owned())
 		.await
 		.unwrap();
 
-	// Build the scheduler and add a job to it
+	// Build the scheduler and add the jobs to it
 	let mut scheduler = AsyncScheduler::new();
-	scheduler
-		.every(15.minutes())
-		.run(move || prune_database::run(connection2.to_owned()));
+	schedule_jobs(&mut scheduler, connection2, key_ring.to_owned(), &config);
+
+	// Every project has its own database and signing keys, which are kept like the default project's
+	let mut project_states = vec![];
+	for project in config.projects.iter() {
+		let project_config = config.for_project(project);
+		let project_connection = sea_orm::Database::connect(project_config.connection_url.to_owned())
+			.await
+			.unwrap();
+		Migrator::up(&project_connection, None).await.unwrap();
+		let project_key_ring = KeyRing::load(&project_connection, &project_config.signing)
+			.await
+			.expect("Unable to load the signing keys");
+		schedule_jobs(
+			&mut scheduler,
+			project_connection.to_owned(),
+			project_key_ring.to_owned(),
+			&project_config,
+		);
+		project_states.push((project.to_owned(), project_config, project_connection, project_key_ring));
+	}
 
 	// Move the scheduler into a new thread
 	spawn(async move {
@@ -56,6 +98,9 @@ async fn main() -> std::io::Result<()> {
 		}
 	});
 
+	// Every worker must share the store, or each would count requests on its own
+	let rate_limit_store = RateLimitStore::new(config.rate_limit.store, connection.to_owned());
+
 	HttpServer::new(move || {
 		let ua_parser = UserAgentParser::from_yaml("regexes.yaml").unwrap();
 
@@ -74,7 +119,22 @@ async fn main() -> std::io::Result<()> {
 			auth: false,
 		});
 
-        let mut cors = Cors::permissive();
+		let mut projects = Projects::default();
+		for (project, project_config, project_connection, project_key_ring) in project_states.iter() {
+			projects.add(
+				project,
+				AppState {
+					connection: project_connection.to_owned(),
+					config: project_config.to_owned(),
+					ua_parser: UserAgentParser::from_yaml("regexes.yaml").unwrap(),
+					key_ring: project_key_ring.to_owned(),
+					breached_passwords: breached_passwords.to_owned(),
+					sms: sms.to_owned(),
+				},
+			);
+		}
+
+        let cors = Cors::permissive();
         // TODO: Remove permissive, and use the below code with allowed methods added
         // for uri in config.allowed_origins.iter() {
         //     cors = cors.allowed_origin(uri);
@@ -86,6 +146,9 @@ async fn main() -> std::io::Result<()> {
 				connection: connection.to_owned(),
 				config: config.to_owned(),
 				ua_parser,
+				key_ring: key_ring.to_owned(),
+				breached_passwords: breached_passwords.to_owned(),
+				sms: sms.to_owned(),
 			}))
 			.service(
 				web::resource("/api/health/ws").route(web::get().to(api::health::ws::sysinfo_ws)),
@@ -94,13 +157,57 @@ async fn main() -> std::io::Result<()> {
 			.app_data(ws_data)
 			.configure(api::auth::add_routes)
             .configure(api::health::add_routes)
-            .configure(api::admin::add_routes)
+            .configure(|cfg| {
+                api::admin::add_routes(cfg, |route, permission| {
+                    route.wrap(requires(permission, key_ring.clone()))
+                })
+            })
+            .configure(api::idp::add_routes)
             .wrap(middleware::DefaultHeaders::new().add((SERVER, "TurboCore")))
 			.wrap(Logger::default())
-            .wrap(middlewares::admin_middleware::AdminMiddlewareFactory::new(config.secret_key.clone(), connection.clone()))
+            .wrap(middlewares::admin_middleware::AdminMiddlewareFactory::new(key_ring.clone(), connection.clone()))
+			.wrap(RateLimitMiddlewareFactory::new(
+				config.rate_limit.policies.to_owned(),
+				rate_limit_store.to_owned(),
+				key_ring.to_owned(),
+				config.trusted_proxies.to_owned(),
+			))
+			.wrap(ProjectMiddlewareFactory::new(projects))
             .wrap(cors)
 	})
 	.bind(bind_addr)?
 	.run()
 	.await
 }
+
+/// Schedules the jobs that prune a project's database and rotate its signing keys
+fn schedule_jobs(
+	scheduler: &mut AsyncScheduler,
+	connection: DatabaseConnection,
+	key_ring: KeyRing,
+	config: &Config,
+) {
+	let connection2 = connection.to_owned();
+	let token_config = config.tokens.to_owned();
+	let lockout_config = config.lockout.to_owned();
+	let anonymous_config = config.anonymous_users.to_owned();
+	scheduler.every(15.minutes()).run(move || {
+		prune_database::run(
+			connection2.to_owned(),
+			token_config.to_owned(),
+			lockout_config.to_owned(),
+			anonymous_config.to_owned(),
+		)
+	});
+
+	// Rotate the signing keys, and pick up keys created by other instances. This must run more often
+	// than a new key waits before it starts signing.
+	let signing_config = config.signing.to_owned();
+	scheduler.every(5.minutes()).run(move || {
+		rotate_keys::run(
+			connection.to_owned(),
+			key_ring.to_owned(),
+			signing_config.to_owned(),
+		)
+	});
+}
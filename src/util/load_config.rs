@@ -1,10 +1,11 @@
-use api::{Argon2Config, Config, EmailConfig};
+use api::{
+	AnonymousUserConfig, Argon2Config, BreachedPasswordConfig, Config, EmailConfig, IdentityProviderConfig,
+	LockoutConfig, OAuthProviderConfig, PasswordHashConfig, PasswordPolicyConfig, ProjectConfig,
+	RateLimitConfig, SigningConfig, SmsConfig, TokenConfig, WebauthnConfig,
+};
 use hmac::{Hmac, Mac};
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::transport::smtp::client::{Tls, TlsParameters};
-use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::{collections::HashSet, fs, net::IpAddr};
 use uuid::Uuid;
 
 /// The config struct represents data located in the config.json file.
@@ -18,9 +19,23 @@ pub struct ConfigInternal {
 	pub debug_level: Option<String>,
 	pub bind_addr: Option<String>,
 	pub argon2_params: Option<Argon2Config>,
+	pub password_hashing: Option<PasswordHashConfig>,
 	pub email: Option<EmailConfig>,
+	pub sms: Option<SmsConfig>,
 	pub minimum_password_strength: Option<u8>,
-    pub allowed_origins: Vec<String>
+	pub password_policy: Option<PasswordPolicyConfig>,
+    pub allowed_origins: Vec<String>,
+	pub webauthn: Option<WebauthnConfig>,
+	pub oauth_providers: Option<Vec<OAuthProviderConfig>>,
+	pub identity_provider: Option<IdentityProviderConfig>,
+	pub signing: Option<SigningConfig>,
+	pub tokens: Option<TokenConfig>,
+	pub lockout: Option<LockoutConfig>,
+	pub anonymous_users: Option<AnonymousUserConfig>,
+	pub rate_limit: Option<RateLimitConfig>,
+	pub trusted_proxies: Option<Vec<IpAddr>>,
+	pub breached_passwords: Option<BreachedPasswordConfig>,
+	pub projects: Option<Vec<ProjectConfig>>,
 }
 
 fn verify_connection_url(url: &str) -> bool {
@@ -28,6 +43,10 @@ fn verify_connection_url(url: &str) -> bool {
 	url.starts_with("mysql:") || url.starts_with("postgres:") || url.starts_with("sqlite:")
 }
 
+fn is_absolute_url(url: &str) -> bool {
+	url.starts_with("https://") || url.starts_with("http://")
+}
+
 /// Loads the contents of config.json and returns it as a Config object
 /// Panics if the contents are invalid JSON or if a key-value pair is not supported.
 pub fn load_config() -> Config {
@@ -35,6 +54,17 @@ pub fn load_config() -> Config {
 	let json_config: ConfigInternal =
 		serde_json::from_str(&config_str).expect("Failed to parse config file.");
 
+	// By default, passkeys are scoped to the host that TurboCore is served from
+	let webauthn = json_config
+		.webauthn
+		.unwrap_or_else(|| WebauthnConfig::for_base_url(&json_config.base_url));
+
+	// `bcrypt_cost` predates the password hashing section, and is still honoured
+	let mut password_hashing = json_config.password_hashing.unwrap_or_default();
+	if let Some(cost) = json_config.bcrypt_cost {
+		password_hashing.bcrypt_cost = cost;
+	}
+
 	let config = Config {
 		base_url: json_config.base_url,
 		connection_url: json_config.connection_url,
@@ -59,41 +89,25 @@ pub fn load_config() -> Config {
 			Some(addr) => addr,
 			None => "127.0.0.1:8080".to_string(),
 		},
-		argon2_config: match json_config.argon2_params {
-			Some(c) => c,
-			None => Argon2Config::default(),
-		},
+		argon2_config: json_config.argon2_params.unwrap_or_default(),
+		password_hashing,
 		minimum_password_strength: json_config.minimum_password_strength.unwrap_or(1),
-		mailer: match json_config.email {
-			Some(ref email_config) => {
-				let mut mailer =
-					AsyncSmtpTransport::<Tokio1Executor>::relay(&email_config.smtp_server)
-						.unwrap()
-						.port(email_config.smtp_port);
-				let tls = match email_config.smtp_encryption.as_str() {
-					"None" | "none" => Tls::None,
-					"TLS" | "tls" => Tls::Required(
-						TlsParameters::new_native(email_config.smtp_server.clone()).unwrap(),
-					),
-					"STARTTLS" | "starttls" => Tls::Opportunistic(
-						TlsParameters::new_native(email_config.smtp_server.clone()).unwrap(),
-					),
-					_ => {
-						panic!("Provided TLS method is not supported")
-					}
-				};
-				if !"".eq(&email_config.smtp_username) {
-					mailer = mailer.credentials(Credentials::new(
-						email_config.smtp_username.clone(),
-						email_config.smtp_password.clone(),
-					));
-				}
-				Some(mailer.tls(tls).build())
-			}
-			None => None,
-		},
+		password_policy: json_config.password_policy.unwrap_or_default(),
+		mailer: json_config.email.as_ref().map(|email_config| email_config.mailer()),
 		email: json_config.email,
-        allowed_origins: json_config.allowed_origins
+		sms: json_config.sms,
+        allowed_origins: json_config.allowed_origins,
+		webauthn,
+		oauth_providers: json_config.oauth_providers.unwrap_or_default(),
+		identity_provider: json_config.identity_provider,
+		signing: json_config.signing.unwrap_or_default(),
+		tokens: json_config.tokens.unwrap_or_default(),
+		lockout: json_config.lockout.unwrap_or_default(),
+		anonymous_users: json_config.anonymous_users.unwrap_or_default(),
+		rate_limit: json_config.rate_limit.unwrap_or_default(),
+		trusted_proxies: json_config.trusted_proxies.unwrap_or_default(),
+		breached_passwords: json_config.breached_passwords,
+		projects: json_config.projects.unwrap_or_default(),
 	};
 
 	if !verify_connection_url(&config.connection_url) {
@@ -102,5 +116,52 @@ pub fn load_config() -> Config {
 	if config.argon2_config.salt_length < 8 {
 		panic!("Salt length too short. Must be at least 8")
 	}
+	if !(4..=31).contains(&config.password_hashing.bcrypt_cost) {
+		panic!("Unsupported bcrypt cost: {}. Must be between 4 and 31", config.password_hashing.bcrypt_cost)
+	}
+	for provider in config.oauth_providers.iter() {
+		if provider.name.is_empty() || !provider.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+			panic!("Invalid OAuth provider name: {}", provider.name)
+		}
+		if api::auth::identities::RESERVED_PROVIDERS.contains(&provider.name.as_str()) {
+			panic!("OAuth provider name {} is reserved", provider.name)
+		}
+		if !provider.scopes.iter().any(|scope| scope == "openid") {
+			panic!("OAuth provider {} must request the openid scope", provider.name)
+		}
+	}
+	if let Some(ref idp) = config.identity_provider {
+		if !is_absolute_url(&idp.login_url) {
+			panic!("Invalid identity provider login URL: {}", idp.login_url)
+		}
+		for client in idp.clients.iter() {
+			if !client.redirect_uris.iter().all(|uri| is_absolute_url(uri)) {
+				panic!("Client {} has an invalid redirect URI", client.client_id)
+			}
+		}
+	}
+	let mut hosts = HashSet::new();
+	for (i, project) in config.projects.iter().enumerate() {
+		if project.id.is_empty() || !project.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+			panic!("Invalid project id: {}", project.id)
+		}
+		if config.projects[..i].iter().any(|other| other.id == project.id) {
+			panic!("Project {} is configured more than once", project.id)
+		}
+		if !verify_connection_url(&project.connection_url) {
+			panic!("Unsupported connection URL for project {}: {}", project.id, project.connection_url)
+		}
+		// Sharing a database would mix the projects' users
+		if project.connection_url == config.connection_url
+			|| config.projects[..i].iter().any(|other| other.connection_url == project.connection_url)
+		{
+			panic!("Project {} must have its own database", project.id)
+		}
+		for host in project.hosts.iter() {
+			if !hosts.insert(host.to_lowercase()) {
+				panic!("Host {} is used by more than one project", host)
+			}
+		}
+	}
 	config
 }
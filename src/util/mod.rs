@@ -1,2 +1,4 @@
 pub mod load_config;
 pub mod prune_database;
+pub mod rotate_keys;
+pub mod transfer_users;
@@ -1,12 +1,102 @@
+use api::{auth::anonymous, AnonymousUserConfig, LockoutConfig, TokenConfig};
 use chrono::{Duration, Utc};
-use entity::refresh_tokens;
-use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use entity::{
+	action_tokens, email_changes, login_codes, login_failures, mfa_challenges, oauth_states,
+	oidc_auth_codes, rate_limits, refresh_tokens, security_events, sessions, signing_keys,
+};
+use sea_orm::{ColumnTrait, Condition, DatabaseConnection, EntityTrait, QueryFilter};
 
-pub async fn run(database_connection: DatabaseConnection) {
-	// We will delete all refresh tokens that expired more than 21 days ago
-	let expiry_date = Utc::now() - Duration::days(21);
+pub async fn run(
+	database_connection: DatabaseConnection,
+	config: TokenConfig,
+	lockout: LockoutConfig,
+	anonymous_users: AnonymousUserConfig,
+) {
+	// We will delete all refresh tokens that expired more than `prune_after_days` ago
+	let expiry_date = Utc::now() - Duration::days(config.prune_after_days);
 	let _res = refresh_tokens::Entity::delete_many()
 		.filter(refresh_tokens::Column::Expiry.lte(expiry_date))
 		.exec(&database_connection)
 		.await;
+
+	// And the sessions they belonged to
+	let _res = sessions::Entity::delete_many()
+		.filter(sessions::Column::Expiry.lte(expiry_date))
+		.exec(&database_connection)
+		.await;
+
+	// Security events are kept for `security_event_retention_days`
+	let retention = Duration::days(config.security_event_retention_days);
+	let _res = security_events::Entity::delete_many()
+		.filter(security_events::Column::CreatedAt.lte(Utc::now() - retention))
+		.exec(&database_connection)
+		.await;
+
+	// Failed logins that have been forgotten, once their lock has expired
+	let _res = login_failures::Entity::delete_many()
+		.filter(
+			login_failures::Column::LastFailure
+				.lte(Utc::now().naive_utc() - Duration::seconds(lockout.reset_after)),
+		)
+		.filter(
+			Condition::any()
+				.add(login_failures::Column::LockedUntil.is_null())
+				.add(login_failures::Column::LockedUntil.lte(Utc::now().naive_utc())),
+		)
+		.exec(&database_connection)
+		.await;
+
+	// Emailed tokens that can no longer be used
+	let _res = action_tokens::Entity::delete_many()
+		.filter(action_tokens::Column::Expiry.lte(Utc::now().naive_utc()))
+		.exec(&database_connection)
+		.await;
+
+	// Email changes that were never confirmed
+	let _res = email_changes::Entity::delete_many()
+		.filter(email_changes::Column::Expiry.lte(Utc::now().naive_utc()))
+		.exec(&database_connection)
+		.await;
+
+	// Login codes that were never used
+	let _res = login_codes::Entity::delete_many()
+		.filter(login_codes::Column::Expiry.lte(Utc::now().naive_utc()))
+		.exec(&database_connection)
+		.await;
+
+	// MFA challenges that have expired
+	let _res = mfa_challenges::Entity::delete_many()
+		.filter(mfa_challenges::Column::Expiry.lte(Utc::now().naive_utc()))
+		.exec(&database_connection)
+		.await;
+
+	// Rate limit windows that have reset
+	let _res = rate_limits::Entity::delete_many()
+		.filter(rate_limits::Column::ResetsAt.lte(Utc::now().naive_utc()))
+		.exec(&database_connection)
+		.await;
+
+	// Abandoned OAuth logins
+	let _res = oauth_states::Entity::delete_many()
+		.filter(oauth_states::Column::Expiry.lte(Utc::now().naive_utc()))
+		.exec(&database_connection)
+		.await;
+
+	// Authorization codes that were never exchanged
+	let _res = oidc_auth_codes::Entity::delete_many()
+		.filter(oidc_auth_codes::Column::Expiry.lte(Utc::now().naive_utc()))
+		.exec(&database_connection)
+		.await;
+
+	// Retired signing keys, once every token they signed has expired
+	let _res = signing_keys::Entity::delete_many()
+		.filter(
+			signing_keys::Column::RetiredAt
+				.lte(Utc::now().naive_utc() - config.retired_key_lifetime()),
+		)
+		.exec(&database_connection)
+		.await;
+
+	// Anonymous users that can no longer get back into their account
+	let _res = anonymous::prune(&database_connection, &anonymous_users).await;
 }
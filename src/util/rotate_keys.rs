@@ -0,0 +1,15 @@
+use api::{keys::KeyRing, SigningConfig};
+use sea_orm::DatabaseConnection;
+
+pub async fn run(
+	database_connection: DatabaseConnection,
+	key_ring: KeyRing,
+	config: SigningConfig,
+) {
+	// Rotating also reloads the ring, which picks up keys created by other instances
+	match key_ring.rotate(&database_connection, &config).await {
+		Ok(true) => log::info!("Created a new signing key"),
+		Ok(false) => (),
+		Err(e) => log::error!("Unable to rotate signing keys. Error: {}", e.to_string()),
+	}
+}
@@ -0,0 +1,100 @@
+//! The `import-users` and `export-users` commands, for moving users in and out of TurboCore without going
+//! through the admin API:
+//!
+//! ```sh
+//! TurboCore import-users users.json firebase
+//! TurboCore export-users users.ndjson
+//! ```
+
+use api::{
+	admin::{
+		user_export,
+		user_import::{self, ImportFormat},
+	},
+	Config,
+};
+use sea_orm::DatabaseConnection;
+use std::{
+	fs::{self, File},
+	io::{self, BufWriter, Write},
+};
+
+/// Runs a command, and returns whether there was one to run. Anything else starts the server.
+pub async fn run(
+	connection: &DatabaseConnection,
+	config: &Config,
+	args: &[String],
+) -> Option<io::Result<()>> {
+	match args {
+		[command, path, rest @ ..] if command == "import-users" => {
+			Some(import(connection, config, path, rest.first()).await)
+		}
+		[command, path] if command == "export-users" => Some(export(connection, path).await),
+		[command, ..] if command == "import-users" || command == "export-users" => {
+			Some(Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"Usage: TurboCore import-users <file> [json|csv|firebase|auth0], or TurboCore export-users <file>",
+			)))
+		}
+		_ => None,
+	}
+}
+
+async fn import(
+	connection: &DatabaseConnection,
+	config: &Config,
+	path: &str,
+	format: Option<&String>,
+) -> io::Result<()> {
+	let format = match format {
+		Some(format) => format
+			.parse()
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+		None if path.ends_with(".csv") => ImportFormat::Csv,
+		None => ImportFormat::Json,
+	};
+
+	let input = fs::read(path)?;
+	let rows = user_import::parse(&input, format)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+	let report = user_import::import(connection, config, rows)
+		.await
+		.map_err(io::Error::other)?;
+
+	for error in report.errors.iter() {
+		eprintln!(
+			"Row {} ({}): {} {}",
+			error.row,
+			error.email.as_deref().unwrap_or("no email"),
+			error.error_code,
+			error.message
+		);
+	}
+	println!(
+		"Imported {} users, {} could not be imported",
+		report.imported,
+		report.errors.len()
+	);
+	Ok(())
+}
+
+async fn export(connection: &DatabaseConnection, path: &str) -> io::Result<()> {
+	let mut file = BufWriter::new(File::create(path)?);
+	let mut after = None;
+	let mut exported = 0;
+	loop {
+		let users = user_export::page(connection, after)
+			.await
+			.map_err(io::Error::other)?;
+		let full = users.len() as u64 == user_export::PAGE_SIZE;
+		after = users.last().map(|user| user.uid);
+		exported += users.len();
+		file.write_all(user_export::lines(users).as_bytes())?;
+		if !full {
+			break;
+		}
+	}
+	file.flush()?;
+	println!("Exported {exported} users to {path}");
+	Ok(())
+}